@@ -11,6 +11,28 @@ pub fn push_ring(c: &mut Criterion) {
     });
 }
 
+/// Pushing into a small ring, so most of the pushes are wrapping around it
+pub fn push_small_ring(c: &mut Criterion) {
+    let mut dr = DumpRing::new(4);
+    let pl = Payload::default();
+    c.bench_function("push small ring", |b| {
+        b.iter(|| {
+            dr.next_push().clone_from(black_box(&pl));
+        })
+    });
+}
+
+/// Reading the ring back oldest first, from partway around it
+pub fn iter_ring(c: &mut Criterion) {
+    let mut dr = DumpRing::new(15);
+    for count in 0..(3 << 13) {
+        dr.next_push().count = count;
+    }
+    c.bench_function("iterate ring", |b| {
+        b.iter(|| black_box(dr.iter_ordered().map(|pl| pl.count).sum::<u64>()))
+    });
+}
+
 pub fn to_ndarray(c: &mut Criterion) {
     let payload = Payload::default();
     c.bench_function("payload to nd", |b| {
@@ -18,5 +40,5 @@ pub fn to_ndarray(c: &mut Criterion) {
     });
 }
 
-criterion_group!(benches, push_ring, to_ndarray,);
+criterion_group!(benches, push_ring, push_small_ring, iter_ring, to_ndarray,);
 criterion_main!(benches);
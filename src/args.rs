@@ -1,68 +1,412 @@
-use clap::{Parser, Subcommand};
+use crate::{
+    capture,
+    convert::Convert,
+    discover::{Discover, Subnet},
+    dumps,
+    events::Query,
+    exfil,
+    fpga::{self, Board},
+    gps::GpsSource,
+    injection::Polarization,
+    observation::Observation,
+    peek,
+    servo::ServoMode,
+    simulate::Simulate,
+    weights::{self, BandMask},
+};
+use clap::{Parser, Subcommand, ValueEnum};
 use regex::Regex;
-use std::{net::SocketAddr, ops::RangeInclusive, path::PathBuf};
+use std::{
+    ffi::OsString,
+    net::{Ipv4Addr, SocketAddr},
+    ops::RangeInclusive,
+    path::PathBuf,
+};
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    args_override_self = true
+)]
 pub struct Cli {
+    /// Start from the defaults for a common setup (explicit flags still win)
+    #[arg(long, value_enum)]
+    pub profile: Option<Profile>,
     /// Path to save voltage dumps
     #[arg(long, default_value = ".")]
     pub dump_path: PathBuf,
-    /// Path to save filterbanks
+    /// Path to save filterbanks (and PSRFITS)
     #[arg(long, default_value = ".")]
     pub filterbank_path: PathBuf,
+    /// Path to save the levels each 8-bit DADA stream was requantized with
+    #[arg(long, default_value = ".")]
+    pub dada_levels_path: PathBuf,
     /// CPU cores to which we'll build tasks. They should share a NUMA node.
     #[arg(long, default_value = "0:7", value_parser = parse_core_range)]
     pub core_range: RangeInclusive<usize>,
-    /// MAC address of the interface which data comes in on (used in ARP)
-    #[arg(long, value_parser=parse_mac)]
-    pub mac: [u8; 6],
-    /// Port which we expect packets to be directed to
-    #[arg(long, default_value_t = 60000)]
+    /// MAC address of the interface which data comes in on (used in ARP), read from `--nic` if not given
+    #[arg(long, value_parser=parse_mac, required_unless_present_any = ["self_test", "replay", "simulate", "nic"])]
+    pub mac: Option<[u8; 6]>,
+    /// Addresses of the SNAP's 10 GbE link
+    #[command(flatten)]
+    pub network: fpga::Network,
+    /// Network interface the SNAP data comes in on, to check where its interrupts are serviced
+    #[arg(long)]
+    pub nic: Option<String>,
+    /// Pin the NIC's interrupts and RPS/XPS masks to spare cores next to the capture thread (needs root)
+    #[arg(long, requires = "nic")]
+    pub pin_irqs: bool,
+    /// Port which we expect packets to be directed to, or one per board (comma separated, the
+    /// first is the SNAP we control and time against)
+    #[arg(long, default_values_t = [60000], value_delimiter = ',')]
     #[clap(value_parser = clap::value_parser!(u16).range(1..))]
-    pub cap_port: u16,
+    pub cap_port: Vec<u16>,
+    /// Most packets to pull from the socket per syscall
+    #[arg(long, default_value_t = capture::DEFAULT_BATCH)]
+    pub capture_batch: usize,
+    /// Packets past a gap in the packet count to wait for the missing ones before zero-filling them
+    #[arg(long, default_value_t = capture::DEFAULT_REORDER_WINDOW)]
+    pub reorder_window: u64,
+    /// What to send on in place of dropped packets (nan marks the spectra they end up in with NaNs)
+    #[arg(long, value_enum, default_value_t = capture::FillPolicy::Zeros)]
+    pub drop_fill: capture::FillPolicy,
+    /// Throw away packets whose count is this far past the expected one as corrupt (unless a run of
+    /// them agrees on it, as after an outage)
+    #[arg(long)]
+    pub max_count_jump: Option<u64>,
+    /// How to get packets off the NIC (xdp needs `--nic` and an XDP program redirecting to our map)
+    #[arg(long, value_enum, default_value_t = capture::CaptureBackend::Socket)]
+    pub capture_backend: capture::CaptureBackend,
+    #[command(flatten)]
+    pub socket: capture::SocketOptions,
+    #[command(flatten)]
+    pub xdp: capture::XdpConfig,
+    /// Replay the packets recorded in this pcap file instead of capturing (no SNAP needed)
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+    /// Replay as fast as the pipeline keeps up, rather than at the recorded cadence
+    #[arg(long, requires = "replay")]
+    pub replay_fast: bool,
+    /// Run on simulated packets (bandpassed noise) made in-process, instead of a SNAP
+    #[arg(long, conflicts_with = "replay")]
+    pub simulate: bool,
+    /// DM (pc cm^-3) of pulses to add to the simulated packets, every 10 seconds (none if unset)
+    #[arg(long, requires = "simulate")]
+    pub simulate_pulse_dm: Option<f64>,
+    /// Directory to record every captured packet to, as rotating pcap files
+    #[arg(long)]
+    pub record_raw: Option<PathBuf>,
+    /// Size (MiB) at which to start a new raw recording file
+    #[arg(long, default_value_t = 1024, requires = "record_raw")]
+    pub record_raw_file_size: u64,
+    /// Number of raw recording files to keep, deleting the oldest
+    #[arg(long, default_value_t = 10, requires = "record_raw")]
+    pub record_raw_keep: usize,
+    /// Directory to write the raw voltages to as VDIF (about 1 GB/s), alongside the exfil
+    #[arg(long)]
+    pub vdif_path: Option<PathBuf>,
+    /// Stop writing VDIF after this many seconds, for a short scan
+    #[arg(long, requires = "vdif_path")]
+    pub vdif_seconds: Option<f64>,
+    /// Two character VDIF station code
+    #[arg(long, default_value = "GR", value_parser = valid_station, requires = "vdif_path")]
+    pub vdif_station: [u8; 2],
     /// Port which we expect to receive trigger messages
     #[arg(long, default_value_t = 65432)]
     #[clap(value_parser = clap::value_parser!(u16).range(1..))]
     pub trig_port: u16,
+    /// Port on which to listen for Heimdall/T2 candidates to trigger dumps from (disabled if unset)
+    #[arg(long)]
+    #[clap(value_parser = clap::value_parser!(u16).range(1..))]
+    pub cand_port: Option<u16>,
+    /// Minimum S/N of a candidate to trigger a dump
+    #[arg(long, default_value_t = 10.0)]
+    pub cand_min_snr: f64,
+    /// Minimum DM (pc cm^-3) of a candidate to trigger a dump
+    #[arg(long, default_value_t = 20.0)]
+    pub cand_min_dm: f64,
+    /// Maximum boxcar filter index (width of 2^n samples) of a candidate to trigger a dump
+    #[arg(long, default_value_t = 10)]
+    pub cand_max_filter: u32,
+    /// The band and telescope we're observing with
+    #[command(flatten)]
+    pub observation: Observation,
+    /// Port on which to listen for pointing/source updates from the telescope control system (disabled if unset)
+    #[arg(long)]
+    #[clap(value_parser = clap::value_parser!(u16).range(1..))]
+    pub pointing_port: Option<u16>,
+    /// Address of the T2 machine to exchange heartbeats with (disabled if unset)
+    #[arg(long)]
+    pub t2_addr: Option<SocketAddr>,
+    /// Port which we expect to receive T2 heartbeats
+    #[arg(long, default_value_t = 65433)]
+    #[clap(value_parser = clap::value_parser!(u16).range(1..))]
+    pub t2_port: u16,
     /// Port to respond to prometheus requests for metrics
     #[arg(long, default_value_t = 8083)]
     #[clap(value_parser = clap::value_parser!(u16).range(1..))]
     pub metrics_port: u16,
+    /// Number of monitoring spectra (about 8.6 seconds each) to keep for `/spectrum/history`
+    #[arg(long, default_value_t = 420)]
+    pub spectrum_history: usize,
+    /// Port for the gRPC control plane
+    #[cfg(feature = "grpc")]
+    #[arg(long, default_value_t = 50051)]
+    #[clap(value_parser = clap::value_parser!(u16).range(1..))]
+    pub grpc_port: u16,
     /// Downsample power of 2, up to 9 (as that's the size of the capture window).
     #[clap(value_parser = clap::value_parser!(u32).range(1..=9))]
     #[arg(long, short, default_value_t = 2)]
     pub downsample_power: u32,
+    /// Compute full Stokes (I, Q, U, and V) and write all four to the exfil (NPOL=4 for DADA, four
+    /// IFs in a filterbank), rather than just Stokes I
+    #[arg(long)]
+    pub full_stokes: bool,
     /// Voltage buffer size as a power of 2
     #[arg(long, short, default_value_t = 15)]
     pub vbuf_power: u32,
-    /// Socket address of the SNAP Board
-    #[arg(long, default_value = "192.168.0.3:69")]
-    pub fpga_addr: SocketAddr,
+    /// Hold dump triggers that arrive before the voltage buffer has filled until it has (instead of
+    /// dumping startup zeros)
+    #[arg(long)]
+    pub defer_early_dumps: bool,
+    /// Only dump the voltages within this many seconds of a trigger's candidate (unless the trigger
+    /// gives its own window), rather than the whole ring
+    #[arg(long)]
+    pub dump_window: Option<f64>,
+    /// Deflate level of the voltage dumps, from 0 (uncompressed) to 9
+    #[clap(value_parser = clap::value_parser!(u32).range(0..=9))]
+    #[arg(long, default_value_t = 1)]
+    pub dump_compression: u32,
+    /// Merge triggers that come within this many seconds of the last dump into it, rather than
+    /// dumping again
+    #[arg(long)]
+    pub coalesce_triggers: Option<f64>,
+    /// Most voltage dumps to write in any hour, further triggers are dropped
+    #[arg(long)]
+    pub max_dumps_per_hour: Option<usize>,
+    /// Send voltage dumps on to a storage node (tcp://host:port, running `receive-dumps`) rather
+    /// than keeping them in `dump_path`
+    #[arg(long)]
+    pub dump_target: Option<dumps::stream::DumpTarget>,
+    /// File format of the voltage dumps
+    #[arg(long, value_enum, default_value_t = dumps::DumpFormat::Netcdf)]
+    pub dump_format: dumps::DumpFormat,
+    /// Seconds of (further downsampled) Stokes to keep for context around events (disabled if unset)
+    #[arg(long)]
+    pub history_seconds: Option<f64>,
+    /// Additional downsample power of 2 for the Stokes history, on top of `downsample_power`
+    #[arg(long, default_value_t = 5)]
+    pub history_downsample_power: u32,
+    /// Port which we expect to receive Stokes history dump triggers
+    #[arg(long, default_value_t = 65435)]
+    #[clap(value_parser = clap::value_parser!(u16).range(1..))]
+    pub history_trig_port: u16,
+    /// Which digitizer board we're driving
+    #[arg(long, value_enum, default_value_t = Board::Snap)]
+    pub board: Board,
+    /// Socket address of the SNAP Board (found by scanning `discover_subnet` if unset)
+    #[arg(long)]
+    pub fpga_addr: Option<SocketAddr>,
+    /// Most attempts to reach the SNAP before giving up
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    pub fpga_connect_attempts: u32,
+    /// Give up trying to reach the SNAP after this many seconds
+    #[arg(long, default_value_t = 60.0)]
+    pub fpga_connect_timeout: f64,
+    /// Subnet to look for the SNAP on when `fpga_addr` isn't given
+    #[arg(long, default_value = "192.168.0.0/24")]
+    pub discover_subnet: Subnet,
+    /// Gateware (.fpg) the SNAP is running, if it's a different revision than the one we were built against
+    #[arg(long)]
+    pub fpg_path: Option<PathBuf>,
+    /// Gateware (.fpg) to program the SNAP with before setting it up, if it isn't running it already
+    #[arg(long, conflicts_with = "resume")]
+    pub program: Option<PathBuf>,
     /// NTP server to synchronize against
     #[arg(long, default_value = "time.google.com")]
     pub ntp_addr: String,
+    /// How often to resynchronize with NTP to correct timestamps (seconds, 0 to disable)
+    #[arg(long, default_value_t = 60)]
+    pub ntp_interval: u64,
+    /// How often to check the packet counts against the host clock (seconds, 0 to disable)
+    #[arg(long, default_value_t = 10)]
+    pub drift_interval: u64,
+    /// Warn when the packet counts are this far off the host clock (ms)
+    #[arg(long, default_value_t = 10.0)]
+    pub drift_threshold_ms: f64,
+    /// Coarse gain of the SNAP's ADCs in dB (left as is if unset)
+    #[arg(long)]
+    #[clap(value_parser = clap::value_parser!(u8).range(0..=12))]
+    pub adc_gain: Option<u8>,
     /// Manual requantization gain (disables bandpass flattening)
     #[arg(long)]
     pub requant_gain: Option<u16>,
+    /// Run the simulator through capture and processing on localhost, report pass/fail, and exit
+    #[arg(long)]
+    pub self_test: bool,
+    /// What to do when the ADC input power drifts from where it was at calibration
+    #[arg(long, value_enum, default_value_t = ServoMode::Flag)]
+    pub gain_servo: ServoMode,
+    /// Fractional drift in ADC RMS the gain servo tolerates before acting
+    #[arg(long, default_value_t = 0.1)]
+    pub gain_servo_window: f64,
+    /// Largest fractional change the gain servo makes to the gains at once
+    #[arg(long, default_value_t = 0.05)]
+    pub gain_servo_step: f64,
     /// Force a pps trigger
     #[arg(long)]
     pub trig: bool,
     /// Sync FPGA timing without NTP
     #[arg(long)]
     pub skip_ntp: bool,
+    /// Time the PPS trigger from a local GPS receiver instead of NTP
+    /// (`gpsd`, `gpsd://host:port`, or the path to an NMEA serial device)
+    #[arg(long)]
+    pub gps: Option<GpsSource>,
     /// Pulse injection cadence (seconds)
     #[arg(short, long, default_value_t = 3600)]
     pub injection_cadence: u64,
+    /// Start with the regular pulse injections turned off
+    #[arg(long)]
+    pub no_injection: bool,
     /// Path to .dat files for pulse injection
     #[arg(short, long, default_value = "./fake")]
     pub pulse_path: PathBuf,
-    /// Exfil method - leaving this unspecified will not save stokes data
+    #[command(flatten)]
+    pub injection_polarization: Polarization,
+    /// Directory to keep RFI occupancy statistics in (occupancy isn't tracked if unset)
+    #[arg(long)]
+    pub rfi_path: Option<PathBuf>,
+    /// Threshold (in standard deviations above the baseline) for a channel to count as occupied
+    #[arg(long, default_value_t = 5.0)]
+    pub rfi_threshold: f32,
+    /// Channels to zero at the edges of the band, as comma-separated ranges like `0-250,1797-2047`
+    /// (or `none`)
+    #[arg(long, default_value = weights::DEFAULT_BAND_MASK)]
+    pub band_mask: BandMask,
+    /// Keep a Heimdall kill file of the masked, dead, and RFI-occupied channels up to date here
+    #[arg(long)]
+    pub kill_file: Option<PathBuf>,
+    /// Flux calibration table to convert the exfil output to Janskys
+    #[arg(long)]
+    pub fluxcal_table: Option<PathBuf>,
+    /// Write our PID to this file (locked for the lifetime of the process)
+    #[arg(long)]
+    pub pidfile: Option<PathBuf>,
+    /// Directory for the lock files guarding the capture port and DADA key
+    #[arg(long, default_value = "/tmp")]
+    pub lock_dir: PathBuf,
+    /// TOML file with the structured settings (e.g. the `[cluster]` section)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// File to keep the runtime state in, for `--resume`
+    #[arg(long, default_value = "grex_t0_state.json")]
+    pub state_file: PathBuf,
+    /// Rejoin the packet stream of a SNAP left running by a crashed run, instead of setting it up again
+    #[arg(long)]
+    pub resume: bool,
+    /// SQLite database recording triggers, dumps, calibrations, and injections
+    #[arg(long, default_value = "grex_t0_events.db")]
+    pub event_db: PathBuf,
+    /// JSON lines catalog of every data product we write, for archive ingestion
+    #[arg(long, default_value = "grex_t0_catalog.jsonl")]
+    pub catalog: PathBuf,
+    /// Another exfil to run alongside the subcommand's, given as its own subcommand line (e.g.
+    /// `--tee "filterbank --rebin-power 2"`), can be given more than once
+    #[arg(long, value_parser = parse_exfil)]
+    pub tee: Vec<Exfil>,
+    /// Exfil method (leaving this unspecified will not save stokes data), or a standalone tool
     #[command(subcommand)]
-    pub exfil: Option<Exfil>,
+    pub command: Option<Command>,
+}
+
+impl Cli {
+    /// Every exfil we were asked for, the subcommand's first and then any `--tee`s
+    pub fn exfils(&self) -> Vec<Exfil> {
+        let first = match &self.command {
+            Some(Command::Exfil(exfil)) => Some(*exfil),
+            _ => None,
+        };
+        first.into_iter().chain(self.tee.iter().copied()).collect()
+    }
+
+    /// Parse the command line, filling in whatever the `--profile` (if any) sets that wasn't given
+    pub fn parse_with_profile() -> Self {
+        let args: Vec<OsString> = std::env::args_os().collect();
+        let cli = Self::parse_from(&args);
+        let Some(profile) = cli.profile else {
+            return cli;
+        };
+        let (flags, exfil) = profile.args();
+        // Later flags override earlier ones, so the profile goes first
+        let mut with_profile = args[..1].to_vec();
+        with_profile.extend(flags.iter().map(OsString::from));
+        with_profile.extend_from_slice(&args[1..]);
+        if cli.command.is_none() {
+            with_profile.extend(exfil.iter().map(OsString::from));
+        }
+        Self::parse_from(with_profile)
+    }
+}
+
+/// Bundles of flags for the usual ways of running T0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// A SNAP on the bench: no NTP, an injection every five minutes, and filterbanks to look at
+    Lab,
+    /// Observing: NTP timing, hourly injections, the pipeline on cores 8-15, and PSRDADA for Heimdall
+    Field,
+    /// Simulated or replayed packets: no NTP, no injections, and filterbanks
+    Replay,
+}
+
+impl Profile {
+    /// The flags this profile stands for, and the exfil to use if none was given
+    fn args(self) -> (&'static [&'static str], &'static [&'static str]) {
+        match self {
+            Profile::Lab => (
+                &["--skip-ntp", "--injection-cadence", "300"],
+                &["filterbank"],
+            ),
+            Profile::Field => (
+                &[
+                    "--core-range",
+                    "8:15",
+                    "--ntp-interval",
+                    "60",
+                    "--injection-cadence",
+                    "3600",
+                ],
+                &["psrdada", "--key", "dada"],
+            ),
+            Profile::Replay => (&["--skip-ntp", "--no-injection"], &["filterbank"]),
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(flatten)]
+    Exfil(Exfil),
+    /// Query the event database instead of running the pipeline
+    Query(Query),
+    /// Pretend to be a SNAP, sending simulated packets instead of running the pipeline
+    Simulate(Simulate),
+    /// Convert a voltage dump to a Stokes I filterbank instead of running the pipeline
+    Convert(Convert),
+    /// List the SNAP boards on a subnet instead of running the pipeline
+    Discover(Discover),
+    /// Read or write the SNAP's registers by name instead of running the pipeline
+    Fpga(peek::Fpga),
+    /// Receive voltage dumps sent with `--dump-target` instead of running the pipeline
+    ReceiveDumps(dumps::stream::Receive),
+}
+
+#[derive(Debug, Clone, Copy, Subcommand)]
 pub enum Exfil {
     /// Use PSRDADA for exfil
     Psrdada {
@@ -72,14 +416,140 @@ pub enum Exfil {
         /// Window size in number of time samples
         #[clap(short, long, default_value_t = 65536)]
         samples: usize,
+        /// Bits per sample, 8 rescales the Stokes with levels refit every block (recorded in a
+        /// file per stream under `dada_levels_path`)
+        #[clap(long, default_value_t = 32, value_parser = valid_nbit)]
+        nbit: u32,
+        /// Average another 2^n spectra for this exfil, on top of `downsample_power`
+        #[clap(long, default_value_t = 0)]
+        rebin_power: u32,
+    },
+    /// Write a filterbank to `filterbank_path`
+    Filterbank {
+        /// Average another 2^n spectra for this exfil, on top of `downsample_power`
+        #[clap(long, default_value_t = 0)]
+        rebin_power: u32,
+        /// Start a new file after this many seconds of data
+        #[clap(long)]
+        rotate_secs: Option<f64>,
+        /// Start a new file once the current one reaches this many GB
+        #[clap(long)]
+        rotate_gb: Option<f64>,
+        /// Write each file into a `YYYY-MM-DD` subdirectory for the (UTC) day it starts
+        #[clap(long)]
+        dated_dirs: bool,
+    },
+    /// Write search-mode PSRFITS to `filterbank_path`
+    Psrfits {
+        /// Spectra per SUBINT row
+        #[clap(long, default_value_t = 2048)]
+        nsblk: usize,
+        /// Average another 2^n spectra for this exfil, on top of `downsample_power`
+        #[clap(long, default_value_t = 0)]
+        rebin_power: u32,
+    },
+    /// Write the dynamic spectrum as chunked HDF5 or Zarr to `filterbank_path`
+    Dynspec {
+        /// File format
+        #[clap(long, value_enum, default_value_t = exfil::DynspecFormat::Hdf5)]
+        format: exfil::DynspecFormat,
+        /// Spectra per chunk
+        #[clap(long, default_value_t = 1024)]
+        chunk: usize,
+        /// Average another 2^n spectra for this exfil, on top of `downsample_power`
+        #[clap(long, default_value_t = 0)]
+        rebin_power: u32,
     },
-    Filterbank,
+    /// Stream the spectra over TCP to whoever connects
+    Stream {
+        /// Port subscribers connect to
+        #[clap(long, default_value_t = 65437)]
+        port: u16,
+        /// Average another 2^n spectra for this exfil, on top of `downsample_power`
+        #[clap(long, default_value_t = 0)]
+        rebin_power: u32,
+    },
+    /// Multicast the occasional spectrum over UDP for quick-look displays
+    Multicast {
+        /// Multicast group to send to
+        #[clap(long, default_value = "239.2.0.1")]
+        group: Ipv4Addr,
+        /// Port to send to
+        #[clap(long, default_value_t = 65438)]
+        port: u16,
+        /// Send one spectrum in this many
+        #[clap(long, default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..))]
+        every: u32,
+        /// Hops the datagrams may take (1 keeps them on the local network)
+        #[clap(long, default_value_t = 1)]
+        ttl: u32,
+        /// Address of the interface to send from (the default route's otherwise)
+        #[clap(long)]
+        interface: Option<Ipv4Addr>,
+        /// Average another 2^n spectra for this exfil, on top of `downsample_power`
+        #[clap(long, default_value_t = 0)]
+        rebin_power: u32,
+    },
+}
+
+impl Exfil {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exfil::Psrdada { .. } => "psrdada",
+            Exfil::Filterbank { .. } => "filterbank",
+            Exfil::Psrfits { .. } => "psrfits",
+            Exfil::Dynspec { .. } => "dynspec",
+            Exfil::Stream { .. } => "stream",
+            Exfil::Multicast { .. } => "multicast",
+        }
+    }
+
+    /// How many more spectra (as a power of 2) this exfil averages together
+    pub fn rebin_power(&self) -> u32 {
+        match *self {
+            Exfil::Psrdada { rebin_power, .. }
+            | Exfil::Filterbank { rebin_power, .. }
+            | Exfil::Psrfits { rebin_power, .. }
+            | Exfil::Dynspec { rebin_power, .. }
+            | Exfil::Stream { rebin_power, .. }
+            | Exfil::Multicast { rebin_power, .. } => rebin_power,
+        }
+    }
+}
+
+/// Parse an exfil subcommand line, for `--tee`
+fn parse_exfil(s: &str) -> Result<Exfil, String> {
+    #[derive(Parser)]
+    #[command(no_binary_name = true)]
+    struct Tee {
+        #[command(subcommand)]
+        exfil: Exfil,
+    }
+    Tee::try_parse_from(s.split_whitespace())
+        .map(|t| t.exfil)
+        .map_err(|e| e.to_string())
 }
 
 fn valid_dada_key(s: &str) -> Result<i32, String> {
     i32::from_str_radix(s, 16).map_err(|_| "Invalid hex litteral".to_string())
 }
 
+fn valid_station(s: &str) -> Result<[u8; 2], String> {
+    s.as_bytes()
+        .try_into()
+        .ok()
+        .filter(|code: &[u8; 2]| code.iter().all(u8::is_ascii_alphanumeric))
+        .ok_or_else(|| "Station codes are two ASCII letters or digits".to_string())
+}
+
+fn valid_nbit(s: &str) -> Result<u32, String> {
+    match s {
+        "8" => Ok(8),
+        "32" => Ok(32),
+        _ => Err("Only 8 or 32 bits are supported".to_string()),
+    }
+}
+
 pub fn parse_core_range(input: &str) -> Result<RangeInclusive<usize>, String> {
     let re = Regex::new(r"(\d+):(\d+)").unwrap();
     let cap = re.captures(input).unwrap();
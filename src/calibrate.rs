@@ -1,23 +1,124 @@
 //! Pre pipeline calibration routine
 
-use crate::fpga::Device;
+use crate::{fpga::Device, weights};
 use eyre::eyre;
+use hifitime::prelude::*;
+use lazy_static::lazy_static;
 use median::Filter;
+use prometheus::{register_gauge, register_int_gauge, Gauge, IntGauge};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    RwLock,
+};
 use tracing::{info, warn};
 use whittaker_smoother::whittaker_smoother;
 
 // Around 1 second at 8.192us
 const CALIBRATION_ACCUMULATIONS: u32 = 131072;
 // Whittaker Settings
-const SMOOTH_LAMBDA: f64 = 50.0;
-const SMOOTH_ORDER: usize = 3;
+pub const SMOOTH_LAMBDA: f64 = 50.0;
+pub const SMOOTH_ORDER: usize = 3;
 // What fraction of the resulting requant byte do we want to scale to
 // This determines headroom for RFI
-const REQUANT_SCALE: f64 = 0.1;
+pub const REQUANT_SCALE: f64 = 0.1;
 // Median filter width
 const MEDIAN_FILTER_WIDTH: usize = 50;
+// Full scale of each requantized (8 bit) real and imaginary part
+const REQUANT_FULL_SCALE: f64 = 127.0;
+// How far off (as a ratio, either way) a channel's requantized level can be and still pass
+const VERIFY_TOLERANCE: f64 = 2.0;
 
-fn compute_gains(
+lazy_static! {
+    static ref CAL_LEVEL: Gauge = register_gauge!(
+        "calibration_level",
+        "Median requantized voltage RMS across the band after calibration, as a fraction of full scale"
+    )
+    .unwrap();
+    static ref CAL_FAILED: IntGauge = register_int_gauge!(
+        "calibration_failed_channels",
+        "Number of channels whose requantized level missed the target after calibration"
+    )
+    .unwrap();
+}
+
+/// Set when someone has asked for the bandpass to be recalibrated while we're running
+static RECALIBRATE: AtomicBool = AtomicBool::new(false);
+
+/// Ask the thread that owns the SNAP to redo the bandpass calibration
+pub fn request_recalibration() {
+    RECALIBRATE.store(true, Ordering::Release);
+}
+
+/// Has a recalibration been requested (clearing the request)?
+pub fn take_recalibration_request() -> bool {
+    RECALIBRATE.swap(false, Ordering::AcqRel)
+}
+
+/// The state of the SNAP's signal chain, recorded with the voltage dumps so they can be put back
+/// on a power scale offline
+#[derive(Debug, Clone)]
+pub struct CalState {
+    /// Requant gains of (a, b), empty if they haven't been set
+    pub gains: (Vec<u16>, Vec<u16>),
+    /// When the bandpass was last calibrated (or the gains set by hand)
+    pub calibrated: Option<Epoch>,
+    /// ADC coarse gain (dB), if we set one
+    pub adc_gain: Option<u8>,
+    /// Latest ADC RMS of (a, b), in counts
+    pub adc_rms: Option<(f64, f64)>,
+    /// How the last calibration checked out
+    pub verification: Option<Verification>,
+}
+
+/// The requantized levels we measured after setting the gains
+#[derive(Debug, Clone)]
+pub struct Verification {
+    /// Median voltage RMS across the band, as a fraction of full scale (should be `REQUANT_SCALE`)
+    pub level: f64,
+    /// Channels more than `VERIFY_TOLERANCE` away from the target
+    pub failed: Vec<usize>,
+}
+
+static STATE: RwLock<CalState> = RwLock::new(CalState {
+    gains: (Vec::new(), Vec::new()),
+    calibrated: None,
+    adc_gain: None,
+    adc_rms: None,
+    verification: None,
+});
+
+/// A snapshot of the signal chain state
+#[allow(clippy::missing_panics_doc)]
+pub fn state() -> CalState {
+    STATE.read().unwrap().clone()
+}
+
+/// Note the requant gains the SNAP is now using
+#[allow(clippy::missing_panics_doc)]
+pub fn record_gains(a: &[u16], b: &[u16]) {
+    STATE.write().unwrap().gains = (a.to_vec(), b.to_vec());
+}
+
+/// Note that the gains were just (re)calibrated
+#[allow(clippy::missing_panics_doc)]
+pub fn record_calibration(when: Epoch) {
+    STATE.write().unwrap().calibrated = Some(when);
+}
+
+/// Note the ADC coarse gain the SNAP is now using
+#[allow(clippy::missing_panics_doc)]
+pub fn record_adc_gain(db: u8) {
+    STATE.write().unwrap().adc_gain = Some(db);
+}
+
+/// Note the latest ADC RMS measurement
+#[allow(clippy::missing_panics_doc)]
+pub fn record_adc_rms(a: f64, b: f64) {
+    STATE.write().unwrap().adc_rms = Some((a, b));
+}
+
+/// Compute requant gains that flatten the bandpass from `n` accumulated spectra
+pub fn compute_gains(
     scale: f64,
     n: u32,
     powers: &[u64],
@@ -53,6 +154,67 @@ fn compute_gains(
     Ok(gain)
 }
 
+/// Compact a sorted list of channels into ranges for printing, e.g. "3-7, 12"
+fn channel_ranges(chans: &[usize]) -> String {
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for &c in chans {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == c => *end = c,
+            _ => ranges.push((c, c)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(s, e)| {
+            if s == e {
+                s.to_string()
+            } else {
+                format!("{s}-{e}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Measure the requantized levels with the stokes accumulator, checking they sit where the gains
+/// were meant to put them (outside of the band edges)
+pub fn verify(fpga: &mut Device) -> eyre::Result<Verification> {
+    let stokes = fpga.perform_stokes_vacc(CALIBRATION_ACCUMULATIONS)?;
+    // Stokes I is the sum of four squared parts (two pols, re and im), so back out the RMS of each
+    let levels: Vec<_> = stokes
+        .iter()
+        .map(|&s| {
+            (s as f64 / (4.0 * f64::from(CALIBRATION_ACCUMULATIONS))).sqrt() / REQUANT_FULL_SCALE
+        })
+        .collect();
+    let usable = weights::band_mask().unmasked();
+    let mut sorted: Vec<_> = usable.iter().map(|&c| levels[c]).collect();
+    sorted.sort_by(f64::total_cmp);
+    let level = sorted.get(sorted.len() / 2).copied().unwrap_or_default();
+    let failed: Vec<_> = usable
+        .into_iter()
+        .filter(|&c| {
+            let ratio = levels[c] / REQUANT_SCALE;
+            !(1.0 / VERIFY_TOLERANCE..=VERIFY_TOLERANCE).contains(&ratio)
+        })
+        .collect();
+    CAL_LEVEL.set(level);
+    CAL_FAILED.set(failed.len() as i64);
+    if failed.is_empty() {
+        info!(level, "Calibration verified");
+    } else {
+        warn!(
+            level,
+            n = failed.len(),
+            channels = channel_ranges(&failed),
+            "Channels missed their requantized level after calibration"
+        );
+    }
+    let verification = Verification { level, failed };
+    STATE.write().unwrap().verification = Some(verification.clone());
+    Ok(verification)
+}
+
 pub fn calibrate(fpga: &mut Device) -> eyre::Result<()> {
     // Assuming the fpga has been setup (but not adjusted in requant gains),
     // Capture the spectrum
@@ -73,6 +235,10 @@ pub fn calibrate(fpga: &mut Device) -> eyre::Result<()> {
         SMOOTH_ORDER,
     )?;
     fpga.set_requant_gains(&a_gain, &b_gain)?;
+    weights::set_calibration_dead(&a_gain, &b_gain);
+    record_calibration(Epoch::now()?);
     info!("Calibration complete!");
+    // Close the loop, so a bad calibration shows up now rather than hours later in the data
+    verify(fpga)?;
     Ok(())
 }
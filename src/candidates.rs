@@ -0,0 +1,132 @@
+//! Listening for Heimdall/T2 candidates, turning the interesting ones into voltage dump triggers
+use crate::{
+    cluster,
+    common::{epoch_after, PACKET_CADENCE},
+    dumps::Trigger,
+    exfil,
+};
+use hifitime::prelude::*;
+use std::{net::SocketAddr, str::FromStr};
+use thingbuf::mpsc::{blocking::Sender, errors::TrySendError};
+use tokio::{net::UdpSocket, sync::broadcast};
+use tracing::{debug, info, warn};
+
+#[derive(thiserror::Error, Debug)]
+/// Errors that can be produced from parsing candidates
+pub enum Error {
+    #[error("Candidate line had {0} fields, but we need at least 6")]
+    MissingFields(usize),
+    #[error("Couldn't parse candidate field `{0}`")]
+    BadField(&'static str),
+}
+
+/// A single candidate, as written by Heimdall (one per line, whitespace separated):
+/// `snr sample time filter dm_trial dm [members begin end ...]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub snr: f64,
+    /// Sample index since the start of the stream
+    pub sample: u64,
+    /// Seconds since the start of the stream
+    pub time: f64,
+    /// Boxcar filter index (width is 2^filter samples)
+    pub filter: u32,
+    /// Dispersion measure (pc cm^-3)
+    pub dm: f64,
+}
+
+impl FromStr for Candidate {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<_> = s.split_whitespace().collect();
+        if fields.len() < 6 {
+            return Err(Error::MissingFields(fields.len()));
+        }
+        Ok(Self {
+            snr: fields[0].parse().map_err(|_| Error::BadField("snr"))?,
+            sample: fields[1].parse().map_err(|_| Error::BadField("sample"))?,
+            time: fields[2].parse().map_err(|_| Error::BadField("time"))?,
+            filter: fields[3].parse().map_err(|_| Error::BadField("filter"))?,
+            dm: fields[5].parse().map_err(|_| Error::BadField("dm"))?,
+        })
+    }
+}
+
+/// Thresholds a candidate must pass to trigger a dump
+#[derive(Debug, Clone, Copy)]
+pub struct CandidateFilter {
+    pub min_snr: f64,
+    pub min_dm: f64,
+    pub max_filter: u32,
+}
+
+impl CandidateFilter {
+    pub fn accepts(&self, cand: &Candidate) -> bool {
+        cand.snr >= self.min_snr && cand.dm >= self.min_dm && cand.filter <= self.max_filter
+    }
+}
+
+/// Listen for candidates on `port`, forwarding the ones that pass `filter` as dump triggers.
+/// `packet_start` and `tsamp` are needed to turn stream-relative times into absolute ones.
+pub async fn candidate_task(
+    sender: Sender<Trigger>,
+    port: Option<u16>,
+    filter: CandidateFilter,
+    packet_start: Epoch,
+    tsamp: f64,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    let port = match port {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    info!("Starting candidate listener task!");
+    let sock = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    let mut buf = [0u8; 65536];
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("Candidate listener task stopping");
+                break;
+            }
+            Ok((n, _)) = sock.recv_from(&mut buf) => {
+                let msg = String::from_utf8_lossy(&buf[..n]);
+                for line in msg.lines().filter(|l| !l.trim().is_empty()) {
+                    let cand: Candidate = match line.parse() {
+                        Ok(c) => c,
+                        Err(e) => {
+                            warn!("Bad candidate - {e}");
+                            continue;
+                        }
+                    };
+                    if !filter.accepts(&cand) {
+                        debug!(?cand, "Candidate rejected");
+                        continue;
+                    }
+                    info!(?cand, "Triggering dump from candidate");
+                    // Same reference as the UTC_START we give heimdall
+                    let stream_start = epoch_after(
+                        &packet_start,
+                        (PACKET_CADENCE * exfil::dada_start_count() as f64).seconds(),
+                    );
+                    let trigger = Trigger {
+                        source: "heimdall".to_owned(),
+                        time: Some(epoch_after(&stream_start, cand.time.seconds())),
+                        dm: Some(cand.dm),
+                        snr: Some(cand.snr),
+                        width: Some(2f64.powi(cand.filter as i32) * tsamp),
+                        window: None,
+                    };
+                    cluster::fan_out(&trigger);
+                    match sender.try_send(trigger) {
+                        Ok(_) => (),
+                        Err(TrySendError::Full(_)) => warn!("Trigger queue full, dropping candidate"),
+                        Err(_) => eyre::bail!("Trigger channel closed"),
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
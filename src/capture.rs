@@ -1,16 +1,41 @@
 //! Logic for capturing raw packets from the NIC, parsing them into payloads, and sending them to other processing threads
 
-use crate::common::Payload;
+use crate::{
+    common::Payload,
+    latency,
+    simulate::{Generator, Simulate},
+    state, timing,
+};
+use clap::{Args, ValueEnum};
+use lazy_static::lazy_static;
+use prometheus::{register_gauge, register_int_counter, Gauge, IntCounter};
+use serde::{Deserialize, Serialize};
 use socket2::{Domain, Socket, Type};
 use std::net::UdpSocket;
 use std::{
+    collections::BTreeMap,
     net::SocketAddr,
-    sync::atomic::AtomicU64,
+    os::fd::AsRawFd,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
     time::{Duration, Instant},
 };
 use thingbuf::mpsc::blocking::{Sender, StaticSender};
 use tokio::sync::broadcast;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
+
+mod arrivals;
+mod frame;
+mod record;
+mod replay;
+mod uring;
+mod xdp;
+pub use arrivals::Throughput;
+pub use record::{record_task, RecordConfig, RECORD_CHANNEL_SIZE};
+pub use replay::ReplayConfig;
+pub use xdp::XdpConfig;
 
 /// Size of the packet count header
 const TIMESTAMP_SIZE: usize = 8;
@@ -18,10 +43,67 @@ const TIMESTAMP_SIZE: usize = 8;
 const SPECTRA_SIZE: usize = 8192;
 /// Total UDP payload size
 pub const PAYLOAD_SIZE: usize = SPECTRA_SIZE + TIMESTAMP_SIZE;
+/// Packets to receive per syscall, unless told otherwise
+pub const DEFAULT_BATCH: usize = 32;
+/// Packets past a gap to wait for it to fill, unless told otherwise (about half a millisecond)
+pub const DEFAULT_REORDER_WINDOW: u64 = 64;
+/// Capture socket receive buffer size, unless told otherwise (256 MiB)
+pub const DEFAULT_RX_BUFFER_BYTES: usize = 256 * 1024 * 1024;
+/// Consecutive packets that have to agree on a jump in count past `max_count_jump` to believe it
+const JUMP_CONFIRMATION: u64 = 16;
+/// How long to wait between attempts to bind the capture socket again after it fails
+const REBIND_BACKOFF: Duration = Duration::from_secs(1);
 /// Polling interval for stats
 const STATS_POLL_DURATION: Duration = Duration::from_secs(20);
+/// The board our timing (and the detection path) follows, the rest are just captured and tagged
+pub const PRIMARY_BOARD: u8 = 0;
 /// Global atomic to hold the count of the first packet
 pub static FIRST_PACKET: AtomicU64 = AtomicU64::new(0);
+/// Added to the SNAP's packet counts, so they stay on the original timeline after it's re-triggered
+pub static COUNT_OFFSET: AtomicU64 = AtomicU64::new(0);
+/// Global atomic to hold the count of the most recently captured packet
+pub static LAST_PACKET: AtomicU64 = AtomicU64::new(0);
+/// Most dropped packets we'll fill in at once (about half a second). Filling in more would hold up
+/// capture for too long, so past this we skip ahead instead. It's a power of two bigger than any
+/// averaging, so spectra downstream keep the same boundaries across the skip.
+pub const MAX_FILL: u64 = 1 << 16;
+/// Where the primary board's packet count jumped further than we'd fill in, as (count where it
+/// happened on the timeline downstream sees, with every payload one after the last, total packets
+/// skipped from then on), oldest first
+static SKIPS: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+lazy_static! {
+    static ref RX_LATENCY_GAUGE: Gauge = register_gauge!(
+        "packet_rx_latency_seconds",
+        "Host receive time of a recent packet minus its timestamp (FPGA-to-host latency plus clock drift)"
+    )
+    .unwrap();
+    static ref SKIPPED_PACKETS: IntCounter = register_int_counter!(
+        "skipped_packets",
+        "Dropped packets past what we'll fill in, skipped over rather than filled"
+    )
+    .unwrap();
+}
+
+/// Packets skipped over (rather than filled in) up to `count`, which counts every payload that came
+/// out of capture one after the other (as downstream does, from [`FIRST_PACKET`])
+#[allow(clippy::missing_panics_doc)]
+pub fn skipped_before(count: u64) -> u64 {
+    SKIPS
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|(at, _)| *at <= count)
+        .map_or(0, |(_, skipped)| *skipped)
+}
+
+/// Note that the primary board's payloads skip `packets` at `count` (on the gap-free timeline)
+fn record_skip(count: u64, packets: u64) {
+    let mut skips = SKIPS.lock().unwrap();
+    let total = skips.last().map_or(0, |(_, skipped)| *skipped) + packets;
+    skips.push((count, total));
+}
 
 #[derive(thiserror::Error, Debug)]
 /// Errors that can be produced from captures
@@ -30,149 +112,795 @@ pub enum Error {
     SizeMismatch(usize),
     #[error("Failed to set the recv buffer size. We tried to set {expected}, but found {found}. Check sysctl net.core.rmem_max")]
     SetRecvBufferFailed { expected: usize, found: usize },
+    #[error("Failed to set busy polling to {0} us ({1}). Going past sysctl net.core.busy_read needs CAP_NET_ADMIN")]
+    SetBusyPollFailed(u32, std::io::Error),
+    #[error("Failed to set SO_REUSEPORT ({0}). Every socket sharing the port needs it set")]
+    SetReusePortFailed(std::io::Error),
+}
+
+/// How to set up the capture socket
+#[derive(Debug, Clone, Args)]
+pub struct SocketOptions {
+    /// Receive buffer size of the capture socket (bytes), which net.core.rmem_max has to allow
+    #[arg(long, default_value_t = DEFAULT_RX_BUFFER_BYTES)]
+    pub rx_buffer_bytes: usize,
+    /// Busy-poll the NIC for up to this long (us) when waiting for packets, trading CPU for latency
+    #[arg(long)]
+    pub busy_poll_us: Option<u32>,
+    /// Let other sockets bind the capture port too (SO_REUSEPORT), so the kernel spreads packets
+    /// across them
+    #[arg(long)]
+    pub reuseport: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            rx_buffer_bytes: DEFAULT_RX_BUFFER_BYTES,
+            busy_poll_us: None,
+            reuseport: false,
+        }
+    }
+}
+
+/// How we get packets off the NIC
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureBackend {
+    /// Through the kernel's network stack to a UDP socket
+    Socket,
+    /// Straight from the NIC's receive ring with AF_XDP
+    Xdp,
+    /// Through the kernel's network stack, with batched io_uring receives
+    Uring,
+}
+
+/// What to send on in place of dropped packets (always marked as synthetic)
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FillPolicy {
+    /// All-zero voltages
+    #[default]
+    Zeros,
+    /// All-zero voltages, with the spectra they end up in replaced by NaNs downstream
+    Nan,
+    /// The voltages of the last packet we did get
+    Repeat,
+}
+
+/// How to set up one capture thread
+#[derive(Debug, Clone)]
+pub struct CaptureConfig {
+    /// The board we're capturing, which gets tagged on every payload
+    pub board: u8,
+    pub port: u16,
+    /// Most packets to receive per syscall
+    pub batch: usize,
+    /// Packets past a gap to wait for it to fill
+    pub reorder_window: u64,
+    /// What to fill in dropped packets with
+    pub fill: FillPolicy,
+    /// Furthest ahead of the expected count to believe a packet, if we're checking
+    pub max_count_jump: Option<u64>,
+    /// How to set up the socket, for the inputs that use one
+    pub socket: SocketOptions,
+    pub input: Input,
+}
+
+/// Where to get packets from
+#[derive(Debug, Clone, Default)]
+pub enum Input {
+    /// A UDP socket on the capture port
+    #[default]
+    Socket,
+    /// A UDP socket on the capture port, read through io_uring
+    Uring,
+    /// AF_XDP on a receive queue of `interface`
+    Xdp {
+        interface: String,
+        config: XdpConfig,
+    },
+    /// Packets recorded in a pcap file
+    Replay(ReplayConfig),
+    /// Packets made up on the spot
+    Simulate(Simulate),
+}
+
+/// Where packets come in from
+enum Source {
+    /// A regular UDP socket, bound to `port`
+    Socket { sock: UdpSocket, port: u16 },
+    /// A UDP socket that failed, which we keep trying to bind again
+    Rebinding { port: u16 },
+    /// A UDP socket read through io_uring
+    Uring(uring::UringSocket),
+    /// An AF_XDP socket, fed by an XDP program on the NIC
+    Xdp(xdp::XdpSocket),
+    /// A recording
+    Replay(replay::Replay),
+    /// The simulator, standing in for the SNAP
+    Simulate(Box<Generator>),
 }
 
 pub struct Capture {
-    /// The socket itself
-    sock: UdpSocket,
+    /// Where we get packets from
+    source: Source,
+    /// Which board the packets are from
+    pub board: u8,
+    /// How the socket was set up, to set it up again the same way if it fails
+    socket_options: SocketOptions,
+    /// Where to tee every payload for the raw recording, if we're making one
+    recorder: Option<Sender<Payload>>,
     /// How many packets we've dropped because the incoming one wasn't n+1
     pub drops: usize,
-    /// How many packets from the past we've recieved (indicating there was a shuffle somewhere)
+    /// How many packets from the past we've recieved too late to put back in order
     pub shuffled: usize,
+    /// How many packets arrived out of order but were put back in place
+    pub reordered: usize,
+    /// How many times the socket failed and we bound it again
+    pub restarts: usize,
+    /// How many packets we threw away as corrupt
+    pub malformed: usize,
     /// The number of packets we've actually processed
     pub processed: usize,
+    /// How many packets the kernel dropped because our socket's receive queue was full
+    pub kernel_drops: u32,
     /// Marker bool for the first packet
     first_payload: bool,
     /// The next payload count we expect
     next_expected_count: u64,
+    /// How far (in packets) past a gap we'll wait for the missing ones before giving up on them
+    reorder_window: u64,
+    /// Furthest ahead of the next expected packet count we'll believe a packet, if we're checking
+    max_count_jump: Option<u64>,
+    /// A run of packets too far ahead to believe, which we will if it goes on long enough
+    jump_run: Option<(u64, u64)>,
+    /// Payloads that came in ahead of a gap, waiting for it to fill
+    held: BTreeMap<u64, Payload>,
+    /// The last payload we sent on, kept only when we fill by repeating it
+    last_sent: Option<Box<Payload>>,
+    /// How fast and how evenly packets have come in since the last stats
+    arrivals: arrivals::Arrivals,
+    /// Headers for receiving from a socket, kept so each batch doesn't allocate them again
+    headers: RecvHeaders,
 }
 
 impl Capture {
-    pub fn new(port: u16) -> eyre::Result<Self> {
-        // Create UDP socket
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
-        // Bind our listening address
-        let address = SocketAddr::from(([0, 0, 0, 0], port));
-        socket.bind(&address.into())?;
-        // Reuse local address without timeout
-        socket.reuse_address()?;
-        // Set the buffer size to 256MiB (it will read as double, for some reason)
-        let sock_buf_size = 256 * 1024 * 1024;
-        socket.set_recv_buffer_size(sock_buf_size)?;
-        // Check
-        let current_buf_size = socket.recv_buffer_size()?;
-        if current_buf_size != sock_buf_size * 2 {
-            return Err(Error::SetRecvBufferFailed {
-                expected: sock_buf_size * 2,
-                found: current_buf_size,
-            }
-            .into());
-        }
-        // Replace the socket2 socket with a std socket
-        let sock = socket.into();
-        Ok(Self {
-            sock,
+    pub fn new(port: u16, options: &SocketOptions) -> eyre::Result<Self> {
+        let sock = bind_socket(port, options)?;
+        let mut cap = Self::from_source(Source::Socket { sock, port });
+        cap.socket_options = options.clone();
+        Ok(cap)
+    }
+
+    /// Capture packets for `port` through io_uring, rather than a syscall per batch
+    pub fn uring(port: u16, options: &SocketOptions) -> eyre::Result<Self> {
+        Ok(Self::from_source(Source::Uring(uring::UringSocket::new(
+            port, options,
+        )?)))
+    }
+
+    /// Capture packets for `port` with AF_XDP on `interface`, instead of through the kernel's
+    /// network stack
+    pub fn xdp(interface: &str, port: u16, config: &XdpConfig) -> eyre::Result<Self> {
+        Ok(Self::from_source(Source::Xdp(xdp::XdpSocket::new(
+            interface, port, config,
+        )?)))
+    }
+
+    /// Replay the packets for `port` recorded in a pcap file
+    pub fn replay(config: &ReplayConfig, port: u16) -> eyre::Result<Self> {
+        Ok(Self::from_source(Source::Replay(replay::Replay::new(
+            &config.path,
+            port,
+            config.realtime,
+        )?)))
+    }
+
+    /// Capture packets from the simulator instead of the network
+    pub fn simulate(args: &Simulate) -> eyre::Result<Self> {
+        info!("Capturing simulated packets");
+        Ok(Self::from_source(Source::Simulate(Box::new(
+            Generator::new(args)?,
+        ))))
+    }
+
+    fn from_source(source: Source) -> Self {
+        Self {
+            source,
+            board: PRIMARY_BOARD,
+            socket_options: SocketOptions::default(),
+            recorder: None,
             drops: 0,
+            kernel_drops: 0,
             processed: 0,
             shuffled: 0,
+            reordered: 0,
+            restarts: 0,
+            malformed: 0,
             first_payload: true,
             next_expected_count: 0,
-        })
+            reorder_window: DEFAULT_REORDER_WINDOW,
+            max_count_jump: None,
+            jump_run: None,
+            held: BTreeMap::new(),
+            last_sent: None,
+            arrivals: arrivals::Arrivals::default(),
+            headers: RecvHeaders::default(),
+        }
+    }
+
+    /// Throw away packets whose count is more than `jump` ahead of the one we expect next, unless
+    /// enough of them in a row agree on it (as after an outage)
+    pub fn set_max_count_jump(&mut self, jump: Option<u64>) {
+        self.max_count_jump = jump;
+    }
+
+    /// Fill in dropped packets according to `fill`
+    pub fn set_fill_policy(&mut self, fill: FillPolicy) {
+        self.last_sent = (fill == FillPolicy::Repeat).then(Box::default);
+    }
+
+    /// Wait for packets up to `window` behind the newest before counting them as dropped
+    pub fn set_reorder_window(&mut self, window: u64) {
+        self.reorder_window = window;
+    }
+
+    /// Carry on from a previous run's counters, expecting `next_count` next (so the outage since
+    /// then is filled in like any other dropped packets, or skipped if it's too long to fill).
+    /// Downstream counts from [`FIRST_PACKET`] again, so that's a skip up to `next_count`.
+    pub fn resume(&mut self, stats: &Stats, next_count: u64) {
+        if self.board == PRIMARY_BOARD {
+            let first = FIRST_PACKET.load(Ordering::Acquire);
+            *SKIPS.lock().unwrap() = vec![(first, next_count.saturating_sub(first))];
+        }
+        self.drops = stats.drops;
+        self.processed = stats.processed;
+        self.shuffled = stats.shuffled;
+        self.reordered = stats.reordered;
+        self.restarts = stats.restarts;
+        self.malformed = stats.malformed;
+        self.first_payload = false;
+        self.next_expected_count = next_count;
+    }
+
+    /// Receive up to `payloads.len()` packets straight into `payloads`, returning how many we got
+    pub fn capture(&mut self, payloads: &mut [Payload]) -> eyre::Result<usize> {
+        match &mut self.source {
+            Source::Socket { sock, port } => {
+                let port = *port;
+                let res = recv_socket(
+                    sock,
+                    payloads,
+                    &mut self.headers,
+                    &mut self.kernel_drops,
+                    &mut self.malformed,
+                );
+                // Problems with the socket itself (rather than what came in on it) shouldn't end
+                // the observation, so we start over with a new one
+                if let Err(e) = &res {
+                    if e.downcast_ref::<std::io::Error>().is_some() {
+                        warn!(port, "Capture socket failed, binding it again - {e}");
+                        self.source = Source::Rebinding { port };
+                        return Ok(0);
+                    }
+                }
+                res
+            }
+            Source::Rebinding { port } => {
+                let port = *port;
+                std::thread::sleep(REBIND_BACKOFF);
+                match bind_socket(port, &self.socket_options) {
+                    Ok(sock) => {
+                        info!(port, "Capture socket bound again");
+                        self.restarts += 1;
+                        self.source = Source::Socket { sock, port };
+                    }
+                    Err(e) => warn!(port, "Couldn't bind the capture socket again - {e}"),
+                }
+                Ok(0)
+            }
+            Source::Uring(uring) => uring.recv(payloads, &mut self.malformed),
+            Source::Xdp(xsk) => xsk.recv(payloads, &mut self.malformed),
+            Source::Replay(replay) => replay.recv(payloads, &mut self.malformed),
+            Source::Simulate(generator) => Ok(generator.fill(payloads)),
+        }
+    }
+
+    /// If there are no more packets coming (only for replays and simulations that stop)
+    pub fn finished(&self) -> bool {
+        match &self.source {
+            Source::Replay(replay) => replay.finished,
+            Source::Simulate(generator) => generator.finished(),
+            _ => false,
+        }
+    }
+
+    /// Update the count of packets the kernel dropped, for sources that don't tell us as we go
+    fn poll_kernel_drops(&mut self) {
+        if let Source::Xdp(xsk) = &self.source {
+            match xsk.dropped() {
+                Ok(d) => self.kernel_drops = d as u32,
+                Err(e) => warn!("Couldn't get AF_XDP statistics - {e}"),
+            }
+        }
+    }
+
+    /// Send on a freshly captured payload, holding it back if it's ahead of a gap (until the gap
+    /// fills or we give up on it)
+    fn process(
+        &mut self,
+        payload: &Payload,
+        payload_sender: &StaticSender<Payload>,
+    ) -> eyre::Result<()> {
+        // Check first payload
+        if self.first_payload {
+            self.first_payload = false;
+            if self.board == PRIMARY_BOARD {
+                FIRST_PACKET.store(payload.count, Ordering::Release);
+            }
+            // And send the first one
+            self.forward(*payload, payload_sender)?;
+            self.next_expected_count = payload.count + 1;
+            return Ok(());
+        }
+        if !self.believable(payload.count) {
+            self.malformed += 1;
+            return Ok(());
+        }
+        if payload.count == self.next_expected_count && self.held.is_empty() {
+            // The usual case, straight through
+            self.next_expected_count += 1;
+            self.forward(*payload, payload_sender)?;
+            return Ok(());
+        }
+        if payload.count < self.next_expected_count || self.held.contains_key(&payload.count) {
+            // If the packet is from the past (or a repeat), we drop it
+            warn!("Anachronistic payload, dropping packet");
+            self.shuffled += 1;
+            return Ok(());
+        }
+        if self
+            .held
+            .last_key_value()
+            .is_some_and(|(newest, _)| *newest > payload.count)
+        {
+            // This one's filling in a gap
+            self.reordered += 1;
+        }
+        self.held.insert(payload.count, *payload);
+        // Send on everything that's now in order
+        while let Some(&count) = self.held.keys().next() {
+            if count != self.next_expected_count {
+                let newest = *self.held.last_key_value().unwrap().0;
+                if newest - self.next_expected_count <= self.reorder_window {
+                    // Still within the window, keep waiting
+                    break;
+                }
+                // Packets were dropped, fill them in (hopefully not too many)
+                self.fill_gap(count, payload_sender)?;
+                continue;
+            }
+            let payload = self.held.remove(&count).unwrap();
+            self.forward(payload, payload_sender)?;
+            self.next_expected_count += 1;
+        }
+        Ok(())
     }
 
-    pub fn capture(&mut self, buf: &mut [u8]) -> eyre::Result<()> {
-        let n = self.sock.recv(buf)?;
-        if n != buf.len() {
-            Err(Error::SizeMismatch(n).into())
+    /// Is a packet count within reach of the one we expect next? Counts too far ahead are most
+    /// likely corrupt, unless a run of consecutive packets carry on from one.
+    fn believable(&mut self, count: u64) -> bool {
+        let Some(jump) = self.max_count_jump else {
+            return true;
+        };
+        if count <= self.next_expected_count.saturating_add(jump) {
+            self.jump_run = None;
+            return true;
+        }
+        let run = match self.jump_run {
+            Some((last, run)) if count == last + 1 => run + 1,
+            _ => 1,
+        };
+        if run >= JUMP_CONFIRMATION {
+            warn!(
+                count,
+                "Believing a jump in packet count, as it's carried on"
+            );
+            self.jump_run = None;
+            return true;
+        }
+        debug!(count, "Skipping a packet with an unbelievable count");
+        self.jump_run = Some((count, run));
+        false
+    }
+
+    /// Send a payload on, keeping a copy if we might need to repeat it
+    fn forward(
+        &mut self,
+        payload: Payload,
+        payload_sender: &StaticSender<Payload>,
+    ) -> eyre::Result<()> {
+        if let Some(last) = self.last_sent.as_mut() {
+            **last = payload;
+        }
+        // Periodically send a latency probe through the pipeline, on one that's actually going
+        if self.board == PRIMARY_BOARD && payload.count.is_multiple_of(latency::PROBE_EVERY) {
+            latency::launch(payload.count);
+        }
+        payload_sender.send(payload)?;
+        Ok(())
+    }
+
+    /// Give up on the packets from the next expected one up to `until`, sending fill in their place.
+    /// Gaps longer than [`MAX_FILL`] are only filled in up to the next multiple of it downstream
+    /// (nothing for the other boards), and the rest is skipped over.
+    fn fill_gap(&mut self, until: u64, payload_sender: &StaticSender<Payload>) -> eyre::Result<()> {
+        let drops = until - self.next_expected_count;
+        let fill = if drops <= MAX_FILL {
+            warn!("Jump in packet count, dropping {} packets", drops);
+            drops
+        } else if self.board == PRIMARY_BOARD {
+            // Where we are on the timeline downstream sees, counting from the first packet
+            let skipped = skipped_before(u64::MAX);
+            let first = FIRST_PACKET.load(Ordering::Acquire);
+            let offset = self
+                .next_expected_count
+                .saturating_sub(skipped)
+                .saturating_sub(first);
+            let fill = offset.next_multiple_of(MAX_FILL) - offset;
+            warn!(
+                skipped = drops - fill,
+                "Jump in packet count of {drops} packets, too many to fill in, so skipping ahead"
+            );
+            record_skip(first + offset + fill, drops - fill);
+            fill
         } else {
-            Ok(())
+            warn!(
+                board = self.board,
+                "Jump in packet count of {drops} packets, too many to fill in, so skipping ahead"
+            );
+            0
+        };
+        SKIPPED_PACKETS.inc_by(drops - fill);
+        for d in 0..fill {
+            // Create the payload in it's place
+            let mut pl = match &self.last_sent {
+                Some(last) => **last,
+                None => Payload::default(),
+            };
+            pl.count = self.next_expected_count + d;
+            pl.board = self.board;
+            pl.rx_time = 0;
+            pl.synthetic = true;
+            // And send
+            payload_sender.send(pl)?;
         }
+        // Increment our drops counter
+        self.drops += drops as usize;
+        // And finally update the next expected
+        self.next_expected_count = until;
+        Ok(())
     }
 
+    /// Capture packets (up to `batch` per syscall) until shutdown, or the end of a replay
     pub fn start(
         &mut self,
+        batch: usize,
         payload_sender: StaticSender<Payload>,
         stats_send: Sender<Stats>,
         stats_polling_time: Duration,
         mut shutdown: broadcast::Receiver<()>,
     ) -> eyre::Result<()> {
         let mut last_stats = Instant::now();
-        let mut capture_buf = [0u8; PAYLOAD_SIZE];
+        // Packets land right in these, the FPGA code ensures any 8200 bytes is a valid payload
+        let mut payloads = vec![Payload::default(); batch.max(1)];
         loop {
             // Look for shutdown signal
             if shutdown.try_recv().is_ok() {
                 info!("Capture task stopping");
                 break;
             }
-            // Capture into buf
-            self.capture(&mut capture_buf[..])?;
-            // Transmute into a payload
-            // Safety: We will always own the bytes, and the FPGA code ensures this is a valid thing to do
-            // Also, we've checked that we've captured exactly 8200 bytes, which is the size of the payload
-            let payload = unsafe { &*(capture_buf.as_ptr() as *const Payload) };
-            self.processed += 1;
+            // Capture a burst
+            let got = self.capture(&mut payloads)?;
+            for payload in &mut payloads[..got] {
+                payload.board = self.board;
+                if let Some(recorder) = &self.recorder {
+                    record::offer(recorder, payload);
+                }
+                self.processed += 1;
+                self.arrivals.record(payload.count, payload.rx_time);
+                // Only the primary board is re-triggered with the SNAP, and followed downstream
+                if self.board == PRIMARY_BOARD {
+                    payload.count += COUNT_OFFSET.load(Ordering::Relaxed);
+                    LAST_PACKET.store(payload.count, Ordering::Relaxed);
+                }
+                self.process(payload, &payload_sender)?;
+            }
             // Send away the stats if the time has come (non blocking)
             if last_stats.elapsed() >= stats_polling_time {
+                self.poll_kernel_drops();
+                if let (PRIMARY_BOARD, Some(sync), Some(last)) =
+                    (self.board, timing::time_sync(), payloads[..got].last())
+                {
+                    if let Some(latency) = last.rx_latency(&sync.trigger_epoch) {
+                        RX_LATENCY_GAUGE.set(latency.to_seconds());
+                    }
+                }
                 let _ = stats_send.try_send(Stats {
+                    board: self.board,
                     drops: self.drops,
+                    kernel_drops: self.kernel_drops,
                     processed: self.processed,
                     shuffled: self.shuffled,
+                    reordered: self.reordered,
+                    restarts: self.restarts,
+                    malformed: self.malformed,
+                    throughput: self.arrivals.take(),
                 });
                 last_stats = Instant::now();
             }
-            // Check first payload
-            if self.first_payload {
-                self.first_payload = false;
-                // And send the first one
-                payload_sender.send(*payload)?;
-                self.next_expected_count = payload.count + 1;
-            } else if payload.count == self.next_expected_count {
-                self.next_expected_count += 1;
-                // And send
-                payload_sender.send(*payload)?;
-            } else if payload.count < self.next_expected_count {
-                // If the packet is from the past, we drop it
-                warn!("Anachronistic payload, dropping packet");
-                self.shuffled += 1;
-            } else {
-                // payload.count > self.next_expected_count
-                // Packets were dropped, fill in with zeros (hopefully not too many)
-                let drops = payload.count - self.next_expected_count;
-                warn!("Jump in packet count, dropping {} packets", drops);
-                for d in 0..drops {
-                    // Create the payload in it's place
-                    let pl = Payload {
-                        count: self.next_expected_count + d,
-                        ..Default::default()
-                    };
-                    // And send
-                    payload_sender.send(pl)?;
-                }
-                // Increment our drops counter
-                self.drops += drops as usize;
-                // And finally update the next expected
-                self.next_expected_count = payload.count + 1;
+            if self.finished() {
+                break;
             }
         }
         Ok(())
     }
 }
 
-#[derive(Debug, Clone, Default)]
+/// Bind a UDP socket on `port` for capture
+fn bind_socket(port: u16, options: &SocketOptions) -> eyre::Result<UdpSocket> {
+    // Create UDP socket
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    // Sharing the port has to be agreed on before binding
+    if options.reuseport {
+        set_socket_option(&socket, libc::SO_REUSEPORT, 1).map_err(Error::SetReusePortFailed)?;
+    }
+    // Bind our listening address
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    socket.bind(&address.into())?;
+    // Reuse local address without timeout
+    socket.reuse_address()?;
+    // Set the buffer size (it will read as double, as the kernel leaves room for its bookkeeping)
+    let sock_buf_size = options.rx_buffer_bytes;
+    socket.set_recv_buffer_size(sock_buf_size)?;
+    // Check
+    let current_buf_size = socket.recv_buffer_size()?;
+    if current_buf_size != sock_buf_size * 2 {
+        return Err(Error::SetRecvBufferFailed {
+            expected: sock_buf_size * 2,
+            found: current_buf_size,
+        }
+        .into());
+    }
+    // Spin on the NIC's queue for a while before sleeping when there's nothing there
+    if let Some(us) = options.busy_poll_us {
+        set_socket_option(&socket, libc::SO_BUSY_POLL, us as libc::c_int)
+            .map_err(|e| Error::SetBusyPollFailed(us, e))?;
+    }
+    // Have the kernel tell us how many packets it dropped at the socket with each one we get,
+    // so we can tell host receive queue overflows apart from loss on the network
+    set_socket_option(&socket, libc::SO_RXQ_OVFL, 1)?;
+    // And when each packet arrived, by the NIC's clock if it's been set up to timestamp
+    // everything (e.g. `hwstamp_ctl -r 1`), otherwise the kernel's
+    set_socket_option(
+        &socket,
+        libc::SO_TIMESTAMPING,
+        (libc::SOF_TIMESTAMPING_RX_HARDWARE
+            | libc::SOF_TIMESTAMPING_RAW_HARDWARE
+            | libc::SOF_TIMESTAMPING_RX_SOFTWARE
+            | libc::SOF_TIMESTAMPING_SOFTWARE) as libc::c_int,
+    )?;
+    // Replace the socket2 socket with a std socket
+    Ok(socket.into())
+}
+
+/// Set an integer `SOL_SOCKET` option
+fn set_socket_option(
+    socket: &Socket,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    // Safety: We're passing a valid fd and an int option of the right size
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            name,
+            std::ptr::addr_of!(value).cast(),
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Nanoseconds since the Unix epoch of `ts`
+fn timespec_nanos(ts: &libc::timespec) -> u64 {
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// The message headers `recvmmsg` fills in, one per payload in a batch
+#[derive(Default)]
+struct RecvHeaders {
+    iovs: Vec<libc::iovec>,
+    /// Room for the control messages we asked for (the socket's drop count and the receive
+    /// timestamps), suitably aligned
+    controls: Vec<[u64; 16]>,
+    msgs: Vec<libc::mmsghdr>,
+}
+
+// Safety: The pointers are only followed during a receive, which points them all anew first
+unsafe impl Send for RecvHeaders {}
+
+impl RecvHeaders {
+    /// Point the headers at `payloads` (and their own control buffers), only allocating if the
+    /// batch is bigger than any before
+    fn point_at(&mut self, payloads: &mut [Payload]) -> &mut [libc::mmsghdr] {
+        let n = payloads.len();
+        if self.msgs.len() < n {
+            // Safety: All-zero iovecs and mmsghdrs are valid (and empty)
+            self.iovs.resize(n, unsafe { std::mem::zeroed() });
+            self.controls.resize(n, [0; 16]);
+            self.msgs.resize(n, unsafe { std::mem::zeroed() });
+        }
+        for (((pl, iov), control), msg) in payloads
+            .iter_mut()
+            .zip(&mut self.iovs)
+            .zip(&mut self.controls)
+            .zip(&mut self.msgs)
+        {
+            iov.iov_base = (pl as *mut Payload).cast();
+            iov.iov_len = PAYLOAD_SIZE;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = control.as_mut_ptr().cast();
+            // The kernel shrinks this to what it wrote, so it has to be reset every time
+            msg.msg_hdr.msg_controllen = std::mem::size_of_val(control) as _;
+            msg.msg_hdr.msg_flags = 0;
+        }
+        &mut self.msgs[..n]
+    }
+}
+
+/// Receive up to `payloads.len()` packets straight into `payloads` with one syscall, blocking until
+/// at least one arrives, and returning how many we got
+fn recv_socket(
+    sock: &UdpSocket,
+    payloads: &mut [Payload],
+    headers: &mut RecvHeaders,
+    kernel_drops: &mut u32,
+    malformed: &mut usize,
+) -> eyre::Result<usize> {
+    let n = payloads.len();
+    let msgs = headers.point_at(payloads);
+    // Safety: Everything msgs points to outlives the call, and there are `n` of them
+    let got = unsafe {
+        libc::recvmmsg(
+            sock.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            n as _,
+            libc::MSG_WAITFORONE,
+            std::ptr::null_mut(),
+        )
+    };
+    if got < 0 {
+        let err = std::io::Error::last_os_error();
+        // A signal got to us first, just try again
+        if err.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(0);
+        }
+        return Err(err.into());
+    }
+    let got = got as usize;
+    // Packets we're keeping, shuffled down over any malformed ones
+    let mut kept = 0;
+    for (i, msg) in msgs[..got].iter().enumerate() {
+        let payload = &mut payloads[i];
+        payload.rx_time = 0;
+        // The kernel only includes the count once it's dropped something.
+        // Safety: The cmsg macros walk the control buffer the kernel just filled in
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET {
+                    match (*cmsg).cmsg_type {
+                        libc::SO_RXQ_OVFL => {
+                            *kernel_drops =
+                                std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<u32>());
+                        }
+                        libc::SCM_TIMESTAMPING => {
+                            // Software, (deprecated), and raw hardware timestamps
+                            let [sw, _, hw] = std::ptr::read_unaligned(
+                                libc::CMSG_DATA(cmsg).cast::<[libc::timespec; 3]>(),
+                            );
+                            let hw = timespec_nanos(&hw);
+                            payload.rx_time = if hw != 0 { hw } else { timespec_nanos(&sw) };
+                        }
+                        _ => (),
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg.msg_hdr, cmsg);
+            }
+        }
+        // Anything bigger than a payload was truncated to fit, and flagged as such
+        if msg.msg_len as usize != PAYLOAD_SIZE || msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0 {
+            debug!(
+                "Skipping a malformed packet - {}",
+                Error::SizeMismatch(msg.msg_len as usize)
+            );
+            *malformed += 1;
+            continue;
+        }
+        if kept != i {
+            payloads[kept] = payloads[i];
+        }
+        kept += 1;
+    }
+    Ok(kept)
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 /// Statistics we send to the monitoring thread
 pub struct Stats {
+    /// The board these are for
+    #[serde(default)]
+    pub board: u8,
+    /// Gaps in the packet count, wherever the packets were lost
     pub drops: usize,
+    /// Packets the kernel dropped because our receive queue was full
+    pub kernel_drops: u32,
     pub processed: usize,
+    /// Packets that came too late to put back in order
     pub shuffled: usize,
+    /// Packets that came out of order but were put back in place
+    #[serde(default)]
+    pub reordered: usize,
+    /// Times the capture socket failed and was bound again
+    #[serde(default)]
+    pub restarts: usize,
+    /// Packets thrown away as corrupt (the wrong size, or an unbelievable count)
+    #[serde(default)]
+    pub malformed: usize,
+    /// Rates and jitter over the last stats interval
+    #[serde(default)]
+    pub throughput: Throughput,
 }
 
 pub fn cap_task(
-    port: u16,
+    config: CaptureConfig,
+    recorder: Option<Sender<Payload>>,
     cap_send: StaticSender<Payload>,
     stats_send: Sender<Stats>,
     shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
-    info!("Starting capture task!");
-    let mut cap = Capture::new(port).unwrap();
-    cap.start(cap_send, stats_send, STATS_POLL_DURATION, shutdown)
+    let CaptureConfig {
+        board,
+        port,
+        batch,
+        reorder_window,
+        fill,
+        max_count_jump,
+        socket,
+        input,
+    } = config;
+    info!(board, port, "Starting capture task!");
+    let mut cap = match input {
+        Input::Socket => Capture::new(port, &socket)?,
+        Input::Uring => Capture::uring(port, &socket)?,
+        Input::Xdp { interface, config } => Capture::xdp(&interface, port, &config)?,
+        Input::Replay(config) => Capture::replay(&config, port)?,
+        Input::Simulate(args) => Capture::simulate(&args)?,
+    };
+    cap.board = board;
+    cap.recorder = recorder;
+    cap.set_reorder_window(reorder_window);
+    cap.set_fill_policy(fill);
+    cap.set_max_count_jump(max_count_jump);
+    if let Some(resumed) = state::resumed().filter(|_| board == PRIMARY_BOARD) {
+        let next = resumed.next_count();
+        info!(next, "Picking the packet stream back up");
+        cap.resume(&resumed.stats, next);
+    }
+    cap.start(batch, cap_send, stats_send, STATS_POLL_DURATION, shutdown)
 }
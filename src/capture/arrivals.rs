@@ -0,0 +1,90 @@
+//! Throughput and packet arrival jitter over each stats interval
+//!
+//! Jitter is how far each packet's arrival (by its receive timestamp) strays from when it should
+//! have come, given the packet before it and the packet cadence. It's kept as a histogram of fixed
+//! width bins, so taking the percentiles doesn't mean keeping every packet around.
+use super::PAYLOAD_SIZE;
+use crate::common::PACKET_CADENCE;
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// Width of each jitter bin (ns)
+const JITTER_BIN_NS: u64 = 100;
+/// Number of jitter bins (out to a millisecond), anything past that goes in one last bin
+const JITTER_BINS: usize = 10_000;
+
+/// How the packets came in over a stats interval
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct Throughput {
+    pub packets_per_second: f64,
+    pub bytes_per_second: f64,
+    /// Median, 99th, and 99.9th percentile jitter (s), if the packets had receive timestamps
+    pub jitter: Option<[f64; 3]>,
+}
+
+pub struct Arrivals {
+    since: Instant,
+    packets: u64,
+    /// Count and receive time (ns) of the last packet with a timestamp
+    last: Option<(u64, u64)>,
+    jitter: Vec<u64>,
+}
+
+impl Default for Arrivals {
+    fn default() -> Self {
+        Self {
+            since: Instant::now(),
+            packets: 0,
+            last: None,
+            jitter: vec![0; JITTER_BINS + 1],
+        }
+    }
+}
+
+impl Arrivals {
+    /// Note a packet with `count` received at `rx_time` (ns since the Unix epoch, 0 if unknown)
+    pub fn record(&mut self, count: u64, rx_time: u64) {
+        self.packets += 1;
+        if rx_time == 0 {
+            return;
+        }
+        if let Some((last_count, last_time)) = self.last {
+            if count > last_count {
+                let expected = ((count - last_count) as f64 * PACKET_CADENCE * 1e9) as u64;
+                let bin = (rx_time.abs_diff(last_time).abs_diff(expected) / JITTER_BIN_NS) as usize;
+                self.jitter[bin.min(JITTER_BINS)] += 1;
+            }
+        }
+        self.last = Some((count, rx_time));
+    }
+
+    /// Upper edge (s) of the bin the `q`th quantile of the jitter falls in
+    fn quantile(&self, q: f64, total: u64) -> f64 {
+        let target = (q * total as f64).ceil() as u64;
+        let mut seen = 0;
+        let bin = self
+            .jitter
+            .iter()
+            .position(|n| {
+                seen += n;
+                seen >= target
+            })
+            .unwrap_or(JITTER_BINS);
+        ((bin as u64 + 1) * JITTER_BIN_NS) as f64 * 1e-9
+    }
+
+    /// The throughput and jitter since we last took them, starting afresh
+    pub fn take(&mut self) -> Throughput {
+        let elapsed = self.since.elapsed().as_secs_f64();
+        let total: u64 = self.jitter.iter().sum();
+        let throughput = Throughput {
+            packets_per_second: self.packets as f64 / elapsed,
+            bytes_per_second: (self.packets * PAYLOAD_SIZE as u64) as f64 / elapsed,
+            jitter: (total > 0).then(|| [0.5, 0.99, 0.999].map(|q| self.quantile(q, total))),
+        };
+        self.since = Instant::now();
+        self.packets = 0;
+        self.jitter.iter_mut().for_each(|n| *n = 0);
+        throughput
+    }
+}
@@ -0,0 +1,98 @@
+//! Pulling our payloads out of whole packets, for the backends that get them with headers attached
+use super::{Error, PAYLOAD_SIZE};
+use crate::common::Payload;
+use std::ops::Range;
+
+/// Ethernet, IPv4, and UDP header bits we look at
+const ETH_HEADER: usize = 14;
+const VLAN_TAG: usize = 4;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const IPPROTO_UDP: u8 = 17;
+const UDP_HEADER: usize = 8;
+
+fn be16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+/// The UDP payload of the Ethernet `frame` if it's headed for `port`
+pub fn udp_payload(frame: &[u8], port: u16) -> Option<&[u8]> {
+    frame.get(udp_payload_span(frame, port)?)
+}
+
+/// Where the UDP payload is in the Ethernet frame if it's headed for `port`, which only needs
+/// `headers` to hold the start of the frame up to the end of the UDP header
+pub fn udp_payload_span(headers: &[u8], port: u16) -> Option<Range<usize>> {
+    let mut ip = ETH_HEADER;
+    let mut ethertype = be16(headers, 12)?;
+    if ethertype == ETHERTYPE_VLAN {
+        ethertype = be16(headers, 16)?;
+        ip += VLAN_TAG;
+    }
+    if ethertype != ETHERTYPE_IPV4 {
+        return None;
+    }
+    let span = ipv4_udp_payload_span(headers.get(ip..)?, port)?;
+    Some(ip + span.start..ip + span.end)
+}
+
+/// The UDP payload of the IPv4 `packet` if it's headed for `port`
+pub fn ipv4_udp_payload(packet: &[u8], port: u16) -> Option<&[u8]> {
+    packet.get(ipv4_udp_payload_span(packet, port)?)
+}
+
+/// Where the UDP payload is in the IPv4 packet starting `headers` if it's headed for `port`
+fn ipv4_udp_payload_span(headers: &[u8], port: u16) -> Option<Range<usize>> {
+    if *headers.get(9)? != IPPROTO_UDP {
+        return None;
+    }
+    let udp = usize::from(headers.first()? & 0x0f) * 4;
+    if be16(headers, udp + 2)? != port {
+        return None;
+    }
+    let len = usize::from(be16(headers, udp + 4)?);
+    Some(udp + UDP_HEADER..udp + len.max(UDP_HEADER))
+}
+
+/// Copy a packet's UDP payload into `payload`
+pub fn fill_payload(payload: &mut Payload, data: &[u8]) -> Result<(), Error> {
+    gather_payload(payload, 0..data.len(), [data])
+}
+
+/// Copy the UDP payload at `span` of a packet that came in as consecutive `pieces` into `payload`
+pub fn gather_payload<'a>(
+    payload: &mut Payload,
+    span: Range<usize>,
+    pieces: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<(), Error> {
+    if span.len() != PAYLOAD_SIZE {
+        return Err(Error::SizeMismatch(span.len()));
+    }
+    let dst = (payload as *mut Payload).cast::<u8>();
+    // Where the piece we're on starts in the packet
+    let mut at = 0;
+    for piece in pieces {
+        // The part of the payload in this piece
+        let start = span.start.max(at);
+        let end = span.end.min(at + piece.len());
+        if start < end {
+            // Safety: Payload is repr(C) plain-old-data starting with the PAYLOAD_SIZE bytes of
+            // the packet (and the FPGA code ensures any 8200 bytes is a valid one), and
+            // start..end is within both the span and the piece
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    piece.as_ptr().add(start - at),
+                    dst.add(start - span.start),
+                    end - start,
+                );
+            }
+        }
+        at += piece.len();
+    }
+    // The packet was cut short of the length in its header
+    if at < span.end {
+        return Err(Error::SizeMismatch(at.saturating_sub(span.start)));
+    }
+    payload.rx_time = 0;
+    Ok(())
+}
@@ -0,0 +1,184 @@
+//! Recording every packet we capture to pcap files, for digging into corrupted data after the fact
+//!
+//! Capture offers each payload (as it came off the wire) to a writer thread, which never holds
+//! capture up: if the writer falls behind, payloads are dropped from the recording and counted. We
+//! only have the UDP payloads, so each is wrapped in made-up IPv4 and UDP headers (addressed to the
+//! board's capture port) to keep the files readable by the usual tools and by `--replay`. Files are
+//! rotated by size, keeping only the most recent few.
+use super::PAYLOAD_SIZE;
+use crate::{
+    common::{Payload, BLOCK_TIMEOUT},
+    timing,
+};
+use hifitime::Epoch;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use thingbuf::mpsc::{
+    blocking::{Receiver, Sender},
+    errors::RecvTimeoutError,
+};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Payloads the writer can fall behind by before we start dropping them from the recording
+pub const RECORD_CHANNEL_SIZE: usize = 16384;
+/// pcap magic number for nanosecond timestamps, and the raw IPv4 link type
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const LINKTYPE_IPV4: u32 = 228;
+/// Sizes of the headers we make up
+const IPV4_HEADER: usize = 20;
+const UDP_HEADER: usize = 8;
+const PACKET_LEN: usize = IPV4_HEADER + UDP_HEADER + PAYLOAD_SIZE;
+
+lazy_static! {
+    static ref RECORD_DROPPED: IntCounter = register_int_counter!(
+        "raw_record_dropped_payloads",
+        "Number of payloads left out of the raw recording because the writer was behind"
+    )
+    .unwrap();
+}
+
+/// Where to record raw packets and how much to keep
+#[derive(Debug, Clone)]
+pub struct RecordConfig {
+    /// Directory the pcap files go in
+    pub path: PathBuf,
+    /// Size at which to start a new file (bytes)
+    pub file_size: u64,
+    /// Number of files to keep, deleting the oldest
+    pub keep: usize,
+}
+
+/// Offer a copy of a freshly captured payload to the recorder
+pub fn offer(recorder: &Sender<Payload>, payload: &Payload) {
+    if recorder.try_send(*payload).is_err() {
+        RECORD_DROPPED.inc();
+    }
+}
+
+/// IPv4 and UDP headers for a payload sent to `port`
+fn headers(port: u16) -> [u8; IPV4_HEADER + UDP_HEADER] {
+    let mut h = [0u8; IPV4_HEADER + UDP_HEADER];
+    // Version 4, 5 word header, don't fragment, TTL 64, UDP, from and to 0.0.0.0
+    h[0] = 0x45;
+    h[2..4].copy_from_slice(&(PACKET_LEN as u16).to_be_bytes());
+    h[6] = 0x40;
+    h[8] = 64;
+    h[9] = 17;
+    let checksum = !h[..IPV4_HEADER]
+        .chunks(2)
+        .map(|w| u32::from(u16::from_be_bytes([w[0], w[1]])))
+        .fold(0u32, |sum, w| {
+            let sum = sum + w;
+            (sum & 0xffff) + (sum >> 16)
+        }) as u16;
+    h[10..12].copy_from_slice(&checksum.to_be_bytes());
+    // UDP, with no checksum
+    h[20..22].copy_from_slice(&port.to_be_bytes());
+    h[22..24].copy_from_slice(&port.to_be_bytes());
+    h[24..26].copy_from_slice(&((UDP_HEADER + PAYLOAD_SIZE) as u16).to_be_bytes());
+    h
+}
+
+/// The pcap file being written
+struct Recording {
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl Recording {
+    fn create(path: &Path) -> eyre::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&MAGIC_NANOS.to_le_bytes())?;
+        // Version 2.4, no timezone or accuracy
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&[0u8; 8])?;
+        file.write_all(&(PACKET_LEN as u32).to_le_bytes())?;
+        file.write_all(&LINKTYPE_IPV4.to_le_bytes())?;
+        Ok(Self { file, written: 24 })
+    }
+
+    fn write(&mut self, headers: &[u8], payload: &Payload, rx_time: u64) -> eyre::Result<()> {
+        let secs = (rx_time / 1_000_000_000) as u32;
+        let nanos = (rx_time % 1_000_000_000) as u32;
+        self.file.write_all(&secs.to_le_bytes())?;
+        self.file.write_all(&nanos.to_le_bytes())?;
+        self.file.write_all(&(PACKET_LEN as u32).to_le_bytes())?;
+        self.file.write_all(&(PACKET_LEN as u32).to_le_bytes())?;
+        self.file.write_all(headers)?;
+        self.file.write_all(payload.as_packet())?;
+        self.written += (16 + PACKET_LEN) as u64;
+        Ok(())
+    }
+}
+
+/// Nanoseconds since the Unix epoch, now
+fn now_nanos() -> eyre::Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64)
+}
+
+/// Write the payloads from `receiver` (from boards capturing on `ports`) to rotating pcap files
+pub fn record_task(
+    config: RecordConfig,
+    ports: Vec<u16>,
+    receiver: Receiver<Payload>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    std::fs::create_dir_all(&config.path)?;
+    info!("Recording raw packets to {}", config.path.display());
+    let headers: Vec<_> = ports.iter().map(|p| headers(*p)).collect();
+    let mut files = VecDeque::new();
+    let mut recording: Option<Recording> = None;
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Raw packet recorder stopping");
+            break;
+        }
+        let payload = match receiver.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(p) => p,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        };
+        // Move on to a new file once this one's full
+        if !matches!(&recording, Some(r) if r.written < config.file_size) {
+            if let Some(mut r) = recording.take() {
+                r.file.flush()?;
+            }
+            let now = timing::iso8601(Epoch::now()?).replace(':', "-");
+            let path = config.path.join(format!("grex_raw_{now}.pcap"));
+            recording = Some(Recording::create(&path)?);
+            files.push_back(path);
+            while files.len() > config.keep.max(1) {
+                let old = files.pop_front().unwrap();
+                if let Err(e) = std::fs::remove_file(&old) {
+                    warn!("Couldn't remove old raw recording {} - {e}", old.display());
+                }
+            }
+        }
+        let rx_time = if payload.rx_time == 0 {
+            now_nanos()?
+        } else {
+            payload.rx_time
+        };
+        let headers = headers
+            .get(usize::from(payload.board))
+            .unwrap_or(&headers[0]);
+        recording
+            .as_mut()
+            .unwrap()
+            .write(headers, &payload, rx_time)?;
+    }
+    if let Some(mut r) = recording {
+        r.file.flush()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,178 @@
+//! Replaying packets recorded in a pcap file (e.g. with `tcpdump -w`), in place of a live socket
+//!
+//! Only the classic pcap format is supported (not pcapng), with either Ethernet or raw IP link
+//! layers. Packets are replayed either at the cadence they were recorded at, or as fast as the rest
+//! of the pipeline will take them.
+use super::frame::{fill_payload, ipv4_udp_payload, udp_payload};
+use crate::common::Payload;
+use eyre::bail;
+use std::{
+    fs::File,
+    io::{BufReader, ErrorKind, Read},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+/// Magic numbers of pcap files with microsecond and nanosecond timestamps
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// Link layers we know how to get IPv4 packets out of
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
+/// Size of the file and per-packet headers
+const FILE_HEADER: usize = 24;
+const RECORD_HEADER: usize = 16;
+
+/// What to replay and how fast
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    pub path: PathBuf,
+    /// Keep to the recorded packet timing, rather than going as fast as we can
+    pub realtime: bool,
+}
+
+pub struct Replay {
+    reader: BufReader<File>,
+    /// Byte order of the file's header fields
+    big_endian: bool,
+    /// If the timestamps' fractional part is nanoseconds (rather than microseconds)
+    nanos: bool,
+    /// If records start at the Ethernet header (rather than the IP header)
+    ethernet: bool,
+    /// UDP port the packets we want were sent to
+    port: u16,
+    realtime: bool,
+    /// Recorded time (ns since the Unix epoch) of the first packet and when we replayed it
+    start: Option<(u64, Instant)>,
+    /// The record we're looking at
+    record: Vec<u8>,
+    /// Whether we've run out of packets
+    pub finished: bool,
+    /// How many records we've replayed
+    replayed: u64,
+}
+
+impl Replay {
+    /// Open the pcap file at `path`, to replay the packets in it sent to `port`
+    pub fn new(path: &Path, port: u16, realtime: bool) -> eyre::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; FILE_HEADER];
+        reader.read_exact(&mut header)?;
+        let magic = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nanos) = match (u32::from_le_bytes(magic), u32::from_be_bytes(magic)) {
+            (MAGIC_MICROS, _) => (false, false),
+            (MAGIC_NANOS, _) => (false, true),
+            (_, MAGIC_MICROS) => (true, false),
+            (_, MAGIC_NANOS) => (true, true),
+            _ => bail!(
+                "{} isn't a pcap file (pcapng isn't supported)",
+                path.display()
+            ),
+        };
+        let mut replay = Self {
+            reader,
+            big_endian,
+            nanos,
+            ethernet: true,
+            port,
+            realtime,
+            start: None,
+            record: Vec::new(),
+            finished: false,
+            replayed: 0,
+        };
+        // The link type sits in the low 16 bits, the rest are flags we don't care about
+        replay.ethernet = match replay.u32_at(&header, 20) & 0xffff {
+            LINKTYPE_ETHERNET => true,
+            LINKTYPE_RAW | LINKTYPE_IPV4 => false,
+            link => bail!("Can't replay packets with link type {link}"),
+        };
+        info!(realtime, "Replaying packets from {}", path.display());
+        Ok(replay)
+    }
+
+    fn u32_at(&self, bytes: &[u8], at: usize) -> u32 {
+        let word = [bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]];
+        if self.big_endian {
+            u32::from_be_bytes(word)
+        } else {
+            u32::from_le_bytes(word)
+        }
+    }
+
+    /// Read the next record, returning its timestamp (ns since the Unix epoch), or None at the end
+    /// of the file
+    fn next_record(&mut self) -> eyre::Result<Option<u64>> {
+        let mut header = [0u8; RECORD_HEADER];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let secs = self.u32_at(&header, 0);
+        let frac = self.u32_at(&header, 4);
+        let len = self.u32_at(&header, 8) as usize;
+        self.record.resize(len, 0);
+        match self.reader.read_exact(&mut self.record) {
+            Ok(()) => (),
+            // Whoever was recording stopped partway through this one
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        self.replayed += 1;
+        let frac_nanos = if self.nanos { 1 } else { 1000 };
+        Ok(Some(
+            u64::from(secs) * 1_000_000_000 + u64::from(frac) * frac_nanos,
+        ))
+    }
+
+    /// Wait until it's time for a packet recorded at `t`
+    fn pace(&mut self, t: u64) {
+        let (first, start) = *self.start.get_or_insert((t, Instant::now()));
+        if !self.realtime {
+            return;
+        }
+        let due = start + Duration::from_nanos(t.saturating_sub(first));
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+    }
+
+    /// Copy up to `payloads.len()` of the recorded packets into `payloads`, returning how many we
+    /// got (fewer once we've reached the end of the file), and counting any that weren't
+    /// payload-sized in `malformed`
+    pub fn recv(&mut self, payloads: &mut [Payload], malformed: &mut usize) -> eyre::Result<usize> {
+        let mut got = 0;
+        while got < payloads.len() {
+            let Some(t) = self.next_record()? else {
+                if !self.finished {
+                    info!(records = self.replayed, "Finished replaying packets");
+                }
+                self.finished = true;
+                break;
+            };
+            let data = if self.ethernet {
+                udp_payload(&self.record, self.port)
+            } else {
+                ipv4_udp_payload(&self.record, self.port)
+            };
+            let Some(data) = data else {
+                debug!("Skipping a recorded packet that isn't for us");
+                continue;
+            };
+            if let Err(e) = fill_payload(&mut payloads[got], data) {
+                debug!("Skipping a malformed recorded packet - {e}");
+                *malformed += 1;
+                continue;
+            }
+            // Keeping the time it was recorded as its receive time
+            payloads[got].rx_time = t;
+            got += 1;
+            self.pace(t);
+        }
+        Ok(got)
+    }
+}
@@ -0,0 +1,182 @@
+//! io_uring capture, with one multishot receive feeding a ring of buffers we lend the kernel
+//!
+//! The receive stays armed on the socket and completes once per packet, into the next free buffer
+//! of a provided buffer ring, so a whole burst of packets costs a single `io_uring_enter`. We copy
+//! each packet out into a payload and hand its buffer straight back. This needs Linux 6.0 or newer
+//! and, unlike the regular socket, doesn't get receive timestamps or the kernel's drop count.
+use super::{bind_socket, frame::fill_payload, SocketOptions};
+use crate::common::Payload;
+use io_uring::{cqueue, opcode, types, IoUring};
+use memmap2::MmapMut;
+use std::{
+    io,
+    net::UdpSocket,
+    os::fd::AsRawFd,
+    sync::atomic::{AtomicU16, Ordering},
+};
+use tracing::{debug, info};
+
+/// Number of receive buffers (a power of two, at most 32768)
+const BUFFERS: u16 = 4096;
+/// Size of each receive buffer, with room to spare so oversized packets show up as such
+const BUFFER_SIZE: usize = 16384;
+/// Buffer group the receive buffers are registered as
+const BUFFER_GROUP: u16 = 0;
+/// Submission queue entries, we only ever have the one receive in flight
+const SUBMISSION_ENTRIES: u32 = 8;
+/// How long to wait for packets before checking back in (ns)
+const WAIT_TIMEOUT_NS: u32 = 100_000_000;
+
+/// The buffers we lend the kernel to receive into, and the ring we lend them through
+struct BufRing {
+    /// Buffer descriptors, shared with the kernel
+    ring: MmapMut,
+    buffers: Vec<u8>,
+    /// Where we'll put the next returned buffer (the kernel only sees it once it's published)
+    tail: u16,
+}
+
+impl BufRing {
+    fn new() -> io::Result<Self> {
+        let ring =
+            MmapMut::map_anon(usize::from(BUFFERS) * std::mem::size_of::<types::BufRingEntry>())?;
+        let mut bufs = Self {
+            ring,
+            buffers: vec![0; usize::from(BUFFERS) * BUFFER_SIZE],
+            tail: 0,
+        };
+        for bid in 0..BUFFERS {
+            bufs.push(bid);
+        }
+        bufs.publish();
+        Ok(bufs)
+    }
+
+    fn entries(&mut self) -> *mut types::BufRingEntry {
+        self.ring.as_mut_ptr().cast()
+    }
+
+    fn buffer(&self, bid: u16, len: usize) -> &[u8] {
+        &self.buffers[usize::from(bid) * BUFFER_SIZE..][..len.min(BUFFER_SIZE)]
+    }
+
+    /// Put buffer `bid` back in the ring
+    fn push(&mut self, bid: u16) {
+        let addr = self.buffers[usize::from(bid) * BUFFER_SIZE..].as_ptr() as u64;
+        let idx = usize::from(self.tail & (BUFFERS - 1));
+        // Safety: The index is masked to within the ring
+        let entry = unsafe { &mut *self.entries().add(idx) };
+        entry.set_addr(addr);
+        entry.set_len(BUFFER_SIZE as u32);
+        entry.set_bid(bid);
+        self.tail = self.tail.wrapping_add(1);
+    }
+
+    /// Let the kernel have the buffers we've put back
+    fn publish(&mut self) {
+        // Safety: The tail lives in the first entry, which is always there and initialized
+        unsafe {
+            let tail = types::BufRingEntry::tail(self.entries()).cast::<AtomicU16>();
+            (*tail).store(self.tail, Ordering::Release);
+        }
+    }
+}
+
+pub struct UringSocket {
+    // The ring has to go (and let go of the buffers) before the buffers do
+    ring: IoUring,
+    bufs: BufRing,
+    sock: UdpSocket,
+    /// If our multishot receive is still going
+    armed: bool,
+}
+
+impl UringSocket {
+    /// Bind a socket on `port` and set up io_uring to receive from it
+    pub fn new(port: u16, options: &SocketOptions) -> eyre::Result<Self> {
+        let sock = bind_socket(port, options)?;
+        let ring = IoUring::builder()
+            .setup_cqsize(2 * u32::from(BUFFERS))
+            .build(SUBMISSION_ENTRIES)?;
+        let bufs = BufRing::new()?;
+        // Safety: The buffer ring outlives the io_uring it's registered with
+        unsafe {
+            ring.submitter().register_buf_ring_with_flags(
+                bufs.ring.as_ptr() as u64,
+                BUFFERS,
+                BUFFER_GROUP,
+                0,
+            )?;
+        }
+        info!(port, "Capturing with io_uring");
+        Ok(Self {
+            ring,
+            bufs,
+            sock,
+            armed: false,
+        })
+    }
+
+    /// Receive up to `payloads.len()` packets into `payloads`, returning how many we got (none if
+    /// nothing came in for a while), and counting any that weren't payload-sized in `malformed`
+    pub fn recv(&mut self, payloads: &mut [Payload], malformed: &mut usize) -> eyre::Result<usize> {
+        if !self.armed {
+            let recv =
+                opcode::RecvMulti::new(types::Fd(self.sock.as_raw_fd()), BUFFER_GROUP).build();
+            // Safety: The buffers it receives into are registered, and outlive the request
+            unsafe { self.ring.submission().push(&recv)? };
+            self.ring.submit()?;
+            self.armed = true;
+        }
+        // Wait for something to come in, checking back in every so often
+        if self.ring.completion().is_empty() {
+            let timeout = types::Timespec::new().nsec(WAIT_TIMEOUT_NS);
+            let args = types::SubmitArgs::new().timespec(&timeout);
+            match self.ring.submitter().submit_with_args(1, &args) {
+                Ok(_) => (),
+                Err(e) if e.raw_os_error() == Some(libc::ETIME) => (),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut got = 0;
+        let mut result = Ok(());
+        let mut completions = self.ring.completion();
+        while got < payloads.len() {
+            let Some(cqe) = completions.next() else {
+                break;
+            };
+            if !cqueue::more(cqe.flags()) {
+                // The receive's done, we'll start another next time
+                self.armed = false;
+            }
+            if cqe.result() == -libc::ENOBUFS {
+                // We fell behind and ran out of buffers, the packets wait in the socket meanwhile
+                debug!("Out of io_uring receive buffers");
+                continue;
+            }
+            if cqe.result() < 0 {
+                result = Err(io::Error::from_raw_os_error(-cqe.result()).into());
+                break;
+            }
+            let Some(bid) = cqueue::buffer_select(cqe.flags()) else {
+                continue;
+            };
+            let filled = fill_payload(
+                &mut payloads[got],
+                self.bufs.buffer(bid, cqe.result() as usize),
+            );
+            self.bufs.push(bid);
+            match filled {
+                Ok(()) => got += 1,
+                Err(e) => {
+                    debug!("Skipping a malformed packet - {e}");
+                    *malformed += 1;
+                }
+            }
+        }
+        drop(completions);
+        self.bufs.publish();
+        result.map(|()| got)
+    }
+}
@@ -0,0 +1,423 @@
+//! AF_XDP capture, skipping the kernel's network stack
+//!
+//! An XDP program on the NIC (loaded separately, e.g. with `xdp-loader`, and built with frags
+//! support for our jumbo frames) redirects the SNAP's packets into an XSKMAP pinned in bpffs. We open
+//! an XSK socket on one of the NIC's receive queues, give it a UMEM of frames to DMA into, and put
+//! ourselves in that map. Each packet then shows up on our RX ring as a chain of frames (the Ethernet
+//! frame is bigger than one), which we hold onto until the last of them arrives. Then we read the
+//! headers out of the first, copy the payload straight out of the UMEM wherever it falls across the
+//! chain, and hand the frames back to the NIC through the fill ring.
+use super::frame::{gather_payload, udp_payload_span};
+use crate::common::Payload;
+use clap::Args;
+use eyre::eyre;
+use std::{
+    ffi::{c_void, CString},
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use tracing::{debug, info};
+
+/// Size of each UMEM frame, the largest the kernel allows
+const FRAME_SIZE: u32 = 4096;
+/// Number of frames (and entries in the fill and RX rings)
+const FRAMES: u32 = 8192;
+/// We never transmit, but the kernel wants a completion ring anyway
+const COMPLETION_SIZE: u32 = 64;
+/// How long to wait for packets before checking back in (ms)
+const POLL_TIMEOUT_MS: i32 = 100;
+/// bpf(2) commands
+const BPF_MAP_UPDATE_ELEM: libc::c_long = 2;
+const BPF_OBJ_GET: libc::c_long = 7;
+
+/// Where to capture from with AF_XDP
+#[derive(Debug, Clone, Args)]
+pub struct XdpConfig {
+    /// Receive queue of the NIC the SNAP's packets are steered to
+    #[arg(long, default_value_t = 0)]
+    pub xdp_queue: u32,
+    /// XSKMAP (pinned in bpffs) the XDP program redirects packets through
+    #[arg(long, default_value = "/sys/fs/bpf/grex_xsks_map")]
+    pub xdp_map: PathBuf,
+}
+
+/// One of the rings shared with the kernel
+struct Ring<T> {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    descs: *mut T,
+    mask: u32,
+    map: *mut c_void,
+    map_len: usize,
+}
+
+impl<T> Ring<T> {
+    /// Map the ring of `size` entries at `pgoff` of the socket
+    fn map(
+        fd: &OwnedFd,
+        offsets: &libc::xdp_ring_offset,
+        size: u32,
+        pgoff: libc::off_t,
+    ) -> io::Result<Self> {
+        let map_len = offsets.desc as usize + size as usize * std::mem::size_of::<T>();
+        // Safety: Mapping a region the kernel set up for this socket, checked below
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                pgoff,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: The offsets come from the kernel and are within the mapping
+        unsafe {
+            let base = map.cast::<u8>();
+            Ok(Self {
+                producer: base.add(offsets.producer as usize).cast(),
+                consumer: base.add(offsets.consumer as usize).cast(),
+                descs: base.add(offsets.desc as usize).cast(),
+                mask: size - 1,
+                map,
+                map_len,
+            })
+        }
+    }
+
+    fn producer(&self) -> &AtomicU32 {
+        // Safety: Points into our mapping, which lives as long as we do
+        unsafe { &*self.producer }
+    }
+
+    fn consumer(&self) -> &AtomicU32 {
+        // Safety: As above
+        unsafe { &*self.consumer }
+    }
+
+    /// Pointer to the entry at (unwrapped) index `idx`
+    fn entry(&self, idx: u32) -> *mut T {
+        // Safety: Masked into the ring
+        unsafe { self.descs.add((idx & self.mask) as usize) }
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        // Safety: We mapped this and nothing points into it anymore
+        unsafe { libc::munmap(self.map, self.map_len) };
+    }
+}
+
+/// The frames the NIC writes packets into
+struct Umem {
+    area: *mut c_void,
+    len: usize,
+}
+
+impl Umem {
+    /// The packet data `desc` points at
+    fn frame(&self, desc: &libc::xdp_desc) -> &[u8] {
+        // Safety: The kernel only hands us descriptors pointing into our UMEM
+        unsafe {
+            std::slice::from_raw_parts(
+                self.area.cast::<u8>().add(desc.addr as usize),
+                desc.len as usize,
+            )
+        }
+    }
+}
+
+impl Drop for Umem {
+    fn drop(&mut self) {
+        // Safety: We mapped this, and the socket using it is already closed
+        unsafe { libc::munmap(self.area, self.len) };
+    }
+}
+
+pub struct XdpSocket {
+    // Dropped in order: the rings, the socket, then the memory it was using
+    rx: Ring<libc::xdp_desc>,
+    fill: Ring<u64>,
+    _completion: Ring<u64>,
+    fd: OwnedFd,
+    umem: Umem,
+    /// UDP port the SNAP sends to
+    port: u16,
+    /// The frames of the packet we're partway through
+    chain: Vec<libc::xdp_desc>,
+}
+
+// Safety: The rings and UMEM are only ever touched through the one socket that owns them
+unsafe impl Send for XdpSocket {}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn set_opt<T>(fd: &OwnedFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    // Safety: `value` is the type the kernel expects for `name`
+    check(unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_XDP,
+            name,
+            (value as *const T).cast(),
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    })
+}
+
+fn get_opt<T>(fd: &OwnedFd, name: libc::c_int) -> io::Result<T> {
+    // Safety: The kernel fills in (at most) a T
+    unsafe {
+        let mut value: T = std::mem::zeroed();
+        let mut len = std::mem::size_of::<T>() as libc::socklen_t;
+        check(libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_XDP,
+            name,
+            (&mut value as *mut T).cast(),
+            &mut len,
+        ))?;
+        Ok(value)
+    }
+}
+
+/// bpf(2), for the couple of commands we need
+fn bpf<T>(cmd: libc::c_long, attr: &T) -> io::Result<libc::c_long> {
+    // Safety: `attr` is the prefix of `union bpf_attr` that `cmd` reads, the rest is taken as zero
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            (attr as *const T).cast::<c_void>(),
+            std::mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Put our socket in the XSKMAP pinned at `path`, for packets on `queue`
+fn register(path: &std::path::Path, queue: u32, fd: &OwnedFd) -> eyre::Result<()> {
+    #[repr(C)]
+    struct ObjGet {
+        pathname: u64,
+        bpf_fd: u32,
+        file_flags: u32,
+    }
+    #[repr(C)]
+    struct MapUpdate {
+        map_fd: u32,
+        _pad: u32,
+        key: u64,
+        value: u64,
+        flags: u64,
+    }
+    let c_path = CString::new(path.as_os_str().as_encoded_bytes())?;
+    let map_fd = bpf(
+        BPF_OBJ_GET,
+        &ObjGet {
+            pathname: c_path.as_ptr() as u64,
+            bpf_fd: 0,
+            file_flags: 0,
+        },
+    )
+    .map_err(|e| eyre!("Couldn't open the XSKMAP at {} - {e}", path.display()))?;
+    // Safety: bpf just handed us this fd
+    let map_fd = unsafe { OwnedFd::from_raw_fd(map_fd as i32) };
+    let key = queue;
+    let value = fd.as_raw_fd() as u32;
+    bpf(
+        BPF_MAP_UPDATE_ELEM,
+        &MapUpdate {
+            map_fd: map_fd.as_raw_fd() as u32,
+            _pad: 0,
+            key: std::ptr::addr_of!(key) as u64,
+            value: std::ptr::addr_of!(value) as u64,
+            flags: 0,
+        },
+    )?;
+    Ok(())
+}
+
+impl XdpSocket {
+    /// Bind to `queue` of `interface`, receiving packets for UDP `port`
+    pub fn new(interface: &str, port: u16, config: &XdpConfig) -> eyre::Result<Self> {
+        let c_interface = CString::new(interface)?;
+        // Safety: A valid C string
+        let ifindex = unsafe { libc::if_nametoindex(c_interface.as_ptr()) };
+        if ifindex == 0 {
+            return Err(eyre!("No interface named {interface}"));
+        }
+        // Safety: Plain socket creation, checked
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW, 0) };
+        check(fd)?;
+        // Safety: We just made it
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // The frames packets land in
+        let len = (FRAMES * FRAME_SIZE) as usize;
+        // Safety: Anonymous mapping, checked
+        let area = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if area == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        let umem = Umem { area, len };
+        // Safety: An all-zero registration is valid, and we fill in what we use
+        let mut reg: libc::xdp_umem_reg = unsafe { std::mem::zeroed() };
+        reg.addr = area as u64;
+        reg.len = len as u64;
+        reg.chunk_size = FRAME_SIZE;
+        set_opt(&fd, libc::XDP_UMEM_REG, &reg)?;
+        set_opt(&fd, libc::XDP_UMEM_FILL_RING, &FRAMES)?;
+        set_opt(&fd, libc::XDP_UMEM_COMPLETION_RING, &COMPLETION_SIZE)?;
+        set_opt(&fd, libc::XDP_RX_RING, &FRAMES)?;
+
+        let offsets: libc::xdp_mmap_offsets = get_opt(&fd, libc::XDP_MMAP_OFFSETS)?;
+        let rx = Ring::map(&fd, &offsets.rx, FRAMES, libc::XDP_PGOFF_RX_RING)?;
+        let fill = Ring::map(
+            &fd,
+            &offsets.fr,
+            FRAMES,
+            libc::XDP_UMEM_PGOFF_FILL_RING as libc::off_t,
+        )?;
+        let completion = Ring::map(
+            &fd,
+            &offsets.cr,
+            COMPLETION_SIZE,
+            libc::XDP_UMEM_PGOFF_COMPLETION_RING as libc::off_t,
+        )?;
+        // Hand every frame to the NIC
+        for i in 0..FRAMES {
+            // Safety: In the ring, which the kernel isn't reading yet
+            unsafe { *fill.entry(i) = u64::from(i * FRAME_SIZE) };
+        }
+        fill.producer().store(FRAMES, Ordering::Release);
+
+        // Safety: An all-zero address is valid, and we fill in what we use
+        let mut addr: libc::sockaddr_xdp = unsafe { std::mem::zeroed() };
+        addr.sxdp_family = libc::AF_XDP as u16;
+        // Packets span multiple frames, zero copy if the driver can
+        addr.sxdp_flags = libc::XDP_USE_SG;
+        addr.sxdp_ifindex = ifindex;
+        addr.sxdp_queue_id = config.xdp_queue;
+        // Safety: A valid address of the right size
+        check(unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                std::ptr::addr_of!(addr).cast(),
+                std::mem::size_of_val(&addr) as libc::socklen_t,
+            )
+        })
+        .map_err(|e| {
+            eyre!(
+                "Couldn't bind to queue {} of {interface} - {e}",
+                config.xdp_queue
+            )
+        })?;
+        register(&config.xdp_map, config.xdp_queue, &fd)?;
+        info!(interface, queue = config.xdp_queue, "Capturing with AF_XDP");
+        Ok(Self {
+            rx,
+            fill,
+            _completion: completion,
+            fd,
+            umem,
+            port,
+            chain: Vec::with_capacity(4),
+        })
+    }
+
+    /// Wait (a little while) for packets, copying up to `payloads.len()` of them out and returning
+    /// how many we got (counting any that weren't payload-sized in `malformed`)
+    pub fn recv(&mut self, payloads: &mut [Payload], malformed: &mut usize) -> eyre::Result<usize> {
+        let mut available = self.available();
+        if available == 0 {
+            let mut pfd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // Safety: One valid pollfd
+            check(unsafe { libc::poll(&mut pfd, 1, POLL_TIMEOUT_MS) })?;
+            available = self.available();
+        }
+        let start = self.rx.consumer().load(Ordering::Relaxed);
+        let mut consumed = 0;
+        let mut got = 0;
+        while consumed < available && got < payloads.len() {
+            // Safety: The kernel published this descriptor before bumping the producer
+            let desc = unsafe { std::ptr::read(self.rx.entry(start + consumed)) };
+            consumed += 1;
+            self.chain.push(desc);
+            if desc.options & libc::XDP_PKT_CONTD != 0 {
+                // More of this packet to come
+                continue;
+            }
+            // The headers are all in the first frame
+            let frames = self.chain.iter().map(|desc| self.umem.frame(desc));
+            match udp_payload_span(self.umem.frame(&self.chain[0]), self.port) {
+                Some(span) => match gather_payload(&mut payloads[got], span, frames) {
+                    Ok(()) => got += 1,
+                    Err(e) => {
+                        debug!("Skipping a malformed packet - {e}");
+                        *malformed += 1;
+                    }
+                },
+                None => debug!("Skipping a packet that isn't for us"),
+            }
+            // Give the frames back to the NIC
+            for desc in self.chain.drain(..) {
+                let fill_idx = self.fill.producer().load(Ordering::Relaxed);
+                // Safety: Every frame is either ours or in the fill ring, so there's always room
+                unsafe {
+                    *self.fill.entry(fill_idx) = desc.addr - desc.addr % u64::from(FRAME_SIZE);
+                }
+                self.fill
+                    .producer()
+                    .store(fill_idx.wrapping_add(1), Ordering::Release);
+            }
+        }
+        self.rx
+            .consumer()
+            .store(start.wrapping_add(consumed), Ordering::Release);
+        Ok(got)
+    }
+
+    /// Descriptors waiting on the RX ring
+    fn available(&self) -> u32 {
+        self.rx
+            .producer()
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.rx.consumer().load(Ordering::Relaxed))
+    }
+
+    /// Packets the kernel dropped on the way to us (because our RX ring was full, or otherwise)
+    pub fn dropped(&self) -> io::Result<u64> {
+        let stats: libc::xdp_statistics = get_opt(&self.fd, libc::XDP_STATISTICS)?;
+        Ok(stats.rx_dropped + stats.rx_ring_full)
+    }
+}
@@ -0,0 +1,90 @@
+//! Machine-readable catalog of every data product we write, for automated archive ingestion
+//!
+//! Each product gets one JSON line when it's finished, with the time span it covers and enough
+//! provenance (software version, time sync, pointing) to make sense of it without our logs.
+//! Like the event database, cataloging is best-effort and never stops the pipeline.
+use crate::{pointing, timing};
+use hifitime::prelude::*;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+use tracing::warn;
+
+static CATALOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Product {
+    Filterbank,
+    Psrfits,
+    Dynspec,
+    Vdif,
+    Dump,
+    Calibration,
+}
+
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    product: Product,
+    /// Absent for products that only live on the SNAP (e.g. requant gains)
+    path: Option<&'a Path>,
+    /// MJD (UTC) of the first and last data in the product
+    start_mjd: f64,
+    stop_mjd: f64,
+    cataloged_mjd: f64,
+    provenance: Value,
+    detail: Value,
+}
+
+/// Open (creating if needed) the catalog that `record` appends to
+pub fn open(path: &Path) -> eyre::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let _ = CATALOG.set(Mutex::new(file));
+    Ok(())
+}
+
+/// Where everything we write came from
+fn provenance() -> Value {
+    let mut provenance = json!({
+        "software": "grex_t0",
+        "version": env!("CARGO_PKG_VERSION"),
+        "time_correction": timing::correction().to_seconds(),
+    });
+    if let Some(sync) = timing::time_sync() {
+        provenance["time_source"] = json!(sync.source.to_string());
+        provenance["time_uncertainty"] = json!(sync.uncertainty);
+        provenance["trigger_epoch_mjd"] = json!(sync.trigger_epoch.to_mjd_utc_days());
+    }
+    if let Some(p) = pointing::current() {
+        provenance["pointing"] = json!(p);
+    }
+    provenance
+}
+
+/// Catalog a finished product spanning `start` to `stop`, with some product-specific JSON detail
+pub fn record(product: Product, path: Option<&Path>, start: Epoch, stop: Epoch, detail: Value) {
+    let catalog = match CATALOG.get() {
+        Some(c) => c,
+        None => return,
+    };
+    let entry = Entry {
+        product,
+        path,
+        start_mjd: start.to_mjd_utc_days(),
+        stop_mjd: stop.to_mjd_utc_days(),
+        cataloged_mjd: Epoch::now().map_or(stop.to_mjd_utc_days(), |e| e.to_mjd_utc_days()),
+        provenance: provenance(),
+        detail,
+    };
+    let res = serde_json::to_string(&entry)
+        .map_err(eyre::Report::from)
+        .and_then(|line| Ok(writeln!(catalog.lock().unwrap(), "{line}")?));
+    if let Err(e) = res {
+        warn!("Failed to catalog {product:?} - {e}");
+    }
+}
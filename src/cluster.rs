@@ -0,0 +1,225 @@
+//! Coordination between T0 instances sharing a site (e.g. covering different bands or feeds)
+//!
+//! Each instance listens on its cluster port for JSON datagrams from its peers. Every trigger we
+//! get locally is forwarded so all instances dump together, every `STATUS_INTERVAL` we tell our
+//! peers how we're doing, and calibration can be pinned to a shared time boundary. Forwarded
+//! triggers are never forwarded again, so there are no loops no matter how the peers are wired.
+use crate::{capture::LAST_PACKET, dumps::Trigger, t2, timing};
+use hifitime::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{SocketAddr, UdpSocket},
+    sync::{atomic::Ordering, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use thingbuf::mpsc::blocking::Sender;
+use tokio::{sync::broadcast, time::interval};
+use tracing::{info, warn};
+
+/// How often we send our status to our peers
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
+/// How long we'll go without hearing from a peer before calling it stale
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn default_port() -> u16 {
+    65434
+}
+
+/// Another T0 instance
+#[derive(Debug, Clone, Deserialize)]
+pub struct Peer {
+    pub name: String,
+    /// Address of the peer's cluster port
+    pub addr: SocketAddr,
+}
+
+/// The `[cluster]` section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// What this instance calls itself (e.g. "low" and "high" band)
+    pub name: String,
+    /// Port to listen for our peers on
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// If set, calibrate on the next UTC second divisible by this, so all instances line up
+    pub calibration_align: Option<u64>,
+    #[serde(default)]
+    pub peers: Vec<Peer>,
+}
+
+/// A snapshot of how an instance is doing
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub name: String,
+    pub last_packet: u64,
+    pub t2_connected: bool,
+    pub time_source: Option<String>,
+    pub time_correction: f64,
+}
+
+impl Status {
+    fn ours(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            last_packet: LAST_PACKET.load(Ordering::Relaxed),
+            t2_connected: t2::is_connected(),
+            time_source: timing::time_sync().map(|s| s.source.to_string()),
+            time_correction: timing::correction().to_seconds(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    Trigger {
+        from: String,
+        source: String,
+        mjd: Option<f64>,
+        dm: Option<f64>,
+        snr: Option<f64>,
+        width: Option<f64>,
+        #[serde(default)]
+        window: Option<f64>,
+    },
+    Status(Status),
+}
+
+struct Cluster {
+    config: ClusterConfig,
+    sock: UdpSocket,
+    peer_status: Mutex<HashMap<String, (Instant, Status)>>,
+}
+
+static CLUSTER: OnceLock<Cluster> = OnceLock::new();
+
+impl Cluster {
+    fn send(&self, msg: &Message) {
+        let bytes = serde_json::to_vec(msg).expect("Cluster messages always serialize");
+        for peer in &self.config.peers {
+            if let Err(e) = self.sock.send_to(&bytes, peer.addr) {
+                warn!("Failed to send to cluster peer {} - {e}", peer.name);
+            }
+        }
+    }
+}
+
+/// Join the cluster described by `config`, binding our cluster port
+pub fn init(config: ClusterConfig) -> eyre::Result<()> {
+    let sock = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], config.port)))?;
+    sock.set_nonblocking(true)?;
+    info!(
+        "Joining cluster as {} with {} peer(s)",
+        config.name,
+        config.peers.len()
+    );
+    let _ = CLUSTER.set(Cluster {
+        config,
+        sock,
+        peer_status: Mutex::new(HashMap::new()),
+    });
+    Ok(())
+}
+
+/// Forward a locally-generated trigger to all our peers (no-op outside of a cluster)
+pub fn fan_out(trigger: &Trigger) {
+    if let Some(cluster) = CLUSTER.get() {
+        cluster.send(&Message::Trigger {
+            from: cluster.config.name.clone(),
+            source: trigger.source.clone(),
+            mjd: trigger.time.map(|t| t.to_mjd_utc_days()),
+            dm: trigger.dm,
+            snr: trigger.snr,
+            width: trigger.width,
+            window: trigger.window,
+        });
+    }
+}
+
+/// Block until the shared calibration time boundary, if there is one
+pub fn wait_for_calibration() -> eyre::Result<()> {
+    let align = match CLUSTER.get().and_then(|c| c.config.calibration_align) {
+        Some(a) if a > 0 => a,
+        _ => return Ok(()),
+    };
+    let now = Epoch::now()?;
+    let next = now.ceil((align as f64).seconds());
+    info!("Waiting until {next} to calibrate with the rest of the cluster");
+    std::thread::sleep((next - now).into());
+    Ok(())
+}
+
+/// Our status and the latest from each peer, for the combined status endpoint
+pub fn combined_status() -> Option<serde_json::Value> {
+    let cluster = CLUSTER.get()?;
+    let peer_status = cluster.peer_status.lock().unwrap();
+    let peers: Vec<_> = cluster
+        .config
+        .peers
+        .iter()
+        .map(|peer| match peer_status.get(&peer.name) {
+            Some((heard, status)) => serde_json::json!({
+                "name": peer.name,
+                "stale": heard.elapsed() > STATUS_TIMEOUT,
+                "age_seconds": heard.elapsed().as_secs_f64(),
+                "status": status,
+            }),
+            None => serde_json::json!({ "name": peer.name, "stale": true, "status": null }),
+        })
+        .collect();
+    Some(serde_json::json!({
+        "self": Status::ours(&cluster.config.name),
+        "peers": peers,
+    }))
+}
+
+pub async fn cluster_task(
+    sender: Sender<Trigger>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    let cluster = match CLUSTER.get() {
+        Some(c) => c,
+        None => return Ok(()),
+    };
+    info!("Starting cluster task!");
+    let sock = tokio::net::UdpSocket::from_std(cluster.sock.try_clone()?)?;
+    let mut ticker = interval(STATUS_INTERVAL);
+    let mut buf = [0u8; 1024];
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("Cluster task stopping");
+                break;
+            }
+            _ = ticker.tick() => {
+                cluster.send(&Message::Status(Status::ours(&cluster.config.name)));
+            }
+            Ok((n, addr)) = sock.recv_from(&mut buf) => {
+                match serde_json::from_slice(&buf[..n]) {
+                    Ok(Message::Trigger { from, source, mjd, dm, snr, width, window }) => {
+                        info!("Dump triggered by cluster peer {from}");
+                        sender.send(Trigger {
+                            source: format!("{from}/{source}"),
+                            time: mjd.map(Epoch::from_mjd_utc),
+                            dm,
+                            snr,
+                            width,
+                            window,
+                        })?;
+                    }
+                    Ok(Message::Status(status)) => {
+                        cluster
+                            .peer_status
+                            .lock()
+                            .unwrap()
+                            .insert(status.name.clone(), (Instant::now(), status));
+                    }
+                    Err(e) => warn!("Malformed cluster message from {addr} - {e}"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
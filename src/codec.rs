@@ -0,0 +1,46 @@
+//! The one binary encoding for our own types (payloads, Stokes, capture statistics, triggers)
+//!
+//! Anything we frame ourselves (recordings, replays, streams between our own processes) goes through
+//! here rather than casting structs to bytes, so every reader and writer agrees on the layout. The
+//! encoding is bincode's: little-endian fixed-width integers and floats, `Option`s as a 0/1 byte
+//! and the value, and variable-length sequences and strings prefixed with their length as a u64.
+//! Fixed-size arrays have no prefix, so an encoded [`Payload`](crate::common::Payload) is
+//! byte-for-byte the packet the SNAP sends.
+//!
+//! Formats other programs read (SNAP packets on the hot path, PSRDADA, filterbanks, netCDF) keep
+//! their own layouts.
+use bincode::Options;
+use serde::{de::DeserializeOwned, Serialize};
+use std::io::{Read, Write};
+
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_little_endian()
+        .reject_trailing_bytes()
+}
+
+/// Encode `value` into a new buffer
+pub fn encode<T: Serialize + ?Sized>(value: &T) -> eyre::Result<Vec<u8>> {
+    Ok(options().serialize(value)?)
+}
+
+/// Encode `value` onto the end of `writer`
+pub fn encode_into<T: Serialize + ?Sized, W: Write>(writer: W, value: &T) -> eyre::Result<()> {
+    Ok(options().serialize_into(writer, value)?)
+}
+
+/// The size of `value` once encoded
+pub fn encoded_size<T: Serialize + ?Sized>(value: &T) -> eyre::Result<u64> {
+    Ok(options().serialized_size(value)?)
+}
+
+/// Decode a `T` that's the whole of `bytes`
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> eyre::Result<T> {
+    Ok(options().deserialize(bytes)?)
+}
+
+/// Decode the next `T` from `reader`
+pub fn decode_from<T: DeserializeOwned, R: Read>(reader: R) -> eyre::Result<T> {
+    Ok(options().deserialize_from(reader)?)
+}
@@ -4,6 +4,9 @@ use arrayvec::ArrayVec;
 use hifitime::prelude::*;
 use ndarray::{s, Array3, ArrayView};
 use num_complex::Complex;
+use serde::{Deserialize, Serialize};
+use serde_big_array::BigArray;
+use std::ops::{Deref, DerefMut};
 
 /// Number of frequency channels (set by gateware)
 pub const CHANNELS: usize = 2048;
@@ -13,9 +16,70 @@ pub const PACKET_CADENCE: f64 = 8.192e-6;
 pub const BLOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 
 pub type Stokes = ArrayVec<f32, CHANNELS>;
+/// Full polarization Stokes, as the I, Q, U, then V spectra
+pub type StokesFull = ArrayVec<f32, { 4 * CHANNELS }>;
+
+/// Scale of the Stokes parameters, as the voltages are Fix8_7 (so their products are Fix16_14)
+const STOKES_SCALE: f32 = 1.0 / (1u16 << 14) as f32;
+
+/// A spectrum of one or more Stokes parameters, each `CHANNELS` long, one after the other
+pub trait Spectrum:
+    Default
+    + Clone
+    + Send
+    + Sync
+    + Deref<Target = [f32]>
+    + DerefMut
+    + for<'a> TryFrom<&'a [f32]>
+    + 'static
+{
+    /// Number of Stokes parameters
+    const NPOL: usize;
+    /// Add the Stokes parameters of `payload` to `buf` (`NPOL * CHANNELS` long)
+    fn accumulate(payload: &Payload, buf: &mut [f32]);
+}
+
+impl Spectrum for Stokes {
+    const NPOL: usize = 1;
+
+    fn accumulate(payload: &Payload, buf: &mut [f32]) {
+        buf.iter_mut()
+            .zip(&payload.stokes_i())
+            .for_each(|(x, y)| *x += y);
+    }
+}
+
+impl Spectrum for StokesFull {
+    const NPOL: usize = 4;
+
+    /// With pol a as X and pol b as Y, I = |X|^2 + |Y|^2, Q = |X|^2 - |Y|^2, U = 2 Re(XY*), and
+    /// V = -2 Im(XY*) (the IAU/IEEE convention, matching the injected polarized pulses)
+    fn accumulate(payload: &Payload, buf: &mut [f32]) {
+        let (i, rest) = buf.split_at_mut(CHANNELS);
+        let (q, rest) = rest.split_at_mut(CHANNELS);
+        let (u, v) = rest.split_at_mut(CHANNELS);
+        for (c, (a, b)) in payload.pol_a.iter().zip(&payload.pol_b).enumerate() {
+            let (ar, ai) = (f32::from(a.0.re), f32::from(a.0.im));
+            let (br, bi) = (f32::from(b.0.re), f32::from(b.0.im));
+            let (aa, bb) = (ar * ar + ai * ai, br * br + bi * bi);
+            i[c] += (aa + bb) * STOKES_SCALE;
+            q[c] += (aa - bb) * STOKES_SCALE;
+            u[c] += 2.0 * (ar * br + ai * bi) * STOKES_SCALE;
+            v[c] += -2.0 * (ai * br - ar * bi) * STOKES_SCALE;
+        }
+    }
+}
+
+/// The UTC epoch `elapsed` SI seconds after `start`.
+///
+/// Packet counts tick in real seconds, so the offset is applied in TAI (counting any leap second in
+/// between) and the result is always handed back in UTC, whatever scale `start` came from.
+pub fn epoch_after(start: &Epoch, elapsed: Duration) -> Epoch {
+    (start.in_time_scale(TimeScale::TAI) + elapsed).in_time_scale(TimeScale::UTC)
+}
 
 /// The complex number representing the voltage of a single channel
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Channel(pub Complex<i8>);
 
 impl Channel {
@@ -42,13 +106,25 @@ pub fn stokes_i(a: &Channels, b: &Channels) -> Stokes {
     stokes
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 #[repr(C)]
 pub struct Payload {
     /// Number of packets since the first packet
     pub count: u64,
+    #[serde(with = "BigArray")]
     pub pol_a: Channels,
+    #[serde(with = "BigArray")]
     pub pol_b: Channels,
+    /// Which board this came from (not part of the packet, capture fills it in)
+    #[serde(default)]
+    pub board: u8,
+    /// When the packet reached the host (ns since the Unix epoch) by the NIC's or kernel's clock,
+    /// 0 if we don't know (also filled in by capture)
+    #[serde(default)]
+    pub rx_time: u64,
+    /// If capture made this up in place of a dropped packet, rather than receiving it
+    #[serde(default)]
+    pub synthetic: bool,
 }
 
 impl Default for Payload {
@@ -100,7 +176,66 @@ impl Payload {
 
     /// Return the real UTC time of this packet
     pub fn real_time(&self, start_time: &Epoch) -> Epoch {
-        let second_offset = (self.count as f64 * PACKET_CADENCE).seconds();
-        *start_time + second_offset
+        epoch_after(start_time, (self.count as f64 * PACKET_CADENCE).seconds())
+    }
+
+    /// The bytes of the packet this came from
+    pub fn as_packet(&self) -> &[u8] {
+        // Safety: Payload is repr(C) plain-old-data, starting with the packet capture read into it
+        unsafe {
+            std::slice::from_raw_parts(
+                (self as *const Payload).cast::<u8>(),
+                crate::capture::PAYLOAD_SIZE,
+            )
+        }
+    }
+
+    /// When the packet reached the host, if we know
+    pub fn rx_epoch(&self) -> Option<Epoch> {
+        (self.rx_time != 0).then(|| {
+            Epoch::from_unix_duration(Duration::from_total_nanoseconds(i128::from(self.rx_time)))
+        })
+    }
+
+    /// How long after its timestamp (from `start_time`) the packet reached the host: the
+    /// FPGA-to-host latency, plus however far the two clocks have drifted apart
+    pub fn rx_latency(&self, start_time: &Epoch) -> Option<Duration> {
+        Some(self.rx_epoch()? - self.real_time(start_time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Noon on the last day of 2016, 12 hours before the leap second (23:59:60)
+    fn before_leap() -> Epoch {
+        Epoch::from_gregorian_utc_at_noon(2016, 12, 31)
+    }
+
+    fn across_leap() -> Duration {
+        Unit::Hour * 12 + Unit::Second * 20
+    }
+
+    #[test]
+    fn epoch_after_counts_the_leap_second() {
+        let after = epoch_after(&before_leap(), across_leap());
+        assert_eq!(after.time_scale, TimeScale::UTC);
+        assert_eq!(after.to_gregorian_utc(), (2017, 1, 1, 0, 0, 19, 0));
+        // Naive UTC arithmetic would have skipped over 23:59:60
+        let naive = Epoch::from_gregorian_utc_hms(2017, 1, 1, 0, 0, 20);
+        assert_eq!(naive - after, Unit::Second * 1);
+        assert_eq!(
+            after.to_mjd_utc_days(),
+            Epoch::from_gregorian_utc_hms(2017, 1, 1, 0, 0, 19).to_mjd_utc_days()
+        );
+    }
+
+    #[test]
+    fn epoch_after_is_utc_from_any_scale() {
+        let tai = before_leap().in_time_scale(TimeScale::TAI);
+        let after = epoch_after(&tai, across_leap());
+        assert_eq!(after.time_scale, TimeScale::UTC);
+        assert_eq!(after, epoch_after(&before_leap(), across_leap()));
     }
 }
@@ -0,0 +1,26 @@
+//! Settings that are too structured to comfortably live on the command line, read from a TOML file
+use crate::{
+    cluster::ClusterConfig, fluxcal::FluxCalConfig, fpga::Network, observation::Observation,
+};
+use serde::Deserialize;
+use std::path::Path;
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Coordination with other T0 instances at the same site
+    pub cluster: Option<ClusterConfig>,
+    /// A scheduled flux calibration
+    pub fluxcal: Option<FluxCalConfig>,
+    /// The band and telescope, in place of the flags
+    pub observation: Option<Observation>,
+    /// The SNAP's 10 GbE addresses, in place of the flags
+    pub network: Option<Network>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+}
@@ -0,0 +1,67 @@
+//! Offline conversion of voltage dumps to filterbanks
+//!
+//! The Stokes and filterbank header come from the same code as the live pipeline, so a converted
+//! dump lines up exactly with what the filterbank exfil would have written.
+use crate::{
+    common::{CHANNELS, PACKET_CADENCE},
+    dumps,
+    exfil::{filterbank_writer, stamp_pointing},
+};
+use clap::Args;
+use eyre::eyre;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+use tracing::info;
+
+#[derive(Debug, Args)]
+pub struct Convert {
+    /// Voltage dump (.nc) to convert
+    pub dump: PathBuf,
+    /// Downsample power of 2 (as in the pipeline)
+    #[clap(value_parser = clap::value_parser!(u32).range(0..=9))]
+    #[arg(long, short, default_value_t = 2)]
+    pub downsample_power: u32,
+    /// Filterbank to write (the dump's name with a .fil extension if unset)
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+/// Convert a voltage dump to a Stokes I filterbank, returning where we wrote it
+pub fn convert(args: &Convert) -> eyre::Result<PathBuf> {
+    let dump = dumps::read::open(&args.dump)?;
+    let start = *dump
+        .times
+        .first()
+        .ok_or_else(|| eyre!("{} is empty", args.dump.display()))?;
+    let factor = 2usize.pow(args.downsample_power);
+    let output = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.dump.with_extension("fil"));
+    let mut fb = filterbank_writer(PACKET_CADENCE * factor as f64, 1);
+    fb.tstart = Some(start.to_mjd_utc_days());
+    if let Some(p) = &dump.pointing {
+        stamp_pointing(&mut fb, p);
+    }
+    let mut file = BufWriter::new(File::create(&output)?);
+    file.write_all(&fb.header_bytes())?;
+    // Average just like the downsample task, dropping any partial window at the end
+    let mut spectra = 0;
+    for window in 0..dump.len() / factor {
+        let mut avg = [0f32; CHANNELS];
+        for i in window * factor..(window + 1) * factor {
+            avg.iter_mut()
+                .zip(&dump.payload(i).stokes_i())
+                .for_each(|(x, y)| *x += y);
+        }
+        avg.iter_mut().for_each(|v| *v /= factor as f32);
+        file.write_all(&fb.pack(&avg))?;
+        spectra += 1;
+    }
+    file.flush()?;
+    info!(spectra, "Wrote {}", output.display());
+    Ok(output)
+}
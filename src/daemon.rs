@@ -0,0 +1,69 @@
+//! Guards against multiple instances of T0 fighting over the same resources
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+#[derive(thiserror::Error, Debug)]
+/// Errors that can be produced when locking resources
+pub enum Error {
+    #[error("Another instance (pid {pid}) already holds {resource}, refusing to start")]
+    AlreadyRunning { resource: String, pid: String },
+}
+
+/// An exclusive, advisory lock on a file containing our PID.
+/// The kernel releases the lock when the process exits, so a crash never leaves a stale lock behind.
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Lock the file at `path`, writing our PID into it
+    pub fn at(path: &Path) -> eyre::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        // Safety: The file descriptor is valid for as long as `file` is alive
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let mut pid = String::new();
+            file.read_to_string(&mut pid)?;
+            return Err(Error::AlreadyRunning {
+                resource: path.display().to_string(),
+                pid: pid.trim().to_owned(),
+            }
+            .into());
+        }
+        // We own it now, replace whatever was there with our PID
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        file.flush()?;
+        debug!("Acquired lock on {}", path.display());
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Lock a named resource (like a port or DADA key) within `dir`
+    pub fn named(dir: &Path, resource: &str) -> eyre::Result<Self> {
+        Self::at(&dir.join(format!("grex_t0-{resource}.lock")))
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // The file stays put, as unlinking it would let one instance lock the old inode while
+        // another creates and locks a new one at the same path. Just clear our PID out of it.
+        if self.file.set_len(0).is_ok() {
+            debug!("Released lock on {}", self.path.display());
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Finding SNAP boards on the network
+//!
+//! Boards get their addresses from DHCP in the field, so rather than guessing we can ask every
+//! address on a subnet for its temperature over TAPCP (which only our boards will answer), and then
+//! what it's running.
+use crate::fpga::Board;
+use casperfpga::transport::{tapcp::Tapcp, Transport};
+use clap::Args;
+use eyre::eyre;
+use std::{
+    fmt::Display,
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+    thread,
+};
+use tracing::info;
+
+/// The port TAPCP (TFTP) listens on
+const TAPCP_PORT: u16 = 69;
+/// Smallest prefix we'll scan, as each dead address takes a few seconds to give up on
+const MIN_PREFIX: u32 = 20;
+/// How many addresses to probe at once
+const PROBE_THREADS: usize = 64;
+
+#[derive(Debug, Args)]
+pub struct Discover {
+    /// Subnet to scan for boards
+    #[arg(default_value = "192.168.0.0/24")]
+    pub subnet: Subnet,
+    /// Which kind of board to look for
+    #[arg(long, value_enum, default_value_t = Board::Snap)]
+    pub board: Board,
+}
+
+/// An IPv4 subnet in CIDR notation
+#[derive(Debug, Clone, Copy)]
+pub struct Subnet {
+    base: Ipv4Addr,
+    prefix: u32,
+}
+
+impl FromStr for Subnet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').unwrap_or((s, "24"));
+        let base: Ipv4Addr = addr.parse().map_err(|_| "Invalid subnet address")?;
+        let prefix: u32 = prefix.parse().map_err(|_| "Invalid subnet prefix")?;
+        if !(MIN_PREFIX..=32).contains(&prefix) {
+            return Err(format!("Subnet prefix must be between {MIN_PREFIX} and 32"));
+        }
+        Ok(Self { base, prefix })
+    }
+}
+
+impl Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.base, self.prefix)
+    }
+}
+
+impl Subnet {
+    /// Every host address in the subnet
+    fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+        let network = u32::from(self.base) & mask;
+        let size = 1u32 << (32 - self.prefix);
+        // Skip the network and broadcast addresses, unless that's all there is
+        let (first, last) = if size > 2 {
+            (1, size - 2)
+        } else {
+            (0, size - 1)
+        };
+        (first..=last).map(move |i| Ipv4Addr::from(network + i))
+    }
+}
+
+/// A board that answered
+#[derive(Debug, Clone)]
+pub struct Found {
+    pub addr: SocketAddr,
+    /// FPGA temperature (C)
+    pub temperature: f32,
+    /// If it's running a user design (rather than sitting in the golden image)
+    pub programmed: bool,
+    /// MD5 of the design programmed into flash, from the board's metadata
+    pub md5: Option<String>,
+    /// The design's `sys_board_id` register (the closest thing to a serial we can read over TAPCP)
+    pub board_id: Option<u32>,
+}
+
+/// Ask `addr` if it's a board, and what it's running
+fn probe(addr: SocketAddr, board: Board) -> Option<Found> {
+    let mut transport = Tapcp::connect(addr, board.platform()).ok()?;
+    // Only TAPCP boards will answer this
+    let temperature = transport.temperature().ok()?;
+    let programmed = transport.is_running().unwrap_or(false);
+    let md5 = transport
+        .metadata()
+        .ok()
+        .and_then(|meta| meta.get("md5").cloned());
+    let board_id = programmed
+        .then(|| transport.read::<u32, 4>("sys_board_id", 0).ok())
+        .flatten();
+    Some(Found {
+        addr,
+        temperature,
+        programmed,
+        md5,
+        board_id,
+    })
+}
+
+/// Find every board on `subnet`, in address order
+pub fn scan(subnet: &Subnet, board: Board) -> Vec<Found> {
+    info!(%subnet, "Scanning for boards");
+    let hosts: Vec<_> = subnet
+        .hosts()
+        .map(|ip| SocketAddr::from((ip, TAPCP_PORT)))
+        .collect();
+    let mut found = vec![];
+    for chunk in hosts.chunks(PROBE_THREADS) {
+        thread::scope(|s| {
+            let probes: Vec<_> = chunk
+                .iter()
+                .map(|addr| s.spawn(move || probe(*addr, board)))
+                .collect();
+            found.extend(probes.into_iter().filter_map(|p| p.join().ok().flatten()));
+        });
+    }
+    found
+}
+
+/// The one programmed board on `subnet` (or the one board at all if we're going to program it), for
+/// when we weren't told where it is
+pub fn pick(subnet: &Subnet, board: Board, programming: bool) -> eyre::Result<SocketAddr> {
+    let kind = if programming { "" } else { "programmed " };
+    let candidates: Vec<_> = scan(subnet, board)
+        .into_iter()
+        .filter(|f| programming || f.programmed)
+        .collect();
+    match candidates.as_slice() {
+        [only] => {
+            info!(addr = %only.addr, "Found the board");
+            Ok(only.addr)
+        }
+        [] => Err(eyre!("No {kind}boards found on {subnet}, set --fpga-addr")),
+        many => Err(eyre!(
+            "Found {} {kind}boards on {subnet}, pick one with --fpga-addr",
+            many.len()
+        )),
+    }
+}
+
+/// List the boards on the subnet
+pub fn discover(args: &Discover) -> eyre::Result<()> {
+    let found = scan(&args.subnet, args.board);
+    if found.is_empty() {
+        println!("No boards found");
+    }
+    for f in found {
+        println!(
+            "{:<21} {:<10} board_id={:<10} md5={:<32} {:.1} C",
+            f.addr,
+            if f.programmed { "programmed" } else { "golden" },
+            f.board_id
+                .map_or("unknown".to_owned(), |id| format!("{id:#x}")),
+            f.md5.as_deref().unwrap_or("unknown"),
+            f.temperature
+        );
+    }
+    Ok(())
+}
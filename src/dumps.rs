@@ -1,76 +1,698 @@
 //! Dumping voltage data
+pub(crate) mod h5;
+pub mod manifest;
+pub mod read;
+pub mod stream;
 
-use crate::common::{Payload, BLOCK_TIMEOUT, CHANNELS};
-use crate::exfil::{BANDWIDTH, HIGHBAND_MID_FREQ};
+use crate::capture::PRIMARY_BOARD;
+use crate::common::{Payload, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE};
+use crate::{calibrate, catalog, cluster, events, fpga::FFT_SHIFT, observation, pointing, timing};
+use clap::ValueEnum;
+use eyre::bail;
 use hifitime::prelude::*;
+use lazy_static::lazy_static;
 use ndarray::prelude::*;
+use netcdf::AttributeValue;
+use prometheus::{
+    exponential_buckets, register_gauge, register_histogram, register_int_counter,
+    register_int_counter_vec, Gauge, Histogram, IntCounter, IntCounterVec,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::{
+    collections::VecDeque,
     net::SocketAddr,
     path::{Path, PathBuf},
     str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc, OnceLock,
+    },
+    time::{Duration, Instant},
 };
+use stream::DumpTarget;
 use thingbuf::mpsc::{
     blocking::{Receiver, Sender, StaticReceiver},
     errors::RecvTimeoutError,
 };
 use tokio::{net::UdpSocket, sync::broadcast};
-use tracing::{info, warn};
+use tracing::{error, info, warn};
+
+lazy_static! {
+    static ref DUMPS_COMPLETED: IntCounter =
+        register_int_counter!("dumps_completed", "Number of voltage dumps written").unwrap();
+    static ref DUMP_FAILURES: IntCounter =
+        register_int_counter!("dump_failures", "Number of voltage dumps that failed").unwrap();
+    static ref DUMP_BYTES: IntCounter = register_int_counter!(
+        "dump_bytes_written",
+        "Total size of the voltage dumps written"
+    )
+    .unwrap();
+    static ref RING_FILL: Gauge = register_gauge!(
+        "dump_ring_fill",
+        "Fraction of the voltage ring holding real data (rather than startup zeros)"
+    )
+    .unwrap();
+    static ref RING_FRESH: Gauge = register_gauge!(
+        "dump_ring_fresh",
+        "Fraction of the voltage ring filled since the last dump"
+    )
+    .unwrap();
+    static ref LAST_DUMP: Gauge = register_gauge!(
+        "last_dump_timestamp_seconds",
+        "Unix time at which the last voltage dump finished"
+    )
+    .unwrap();
+    static ref TRIGGERS_RECEIVED: IntCounter = register_int_counter!(
+        "dump_triggers_received",
+        "Number of triggers the dump task has taken in"
+    )
+    .unwrap();
+    static ref TRIGGERS_SKIPPED: IntCounterVec = register_int_counter_vec!(
+        "dump_triggers_skipped",
+        "Number of triggers that didn't get a dump of their own",
+        &["reason"]
+    )
+    .unwrap();
+    static ref LAST_DUMP_DURATION: Gauge = register_gauge!(
+        "last_dump_duration_seconds",
+        "Time taken to write the last voltage dump"
+    )
+    .unwrap();
+    static ref DUMP_DURATION: Histogram = register_histogram!(
+        "dump_duration_seconds",
+        "Time taken to write a voltage dump",
+        exponential_buckets(0.5, 2.0, 10).unwrap()
+    )
+    .unwrap();
+}
+
+/// Limits on how often triggers turn into dumps, so a burst of them doesn't back up behind
+/// dumps we can't write fast enough
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DumpLimits {
+    /// Triggers within this many seconds of the last dump are merged into it
+    pub coalesce: Option<f64>,
+    /// Most dumps to write in any hour, triggers past that are dropped
+    pub per_hour: Option<usize>,
+}
+
+/// Decides which triggers to dump for, under some [`DumpLimits`]
+struct Limiter {
+    limits: DumpLimits,
+    /// When we dumped recently, oldest first
+    recent: VecDeque<Instant>,
+}
+
+impl Limiter {
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn new(limits: DumpLimits) -> Self {
+        Self {
+            limits,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Should we dump for `trigger` now, noting that we did if so
+    fn admit(&mut self, trigger: &Trigger) -> bool {
+        let now = Instant::now();
+        if let (Some(window), Some(last)) = (self.limits.coalesce, self.recent.back()) {
+            if now.duration_since(*last).as_secs_f64() < window {
+                info!(
+                    source = trigger.source.as_str(),
+                    "Merging trigger into the last dump"
+                );
+                TRIGGERS_SKIPPED.with_label_values(&["coalesced"]).inc();
+                return false;
+            }
+        }
+        while self
+            .recent
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= Self::HOUR)
+        {
+            self.recent.pop_front();
+        }
+        if let Some(max) = self.limits.per_hour {
+            if self.recent.len() >= max {
+                warn!(
+                    source = trigger.source.as_str(),
+                    "Dropping trigger, over {max} dumps this hour"
+                );
+                TRIGGERS_SKIPPED.with_label_values(&["rate_limited"]).inc();
+                return false;
+            }
+        }
+        // We only need the last dump to coalesce with, unless we're counting them
+        if self.limits.per_hour.is_none() {
+            self.recent.clear();
+        }
+        self.recent.push_back(now);
+        true
+    }
+}
+
+/// File format of the voltage dumps
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DumpFormat {
+    /// netCDF-4
+    #[default]
+    Netcdf,
+    /// HDF5, with the same datasets and attributes as the netCDF dumps
+    Hdf5,
+}
+
+impl DumpFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            DumpFormat::Netcdf => "nc",
+            DumpFormat::Hdf5 => "h5",
+        }
+    }
+}
+
+/// Center frequencies (MHz) of each channel, as the dumps record them
+fn dump_freqs() -> Array1<f64> {
+    let obs = observation::current();
+    Array1::from_iter((0..CHANNELS).map(|chan| obs.freq(chan)))
+}
+
+/// Timesteps per chunk of the dumped voltages (4 MiB), which is also how many we write at a time
+const DUMP_CHUNK: usize = 512;
+
+/// Number of pushes between updates of the ring fill metrics
+const FILL_UPDATE_INTERVAL: usize = 1024;
+
+/// How long a dying pipeline will wait for the ring to be salvaged
+const SALVAGE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Set when another part of the pipeline has died and we want to save what's in the ring
+static SALVAGE_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Number of dump tasks (one per board) still running, and so able to do a salvage dump
+static DUMP_TASKS_RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts a dump task as running for as long as it's held, however the task ends
+struct Running;
+
+impl Running {
+    fn start() -> Self {
+        DUMP_TASKS_RUNNING.fetch_add(1, Ordering::AcqRel);
+        Self
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        DUMP_TASKS_RUNNING.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// How this run of the pipeline was set up, for the dumps to describe themselves with
+#[derive(Debug, Clone)]
+pub struct RunInfo {
+    /// The gateware the SNAP is running, if there is one
+    pub gateware: Option<String>,
+    pub downsample_power: u32,
+}
+
+static RUN_INFO: OnceLock<RunInfo> = OnceLock::new();
+
+/// Record how this run was set up, for the dumps' metadata
+pub fn set_run_info(info: RunInfo) {
+    if RUN_INFO.set(info).is_err() {
+        warn!("Run info for the dumps was already set");
+    }
+}
+
+/// Count a trigger that was turned away before it reached the dump task, for `reason`
+pub fn count_skipped_trigger(reason: &str) {
+    TRIGGERS_SKIPPED.with_label_values(&[reason]).inc();
+}
+
+/// Ask the dump tasks to write out their rings, blocking until they finish (or we time out).
+/// This is a no-op when called from a dump thread itself, as nobody would be left to do the work,
+/// or when there are no dump tasks left running to ask.
+pub fn request_salvage() {
+    if std::thread::current()
+        .name()
+        .is_some_and(|name| name.starts_with("dump"))
+        || DUMP_TASKS_RUNNING.load(Ordering::Acquire) == 0
+    {
+        return;
+    }
+    if SALVAGE_REQUESTED.swap(true, Ordering::AcqRel) {
+        // Someone else already asked, but we still want to wait for it to finish
+        warn!("Ring salvage already in progress");
+    } else {
+        error!("Fatal error in the pipeline, attempting to salvage the voltage ring");
+    }
+    let start = Instant::now();
+    while DUMP_TASKS_RUNNING.load(Ordering::Acquire) > 0 && start.elapsed() < SALVAGE_TIMEOUT {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Install a panic hook that salvages the voltage ring before the process goes down.
+/// This chains to the previously installed hook, so install it after `color_eyre`.
+pub fn install_salvage_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        request_salvage();
+    }));
+}
+
+/// Metadata describing why a dump was triggered
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Trigger {
+    /// What asked for the dump (e.g. "udp", "heimdall", "salvage")
+    pub source: String,
+    /// Time of the candidate that caused the trigger
+    pub time: Option<Epoch>,
+    /// Dispersion measure of the candidate (pc cm^-3)
+    pub dm: Option<f64>,
+    /// Signal to noise ratio of the candidate
+    pub snr: Option<f64>,
+    /// Width of the candidate (seconds)
+    pub width: Option<f64>,
+    /// Seconds either side of the candidate's time to dump (the whole ring if unset)
+    pub window: Option<f64>,
+}
+
+impl Trigger {
+    /// A trigger with no candidate information
+    pub fn from_source(source: &str) -> Self {
+        Self {
+            source: source.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// A trigger from a datagram, which may carry the candidate as a [`TriggerMessage`] (anything
+    /// else still triggers, just without the candidate)
+    pub fn from_datagram(data: &[u8]) -> Self {
+        if data.iter().all(u8::is_ascii_whitespace) {
+            return Self::from_source("udp");
+        }
+        match serde_json::from_slice(data) {
+            Ok(msg) => Self::from_message(msg, "udp"),
+            Err(e) => {
+                warn!("Couldn't parse the trigger message, dumping without it - {e}");
+                Self::from_source("udp")
+            }
+        }
+    }
+
+    /// A trigger for the candidate in `msg`, from `source` unless it says otherwise
+    pub fn from_message(msg: TriggerMessage, source: &str) -> Self {
+        let mut trigger = Self::from_source(source);
+        if let Some(source) = msg.source {
+            trigger.source = source;
+        }
+        trigger.time = match (msg.utc, msg.mjd) {
+            (Some(utc), _) => match Epoch::from_str(&utc) {
+                Ok(t) => Some(t),
+                Err(e) => {
+                    warn!("Couldn't parse the trigger time {utc} - {e}");
+                    None
+                }
+            },
+            (None, mjd) => mjd.map(Epoch::from_mjd_utc),
+        };
+        trigger.dm = msg.dm;
+        trigger.snr = msg.snr;
+        trigger.width = msg.width;
+        trigger.window = msg.window;
+        trigger
+    }
+
+    /// The candidate's DM and S/N, to tell dumps apart by filename
+    fn file_suffix(&self) -> String {
+        let mut suffix = String::new();
+        if let Some(dm) = self.dm {
+            suffix.push_str(&format!("_dm{dm:.1}"));
+        }
+        if let Some(snr) = self.snr {
+            suffix.push_str(&format!("_snr{snr:.1}"));
+        }
+        suffix
+    }
+
+    /// The trigger as JSON, for the event database
+    pub fn describe(&self) -> serde_json::Value {
+        json!({
+            "source": self.source,
+            "mjd": self.time.map(|t| t.to_mjd_utc_days()),
+            "dm": self.dm,
+            "snr": self.snr,
+            "width": self.width,
+            "window": self.window,
+        })
+    }
+}
+
+/// The JSON a trigger datagram can carry, e.g.
+/// `{"utc": "2024-03-01T04:00:00.123", "dm": 312.5, "width": 0.001, "snr": 9.8}`
+#[derive(Debug, Default, Deserialize)]
+pub struct TriggerMessage {
+    /// Time of the candidate, as an ISO 8601 UTC string
+    pub utc: Option<String>,
+    /// Or as an MJD (UTC)
+    pub mjd: Option<f64>,
+    /// Dispersion measure (pc cm^-3)
+    pub dm: Option<f64>,
+    /// Width (seconds)
+    pub width: Option<f64>,
+    pub snr: Option<f64>,
+    /// What found the candidate, "udp" if not given
+    pub source: Option<String>,
+    /// Seconds either side of the candidate's time to dump
+    pub window: Option<f64>,
+}
 
 pub struct DumpRing {
-    capacity: usize,
+    /// Capacity - 1, the capacity being a power of two
+    mask: usize,
     container: Vec<Payload>,
+    /// Where the next payload goes, which is also the oldest payload once we've wrapped
     write_index: usize,
+    /// Payloads pushed since startup (saturating at the capacity)
+    filled: usize,
+    /// Payloads pushed since the last dump (saturating at the capacity)
+    fresh: usize,
+    /// Deflate level of the dumped voltages (0 for none)
+    compression: u32,
+    format: DumpFormat,
+    /// The board whose voltages these are
+    board: u8,
 }
 
 impl DumpRing {
     pub fn next_push(&mut self) -> &mut Payload {
         let before_idx = self.write_index;
-        self.write_index = (self.write_index + 1) % self.capacity;
+        self.write_index = (self.write_index + 1) & self.mask;
+        self.filled = (self.filled + 1).min(self.capacity());
+        self.fresh = (self.fresh + 1).min(self.capacity());
+        if self.board == PRIMARY_BOARD && self.write_index.is_multiple_of(FILL_UPDATE_INTERVAL) {
+            RING_FILL.set(self.fill());
+            RING_FRESH.set(self.fresh as f64 / self.capacity() as f64);
+        }
         &mut self.container[before_idx]
     }
 
+    /// Number of payloads the ring holds
+    pub fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    /// Indices of every slot in the ring, oldest first
+    fn ordered_indices(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.capacity()).map(|i| (self.write_index + i) & self.mask)
+    }
+
+    /// Is the payload at ring index `i` real data, rather than a startup zero?
+    fn is_real(&self, i: usize) -> bool {
+        // Startup zeros are the oldest payloads, so they come first
+        let age_order = i.wrapping_sub(self.write_index) & self.mask;
+        age_order >= self.capacity() - self.filled
+    }
+
+    /// Every payload in the ring, oldest first (startup zeros included, until it's wrapped)
+    pub fn iter_ordered(&self) -> impl Iterator<Item = &Payload> {
+        self.ordered_indices().map(|i| &self.container[i])
+    }
+
+    /// Fraction of the ring holding real data
+    pub fn fill(&self) -> f64 {
+        self.filled as f64 / self.capacity() as f64
+    }
+
+    /// Has the ring wrapped at least once (so a dump has no startup zeros in it)?
+    pub fn wrapped(&self) -> bool {
+        self.filled == self.capacity()
+    }
+
     pub fn new(size_power: u32) -> Self {
-        let cap = 2usize.pow(size_power);
+        let cap = 1usize << size_power;
         Self {
             container: vec![Payload::default(); cap],
             write_index: 0,
-            capacity: cap,
+            mask: cap - 1,
+            filled: 0,
+            fresh: 0,
+            compression: 0,
+            format: DumpFormat::default(),
+            board: PRIMARY_BOARD,
+        }
+    }
+
+    /// Hold the voltages of `board`, rather than the primary board's
+    pub fn with_board(mut self, board: u8) -> Self {
+        self.board = board;
+        self
+    }
+
+    /// Write dumps as `format`
+    pub fn with_format(mut self, format: DumpFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Deflate the dumped voltages at `level` (0 to 9)
+    pub fn with_compression(mut self, level: u32) -> Self {
+        self.compression = level;
+        self
+    }
+
+    /// Swap the ring's payloads into `snapshot` (an empty ring of the same size), to write out while
+    /// this one keeps filling, from empty
+    fn snapshot_into(&mut self, snapshot: &mut DumpRing) {
+        std::mem::swap(&mut self.container, &mut snapshot.container);
+        snapshot.write_index = self.write_index;
+        snapshot.filled = self.filled;
+        snapshot.fresh = self.fresh;
+        snapshot.compression = self.compression;
+        snapshot.format = self.format;
+        snapshot.board = self.board;
+        self.filled = 0;
+        self.fresh = 0;
+    }
+
+    /// Zero the ring, so it's empty again for the next snapshot
+    fn clear(&mut self) {
+        self.container.fill(Payload::default());
+        self.filled = 0;
+        self.fresh = 0;
+    }
+
+    /// Ring indices (oldest first) of the payloads a trigger asks for: those within its window
+    /// of the candidate's time, or everything
+    fn selection(&self, start_time: &Epoch, trigger: &Trigger) -> eyre::Result<Vec<usize>> {
+        let all = self.ordered_indices();
+        let (Some(time), Some(window)) = (trigger.time, trigger.window) else {
+            return Ok(all.collect());
+        };
+        let (from, to) = (time - window.seconds(), time + window.seconds());
+        let selected: Vec<_> = all
+            .filter(|&i| (from..=to).contains(&self.container[i].real_time(start_time)))
+            .collect();
+        if selected.is_empty() {
+            bail!("Nothing in the voltage ring within {window} s of {time}");
         }
+        Ok(selected)
     }
 
-    // Pack the ring into an array of [time, (pol_a, pol_b), channel, (re, im)]
-    pub fn dump(&self, start_time: &Epoch, path: &Path) -> eyre::Result<()> {
+    /// Global attributes of a dump: where the timestamps came from, where we were looking, the
+    /// signal chain, and why we dumped
+    fn attributes(
+        &self,
+        start_time: &Epoch,
+        cal: &calibrate::CalState,
+        trigger: &Trigger,
+    ) -> Vec<(&'static str, AttributeValue)> {
+        let mut attrs: Vec<(&'static str, AttributeValue)> = vec![];
+
+        // Record how we were run
+        attrs.push(("software_version", env!("CARGO_PKG_VERSION").into()));
+        let args: Vec<_> = std::env::args().collect();
+        attrs.push(("command_line", args.join(" ").into()));
+        if let Some(run) = RUN_INFO.get() {
+            if let Some(gateware) = &run.gateware {
+                attrs.push(("gateware", gateware.as_str().into()));
+            }
+            attrs.push(("downsample_power", run.downsample_power.into()));
+        }
+        attrs.push((
+            "telescope",
+            observation::current().telescope.as_str().into(),
+        ));
+        // The (NTP-derived) time of the first packet, before any timing correction
+        attrs.push(("start_mjd", start_time.to_mjd_utc_days().into()));
+
+        // Record where our timestamps came from
+        if let Some(sync) = timing::time_sync() {
+            attrs.push(("time_source", sync.source.to_string().into()));
+            if let Some(u) = sync.uncertainty {
+                attrs.push(("time_uncertainty", u.into()));
+            }
+            attrs.push((
+                "trigger_epoch_mjd",
+                sync.trigger_epoch.to_mjd_utc_days().into(),
+            ));
+            if let timing::TimeSource::Ntp { offset, .. } = sync.source {
+                attrs.push(("ntp_offset", offset.into()));
+            }
+        }
+
+        // Record where we were looking
+        if let Some(p) = pointing::current() {
+            if let Some(source) = &p.source {
+                attrs.push(("source", source.as_str().into()));
+            }
+            for (name, value) in [("ra", p.ra), ("dec", p.dec), ("az", p.az), ("el", p.el)] {
+                if let Some(v) = value {
+                    attrs.push((name, v.into()));
+                }
+            }
+            attrs.push(("pointing_mjd", p.since_mjd.into()));
+        }
+
+        // Record the signal chain, so the voltages can be put back on a power scale
+        attrs.push(("fft_shift", FFT_SHIFT.into()));
+        if let Some(t) = cal.calibrated {
+            attrs.push(("calibration_mjd", t.to_mjd_utc_days().into()));
+        }
+        if let Some(db) = cal.adc_gain {
+            attrs.push(("adc_gain", db.into()));
+        }
+        if let Some((a, b)) = cal.adc_rms {
+            attrs.push(("adc_rms_a", a.into()));
+            attrs.push(("adc_rms_b", b.into()));
+        }
+
+        attrs.push(("board", self.board.into()));
+        // Anything before this fraction of the ring is startup zeros
+        attrs.push(("ring_fill", self.fill().into()));
+
+        // Record why we dumped
+        attrs.push(("trigger_source", trigger.source.as_str().into()));
+        if let Some(t) = trigger.time {
+            attrs.push(("trigger_mjd", t.to_mjd_utc_days().into()));
+        }
+        for (name, value) in [
+            ("trigger_dm", trigger.dm),
+            ("trigger_snr", trigger.snr),
+            ("trigger_width", trigger.width),
+            ("trigger_window", trigger.window),
+        ] {
+            if let Some(v) = value {
+                attrs.push((name, v.into()));
+            }
+        }
+        attrs
+    }
+
+    /// TDB days since J2000 of each selected payload, with the timing `correction` applied
+    fn tdb_days(
+        &self,
+        start_time: &Epoch,
+        selection: &[usize],
+        correction: hifitime::Duration,
+    ) -> Vec<f64> {
+        selection
+            .iter()
+            .map(|&i| {
+                (self.container[i].real_time(start_time) + correction).to_tdb_days_since_j2000()
+            })
+            .collect()
+    }
+
+    /// Which selected payloads capture filled in for dropped packets
+    fn synthetic_flags(&self, selection: &[usize]) -> Vec<u8> {
+        selection
+            .iter()
+            .map(|&i| u8::from(self.container[i].synthetic))
+            .collect()
+    }
+
+    /// Hand `write` the selected voltages as [time, (pol_a, pol_b), channel, (re, im)], a chunk
+    /// of timesteps at a time, along with where that chunk goes in time
+    fn for_each_chunk(
+        &self,
+        selection: &[usize],
+        mut write: impl FnMut(std::ops::Range<usize>, ArrayView4<i8>) -> eyre::Result<()>,
+    ) -> eyre::Result<()> {
+        let mut chunk = Array4::zeros((DUMP_CHUNK, 2, CHANNELS, 2));
+        for (n, indices) in selection.chunks(DUMP_CHUNK).enumerate() {
+            for (mut timestep, &i) in chunk.outer_iter_mut().zip(indices) {
+                timestep.assign(&self.container[i].into_ndarray());
+            }
+            let start = n * DUMP_CHUNK;
+            write(
+                start..start + indices.len(),
+                chunk.slice(s![..indices.len(), .., .., ..]),
+            )?;
+        }
+        Ok(())
+    }
+
+    // Pack the ring into an array of [time, (pol_a, pol_b), channel, (re, im)], returning the
+    // file and the ring indices that went into it
+    pub fn dump(
+        &self,
+        start_time: &Epoch,
+        path: &Path,
+        trigger: &Trigger,
+    ) -> eyre::Result<(PathBuf, Vec<usize>)> {
+        let selection = self.selection(start_time, trigger)?;
         // Filename with ISO 8610 standard format
         let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
-        let filename = format!("grex_dump-{}.nc", Formatter::new(Epoch::now()?, fmt));
+        let board = if self.board == PRIMARY_BOARD {
+            String::new()
+        } else {
+            format!("_board{}", self.board)
+        };
+        let filename = format!(
+            "grex_dump-{}{}{board}.{}",
+            Formatter::new(Epoch::now()?, fmt),
+            trigger.file_suffix(),
+            self.format.extension()
+        );
         let file_path = path.join(filename);
+        match self.format {
+            DumpFormat::Netcdf => self.write_netcdf(start_time, &file_path, trigger, &selection)?,
+            DumpFormat::Hdf5 => h5::write(self, start_time, &file_path, trigger, &selection)?,
+        }
+        Ok((file_path, selection))
+    }
+
+    fn write_netcdf(
+        &self,
+        start_time: &Epoch,
+        file_path: &Path,
+        trigger: &Trigger,
+        selection: &[usize],
+    ) -> eyre::Result<()> {
         let mut file = netcdf::create(file_path)?;
+        let cal = calibrate::state();
+        for (name, value) in self.attributes(start_time, &cal, trigger) {
+            file.add_attribute(name, value)?;
+        }
 
         // Add the file dimensions
-        file.add_dimension("time", self.capacity)?;
+        file.add_dimension("time", selection.len())?;
         file.add_dimension("pol", 2)?;
         file.add_dimension("freq", CHANNELS)?;
         file.add_dimension("reim", 2)?;
 
         // Describe the dimensions
+        let correction = timing::correction();
         let mut tdb = file.add_variable::<f64>("time", &["time"])?;
         tdb.put_attribute("units", "Days")?;
         tdb.put_attribute("long_name", "Dynamic Barycentric Time (TDB) since J2000")?;
-        // Fill times by traversing the payloads in order
-        let mut read_idx = self.write_index;
-        let mut idx = 0;
-        loop {
-            // Get payload ptr
-            let pl = self.container.get(read_idx).unwrap();
-            tdb.put_value(pl.real_time(start_time).to_tdb_days_since_j2000(), idx)?;
-            // Increment the pointers
-            idx += 1;
-            read_idx = (read_idx + 1) % self.capacity;
-            // Check if we've gone all the way around
-            if read_idx == self.write_index {
-                break;
-            }
-        }
+        tdb.put_attribute("correction_seconds", correction.to_seconds())?;
+        tdb.put_values(&self.tdb_days(start_time, selection, correction), ..)?;
 
         let mut pol = file.add_string_variable("pol", &["pol"])?;
         pol.put_attribute("long_name", "Polarization")?;
@@ -80,37 +702,138 @@ impl DumpRing {
         let mut freq = file.add_variable::<f64>("freq", &["freq"])?;
         freq.put_attribute("units", "Megahertz")?;
         freq.put_attribute("long_name", "Frequency")?;
-        let freqs = Array::linspace(HIGHBAND_MID_FREQ, HIGHBAND_MID_FREQ - BANDWIDTH, CHANNELS);
-        freq.put(.., freqs.view())?;
+        freq.put(.., dump_freqs().view())?;
 
         let mut reim = file.add_string_variable("reim", &["reim"])?;
         reim.put_attribute("long_name", "Complex")?;
         reim.put_string("real", 0)?;
         reim.put_string("imaginary", 1)?;
 
+        // The requant gains the voltages were scaled by
+        let (gain_a, gain_b) = &cal.gains;
+        if gain_a.len() == CHANNELS && gain_b.len() == CHANNELS {
+            let mut gains = file.add_variable::<u16>("requant_gain", &["pol", "freq"])?;
+            gains.put_attribute("long_name", "Requantization Gain")?;
+            gains.put_values(gain_a, (0, ..))?;
+            gains.put_values(gain_b, (1, ..))?;
+        }
+
+        // Which timesteps capture filled in for dropped packets
+        let mut synthetic = file.add_variable::<u8>("synthetic", &["time"])?;
+        synthetic.put_attribute("long_name", "Filled in for a dropped packet")?;
+        synthetic.put_values(&self.synthetic_flags(selection), ..)?;
+
         // Setup our data block
         let mut voltages = file.add_variable::<i8>("voltages", &["time", "pol", "freq", "reim"])?;
         voltages.put_attribute("long_name", "Channelized Voltages")?;
         voltages.put_attribute("units", "Volts")?;
+        voltages.set_chunking(&[DUMP_CHUNK.min(selection.len()), 2, CHANNELS, 2])?;
+        if self.compression > 0 {
+            // Shuffling does nothing for single byte values
+            voltages.set_compression(self.compression as i32, false)?;
+        }
 
-        // Write to the file, one timestep at a time
-        idx = 0;
-        read_idx = self.write_index;
-        loop {
-            let pl = self.container.get(read_idx).unwrap();
-            voltages.put((idx, .., .., ..), pl.into_ndarray().view())?;
-            idx += 1;
-            read_idx = (read_idx + 1) % self.capacity;
-            if read_idx == self.write_index {
-                break;
-            }
+        // Write to the file a chunk at a time
+        self.for_each_chunk(selection, |times, chunk| {
+            Ok(voltages.put((times, .., .., ..), chunk)?)
+        })
+    }
+
+    /// Dump the ring and note it (and why) in the event database
+    fn dump_and_record(
+        &mut self,
+        start_time: &Epoch,
+        path: &Path,
+        trigger: &Trigger,
+    ) -> eyre::Result<PathBuf> {
+        events::record(events::Kind::Trigger, trigger.describe());
+        let started = Instant::now();
+        let (file_path, selection) = self
+            .dump(start_time, path, trigger)
+            .inspect_err(|_| DUMP_FAILURES.inc())?;
+        let write_seconds = started.elapsed().as_secs_f64();
+        let size_bytes = std::fs::metadata(&file_path).map(|m| m.len()).ok();
+        DUMPS_COMPLETED.inc();
+        self.fresh = 0;
+        DUMP_BYTES.inc_by(size_bytes.unwrap_or_default());
+        DUMP_DURATION.observe(write_seconds);
+        LAST_DUMP_DURATION.set(write_seconds);
+        if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            LAST_DUMP.set(now.as_secs_f64());
         }
-        Ok(())
+        let first = &self.container[selection[0]];
+        let last = &self.container[selection[selection.len() - 1]];
+        catalog::record(
+            catalog::Product::Dump,
+            Some(&file_path),
+            first.real_time(start_time) + timing::correction(),
+            last.real_time(start_time) + timing::correction(),
+            json!({ "trigger": trigger.describe(), "ring_fill": self.fill() }),
+        );
+        events::record(
+            events::Kind::Dump,
+            json!({
+                "filename": file_path,
+                "duration_seconds": selection.len() as f64 * PACKET_CADENCE,
+                "size_bytes": size_bytes,
+                "write_seconds": write_seconds,
+                "ring_fill": self.fill(),
+                "trigger": trigger.source,
+            }),
+        );
+        let manifest = self.manifest(start_time, &file_path, trigger, &selection);
+        if let Err(e) = manifest.and_then(|m| manifest::write(&file_path, &m)) {
+            warn!(
+                "Couldn't write the manifest for {} - {e}",
+                file_path.display()
+            );
+        }
+        Ok(file_path)
+    }
+
+    /// What's in the dump at `file_path`, for its manifest
+    fn manifest(
+        &self,
+        start_time: &Epoch,
+        file_path: &Path,
+        trigger: &Trigger,
+        selection: &[usize],
+    ) -> eyre::Result<serde_json::Value> {
+        let time = |i: usize| self.container[i].real_time(start_time) + timing::correction();
+        let (first, last) = (time(selection[0]), time(selection[selection.len() - 1]));
+        let synthetic = selection
+            .iter()
+            .filter(|&&i| self.container[i].synthetic)
+            .count();
+        // Packets that never made it into the ring, filled in or not
+        let real: Vec<_> = selection.iter().filter(|&&i| self.is_real(i)).collect();
+        let missing: u64 = real
+            .windows(2)
+            .map(|w| {
+                let (a, b) = (self.container[*w[0]].count, self.container[*w[1]].count);
+                b.saturating_sub(a).saturating_sub(1)
+            })
+            .sum();
+        Ok(json!({
+            "file": file_path.file_name().map(|n| n.to_string_lossy()),
+            "format": self.format,
+            "size_bytes": std::fs::metadata(file_path)?.len(),
+            "sha256": manifest::checksum(file_path)?,
+            "trigger": trigger.describe(),
+            "first_mjd": first.to_mjd_utc_days(),
+            "last_mjd": last.to_mjd_utc_days(),
+            "first_utc": timing::iso8601(first),
+            "last_utc": timing::iso8601(last),
+            "payloads": selection.len(),
+            "startup_zeros": selection.len() - real.len(),
+            "synthetic_payloads": synthetic,
+            "missing_packets": missing,
+        }))
     }
 }
 
 pub async fn trigger_task(
-    sender: Sender<()>,
+    sender: Sender<Trigger>,
     port: u16,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
@@ -118,42 +841,153 @@ pub async fn trigger_task(
     // Create the socket
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
     let sock = UdpSocket::bind(addr).await?;
-    // Maybe even 0 would work, we don't expect data
-    let mut buf = [0; 10];
+    // Room for a trigger message, which is well under a packet
+    let mut buf = [0; 1500];
     loop {
         tokio::select! {
             _ = shutdown.recv() => {
                 info!("Voltage ringbuffer trigger task stopping");
                 break;
             }
-            _ = sock.recv_from(&mut buf) => {
-                sender.send(())?;
+            res = sock.recv_from(&mut buf) => {
+                let (len, from) = match res {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("Couldn't receive on the trigger socket - {e}");
+                        continue;
+                    }
+                };
+                let trigger = Trigger::from_datagram(&buf[..len]);
+                info!(%from, ?trigger, "Dump triggered");
+                cluster::fan_out(&trigger);
+                sender.send(trigger)?;
             }
         }
     }
     Ok(())
 }
 
+/// Write out ring snapshots as they come in, handing each back once it's written
+fn dump_writer(
+    snapshots: mpsc::Receiver<(DumpRing, Trigger)>,
+    done: mpsc::Sender<DumpRing>,
+    start_time: Epoch,
+    path: PathBuf,
+    target: Option<DumpTarget>,
+) {
+    for (mut snapshot, trigger) in snapshots {
+        match snapshot.dump_and_record(&start_time, &path, &trigger) {
+            Ok(file_path) => {
+                if let Some(target) = &target {
+                    for file_path in [manifest::path_for(&file_path), file_path] {
+                        match stream::send(target, &file_path) {
+                            Ok(()) => info!("Sent {} to {target}", file_path.display()),
+                            Err(e) => warn!(
+                                "Couldn't send {} to {target}, keeping it here - {e}",
+                                file_path.display()
+                            ),
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("Error in dumping buffer - {}", e),
+        }
+        snapshot.clear();
+        if done.send(snapshot).is_err() {
+            break;
+        }
+    }
+}
+
+/// Fill the ring, handing it to a writer thread on every trigger (and carrying on in an empty one)
+/// so we keep filling while it's written out. Every trigger is passed on to the `followers`, the dump tasks of the
+/// other boards, so they dump the same stretch of time.
+#[allow(clippy::too_many_arguments)]
 pub fn dump_task(
     mut ring: DumpRing,
     payload_reciever: StaticReceiver<Payload>,
-    signal_reciever: Receiver<()>,
+    signal_reciever: Receiver<Trigger>,
+    followers: Vec<Sender<Trigger>>,
     start_time: Epoch,
     path: PathBuf,
+    defer_early: bool,
+    default_window: Option<f64>,
+    limits: DumpLimits,
+    target: Option<DumpTarget>,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     info!("Starting voltage ringbuffer fill task!");
+    let _running = Running::start();
+    let mut limiter = Limiter::new(limits);
+    // A trigger we're holding until the ring has wrapped
+    let mut deferred: Option<Trigger> = None;
+    // The empty ring we swap in, while it's not being written out
+    let mut spare = Some(DumpRing::new(ring.capacity().trailing_zeros()));
+    let (snapshot_s, snapshot_r) = mpsc::sync_channel(1);
+    let (done_s, done_r) = mpsc::channel();
+    let writer_path = path.clone();
+    let writer = std::thread::Builder::new()
+        .name("dump_writer".to_string())
+        .spawn(move || dump_writer(snapshot_r, done_s, start_time, writer_path, target))?;
     loop {
         if shutdown.try_recv().is_ok() {
             info!("Dump task stopping");
             break;
         }
-        // First check if we need to dump, as that takes priority
-        if signal_reciever.try_recv().is_ok() {
+        // Something else died, save what we have and stop
+        if SALVAGE_REQUESTED.load(Ordering::Acquire) {
+            match ring.dump_and_record(&start_time, &path, &Trigger::from_source("salvage")) {
+                Ok(_) => info!("Voltage ring salvaged"),
+                Err(e) => error!("Error in salvaging buffer - {}", e),
+            }
+            break;
+        }
+        // Get the snapshot back once it's written
+        if spare.is_none() {
+            spare = done_r.try_recv().ok();
+        }
+        // First check if we need to dump, as that takes priority (once the last one's written,
+        // triggers wait for it in the meantime)
+        let trigger = if spare.is_none() {
+            None
+        } else {
+            match deferred.take() {
+                Some(t) if ring.wrapped() => Some(t),
+                Some(t) => {
+                    deferred = Some(t);
+                    None
+                }
+                None => match signal_reciever.try_recv().inspect(|t| {
+                    if ring.board == PRIMARY_BOARD {
+                        TRIGGERS_RECEIVED.inc();
+                    }
+                    for follower in &followers {
+                        if follower.try_send(t.clone()).is_err() {
+                            warn!("Another board's dump task is too busy for the trigger");
+                        }
+                    }
+                }) {
+                    Ok(t) if defer_early && !ring.wrapped() => {
+                        info!(
+                            fill = ring.fill(),
+                            "Holding dump until the voltage ring has filled"
+                        );
+                        deferred = Some(t);
+                        None
+                    }
+                    Ok(t) => Some(t),
+                    Err(_) => None,
+                },
+            }
+        }
+        .filter(|t| limiter.admit(t));
+        if let Some(mut trigger) = trigger {
             info!("Dumping ringbuffer");
-            match ring.dump(&start_time, &path) {
-                Ok(_) => (),
-                Err(e) => warn!("Error in dumping buffer - {}", e),
+            trigger.window = trigger.window.or(default_window);
+            let mut snapshot = spare.take().unwrap();
+            ring.snapshot_into(&mut snapshot);
+            if snapshot_s.send((snapshot, trigger)).is_err() {
+                bail!("Voltage dump writer went away");
             }
         } else {
             // If we're not dumping, we're pushing data into the ringbuffer
@@ -168,5 +1002,112 @@ pub fn dump_task(
             }
         }
     }
+    // Let any dump in progress finish
+    drop(snapshot_s);
+    if writer.join().is_err() {
+        error!("Voltage dump writer panicked");
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A ring of 8 payloads, after pushing `pushed` payloads counting up from 0
+    fn ring_after(pushed: u64) -> DumpRing {
+        let mut ring = DumpRing::new(3).with_board(PRIMARY_BOARD + 1);
+        for count in 0..pushed {
+            ring.next_push().count = count;
+        }
+        ring
+    }
+
+    fn counts(ring: &DumpRing) -> Vec<u64> {
+        ring.iter_ordered().map(|p| p.count).collect()
+    }
+
+    #[test]
+    fn next_push_fills_then_wraps() {
+        let ring = ring_after(5);
+        assert_eq!(ring.write_index, 5);
+        assert!(!ring.wrapped());
+        assert_eq!(ring.fill(), 5.0 / 8.0);
+        assert_eq!(
+            ring.ordered_indices()
+                .map(|i| ring.is_real(i))
+                .collect::<Vec<_>>(),
+            [false, false, false, true, true, true, true, true]
+        );
+
+        // The write index masks back round to the start at capacity
+        let ring = ring_after(8);
+        assert_eq!(ring.write_index, 0);
+        assert!(ring.wrapped());
+        assert_eq!(ring.fill(), 1.0);
+
+        let ring = ring_after(11);
+        assert_eq!(ring.write_index, 3);
+        assert!(ring.wrapped());
+        assert_eq!(ring.fill(), 1.0);
+        assert!(ring.ordered_indices().all(|i| ring.is_real(i)));
+    }
+
+    #[test]
+    fn iter_ordered_is_oldest_first() {
+        // Before the wrap, the three startup zeros come first
+        assert_eq!(counts(&ring_after(5)), [0, 0, 0, 0, 1, 2, 3, 4]);
+        // Exactly at it
+        assert_eq!(counts(&ring_after(8)), (0..8).collect::<Vec<_>>());
+        // And after it, across the end of the container
+        assert_eq!(counts(&ring_after(11)), (3..11).collect::<Vec<_>>());
+        assert_eq!(counts(&ring_after(16)), (8..16).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn snapshot_leaves_an_empty_ring() {
+        let mut ring = ring_after(11);
+        let mut snapshot = DumpRing::new(3);
+        ring.snapshot_into(&mut snapshot);
+        assert_eq!(counts(&snapshot), (3..11).collect::<Vec<_>>());
+        assert!(snapshot.wrapped());
+        assert_eq!(ring.fill(), 0.0);
+        assert_eq!(snapshot.board, PRIMARY_BOARD + 1);
+    }
+
+    #[test]
+    fn selection_spans_the_wrap() {
+        let ring = ring_after(11);
+        let start = Epoch::from_gregorian_utc_at_midnight(2024, 1, 1);
+        // Counts 7, 8 and 9, which sit either side of the end of the container
+        let trigger = Trigger {
+            time: Some(start + (8.0 * PACKET_CADENCE).seconds()),
+            window: Some(1.5 * PACKET_CADENCE),
+            ..Trigger::from_source("test")
+        };
+        let selected = ring.selection(&start, &trigger).unwrap();
+        assert_eq!(selected, [7, 0, 1]);
+        assert_eq!(
+            selected
+                .iter()
+                .map(|&i| ring.container[i].count)
+                .collect::<Vec<_>>(),
+            [7, 8, 9]
+        );
+
+        // No window is the whole ring, oldest first
+        let whole = Trigger::from_source("test");
+        assert_eq!(
+            ring.selection(&start, &whole).unwrap(),
+            [3, 4, 5, 6, 7, 0, 1, 2]
+        );
+
+        // A window entirely outside the ring is an error
+        let missed = Trigger {
+            time: Some(start + (100.0 * PACKET_CADENCE).seconds()),
+            window: Some(PACKET_CADENCE),
+            ..Trigger::from_source("test")
+        };
+        assert!(ring.selection(&start, &missed).is_err());
+    }
+}
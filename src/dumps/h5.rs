@@ -0,0 +1,126 @@
+//! Writing voltage dumps as HDF5, for tools that don't speak netCDF
+//!
+//! The layout mirrors the netCDF dumps: the same global attributes on the root group, and a dataset
+//! per netCDF variable, with the dimension names each one runs along in its `dimensions` attribute.
+use super::{dump_freqs, DumpRing, Trigger, DUMP_CHUNK};
+use crate::{calibrate, common::CHANNELS, timing};
+use eyre::bail;
+use hdf5::{types::VarLenUnicode, H5Type, Location};
+use hifitime::prelude::*;
+use ndarray::{s, Array2};
+use netcdf::AttributeValue;
+use std::path::Path;
+
+/// Set a scalar attribute `name` on `loc`
+pub(crate) fn scalar<T: H5Type>(loc: &Location, name: &str, value: &T) -> eyre::Result<()> {
+    loc.new_attr::<T>()
+        .shape(())
+        .create(name)?
+        .write_scalar(value)?;
+    Ok(())
+}
+
+pub(crate) fn text(value: &str) -> eyre::Result<VarLenUnicode> {
+    Ok(value.parse()?)
+}
+
+/// Set attribute `name` on `loc` from its netCDF equivalent
+fn attribute(loc: &Location, name: &str, value: &AttributeValue) -> eyre::Result<()> {
+    match value {
+        AttributeValue::Uchar(v) => scalar(loc, name, v),
+        AttributeValue::Ushort(v) => scalar(loc, name, v),
+        AttributeValue::Uint(v) => scalar(loc, name, v),
+        AttributeValue::Int(v) => scalar(loc, name, v),
+        AttributeValue::Longlong(v) => scalar(loc, name, v),
+        AttributeValue::Ulonglong(v) => scalar(loc, name, v),
+        AttributeValue::Float(v) => scalar(loc, name, v),
+        AttributeValue::Double(v) => scalar(loc, name, v),
+        AttributeValue::Str(v) => scalar(loc, name, &text(v)?),
+        other => bail!("No HDF5 equivalent for attribute {name} ({other:?})"),
+    }
+}
+
+/// Describe a dataset as its netCDF variable would be
+pub(crate) fn describe(loc: &Location, long_name: &str, dims: &str) -> eyre::Result<()> {
+    scalar(loc, "long_name", &text(long_name)?)?;
+    scalar(loc, "dimensions", &text(dims)?)
+}
+
+pub(super) fn write(
+    ring: &DumpRing,
+    start_time: &Epoch,
+    file_path: &Path,
+    trigger: &Trigger,
+    selection: &[usize],
+) -> eyre::Result<()> {
+    let file = hdf5::File::create(file_path)?;
+    let cal = calibrate::state();
+    for (name, value) in ring.attributes(start_time, &cal, trigger) {
+        attribute(&file, name, &value)?;
+    }
+
+    let correction = timing::correction();
+    let tdb = file
+        .new_dataset_builder()
+        .with_data(&ring.tdb_days(start_time, selection, correction))
+        .create("time")?;
+    describe(&tdb, "Dynamic Barycentric Time (TDB) since J2000", "time")?;
+    scalar(&tdb, "units", &text("Days")?)?;
+    scalar(&tdb, "correction_seconds", &correction.to_seconds())?;
+
+    let pol = file
+        .new_dataset_builder()
+        .with_data(&[text("a")?, text("b")?])
+        .create("pol")?;
+    describe(&pol, "Polarization", "pol")?;
+
+    let freq = file
+        .new_dataset_builder()
+        .with_data(&dump_freqs())
+        .create("freq")?;
+    describe(&freq, "Frequency", "freq")?;
+    scalar(&freq, "units", &text("Megahertz")?)?;
+
+    let reim = file
+        .new_dataset_builder()
+        .with_data(&[text("real")?, text("imaginary")?])
+        .create("reim")?;
+    describe(&reim, "Complex", "reim")?;
+
+    // The requant gains the voltages were scaled by
+    let (gain_a, gain_b) = &cal.gains;
+    if gain_a.len() == CHANNELS && gain_b.len() == CHANNELS {
+        let mut gains = Array2::zeros((2, CHANNELS));
+        gains.row_mut(0).assign(&ndarray::aview1(gain_a));
+        gains.row_mut(1).assign(&ndarray::aview1(gain_b));
+        let gains = file
+            .new_dataset_builder()
+            .with_data(&gains)
+            .create("requant_gain")?;
+        describe(&gains, "Requantization Gain", "pol freq")?;
+    }
+
+    // Which timesteps capture filled in for dropped packets
+    let synthetic = file
+        .new_dataset_builder()
+        .with_data(&ring.synthetic_flags(selection))
+        .create("synthetic")?;
+    describe(&synthetic, "Filled in for a dropped packet", "time")?;
+
+    // Setup our data block
+    let mut builder = file
+        .new_dataset::<i8>()
+        .shape([selection.len(), 2, CHANNELS, 2])
+        .chunk([DUMP_CHUNK.min(selection.len()), 2, CHANNELS, 2]);
+    if ring.compression > 0 {
+        builder = builder.deflate(ring.compression as u8);
+    }
+    let voltages = builder.create("voltages")?;
+    describe(&voltages, "Channelized Voltages", "time pol freq reim")?;
+    scalar(&voltages, "units", &text("Volts")?)?;
+
+    // Write to the file a chunk at a time
+    ring.for_each_chunk(selection, |times, chunk| {
+        Ok(voltages.write_slice(chunk, s![times, .., .., ..])?)
+    })
+}
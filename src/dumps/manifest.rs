@@ -0,0 +1,56 @@
+//! JSON sidecars describing each dump, and a running index of them all
+//!
+//! Candidate matching only needs to know which dump covers which time and why it was taken, so
+//! this saves opening every dump to find out. Each dump gets a `<dump>.json` manifest beside it,
+//! and the same manifest is appended to `dumps_index.json` in the dump directory.
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Running index of the dumps in a directory
+const INDEX_FILE: &str = "dumps_index.json";
+
+/// Where the manifest of the dump at `file_path` goes
+pub fn path_for(file_path: &Path) -> PathBuf {
+    let mut name = file_path.as_os_str().to_owned();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+/// Hex SHA-256 of the file at `path`
+pub(super) fn checksum(path: &Path) -> eyre::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Write `contents` to `path` by way of a temporary file, so readers never see half of it
+fn write_atomically(path: &Path, contents: &[u8]) -> eyre::Result<()> {
+    let partial = path.with_extension("json.part");
+    std::fs::write(&partial, contents)?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Write the `manifest` of the dump at `file_path` beside it, and add it to the directory's index
+pub(super) fn write(file_path: &Path, manifest: &Value) -> eyre::Result<PathBuf> {
+    let manifest_path = path_for(file_path);
+    write_atomically(&manifest_path, &serde_json::to_vec_pretty(manifest)?)?;
+
+    let dir = file_path.parent().unwrap_or(Path::new("."));
+    let index_path = dir.join(INDEX_FILE);
+    let mut index: Vec<Value> = match std::fs::read(&index_path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| {
+            eyre::eyre!("Not touching {}, it's corrupt - {e}", index_path.display())
+        })?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e.into()),
+    };
+    index.push(manifest.clone());
+    write_atomically(&index_path, &serde_json::to_vec_pretty(&index)?)?;
+    Ok(manifest_path)
+}
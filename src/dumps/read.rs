@@ -0,0 +1,304 @@
+//! Reading voltage dumps back in
+//!
+//! This is the one place that knows the layouts [`DumpRing::dump`](super::DumpRing::dump) writes
+//! (netCDF, or HDF5 mirroring it), so the converter, the Python bindings, and external tools all
+//! agree on them.
+use super::Trigger;
+use crate::{
+    common::{Channel, Payload, CHANNELS},
+    pointing::Pointing,
+};
+use eyre::eyre;
+use hdf5::types::{FloatSize, IntSize, TypeDescriptor, VarLenUnicode};
+use hifitime::prelude::*;
+use ndarray::{Array2, Array4, Axis, Ix2, Ix4};
+use netcdf::AttributeValue;
+use std::path::Path;
+
+/// The contents of a voltage dump
+#[derive(Debug)]
+pub struct Dump {
+    /// Time of each payload (UTC, with the timing correction applied), oldest first
+    pub times: Vec<Epoch>,
+    /// Channel center frequencies (MHz)
+    pub freqs: Vec<f64>,
+    /// Voltages as [time, (pol_a, pol_b), channel, (re, im)]
+    pub voltages: Array4<i8>,
+    /// Requant gains as [(pol_a, pol_b), channel], if the dump recorded them
+    pub requant_gains: Option<Array2<u16>>,
+    /// Which payloads were filled in for dropped packets, if the dump recorded it
+    pub synthetic: Option<Vec<bool>>,
+    /// Where we were looking, if we knew
+    pub pointing: Option<Pointing>,
+    /// Why we dumped
+    pub trigger: Trigger,
+    /// Every global attribute in the file, including the ones parsed above
+    pub attributes: Vec<(String, AttributeValue)>,
+}
+
+impl Dump {
+    pub fn len(&self) -> usize {
+        self.times.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.times.is_empty()
+    }
+
+    /// The `i`th payload, with its count relative to the start of the dump
+    pub fn payload(&self, i: usize) -> Payload {
+        let v = self.voltages.index_axis(Axis(0), i);
+        let mut pl = Payload {
+            count: i as u64,
+            synthetic: self.synthetic.as_ref().is_some_and(|s| s[i]),
+            ..Default::default()
+        };
+        for c in 0..CHANNELS {
+            pl.pol_a[c] = Channel::new(v[[0, c, 0]], v[[0, c, 1]]);
+            pl.pol_b[c] = Channel::new(v[[1, c, 0]], v[[1, c, 1]]);
+        }
+        pl
+    }
+
+    /// All the payloads, oldest first
+    pub fn payloads(&self) -> impl Iterator<Item = Payload> + '_ {
+        (0..self.len()).map(|i| self.payload(i))
+    }
+
+    fn attribute(&self, name: &str) -> Option<&AttributeValue> {
+        self.attributes
+            .iter()
+            .find_map(|(n, v)| (n == name).then_some(v))
+    }
+
+    fn double(&self, name: &str) -> Option<f64> {
+        match self.attribute(name)? {
+            AttributeValue::Double(v) => Some(*v),
+            AttributeValue::Float(v) => Some((*v).into()),
+            _ => None,
+        }
+    }
+
+    fn string(&self, name: &str) -> Option<String> {
+        match self.attribute(name)? {
+            AttributeValue::Str(s) => Some(s.clone()),
+            _ => None,
+        }
+    }
+}
+
+/// Read the voltage dump at `path`, as netCDF or (with an `.h5` extension) HDF5
+pub fn open(path: &Path) -> eyre::Result<Dump> {
+    let mut dump = if path.extension().is_some_and(|ext| ext == "h5") {
+        open_h5(path)?
+    } else {
+        open_netcdf(path)?
+    };
+    if dump.voltages.shape()[1..] != [2, CHANNELS, 2] {
+        return Err(eyre!(
+            "Unexpected voltage shape {:?}",
+            dump.voltages.shape()
+        ));
+    }
+    dump.pointing = dump.double("pointing_mjd").map(|since_mjd| Pointing {
+        source: dump.string("source"),
+        ra: dump.double("ra"),
+        dec: dump.double("dec"),
+        az: dump.double("az"),
+        el: dump.double("el"),
+        since_mjd,
+    });
+    dump.trigger = Trigger {
+        source: dump.string("trigger_source").unwrap_or_default(),
+        time: dump.double("trigger_mjd").map(Epoch::from_mjd_utc),
+        dm: dump.double("trigger_dm"),
+        snr: dump.double("trigger_snr"),
+        width: dump.double("trigger_width"),
+        window: dump.double("trigger_window"),
+    };
+    Ok(dump)
+}
+
+/// Times are stored as TDB days since J2000
+fn tdb_epochs(days: Vec<f64>) -> Vec<Epoch> {
+    days.into_iter()
+        .map(|days| Epoch::from_tdb_duration(days * Unit::Day).in_time_scale(TimeScale::UTC))
+        .collect()
+}
+
+fn open_netcdf(path: &Path) -> eyre::Result<Dump> {
+    let file = netcdf::open(path)?;
+    let variable = |name: &str| {
+        file.variable(name)
+            .ok_or_else(|| eyre!("{} has no {name} variable", path.display()))
+    };
+    let times = tdb_epochs(variable("time")?.get_values::<f64, _>(..)?);
+    let freqs = variable("freq")?.get_values::<f64, _>(..)?;
+    let voltages = variable("voltages")?
+        .get::<i8, _>(..)?
+        .into_dimensionality::<Ix4>()?;
+    // Older dumps don't have the gains
+    let requant_gains = file
+        .variable("requant_gain")
+        .map(|v| -> eyre::Result<_> { Ok(v.get::<u16, _>(..)?.into_dimensionality::<Ix2>()?) })
+        .transpose()?;
+    let synthetic = file
+        .variable("synthetic")
+        .map(|v| -> eyre::Result<_> {
+            Ok(v.get_values::<u8, _>(..)?
+                .into_iter()
+                .map(|f| f != 0)
+                .collect())
+        })
+        .transpose()?;
+    let attributes = file
+        .attributes()
+        .map(|a| Ok((a.name().to_owned(), a.value()?)))
+        .collect::<eyre::Result<Vec<_>>>()?;
+    Ok(Dump {
+        times,
+        freqs,
+        voltages,
+        requant_gains,
+        synthetic,
+        pointing: None,
+        trigger: Trigger::default(),
+        attributes,
+    })
+}
+
+fn open_h5(path: &Path) -> eyre::Result<Dump> {
+    let file = hdf5::File::open(path)?;
+    let dataset = |name: &str| {
+        file.dataset(name)
+            .map_err(|e| eyre!("{} has no {name} dataset - {e}", path.display()))
+    };
+    let times = tdb_epochs(dataset("time")?.read_raw::<f64>()?);
+    let freqs = dataset("freq")?.read_raw::<f64>()?;
+    let voltages = dataset("voltages")?
+        .read_dyn::<i8>()?
+        .into_dimensionality::<Ix4>()?;
+    // The gains are only there if they'd been set
+    let requant_gains = file
+        .link_exists("requant_gain")
+        .then(|| -> eyre::Result<_> { Ok(dataset("requant_gain")?.read_2d::<u16>()?) })
+        .transpose()?;
+    let synthetic = file
+        .link_exists("synthetic")
+        .then(|| -> eyre::Result<_> {
+            Ok(dataset("synthetic")?
+                .read_raw::<u8>()?
+                .into_iter()
+                .map(|f| f != 0)
+                .collect())
+        })
+        .transpose()?;
+    let attributes = file
+        .attr_names()?
+        .into_iter()
+        .map(|name| {
+            let value = h5_attribute(&file.attr(&name)?)?;
+            Ok((name, value))
+        })
+        .collect::<eyre::Result<Vec<_>>>()?;
+    Ok(Dump {
+        times,
+        freqs,
+        voltages,
+        requant_gains,
+        synthetic,
+        pointing: None,
+        trigger: Trigger::default(),
+        attributes,
+    })
+}
+
+/// The netCDF equivalent of a scalar HDF5 attribute, the reverse of what the HDF5 writer does
+fn h5_attribute(attr: &hdf5::Attribute) -> eyre::Result<AttributeValue> {
+    Ok(match attr.dtype()?.to_descriptor()? {
+        TypeDescriptor::Unsigned(IntSize::U1) => AttributeValue::Uchar(attr.read_scalar()?),
+        TypeDescriptor::Unsigned(IntSize::U2) => AttributeValue::Ushort(attr.read_scalar()?),
+        TypeDescriptor::Unsigned(IntSize::U4) => AttributeValue::Uint(attr.read_scalar()?),
+        TypeDescriptor::Unsigned(IntSize::U8) => AttributeValue::Ulonglong(attr.read_scalar()?),
+        TypeDescriptor::Integer(IntSize::U1) => AttributeValue::Schar(attr.read_scalar()?),
+        TypeDescriptor::Integer(IntSize::U2) => AttributeValue::Short(attr.read_scalar()?),
+        TypeDescriptor::Integer(IntSize::U4) => AttributeValue::Int(attr.read_scalar()?),
+        TypeDescriptor::Integer(IntSize::U8) => AttributeValue::Longlong(attr.read_scalar()?),
+        TypeDescriptor::Float(FloatSize::U4) => AttributeValue::Float(attr.read_scalar()?),
+        TypeDescriptor::Float(FloatSize::U8) => AttributeValue::Double(attr.read_scalar()?),
+        TypeDescriptor::VarLenUnicode => {
+            AttributeValue::Str(attr.read_scalar::<VarLenUnicode>()?.as_str().to_owned())
+        }
+        other => {
+            return Err(eyre!(
+                "No netCDF equivalent for attribute {} ({other:?})",
+                attr.name()
+            ))
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        calibrate,
+        dumps::{DumpFormat, DumpRing},
+    };
+
+    /// Dump a small ring as `format` and read it back, checking it matches what went in
+    fn round_trip(format: DumpFormat) {
+        let dir =
+            std::env::temp_dir().join(format!("grex_t0-dump-{format:?}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gain_a: Vec<u16> = (0..CHANNELS as u16).collect();
+        let gain_b: Vec<u16> = gain_a.iter().map(|g| g * 2).collect();
+        calibrate::record_gains(&gain_a, &gain_b);
+
+        // 11 payloads through a ring of 8, so the dump spans the wrap
+        let mut ring = DumpRing::new(3).with_format(format);
+        for count in 0..11u64 {
+            let pl = ring.next_push();
+            pl.count = count;
+            pl.synthetic = count % 3 == 0;
+            for c in 0..CHANNELS {
+                let v = (count as usize + c) as i8;
+                pl.pol_a[c] = Channel::new(v, v.wrapping_neg());
+                pl.pol_b[c] = Channel::new(v.wrapping_add(1), v.wrapping_sub(1));
+            }
+        }
+        let start = Epoch::from_gregorian_utc_at_midnight(2024, 1, 1);
+        let (path, _) = ring
+            .dump(&start, &dir, &Trigger::from_source("test"))
+            .unwrap();
+        let dump = open(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(dump.len(), 8);
+        let expected: Vec<_> = ring.iter_ordered().collect();
+        for (i, pl) in expected.iter().enumerate() {
+            // Times go through TDB days, so only to within a small fraction of a packet
+            let off = (dump.times[i] - pl.real_time(&start)).abs();
+            assert!(off < Unit::Microsecond * 1, "time {i} off by {off}");
+            assert_eq!(dump.payload(i).into_ndarray(), pl.into_ndarray());
+        }
+        assert_eq!(
+            dump.synthetic.unwrap(),
+            expected.iter().map(|pl| pl.synthetic).collect::<Vec<_>>()
+        );
+        let gains = dump.requant_gains.unwrap();
+        assert_eq!(gains.row(0).to_vec(), gain_a);
+        assert_eq!(gains.row(1).to_vec(), gain_b);
+        assert_eq!(dump.trigger.source, "test");
+    }
+
+    #[test]
+    fn netcdf_round_trip() {
+        round_trip(DumpFormat::Netcdf);
+    }
+
+    #[test]
+    fn hdf5_round_trip() {
+        round_trip(DumpFormat::Hdf5);
+    }
+}
@@ -0,0 +1,129 @@
+//! Sending voltage dumps on to a storage node, for when the acquisition machine is short on disk
+//!
+//! Each dump is still written locally first, then sent over its own TCP connection and deleted
+//! once the other end has it, so scratch only ever holds the dump in flight. A connection carries a
+//! `<filename> <size in bytes>` line followed by the file, and the receiver answers `ok` once it's
+//! written the whole thing. `grex_t0 receive-dumps` is the receiving end.
+use clap::Args;
+use eyre::{bail, eyre};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// How long a stalled transfer can sit before we give up on it
+const IO_TIMEOUT: Duration = Duration::from_secs(60);
+/// What the receiver says once it has the whole dump
+const ACK: &[u8] = b"ok\n";
+
+/// Where to send dumps, as `tcp://host:port`
+#[derive(Debug, Clone)]
+pub struct DumpTarget(String);
+
+impl FromStr for DumpTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once("://") {
+            Some(("tcp", addr)) if !addr.is_empty() => Ok(Self(addr.to_string())),
+            Some((scheme, _)) => Err(format!(
+                "Can't send dumps over {scheme}, only tcp://host:port"
+            )),
+            None => Err("Expected tcp://host:port".to_string()),
+        }
+    }
+}
+
+impl Display for DumpTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "tcp://{}", self.0)
+    }
+}
+
+/// Send the dump at `file_path` to `target`, deleting it here once it's arrived
+pub fn send(target: &DumpTarget, file_path: &Path) -> eyre::Result<()> {
+    let name = file_path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| eyre!("{} has no filename", file_path.display()))?;
+    let mut file = File::open(file_path)?;
+    let size = file.metadata()?.len();
+    let mut stream = TcpStream::connect(&target.0)?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.write_all(format!("{name} {size}\n").as_bytes())?;
+    io::copy(&mut file, &mut stream)?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut reply = vec![];
+    stream.read_to_end(&mut reply)?;
+    if reply != ACK {
+        bail!("{target} didn't confirm it got {name}");
+    }
+    std::fs::remove_file(file_path)?;
+    Ok(())
+}
+
+#[derive(Debug, Args)]
+pub struct Receive {
+    /// Port to listen for dumps on
+    #[arg(long, default_value_t = 65436)]
+    pub port: u16,
+    /// Where to write the dumps
+    #[arg(long, default_value = ".")]
+    pub path: PathBuf,
+}
+
+/// Take in one dump from `stream`, returning where we wrote it
+fn receive_one(stream: TcpStream, path: &Path) -> eyre::Result<PathBuf> {
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let (name, size) = header
+        .trim_end()
+        .rsplit_once(' ')
+        .ok_or_else(|| eyre!("Bad dump header {header:?}"))?;
+    let size: u64 = size.parse()?;
+    // Only ever write into our directory
+    if Path::new(name).file_name().and_then(|n| n.to_str()) != Some(name) {
+        bail!("Bad dump filename {name:?}");
+    }
+    let partial = path.join(format!("{name}.part"));
+    let mut file = File::create(&partial)?;
+    let got = io::copy(&mut reader.by_ref().take(size), &mut file)?;
+    if got != size {
+        bail!("{name} was cut off after {got} of {size} bytes");
+    }
+    file.sync_all()?;
+    let file_path = path.join(name);
+    std::fs::rename(&partial, &file_path)?;
+    (&stream).write_all(ACK)?;
+    Ok(file_path)
+}
+
+/// Receive dumps streamed from a pipeline's `--dump-target`, one at a time, forever
+pub fn receive(args: &Receive) -> eyre::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", args.port))?;
+    info!(port = args.port, "Waiting for dumps");
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Couldn't accept a dump connection - {e}");
+                continue;
+            }
+        };
+        let from = stream.peer_addr().ok();
+        match receive_one(stream, &args.path) {
+            Ok(file_path) => info!(?from, "Received {}", file_path.display()),
+            Err(e) => warn!(?from, "Failed to receive a dump - {e}"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,128 @@
+//! Operations record of triggers, dumps, calibrations, injections, and SNAP restarts, kept in a local SQLite
+//! database so "what happened last night" is a query rather than a grep through the logs.
+//!
+//! Recording is best-effort: a failure to write an event is logged but never stops the pipeline.
+use clap::Args;
+use hifitime::prelude::*;
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use std::{
+    path::Path,
+    sync::{Mutex, OnceLock},
+};
+use tracing::warn;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS events (
+    id     INTEGER PRIMARY KEY,
+    time   TEXT NOT NULL,
+    mjd    REAL NOT NULL,
+    kind   TEXT NOT NULL,
+    detail TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS events_kind_mjd ON events (kind, mjd);";
+
+static DB: OnceLock<Mutex<Connection>> = OnceLock::new();
+
+/// The things worth remembering
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Trigger,
+    Dump,
+    Calibration,
+    Injection,
+    Restart,
+    Resume,
+    /// The PPS stopped or came back
+    Pps,
+}
+
+impl Kind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Trigger => "trigger",
+            Kind::Dump => "dump",
+            Kind::Calibration => "calibration",
+            Kind::Injection => "injection",
+            Kind::Restart => "restart",
+            Kind::Resume => "resume",
+            Kind::Pps => "pps",
+        }
+    }
+}
+
+fn connect(path: &Path) -> eyre::Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+    Ok(conn)
+}
+
+/// Open (creating if needed) the event database that `record` writes to
+pub fn open(path: &Path) -> eyre::Result<()> {
+    let _ = DB.set(Mutex::new(connect(path)?));
+    Ok(())
+}
+
+/// Record an event happening now, with some kind-specific JSON detail
+pub fn record(kind: Kind, detail: Value) {
+    let db = match DB.get() {
+        Some(db) => db,
+        None => return,
+    };
+    let now = match Epoch::now() {
+        Ok(t) => t,
+        Err(e) => {
+            warn!("Couldn't timestamp {} event - {e}", kind.as_str());
+            return;
+        }
+    };
+    let res = db.lock().unwrap().execute(
+        "INSERT INTO events (time, mjd, kind, detail) VALUES (?1, ?2, ?3, ?4)",
+        params![
+            now.to_string(),
+            now.to_mjd_utc_days(),
+            kind.as_str(),
+            detail.to_string()
+        ],
+    );
+    if let Err(e) = res {
+        warn!("Failed to record {} event - {e}", kind.as_str());
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Query {
+    /// Only show events of this kind (trigger, dump, calibration, injection, restart, resume, pps)
+    #[arg(long)]
+    pub kind: Option<String>,
+    /// Only show events after this UTC time (e.g. 2024-03-01T00:00:00)
+    #[arg(long)]
+    pub since: Option<Epoch>,
+    /// Show at most this many (most recent) events
+    #[arg(long, default_value_t = 50)]
+    pub limit: usize,
+}
+
+/// Print the events in the database matching the query, oldest first
+pub fn query(path: &Path, query: &Query) -> eyre::Result<()> {
+    let conn = connect(path)?;
+    let since = query.since.map_or(f64::MIN, |t| t.to_mjd_utc_days());
+    let mut stmt = conn.prepare(
+        "SELECT time, kind, detail FROM (
+            SELECT * FROM events
+            WHERE (?1 IS NULL OR kind = ?1) AND mjd >= ?2
+            ORDER BY mjd DESC LIMIT ?3
+        ) ORDER BY mjd ASC",
+    )?;
+    let rows = stmt.query_map(params![query.kind, since, query.limit as i64], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+        ))
+    })?;
+    for row in rows {
+        let (time, kind, detail) = row?;
+        println!("{time}  {kind:<11}  {detail}");
+    }
+    Ok(())
+}
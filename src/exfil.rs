@@ -1,113 +1,1204 @@
-use crate::capture::FIRST_PACKET;
-use crate::common::{Stokes, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE};
+mod dynspec;
+mod multicast;
+mod psrfits;
+mod stream;
+mod vdif;
+
+pub use dynspec::{dynspec_consumer, DynspecFormat};
+pub use multicast::{multicast_consumer, MulticastConfig};
+pub use psrfits::psrfits_consumer;
+pub use stream::stream_consumer;
+pub use vdif::{vdif_task, VdifConfig, VDIF_CHANNEL_SIZE};
+
+use crate::capture::{self, FIRST_PACKET};
+use crate::common::{epoch_after, Spectrum, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE};
+use crate::weights;
+use crate::{
+    catalog, fluxcal, latency, observation, pointing,
+    pointing::Pointing,
+    state::{self, OpenFilterbank},
+    t2, timing,
+};
 use byte_slice_cast::AsByteSlice;
-use eyre::eyre;
+use core_affinity::CoreId;
+use eyre::bail;
 use hifitime::prelude::*;
+use lazy_static::lazy_static;
 use lending_iterator::prelude::*;
-use psrdada::client::DadaClient;
+use prometheus::{
+    register_counter_vec, register_gauge, register_int_counter, register_int_counter_vec,
+    register_int_gauge, Counter, CounterVec, Gauge, IntCounter, IntCounterVec, IntGauge,
+};
+use psrdada::{client::DadaClient, errors::PsrdadaError};
+use serde_json::json;
 use sigproc_filterbank::write::WriteFilterbank;
 use std::fs::File;
-use std::path::Path;
-use std::{collections::HashMap, io::Write, str::FromStr, sync::atomic::Ordering};
-use thingbuf::mpsc::blocking::Receiver;
+use std::path::{Path, PathBuf};
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::{BufWriter, ErrorKind, Write},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
+use thingbuf::mpsc::blocking::{Receiver, Sender};
 use thingbuf::mpsc::errors::RecvTimeoutError;
-use tokio::sync::broadcast;
-use tracing::{debug, info};
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, info, warn};
+
+lazy_static! {
+    static ref REQUANT_CLIPPED: IntCounter = register_int_counter!(
+        "dada_requant_clipped",
+        "Number of 8-bit DADA samples clipped at 0 or 255"
+    )
+    .unwrap();
+    static ref DADA_RECONNECTS: IntCounter = register_int_counter!(
+        "dada_reconnects",
+        "Number of times the DADA exfil reconnected to the PSRDADA buffer after losing it"
+    )
+    .unwrap();
+    static ref EXFIL_SPECTRA: IntCounterVec = register_int_counter_vec!(
+        "exfil_spectra_written",
+        "Number of spectra each exfil has written out",
+        &["exfil"]
+    )
+    .unwrap();
+    static ref EXFIL_BYTES: IntCounterVec = register_int_counter_vec!(
+        "exfil_bytes_written",
+        "Number of bytes each exfil has written out",
+        &["exfil"]
+    )
+    .unwrap();
+    static ref EXFIL_BLOCKS: IntCounterVec = register_int_counter_vec!(
+        "exfil_blocks_committed",
+        "Number of blocks (DADA windows, PSRFITS rows, or dynamic spectrum chunks) each exfil has committed",
+        &["exfil"]
+    )
+    .unwrap();
+    static ref EXFIL_STALL: CounterVec = register_counter_vec!(
+        "exfil_write_stall_seconds",
+        "Time each exfil has spent blocked on whatever it writes to (a free DADA block, or the disk)",
+        &["exfil"]
+    )
+    .unwrap();
+    static ref EXFIL_PAUSED_SPECTRA: IntCounterVec = register_int_counter_vec!(
+        "exfil_paused_spectra",
+        "Number of spectra each exfil has dropped while paused",
+        &["exfil"]
+    )
+    .unwrap();
+    static ref EXFIL_PAUSED: IntGauge =
+        register_int_gauge!("exfil_paused", "If writing out data is paused (1) or not (0)").unwrap();
+    /// If writing out data is paused, for the consumers to watch
+    static ref PAUSED: watch::Sender<bool> = watch::Sender::new(false);
+    static ref DUMMY_RECEIVED: IntCounter = register_int_counter!(
+        "dummy_spectra_received",
+        "Number of spectra the dummy consumer has received"
+    )
+    .unwrap();
+    static ref DUMMY_RATE: Gauge = register_gauge!(
+        "dummy_spectra_rate",
+        "Spectra per second reaching the dummy consumer (over the last report interval)"
+    )
+    .unwrap();
+    static ref DUMMY_JITTER: Gauge = register_gauge!(
+        "dummy_interarrival_jitter_seconds",
+        "Standard deviation of the time between spectra reaching the dummy consumer"
+    )
+    .unwrap();
+}
+
+#[derive(thiserror::Error, Debug)]
+/// Errors that can be produced from the DADA exfil
+pub enum DadaError {
+    #[error("The PSRDADA buffer {key:x} has {found} byte blocks, but a window of spectra is {expected} bytes. Check the buffer's block size against --samples and --nbit")]
+    BlockSize {
+        key: i32,
+        expected: usize,
+        found: usize,
+    },
+    #[error("The PSRDADA buffer went away")]
+    BufferGone,
+    #[error("PSRDADA error - {0}")]
+    Psrdada(#[from] PsrdadaError),
+    #[error("Failed to write to the PSRDADA buffer - {0}")]
+    Write(#[from] std::io::Error),
+    #[error("Couldn't start recording the 8-bit levels - {0}")]
+    Levels(eyre::Report),
+}
+
+/// Throughput of an exfil, to see when Heimdall or the disk is holding it up
+struct ExfilMetrics {
+    spectra: IntCounter,
+    bytes: IntCounter,
+    blocks: IntCounter,
+    stall: Counter,
+    paused: IntCounter,
+}
+
+impl ExfilMetrics {
+    fn new(exfil: &str) -> Self {
+        Self {
+            spectra: EXFIL_SPECTRA.with_label_values(&[exfil]),
+            bytes: EXFIL_BYTES.with_label_values(&[exfil]),
+            blocks: EXFIL_BLOCKS.with_label_values(&[exfil]),
+            stall: EXFIL_STALL.with_label_values(&[exfil]),
+            paused: EXFIL_PAUSED_SPECTRA.with_label_values(&[exfil]),
+        }
+    }
+
+    /// Count `spectra` spectra written out as `bytes` bytes
+    fn wrote(&self, spectra: usize, bytes: usize) {
+        self.spectra.inc_by(spectra as u64);
+        self.bytes.inc_by(bytes as u64);
+    }
+
+    fn committed(&self) {
+        self.blocks.inc();
+    }
+
+    /// Count time spent blocked on the output
+    fn stalled(&self, since: Instant) {
+        self.stall.inc_by(since.elapsed().as_secs_f64());
+    }
+
+    /// Count a spectrum dropped while paused
+    fn dropped(&self) {
+        self.paused.inc();
+    }
+}
+
+/// Stop writing out data (e.g. for an RFI test or maintenance), or start again, while the rest of
+/// the pipeline carries on. The file-writing consumers close their files on pausing and start new
+/// ones on resuming, and the DADA stream ends and starts again with a new header.
+pub fn set_paused(paused: bool) {
+    if PAUSED.send_replace(paused) != paused {
+        if paused {
+            info!("Pausing exfil");
+        } else {
+            info!("Resuming exfil");
+        }
+    }
+    EXFIL_PAUSED.set(paused.into());
+}
+
+/// Time of the start of spectrum `idx` (counting from the first we got), where each is
+/// `downsample_factor` packets, for starting files partway through
+fn spectrum_time(payload_start: &Epoch, downsample_factor: usize, idx: u64) -> Epoch {
+    epoch_after(
+        payload_start,
+        (PACKET_CADENCE * spectrum_count(downsample_factor, idx) as f64).seconds(),
+    ) + timing::correction()
+}
+
+/// Packet count of the first packet in spectrum `idx`, counting anything capture skipped over
+fn spectrum_count(downsample_factor: usize, idx: u64) -> u64 {
+    let count = FIRST_PACKET.load(Ordering::Acquire) + idx * downsample_factor as u64;
+    count + capture::skipped_before(count)
+}
+
+/// Whether capture skipped ahead between the last spectrum we checked and spectrum `idx`, as a
+/// file (or stream) can't span the skip and keep its times right
+fn skipped_ahead(seen: &mut Option<u64>, downsample_factor: usize, idx: u64) -> bool {
+    let skipped = capture::skipped_before(
+        FIRST_PACKET.load(Ordering::Acquire) + idx * downsample_factor as u64,
+    );
+    seen.replace(skipped).is_some_and(|s| s != skipped)
+}
+
+/// Close off the `kind` being written to `current` if capture skipped ahead before spectrum `idx`,
+/// so the next spectrum starts a new one. `close` finishes the file, along with the spectra still
+/// buffered for it if the format allows, and says whether they were written. Only then is the
+/// latency probe among them (if there's a `probe`) visible, otherwise it's left to expire.
+fn close_on_skip<F>(
+    kind: &str,
+    current: &mut Option<F>,
+    skipped: &mut Option<u64>,
+    downsample_factor: usize,
+    idx: u64,
+    probe: &mut bool,
+    close: impl FnOnce(F) -> eyre::Result<bool>,
+) -> eyre::Result<()> {
+    if !skipped_ahead(skipped, downsample_factor, idx) {
+        return Ok(());
+    }
+    let written = match current.take() {
+        Some(done) => {
+            info!("Capture skipped ahead, starting a new {kind}");
+            close(done)?
+        }
+        None => false,
+    };
+    if std::mem::take(probe) && written {
+        latency::visible();
+    }
+    Ok(())
+}
+
+/// Packet count at the start of the current DADA stream (0 until the first one starts)
+static DADA_START: AtomicU64 = AtomicU64::new(0);
+
+/// Packet count that heimdall's candidate times count from, the start of the current DADA stream
+pub fn dada_start_count() -> u64 {
+    match DADA_START.load(Ordering::Acquire) {
+        0 => FIRST_PACKET.load(Ordering::Acquire),
+        count => count,
+    }
+}
 
-// Set by hardware (in MHz)
-pub const HIGHBAND_MID_FREQ: f64 = 1529.93896484375; // Highend of band - half the channel spacing
-pub const BANDWIDTH: f64 = 250.0;
+/// How often the DADA consumer checks in on its writer
+const DADA_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the DADA writer gets to end the stream at shutdown
+const DADA_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bounds on how long to wait between attempts to (re)connect to the PSRDADA buffer
+const DADA_RECONNECT_MIN: Duration = Duration::from_secs(1);
+const DADA_RECONNECT_MAX: Duration = Duration::from_secs(30);
+/// How often the dummy consumer reports its throughput
+const DUMMY_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Averages groups of `factor` spectra, so a sink can be coarser than the detection path
+struct Rebin {
+    factor: usize,
+    buf: Vec<f32>,
+    n: usize,
+}
+
+impl Rebin {
+    /// Average spectra of `len` samples (all the Stokes parameters of a spectrum)
+    fn new(factor: usize, len: usize) -> Self {
+        Self {
+            factor,
+            buf: vec![0.0; len],
+            n: 0,
+        }
+    }
 
-/// Convert a chronno `DateTime` into a heimdall-compatible timestamp string
+    /// Add a spectrum, returning the average once we have `factor` of them
+    fn push(&mut self, stokes: &[f32]) -> Option<&[f32]> {
+        if self.factor == 1 {
+            self.buf.copy_from_slice(stokes);
+            return Some(&self.buf);
+        }
+        if self.n == 0 {
+            self.buf.copy_from_slice(stokes);
+        } else {
+            self.buf.iter_mut().zip(stokes).for_each(|(x, y)| *x += y);
+        }
+        self.n += 1;
+        if self.n < self.factor {
+            return None;
+        }
+        self.n = 0;
+        let norm = self.factor as f32;
+        self.buf.iter_mut().for_each(|v| *v /= norm);
+        Some(&self.buf)
+    }
+}
+
+/// A filterbank writer for our band, with spectra of `npol` Stokes parameters every `tsamp` seconds
+pub fn filterbank_writer(tsamp: f64, npol: usize) -> WriteFilterbank {
+    let obs = observation::current();
+    let mut fb = WriteFilterbank::new(CHANNELS, npol);
+    fb.fch1 = Some(obs.fch1());
+    fb.foff = Some(obs.foff());
+    fb.telescope_id = obs.telescope_id;
+    fb.tsamp = Some(tsamp);
+    fb
+}
+
+/// Record where we were looking in a filterbank header
+pub fn stamp_pointing(fb: &mut WriteFilterbank, p: &Pointing) {
+    fb.source_name.clone_from(&p.source);
+    fb.src_raj = p.sigproc_raj();
+    fb.src_dej = p.sigproc_dej();
+    fb.az_start = p.az;
+    fb.za_start = p.el.map(|el| 90.0 - el);
+}
+
+/// Convert an `Epoch` into a heimdall-compatible (UTC) timestamp string
 fn heimdall_timestamp(time: &Epoch) -> String {
     let fmt = Format::from_str("%Y-%m-%d-%H:%M:%S").unwrap();
-    format!("{}", Formatter::new(*time, fmt))
+    // The formatter prints in whatever scale the epoch is in, and heimdall wants UTC
+    format!("{}", Formatter::in_time_scale(*time, fmt, TimeScale::UTC))
 }
 
-/// A consumer that just grabs stokes off the channel and drops them
-pub fn dummy_consumer(
-    stokes_rcv: Receiver<Stokes>,
+/// Hand every spectrum from `receiver` on to each of the `senders`, averaged by the factor alongside
+/// it, to run more than one sink at once. As with a single sink, a slow one holds the rest up
+/// rather than missing spectra.
+pub fn tee_task<S: Spectrum>(
+    receiver: Receiver<S>,
+    senders: Vec<(Sender<S>, usize)>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting exfil tee to {} sinks", senders.len());
+    let mut sinks: Vec<_> = senders
+        .into_iter()
+        .map(|(sender, factor)| {
+            let rebin = (factor > 1).then(|| Rebin::new(factor, S::NPOL * CHANNELS));
+            (sender, rebin)
+        })
+        .collect();
+    // Averaging sinks count their spectra differently, so latency probes are left to the full
+    // resolution ones. Without any, a probe is visible once an average with it in is handed on.
+    let probing = sinks.iter().all(|(_, rebin)| rebin.is_some());
+    let mut idx = 0u64;
+    let mut probe = false;
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Exfil tee stopping");
+            break;
+        }
+        let spectrum = match receiver.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(s) => s,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        };
+        if probing {
+            probe |= latency::arrived(idx);
+        }
+        idx += 1;
+        let mut handed_on = false;
+        // A sink that's stopped has already said why, so carry on with the others
+        sinks.retain_mut(|(sender, rebin)| {
+            let out = match rebin {
+                Some(rebin) => match rebin.push(&spectrum) {
+                    Some(averaged) => averaged,
+                    None => return true,
+                },
+                None => &spectrum[..],
+            };
+            // Slots come back recycled empty, so build a whole spectrum rather than copy into one
+            let Ok(filled) = S::try_from(out) else {
+                unreachable!("Sinks are handed exactly one spectrum at a time")
+            };
+            match sender.send_ref() {
+                Ok(mut slot) => {
+                    *slot = filled;
+                    handed_on = true;
+                    true
+                }
+                Err(_) => {
+                    warn!("An exfil sink stopped, no longer teeing to it");
+                    false
+                }
+            }
+        });
+        if probe && handed_on {
+            latency::visible();
+            probe = false;
+        }
+        if sinks.is_empty() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Where the 8-bit requantized noise distribution sits, leaving ~8 sigma of headroom for bursts
+const DIGI_MEAN: f32 = 127.5;
+const DIGI_SIGMA: f32 = 15.0;
+
+/// Per-channel levels mapping f32 Stokes onto 8 bits, undone with `x = u8 * scale + offset` (like
+/// PSRFITS's `DAT_SCL` and `DAT_OFFS`)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Levels {
+    pub scales: Vec<f32>,
+    pub offsets: Vec<f32>,
+}
+
+impl Levels {
+    /// The same levels for each of `width` channels, for noise with the given mean and standard
+    /// deviation
+    fn uniform(width: usize, mean: f32, sigma: f32) -> Self {
+        let scale = if sigma > 0.0 { sigma / DIGI_SIGMA } else { 1.0 };
+        Self {
+            scales: vec![scale; width],
+            offsets: vec![mean - DIGI_MEAN * scale; width],
+        }
+    }
+
+    /// Requantize `spectrum` into `out`, with NaN fill going to the mean level, returning how many
+    /// values were clipped
+    fn quantize(&self, spectrum: &[f32], out: &mut [u8]) -> u64 {
+        let mut clipped = 0;
+        let levels = self.offsets.iter().zip(&self.scales);
+        for ((o, &x), (offset, scale)) in out.iter_mut().zip(spectrum).zip(levels) {
+            if x.is_nan() {
+                *o = DIGI_MEAN as u8;
+                continue;
+            }
+            let v = ((x - offset) / scale).round();
+            if !(0.0..=255.0).contains(&v) {
+                clipped += 1;
+            }
+            *o = v.clamp(0.0, 255.0) as u8;
+        }
+        clipped
+    }
+
+    /// Undo the requantization of `spectrum`, into `out`
+    pub fn unquantize(&self, spectrum: &[u8], out: &mut [f32]) {
+        let levels = self.offsets.iter().zip(&self.scales);
+        for ((x, &v), (offset, scale)) in out.iter_mut().zip(spectrum).zip(levels) {
+            *x = f32::from(v) * scale + offset;
+        }
+    }
+}
+
+/// Running per-channel sums to fit [`Levels`] to each channel's mean and standard deviation,
+/// leaving out NaN fill
+#[derive(Debug, Clone)]
+struct LevelFit {
+    counts: Vec<u32>,
+    sums: Vec<f64>,
+    squares: Vec<f64>,
+}
+
+impl LevelFit {
+    fn new(width: usize) -> Self {
+        Self {
+            counts: vec![0; width],
+            sums: vec![0.0; width],
+            squares: vec![0.0; width],
+        }
+    }
+
+    fn add(&mut self, spectrum: &[f32]) {
+        let sums = self
+            .counts
+            .iter_mut()
+            .zip(&mut self.sums)
+            .zip(&mut self.squares);
+        for (((n, s), sq), &x) in sums.zip(spectrum) {
+            if !x.is_nan() {
+                *n += 1;
+                *s += f64::from(x);
+                *sq += f64::from(x) * f64::from(x);
+            }
+        }
+    }
+
+    /// Fit `levels` to the spectra added so far, leaving channels that were all fill as they were,
+    /// and start over
+    fn update(&mut self, levels: &mut Levels) {
+        let sums = self.counts.iter().zip(&self.sums).zip(&self.squares);
+        let fitted = levels.scales.iter_mut().zip(&mut levels.offsets);
+        for (((&n, s), sq), (scale, offset)) in sums.zip(fitted) {
+            if n == 0 {
+                continue;
+            }
+            let n = f64::from(n);
+            let mean = s / n;
+            let sigma = (sq / n - mean * mean).max(0.0).sqrt() as f32;
+            *scale = if sigma > 0.0 { sigma / DIGI_SIGMA } else { 1.0 };
+            *offset = mean as f32 - DIGI_MEAN * *scale;
+        }
+        self.counts.fill(0);
+        self.sums.fill(0.0);
+        self.squares.fill(0.0);
+    }
+}
+
+/// The levels of an 8-bit DADA stream, refit over every block for the next and recorded block by
+/// block in a file of the stream's own
+struct Requant8 {
+    levels: Levels,
+    fit: LevelFit,
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// The block being written, counting from the start of the stream
+    block: u64,
+}
+
+impl Requant8 {
+    /// Start requantizing a stream starting at `start`, adding the levels of its first block to
+    /// its `header`, and recording the header then the levels of each block in a new file in `dir`
+    /// (read back by [`DadaLevels::read`]). The first block's levels are the same for every
+    /// channel, from the median and MAD across the unmasked channels of its first spectrum
+    /// (leaving out any NaN fill), as that's all we have to go on yet.
+    fn start(
+        dir: &Path,
+        start: Epoch,
+        stokes: &[f32],
+        header: &mut HashMap<String, String>,
+    ) -> eyre::Result<Self> {
+        let mut vals: Vec<_> = weights::band_mask()
+            .unmasked()
+            .into_iter()
+            .map(|c| stokes[c])
+            .filter(|v| !v.is_nan())
+            .collect();
+        let (mean, sigma) = if vals.is_empty() {
+            (0.0, 0.0)
+        } else {
+            let median = |v: &mut [f32]| {
+                let mid = v.len() / 2;
+                *v.select_nth_unstable_by(mid, f32::total_cmp).1
+            };
+            let mean = median(&mut vals);
+            let mut devs: Vec<_> = vals.iter().map(|v| (v - mean).abs()).collect();
+            // MAD to standard deviation for Gaussian noise
+            (mean, 1.4826 * median(&mut devs))
+        };
+        let levels = Levels::uniform(stokes.len(), mean, sigma);
+        let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
+        let stem = format!(
+            "grex-{}_dada_levels",
+            Formatter::in_time_scale(start, fmt, TimeScale::UTC)
+        );
+        let (path, file) = create_new(dir, &stem, "txt")?;
+        header.extend([
+            ("NBIT".to_owned(), "8".to_owned()),
+            ("DIGI_MEAN".to_owned(), DIGI_MEAN.to_string()),
+            ("DIGI_SIGMA".to_owned(), DIGI_SIGMA.to_string()),
+            ("DIGI_SCALE".to_owned(), levels.scales[0].to_string()),
+            ("DIGI_OFFSET".to_owned(), levels.offsets[0].to_string()),
+            ("DIGI_LEVELS".to_owned(), path.display().to_string()),
+        ]);
+        let mut file = BufWriter::new(file);
+        let mut entries: Vec<_> = header.iter().collect();
+        entries.sort();
+        write_header_entries(&mut file, entries)?;
+        let mut requant = Self {
+            levels,
+            fit: LevelFit::new(stokes.len()),
+            path,
+            file,
+            block: 0,
+        };
+        requant.record()?;
+        Ok(requant)
+    }
+
+    /// Requantize `stokes` into `out`, folding it into the fit for the next block
+    fn apply(&mut self, stokes: &[f32], out: &mut [u8]) {
+        self.fit.add(stokes);
+        REQUANT_CLIPPED.inc_by(self.levels.quantize(stokes, out));
+    }
+
+    /// Move on to the next block, with each channel's levels fit over the last one
+    fn next_block(&mut self) {
+        self.fit.update(&mut self.levels);
+        self.block += 1;
+        if let Err(e) = self.record() {
+            warn!("Couldn't record the 8-bit levels - {e}");
+        }
+    }
+
+    /// Append the block number and its levels to the file, as `BLOCK`, `DAT_SCL`, and `DAT_OFFS`
+    /// lines
+    fn record(&mut self) -> eyre::Result<()> {
+        writeln!(self.file, "BLOCK {}", self.block)?;
+        for (key, values) in [
+            ("DAT_SCL", &self.levels.scales),
+            ("DAT_OFFS", &self.levels.offsets),
+        ] {
+            write!(self.file, "{key}")?;
+            for v in values {
+                write!(self.file, " {v}")?;
+            }
+            writeln!(self.file)?;
+        }
+        Ok(self.file.flush()?)
+    }
+}
+
+/// The levels of an 8-bit DADA stream, as recorded alongside it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DadaLevels {
+    /// The stream's DADA header
+    pub header: HashMap<String, String>,
+    /// The levels of each block recorded, by block number from the start of the stream
+    pub blocks: Vec<(u64, Levels)>,
+}
+
+impl DadaLevels {
+    /// Read the levels recorded for a stream, named in its header as `DIGI_LEVELS`. That's the
+    /// stream's header as `KEY VALUE` lines, then a `BLOCK <n>` line for each block followed by
+    /// `DAT_SCL` and `DAT_OFFS` lines of each channel's levels. A block cut short at the end of
+    /// the file (if we stopped partway through recording it) is left out.
+    pub fn read(path: &Path) -> eyre::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        // Anything after the last newline didn't finish being written
+        let complete = text.rfind('\n').map_or("", |end| &text[..end]);
+        let mut levels = Self::default();
+        let mut block: Option<(u64, Option<Vec<f32>>)> = None;
+        let values = |line: &str| -> eyre::Result<Vec<f32>> {
+            Ok(line
+                .split_whitespace()
+                .map(str::parse)
+                .collect::<Result<_, _>>()?)
+        };
+        for (n, line) in complete.lines().enumerate() {
+            let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+            match (key, block.take()) {
+                ("BLOCK", _) => block = Some((value.parse()?, None)),
+                ("DAT_SCL", Some((b, None))) => block = Some((b, Some(values(value)?))),
+                ("DAT_OFFS", Some((b, Some(scales)))) => {
+                    let offsets = values(value)?;
+                    if offsets.len() != scales.len() {
+                        bail!(
+                            "Line {}: block {b} has {} offsets for {} scales",
+                            n + 1,
+                            offsets.len(),
+                            scales.len()
+                        );
+                    }
+                    levels.blocks.push((b, Levels { scales, offsets }));
+                }
+                (_, None) if levels.blocks.is_empty() => {
+                    levels.header.insert(key.to_owned(), value.to_owned());
+                }
+                _ => bail!("Line {}: unexpected {key}", n + 1),
+            }
+        }
+        Ok(levels)
+    }
+}
+
+/// Write DADA-style `KEY VALUE` lines to a sidecar file for outputs without room for extra metadata
+fn write_sidecar_header(path: &Path, entries: &[(String, String)]) -> eyre::Result<()> {
+    let mut file = File::create(path)?;
+    write_header_entries(&mut file, entries.iter().map(|(k, v)| (k, v)))?;
+    Ok(())
+}
+
+/// Write `entries` as DADA-style `KEY VALUE` lines
+fn write_header_entries<'a>(
+    out: &mut impl Write,
+    entries: impl IntoIterator<Item = (&'a String, &'a String)>,
+) -> std::io::Result<()> {
+    for (k, v) in entries {
+        writeln!(out, "{k} {v}")?;
+    }
+    Ok(())
+}
+
+/// Running statistics of the time between arrivals (Welford's algorithm)
+#[derive(Debug, Default)]
+struct Interarrival {
+    n: u64,
+    mean: f64,
+    m2: f64,
+    max: f64,
+}
+
+impl Interarrival {
+    fn push(&mut self, dt: f64) {
+        self.n += 1;
+        let delta = dt - self.mean;
+        self.mean += delta / self.n as f64;
+        self.m2 += delta * (dt - self.mean);
+        self.max = self.max.max(dt);
+    }
+
+    fn std(&self) -> f64 {
+        if self.n < 2 {
+            0.0
+        } else {
+            (self.m2 / (self.n - 1) as f64).sqrt()
+        }
+    }
+}
+
+/// A consumer that just grabs stokes off the channel and drops them,
+/// keeping track of how quickly (and evenly) they arrive
+pub fn dummy_consumer<S: Spectrum>(
+    stokes_rcv: Receiver<S>,
+    downsample_factor: usize,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     info!("Starting dummy consumer");
+    let expected_rate = 1.0 / (PACKET_CADENCE * downsample_factor as f64);
+    let mut spectrum_idx = 0u64;
+    let mut first_arrival = None;
+    let mut last_arrival = Instant::now();
+    let mut last_report = Instant::now();
+    let mut report_idx = 0u64;
+    let mut interarrival = Interarrival::default();
     loop {
         if shutdown.try_recv().is_ok() {
             info!("Exfil task stopping");
             break;
         }
         match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
-            Ok(_) | Err(RecvTimeoutError::Timeout) => continue,
+            Ok(_) => {
+                let now = Instant::now();
+                if first_arrival.is_none() {
+                    first_arrival = Some(now);
+                    last_report = now;
+                } else {
+                    interarrival.push((now - last_arrival).as_secs_f64());
+                }
+                last_arrival = now;
+                if latency::arrived(spectrum_idx) {
+                    latency::visible();
+                }
+                spectrum_idx += 1;
+                DUMMY_RECEIVED.inc();
+                if last_report.elapsed() >= DUMMY_REPORT_INTERVAL {
+                    let rate =
+                        (spectrum_idx - report_idx) as f64 / last_report.elapsed().as_secs_f64();
+                    DUMMY_RATE.set(rate);
+                    DUMMY_JITTER.set(interarrival.std());
+                    debug!(
+                        rate,
+                        expected_rate,
+                        jitter = interarrival.std(),
+                        "Exfil throughput"
+                    );
+                    report_idx = spectrum_idx;
+                    last_report = now;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
             Err(RecvTimeoutError::Closed) => break,
             Err(_) => unreachable!(),
         }
     }
+    if let Some(first) = first_arrival {
+        let elapsed = (last_arrival - first).as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            (spectrum_idx - 1) as f64 / elapsed
+        } else {
+            0.0
+        };
+        info!(
+            spectra = spectrum_idx,
+            seconds = elapsed,
+            rate,
+            expected_rate,
+            gbps = rate * (S::NPOL * CHANNELS * std::mem::size_of::<f32>() * 8) as f64 / 1e9,
+            jitter_seconds = interarrival.std(),
+            max_gap_seconds = interarrival.max,
+            "Dummy consumer throughput"
+        );
+    }
     Ok(())
 }
 
-pub fn dada_consumer(
+/// Stream the spectra to the PSRDADA buffer `key` in windows of `window_size`, for Heimdall.
+///
+/// Getting a free block from PSRDADA blocks for as long as the reader takes to free one, with no
+/// way to time out, so the writing happens on a thread of its own (pinned to the same core as
+/// this one, which only waits on it) that we keep an eye on. Come shutdown, it closes off the
+/// block it's filling with an end of data, and if it's still stuck waiting for a block after
+/// [`DADA_SHUTDOWN_TIMEOUT`] we leave it behind.
+///
+/// At 8 bits, the levels each block was requantized with are recorded in a new file in the
+/// directory `levels` for every stream.
+#[allow(clippy::too_many_arguments)]
+pub fn dada_consumer<S: Spectrum>(
     key: i32,
-    stokes_rcv: Receiver<Stokes>,
+    stokes_rcv: Receiver<S>,
     payload_start: Epoch,
     downsample_factor: usize,
     window_size: usize,
+    nbit: u32,
+    levels: Option<PathBuf>,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     info!("Starting DADA consumer");
+    let levels = match levels {
+        Some(dir) if nbit == 8 => Some(dir),
+        None if nbit == 8 => bail!("8-bit DADA needs somewhere to record the levels"),
+        _ => None,
+    };
+    let waiting = Arc::new(AtomicBool::new(false));
+    // We were given a core for exfil, so that's where the writing goes
+    // Safety: sched_getcpu has no preconditions
+    let cpu = usize::try_from(unsafe { libc::sched_getcpu() }).ok();
+    let (done_send, done) = mpsc::channel();
+    {
+        let waiting = waiting.clone();
+        let shutdown = shutdown.resubscribe();
+        std::thread::Builder::new()
+            .name("dada-writer".to_string())
+            .spawn(move || {
+                if let Some(id) = cpu {
+                    if !core_affinity::set_for_current(CoreId { id }) {
+                        warn!("Couldn't pin the DADA writer to core {id}");
+                    }
+                }
+                let _ = done_send.send(dada_writer(
+                    key,
+                    stokes_rcv,
+                    payload_start,
+                    downsample_factor,
+                    window_size,
+                    nbit,
+                    levels,
+                    &waiting,
+                    shutdown,
+                ));
+            })?;
+    }
+    // When the writer started waiting on a block, if it is
+    let mut waiting_since: Option<Instant> = None;
+    let mut warned = false;
+    loop {
+        match done.recv_timeout(DADA_POLL_INTERVAL) {
+            Ok(res) => return res,
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => bail!("DADA writer panicked"),
+        }
+        if shutdown.try_recv().is_ok() {
+            break;
+        }
+        if waiting.load(Ordering::Acquire) {
+            let since = *waiting_since.get_or_insert_with(Instant::now);
+            if !warned && since.elapsed() > BLOCK_TIMEOUT {
+                warn!("Still waiting on a free PSRDADA block, is Heimdall reading?");
+                warned = true;
+            }
+        } else {
+            waiting_since = None;
+            warned = false;
+        }
+    }
+    // The writer got the shutdown too, give it a chance to close off its block
+    match done.recv_timeout(DADA_SHUTDOWN_TIMEOUT) {
+        Ok(res) => res,
+        Err(mpsc::RecvTimeoutError::Timeout) => {
+            warn!("DADA writer is stuck waiting on a free block, leaving it behind");
+            Ok(())
+        }
+        Err(mpsc::RecvTimeoutError::Disconnected) => bail!("DADA writer panicked"),
+    }
+}
+
+/// The writing half of [`dada_consumer`], which sets `waiting` while it waits for a free block.
+///
+/// If the buffer goes away (say Heimdall restarted and recreated it), we drop spectra while we
+/// reconnect with a backoff, then start a new stream with a fresh header.
+#[allow(clippy::too_many_arguments)]
+fn dada_writer<S: Spectrum>(
+    key: i32,
+    stokes_rcv: Receiver<S>,
+    payload_start: Epoch,
+    downsample_factor: usize,
+    window_size: usize,
+    nbit: u32,
+    levels: Option<PathBuf>,
+    waiting: &AtomicBool,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    let window_bytes = window_size * S::NPOL * CHANNELS * nbit as usize / 8;
+    // Index of the next spectrum, which carries on across reconnects to keep the timestamps right
+    let mut spectrum_idx = 0u64;
+    let mut backoff = DADA_RECONNECT_MIN;
+    let mut connected_before = false;
+    loop {
+        let mut client = match DadaClient::new(key) {
+            Ok(client) => client,
+            Err(e) => {
+                warn!(
+                    ?backoff,
+                    "Couldn't connect to the PSRDADA buffer {key:x}, retrying - {e}"
+                );
+                if !discard_for(&stokes_rcv, backoff, &mut spectrum_idx, &mut shutdown) {
+                    return Ok(());
+                }
+                backoff = (backoff * 2).min(DADA_RECONNECT_MAX);
+                continue;
+            }
+        };
+        backoff = DADA_RECONNECT_MIN;
+        if connected_before {
+            info!("Reconnected to the PSRDADA buffer {key:x}");
+            DADA_RECONNECTS.inc();
+        }
+        connected_before = true;
+        let found = client.data_buf_size();
+        if found != window_bytes {
+            return Err(DadaError::BlockSize {
+                key,
+                expected: window_bytes,
+                found,
+            }
+            .into());
+        }
+        match dada_stream(
+            &mut client,
+            &stokes_rcv,
+            payload_start,
+            downsample_factor,
+            window_size,
+            levels.as_deref(),
+            &mut spectrum_idx,
+            waiting,
+            &mut shutdown,
+        ) {
+            Ok(()) => return Ok(()),
+            Err(e @ DadaError::Levels(_)) => return Err(e.into()),
+            Err(e) => {
+                waiting.store(false, Ordering::Release);
+                warn!("Lost the PSRDADA buffer, reconnecting - {e}");
+                if !discard_for(&stokes_rcv, backoff, &mut spectrum_idx, &mut shutdown) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Drop spectra for `duration` (or until shutdown or the spectra stop, returning false)
+fn discard_for<S: Spectrum>(
+    stokes_rcv: &Receiver<S>,
+    duration: Duration,
+    spectrum_idx: &mut u64,
+    shutdown: &mut broadcast::Receiver<()>,
+) -> bool {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if shutdown.try_recv().is_ok() {
+            info!("Exfil task stopping");
+            return false;
+        }
+        match stokes_rcv.recv_ref_timeout(DADA_POLL_INTERVAL) {
+            Ok(_) => {
+                latency::discarded(*spectrum_idx);
+                *spectrum_idx += 1;
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => return false,
+            Err(_) => unreachable!(),
+        }
+    }
+    true
+}
+
+/// Stream to a connected PSRDADA buffer, starting with a new header, until shutdown (`Ok`) or the
+/// buffer goes away (`Err`). With somewhere to record `levels`, the stream is 8-bit.
+#[allow(clippy::too_many_arguments)]
+fn dada_stream<S: Spectrum>(
+    client: &mut DadaClient,
+    stokes_rcv: &Receiver<S>,
+    payload_start: Epoch,
+    downsample_factor: usize,
+    window_size: usize,
+    levels: Option<&Path>,
+    spectrum_idx: &mut u64,
+    waiting: &AtomicBool,
+    shutdown: &mut broadcast::Receiver<()>,
+) -> Result<(), DadaError> {
+    // Levels for 8-bit output, fit at the start of every stream and refit every block
+    let mut requant: Option<Requant8> = None;
+    let mut requant_buf = vec![0u8; S::NPOL * CHANNELS];
     // DADA window
     let mut stokes_cnt = 0usize;
+    // If the current window holds the latency probe
+    let mut probe_in_window = false;
+    let metrics = ExfilMetrics::new("psrdada");
+    let paused = PAUSED.subscribe();
     // We will capture the timestamp on the first packet
     let mut first_payload = true;
+    // If we've ended the stream, and need a new transfer to carry on
+    let mut ended = false;
+    // Packets capture had skipped over as of the last spectrum
+    let mut skipped = None;
     // Send the header (heimdall only wants one)
+    let obs = observation::current();
     let mut header = HashMap::from([
         ("NCHAN".to_owned(), CHANNELS.to_string()),
-        ("BW".to_owned(), (-BANDWIDTH).to_string()),
-        ("FREQ".to_owned(), "1405".to_owned()),
-        ("NPOL".to_owned(), "1".to_owned()),
+        ("BW".to_owned(), obs.signed_bandwidth().to_string()),
+        ("FREQ".to_owned(), obs.center_freq.to_string()),
+        ("TELESCOPE".to_owned(), obs.telescope.clone()),
+        ("NPOL".to_owned(), S::NPOL.to_string()),
         ("NBIT".to_owned(), "32".to_owned()),
         ("OBS_OFFSET".to_owned(), 0.to_string()),
+        ("BAND_MASK".to_owned(), weights::band_mask().to_string()),
         (
             "TSAMP".to_owned(),
             (PACKET_CADENCE * downsample_factor as f64 * 1e6).to_string(),
         ),
     ]);
+    if S::NPOL == 4 {
+        // Each spectrum is all the I channels, then Q, U, and V
+        header.insert("STATE".to_owned(), "Stokes".to_owned());
+        header.insert("POL_ORDER".to_owned(), "IQUV".to_owned());
+    }
     // Grab PSRDADA writing context
-    let mut client = DadaClient::new(key).expect("Could not connect to PSRDADA buffer");
     let (mut hc, mut dc) = client.split();
     let mut data_writer = dc.writer();
     info!("DADA header pushed, starting exfil to Heimdall");
     // Start the main consumer loop
-    // FIXME FIXME How do we timeout of grabbing a dada block?
     loop {
-        // Grab the next psrdada block we can write to (BLOCKING)
-        let mut block = data_writer.next().unwrap();
+        // If T2 isn't reading (or we're paused), don't fill up the ring (and backpressure the
+        // pipeline), just drop data on the floor until it comes back, resuming on a fresh block
+        if !t2::is_connected() || *paused.borrow() {
+            if *paused.borrow() {
+                info!("Exfil paused, holding off on DADA exfil");
+            } else {
+                warn!("T2 isn't consuming, pausing DADA exfil");
+            }
+            while !t2::is_connected() || *paused.borrow() {
+                if shutdown.try_recv().is_ok() {
+                    info!("Exfil task stopping");
+                    return Ok(());
+                }
+                match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
+                    Ok(_) => {
+                        latency::discarded(*spectrum_idx);
+                        *spectrum_idx += 1;
+                        if *paused.borrow() {
+                            metrics.dropped();
+                        } else {
+                            t2::T2_PAUSED_SPECTRA.inc();
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Closed) => return Ok(()),
+                    Err(_) => unreachable!(),
+                }
+            }
+            info!("Resuming DADA exfil");
+            ended = true;
+        }
+        // A restarted T2 will be waiting on a new transfer, whose header has a start time after
+        // the gap
+        if ended {
+            ended = false;
+            drop(data_writer);
+            data_writer = dc.writer();
+            first_payload = true;
+        }
+        // Grab the next psrdada block we can write to (BLOCKING, for as long as the reader takes)
+        waiting.store(true, Ordering::Release);
+        let waited = Instant::now();
+        let mut block = data_writer.next().ok_or(DadaError::BufferGone)?;
+        metrics.stalled(waited);
+        waiting.store(false, Ordering::Release);
         loop {
-            if shutdown.try_recv().is_ok() {
-                info!("Exfil task stopping");
-                return Ok(());
+            // Pausing (or losing T2) ends the stream, so T2 sees where the data stops rather than a
+            // jump in time
+            if *paused.borrow() || !t2::is_connected() {
+                info!(spectra = stokes_cnt, "Ending the DADA stream");
+                block.mark_eod();
+                block.commit();
+                metrics.committed();
+                stokes_cnt = 0;
+                if probe_in_window {
+                    latency::visible();
+                    probe_in_window = false;
+                }
+                ended = true;
+                break;
             }
             // Grab the next stokes parameters (already downsampled)
-            let mut stokes = stokes_rcv
-                .recv_ref()
-                .ok_or_else(|| eyre!("Channel closed"))?;
-            debug_assert_eq!(stokes.len(), CHANNELS);
+            let stokes = if shutdown.try_recv().is_ok() {
+                None
+            } else {
+                match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
+                    Ok(s) => Some(s),
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(RecvTimeoutError::Closed) => None,
+                    Err(_) => unreachable!(),
+                }
+            };
+            let Some(mut stokes) = stokes else {
+                // Close off what we have so the reader sees the stream end, rather than a torn block
+                info!(
+                    spectra = stokes_cnt,
+                    "Exfil task stopping, ending the DADA stream"
+                );
+                block.mark_eod();
+                block.commit();
+                metrics.committed();
+                return Ok(());
+            };
+            // So does capture skipping ahead, as the stream's times all follow from UTC_START
+            if skipped_ahead(&mut skipped, downsample_factor, *spectrum_idx) && !first_payload {
+                info!(
+                    spectra = stokes_cnt,
+                    "Capture skipped ahead, ending the DADA stream"
+                );
+                block.mark_eod();
+                block.commit();
+                metrics.committed();
+                stokes_cnt = 0;
+                if probe_in_window {
+                    latency::visible();
+                    probe_in_window = false;
+                }
+                // This one starts the next, but it can't until we have a new transfer
+                latency::discarded(*spectrum_idx);
+                *spectrum_idx += 1;
+                metrics.dropped();
+                ended = true;
+                break;
+            }
+            debug_assert_eq!(stokes.len(), S::NPOL * CHANNELS);
+            if latency::arrived(*spectrum_idx) {
+                probe_in_window = true;
+            }
+            for pol in stokes.chunks_mut(CHANNELS) {
+                // Zero the band edges to remove their aliasing artifacts
+                weights::band_mask().apply(pol);
+                fluxcal::apply(pol);
+            }
             // Timestamp first one
             if first_payload {
                 first_payload = false;
                 // The first payload we recieve will be payload #1 (as we armed and triggered)
-                // We'll compute the timestamp via the first payload count and the cadence
-                let first_payload_time = payload_start
-                    + (PACKET_CADENCE * FIRST_PACKET.load(Ordering::Acquire) as f64).seconds();
+                // We'll compute the timestamp via the first payload count and the cadence,
+                // accounting for any spectra we skipped while T2 wasn't listening
+                let first_payload_time =
+                    spectrum_time(&payload_start, downsample_factor, *spectrum_idx);
+                DADA_START.store(
+                    spectrum_count(downsample_factor, *spectrum_idx),
+                    Ordering::Release,
+                );
                 let timestamp_str = heimdall_timestamp(&first_payload_time);
                 header.insert("UTC_START".to_owned(), timestamp_str);
+                // Record how that start time was established
+                header.insert(
+                    "TIME_CORRECTION".to_owned(),
+                    timing::correction().to_seconds().to_string(),
+                );
+                if let Some(sync) = timing::time_sync() {
+                    header.extend(sync.header_entries());
+                }
+                if let Some(table) = fluxcal::applied_table() {
+                    header.insert("FLUX_CAL".to_owned(), table.display().to_string());
+                }
+                // Heimdall only reads one header per stream, so this is the pointing at the start
+                if let Some(p) = pointing::current() {
+                    header.extend(p.header_entries());
+                }
+                // The 8-bit levels start from the first spectrum, in the header, and each block's
+                // are recorded in a file for the stream
+                if let Some(dir) = levels {
+                    let started = Requant8::start(dir, first_payload_time, &stokes, &mut header)
+                        .map_err(DadaError::Levels)?;
+                    info!(
+                        "Requantizing DADA stream to 8 bits, recording the levels in {}",
+                        started.path.display()
+                    );
+                    requant = Some(started);
+                }
                 // Write the single header
                 // Safety: All these header keys and values are valid
-                unsafe { hc.push_header(&header).unwrap() };
+                unsafe { hc.push_header(&header)? };
             }
-            // Zero the first and last 250 sample to remove the aliasing artifacts from the edges
-            stokes[0..=250].fill(0.0);
-            stokes[1797..=2047].fill(0.0);
+            *spectrum_idx += 1;
             // Write the block
-            block.write_all(stokes.as_byte_slice()).unwrap();
+            match requant.as_mut() {
+                Some(levels) => {
+                    levels.apply(&stokes, &mut requant_buf);
+                    block.write_all(&requant_buf)?;
+                    metrics.wrote(1, requant_buf.len());
+                }
+                None => {
+                    block.write_all(stokes.as_byte_slice())?;
+                    metrics.wrote(1, stokes.len() * 4);
+                }
+            }
             // Increase our count
             stokes_cnt += 1;
             // If we've filled the window, commit it to PSRDADA
@@ -117,6 +1208,16 @@ pub fn dada_consumer(
                 stokes_cnt = 0;
                 // Commit data and update
                 block.commit();
+                metrics.committed();
+                // Heimdall can see it now
+                if probe_in_window {
+                    latency::visible();
+                    probe_in_window = false;
+                }
+                // The next block gets levels of its own
+                if let Some(requant) = requant.as_mut() {
+                    requant.next_block();
+                }
                 //Break to finish the write
                 break;
             }
@@ -124,29 +1225,355 @@ pub fn dada_consumer(
     }
 }
 
-/// Basically the same as the dada consumer, except write to a filterbank instead with no chunking
-pub fn filterbank_consumer(
-    stokes_rcv: Receiver<Stokes>,
+/// Bumped whenever someone asks for output files to be split, so every consumer writing files
+/// (there may be more than one with a tee) notices
+static SPLITS: AtomicU64 = AtomicU64::new(0);
+
+/// Ask the file-writing consumers to close their files and start new ones (e.g. at a scan boundary)
+pub fn request_split() {
+    SPLITS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// If a split has been asked for since `seen`, which is brought up to date
+fn split_requested(seen: &mut u64) -> bool {
+    let splits = SPLITS.load(Ordering::Acquire);
+    std::mem::replace(seen, splits) != splits
+}
+
+/// When the filterbank consumer starts a new file on its own, so long runs don't end up as one
+/// enormous file
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    /// Seconds of data per file
+    pub secs: Option<f64>,
+    /// Largest a file gets (in bytes) before we start another
+    pub bytes: Option<u64>,
+    /// Put each file in a `YYYY-MM-DD` subdirectory for the (UTC) day it starts
+    pub dated_dirs: bool,
+}
+
+impl Rotation {
+    /// Where a file starting at `start` goes, creating the dated subdirectory if we use them
+    fn dir(&self, path: &Path, start: Epoch) -> eyre::Result<PathBuf> {
+        if !self.dated_dirs {
+            return Ok(path.to_owned());
+        }
+        let fmt = Format::from_str("%Y-%m-%d").unwrap();
+        let dir = path.join(Formatter::in_time_scale(start, fmt, TimeScale::UTC).to_string());
+        std::fs::create_dir_all(&dir)?;
+        Ok(dir)
+    }
+}
+
+/// Create `<stem>.<extension>` in `dir`, or `<stem>-<n>.<extension>` with the first `n` that's
+/// free, never writing over a file that's already there
+pub(super) fn create_new(dir: &Path, stem: &str, extension: &str) -> eyre::Result<(PathBuf, File)> {
+    claim_new(dir, stem, extension, |path| {
+        OpenOptions::new().write(true).create_new(true).open(path)
+    })
+}
+
+/// Like [`create_new`], but making a directory
+pub(super) fn create_new_dir(dir: &Path, stem: &str, extension: &str) -> eyre::Result<PathBuf> {
+    Ok(claim_new(dir, stem, extension, std::fs::create_dir)?.0)
+}
+
+/// Make a new `<stem>.<extension>` in `dir` with `create`, which fails if it's already there,
+/// moving on to `<stem>-<n>.<extension>` until it finds one that isn't
+fn claim_new<T>(
+    dir: &Path,
+    stem: &str,
+    extension: &str,
+    create: impl Fn(&Path) -> std::io::Result<T>,
+) -> eyre::Result<(PathBuf, T)> {
+    let mut n = 0;
+    loop {
+        let path = if n == 0 {
+            dir.join(format!("{stem}.{extension}"))
+        } else {
+            dir.join(format!("{stem}-{n}.{extension}"))
+        };
+        match create(&path) {
+            Ok(created) => return Ok((path, created)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => n += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// One filterbank file (and its sidecar and weights) as it's being written
+struct FilterbankFile {
+    /// The sink writing it
+    sink: String,
+    path: PathBuf,
+    file: File,
+    fb: WriteFilterbank,
+    header_len: u64,
+    tsamp: f64,
+    packets_per_spectrum: u64,
+    /// Stokes parameters in each spectrum
+    npol: usize,
+    /// Time of the first spectrum
+    start: Epoch,
+    /// Packet count of the first spectrum
+    first_count: u64,
+    /// Number of spectra written
+    spectra: u64,
+    sidecar_path: PathBuf,
+    /// Pointing changes after the header get appended to the sidecar
+    pointing_gen: u64,
+    pointing_changes: usize,
+    weights_path: PathBuf,
+    /// Channel weights, which only ever go down over the file (a channel zapped for a while is
+    /// suspect for all of it)
+    weights_gen: u64,
+    weights: Vec<f32>,
+}
+
+impl FilterbankFile {
+    /// Start a new file for `sink` in `dir` with its first spectrum at `start` (packet
+    /// `first_count`), writing the header and companions. The filename has the sink in it too if
+    /// it isn't the only filterbank.
+    fn create(
+        sink: &str,
+        dir: &Path,
+        packets_per_spectrum: u64,
+        npol: usize,
+        start: Epoch,
+        first_count: u64,
+    ) -> eyre::Result<Self> {
+        let tsamp = PACKET_CADENCE * packets_per_spectrum as f64;
+        // Filename with ISO 8610 standard format
+        let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
+        let mut stem = format!(
+            "grex-{}",
+            Formatter::in_time_scale(start, fmt, TimeScale::UTC)
+        );
+        if sink != "filterbank" {
+            stem.push_str(&format!("_{sink}"));
+        }
+        let (path, mut file) = create_new(dir, &stem, "fil")?;
+        let mut fb = filterbank_writer(tsamp, npol);
+        fb.tstart = Some(start.to_mjd_utc_days());
+        let pointing_gen = pointing::generation();
+        let pointing = pointing::current();
+        if let Some(p) = &pointing {
+            stamp_pointing(&mut fb, p);
+        }
+        // Write out the header
+        let header = fb.header_bytes();
+        file.write_all(&header)?;
+        // SIGPROC has nowhere to put time sync provenance, so that goes in a sidecar
+        let sidecar_path = path.with_extension("hdr");
+        let weights_path = weights::companion_path(&path);
+        let mut entries = timing::time_sync()
+            .map(|sync| sync.header_entries())
+            .unwrap_or_default();
+        entries.push((
+            "TIME_CORRECTION".to_owned(),
+            timing::correction().to_seconds().to_string(),
+        ));
+        if let Some(table) = fluxcal::applied_table() {
+            entries.push(("FLUX_CAL".to_owned(), table.display().to_string()));
+        }
+        if let Some(p) = &pointing {
+            entries.extend(p.header_entries());
+        }
+        entries.push(("BAND_MASK".to_owned(), weights::band_mask().to_string()));
+        entries.push(("WEIGHTS".to_owned(), weights_path.display().to_string()));
+        write_sidecar_header(&sidecar_path, &entries)?;
+        let weights_gen = weights::generation();
+        let weights = weights::current();
+        weights::write(&weights_path, &weights)?;
+        info!("Writing filterbank to {}", path.display());
+        state::record_filterbank(
+            sink,
+            Some(OpenFilterbank {
+                path: path.clone(),
+                start,
+                header_len: header.len() as u64,
+                packets_per_spectrum,
+                npol,
+                first_count: Some(first_count),
+            }),
+        );
+        Ok(Self {
+            sink: sink.to_owned(),
+            path,
+            file,
+            fb,
+            header_len: header.len() as u64,
+            tsamp,
+            packets_per_spectrum,
+            npol,
+            start,
+            first_count,
+            spectra: 0,
+            sidecar_path,
+            pointing_gen,
+            pointing_changes: 0,
+            weights_path,
+            weights_gen,
+            weights,
+        })
+    }
+
+    /// Carry on appending to a file `sink` was writing in a crashed run, keeping its first `spectra`
+    /// (dropping any partly written one, and any past where the other filterbanks got to)
+    fn reopen(
+        sink: &str,
+        open: &OpenFilterbank,
+        first_count: u64,
+        spectra: u64,
+    ) -> eyre::Result<Self> {
+        let spectra = spectra.min(open.spectra()?);
+        let file = OpenOptions::new().append(true).open(&open.path)?;
+        file.set_len(open.header_len + spectra * (open.npol * CHANNELS * 4) as u64)?;
+        let tsamp = PACKET_CADENCE * open.packets_per_spectrum as f64;
+        let mut fb = filterbank_writer(tsamp, open.npol);
+        fb.tstart = Some(open.start.to_mjd_utc_days());
+        let sidecar_path = open.path.with_extension("hdr");
+        let pointing_changes = std::fs::read_to_string(&sidecar_path)?
+            .lines()
+            .filter(|l| l.starts_with("POINTING") && l.contains("_SAMPLE "))
+            .count();
+        let mut sidecar = OpenOptions::new().append(true).open(&sidecar_path)?;
+        writeln!(sidecar, "RESUMED_SAMPLE {spectra}")?;
+        let weights_path = weights::companion_path(&open.path);
+        let weights_gen = weights::generation();
+        let weights: Vec<_> = weights::read(&weights_path)?
+            .into_iter()
+            .zip(weights::current())
+            .map(|(old, new)| old.min(new))
+            .collect();
+        weights::write(&weights_path, &weights)?;
+        info!(spectra, "Resuming filterbank {}", open.path.display());
+        state::record_filterbank(
+            sink,
+            Some(OpenFilterbank {
+                first_count: Some(first_count),
+                ..open.clone()
+            }),
+        );
+        Ok(Self {
+            sink: sink.to_owned(),
+            path: open.path.clone(),
+            file,
+            fb,
+            header_len: open.header_len,
+            tsamp,
+            packets_per_spectrum: open.packets_per_spectrum,
+            npol: open.npol,
+            start: open.start,
+            first_count,
+            spectra,
+            sidecar_path,
+            // Anything since will show up as a change on the first spectrum
+            pointing_gen: 0,
+            pointing_changes,
+            weights_path,
+            weights_gen,
+            weights,
+        })
+    }
+
+    /// Time of the next spectrum we'll write
+    fn end(&self) -> Epoch {
+        epoch_after(&self.start, (self.tsamp * self.spectra as f64).seconds())
+    }
+
+    /// Packet count of the next spectrum we'll write
+    fn end_count(&self) -> u64 {
+        self.first_count + self.spectra * self.packets_per_spectrum
+    }
+
+    /// If this file has grown as long or as large as `rotation` allows
+    fn due_for_rotation(&self, rotation: &Rotation) -> bool {
+        let bytes = self.header_len + self.spectra * (self.npol * CHANNELS * 4) as u64;
+        rotation
+            .secs
+            .is_some_and(|secs| self.tsamp * self.spectra as f64 >= secs)
+            || rotation.bytes.is_some_and(|max| bytes >= max)
+    }
+
+    /// Write the next spectrum, noting any pointing or weight changes since the last one
+    fn write(&mut self, spectrum: &[f32]) -> eyre::Result<()> {
+        if pointing::generation() != self.pointing_gen {
+            // We moved mid-file, note where (by spectrum index) in the sidecar
+            self.pointing_gen = pointing::generation();
+            if let Some(p) = pointing::current() {
+                self.pointing_changes += 1;
+                let n = self.pointing_changes;
+                let mut sidecar = OpenOptions::new().append(true).open(&self.sidecar_path)?;
+                writeln!(sidecar, "POINTING{n}_SAMPLE {}", self.spectra)?;
+                for (k, v) in p.header_entries() {
+                    writeln!(sidecar, "POINTING{n}_{k} {v}")?;
+                }
+            }
+        }
+        if weights::generation() != self.weights_gen {
+            self.weights_gen = weights::generation();
+            for (w, new) in self.weights.iter_mut().zip(weights::current()) {
+                *w = w.min(new);
+            }
+            if let Err(e) = weights::write(&self.weights_path, &self.weights) {
+                warn!("Failed to update the filterbank's channel weights - {e}");
+            }
+        }
+        self.file.write_all(&self.fb.pack(spectrum))?;
+        self.spectra += 1;
+        Ok(())
+    }
+
+    /// Close out the file, recording it in the catalog
+    fn finish(self) {
+        state::record_filterbank(&self.sink, None);
+        catalog::record(
+            catalog::Product::Filterbank,
+            Some(&self.path),
+            self.start,
+            self.end(),
+            json!({
+                "nchans": CHANNELS,
+                "nifs": self.npol,
+                "tsamp": self.tsamp,
+                "spectra": self.spectra,
+                "sidecar": self.sidecar_path,
+                "weights": self.weights_path,
+                "flux_cal": fluxcal::applied_table(),
+            }),
+        );
+    }
+}
+
+/// Basically the same as the dada consumer, except write to a filterbank instead with no chunking.
+/// The file is split whenever [`request_split`] is called, or `rotation` says it's time. Files are
+/// named, and picked back up by `--resume`, by the `sink` writing them.
+pub fn filterbank_consumer<S: Spectrum>(
+    sink: &str,
+    stokes_rcv: Receiver<S>,
     payload_start: Epoch,
     downsample_factor: usize,
     path: &Path,
+    rotation: Rotation,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     info!("Starting filterbank consumer");
-    // Filename with ISO 8610 standard format
-    let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
-    let filename = format!("grex-{}.fil", Formatter::new(Epoch::now()?, fmt));
-    let file_path = path.join(filename);
-    // Create the file
-    let mut file = File::create(file_path)?;
-    // Create the filterbank context
-    let mut fb = WriteFilterbank::new(CHANNELS, 1);
-    // Setup the header stuff
-    fb.fch1 = Some(HIGHBAND_MID_FREQ); // End of band + half the step size
-    fb.foff = Some(-(BANDWIDTH / CHANNELS as f64));
-    fb.tsamp = Some(PACKET_CADENCE * downsample_factor as f64);
-    // We will capture the timestamp on the first packet
-    let mut first_payload = true;
+    let packets_per_spectrum = downsample_factor as u64;
+    let metrics = ExfilMetrics::new("filterbank");
+    let paused = PAUSED.subscribe();
+    let mut in_idx = 0u64;
+    // We will open the file (and capture the timestamp) on the first spectrum
+    let mut current: Option<FilterbankFile> = None;
+    // The file we were writing before we crashed, to pick up with the first spectrum
+    let mut resume = state::resumed()
+        .and_then(|s| Some((s.filterbanks.get(sink)?, s)))
+        .filter(|(fb, _)| fb.packets_per_spectrum == packets_per_spectrum && fb.npol == S::NPOL)
+        .map(|(fb, s)| (fb, s, s.spectra_to_keep(fb)));
+    // Packets capture had skipped over as of the last spectrum
+    let mut skipped = None;
+    // Anything from before we started doesn't count
+    let mut splits = SPLITS.load(Ordering::Acquire);
     loop {
         if shutdown.try_recv().is_ok() {
             info!("Exfil task stopping");
@@ -154,23 +1581,196 @@ pub fn filterbank_consumer(
         }
         // Grab next stokes
         match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
-            Ok(stokes) => {
-                // Timestamp first one
-                if first_payload {
-                    first_payload = false;
-                    let first_payload_time = payload_start
-                        + (PACKET_CADENCE * FIRST_PACKET.load(Ordering::Acquire) as f64).seconds();
-                    fb.tstart = Some(first_payload_time.to_mjd_utc_days());
-                    // Write out the header
-                    file.write_all(&fb.header_bytes()).unwrap();
+            Ok(mut stokes) => {
+                if *paused.borrow() {
+                    // Close off the file, starting a new one once we resume
+                    if let Some(done) = current.take() {
+                        done.finish();
+                    }
+                    latency::discarded(in_idx);
+                    in_idx += 1;
+                    metrics.dropped();
+                    continue;
                 }
+                // Each spectrum is written as it comes, so there's never a probe left waiting
+                close_on_skip(
+                    "filterbank",
+                    &mut current,
+                    &mut skipped,
+                    downsample_factor,
+                    in_idx,
+                    &mut false,
+                    |done| {
+                        done.finish();
+                        Ok(true)
+                    },
+                )?;
+                // Only carry on with the old file if we picked up right where it left off
+                let next_count = spectrum_count(downsample_factor, in_idx);
+                let fbf = match current.as_mut() {
+                    Some(fbf) => fbf,
+                    // Pick up the file we were writing before we crashed
+                    None => match resume
+                        .take()
+                        .filter(|(_, s, _)| s.next_count() == next_count)
+                    {
+                        Some((open, s, spectra)) => current.insert(FilterbankFile::reopen(
+                            sink,
+                            open,
+                            s.first_count(open),
+                            spectra,
+                        )?),
+                        None => {
+                            // Timestamp first one
+                            let start = spectrum_time(&payload_start, downsample_factor, in_idx);
+                            info!(
+                                "Applying a timestamp correction of {} to the filterbank",
+                                timing::correction()
+                            );
+                            current.insert(FilterbankFile::create(
+                                sink,
+                                &rotation.dir(path, start)?,
+                                packets_per_spectrum,
+                                S::NPOL,
+                                start,
+                                next_count,
+                            )?)
+                        }
+                    },
+                };
+                for pol in stokes.chunks_mut(CHANNELS) {
+                    weights::band_mask().apply(pol);
+                    fluxcal::apply(pol);
+                }
+                let probe = latency::arrived(in_idx);
+                in_idx += 1;
                 // Stream to FB
-                file.write_all(&fb.pack(&stokes))?;
+                let started = Instant::now();
+                fbf.write(&stokes)?;
+                metrics.stalled(started);
+                metrics.wrote(1, stokes.len() * 4);
+                if probe {
+                    latency::visible();
+                }
+                // Only split between spectra, picking up right where we left off
+                let split = split_requested(&mut splits);
+                let rotate = fbf.due_for_rotation(&rotation);
+                if split || rotate {
+                    if rotate {
+                        info!("Rotating filterbank {}", fbf.path.display());
+                    }
+                    let (next, next_count) = (fbf.end(), fbf.end_count());
+                    if let Some(done) = current.take() {
+                        done.finish();
+                    }
+                    current = Some(FilterbankFile::create(
+                        sink,
+                        &rotation.dir(path, next)?,
+                        packets_per_spectrum,
+                        S::NPOL,
+                        next,
+                        next_count,
+                    )?);
+                }
             }
             Err(RecvTimeoutError::Timeout) => continue,
             Err(RecvTimeoutError::Closed) => break,
             Err(_) => unreachable!(),
         }
     }
+    if let Some(fbf) = current {
+        fbf.finish();
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heimdall_timestamp_is_utc() {
+        // 12 hours and 20 s after noon, over the leap second at the end of 2016
+        let start = Epoch::from_gregorian_utc_at_noon(2016, 12, 31);
+        let after = epoch_after(&start, Unit::Hour * 12 + Unit::Second * 20);
+        assert_eq!(heimdall_timestamp(&after), "2017-01-01-00:00:19");
+        // TAI is 37 s ahead by then, and heimdall mustn't see it
+        let tai = after.in_time_scale(TimeScale::TAI);
+        assert_eq!(heimdall_timestamp(&tai), "2017-01-01-00:00:19");
+    }
+
+    #[test]
+    fn levels_fit_each_channel_and_undo() {
+        // Two channels around very different levels, the second with some NaN fill
+        let spectra: Vec<[f32; 2]> = (0..100)
+            .map(|i| {
+                let wiggle = if i % 2 == 0 { 1.0 } else { -1.0 };
+                let fill = if i % 10 < 2 {
+                    f32::NAN
+                } else {
+                    -500.0 + 20.0 * wiggle
+                };
+                [1e4 + 100.0 * wiggle, fill]
+            })
+            .collect();
+        let mut fit = LevelFit::new(2);
+        spectra.iter().for_each(|s| fit.add(s));
+        let mut levels = Levels::uniform(2, 0.0, 0.0);
+        fit.update(&mut levels);
+        assert_eq!(levels.scales, [100.0 / DIGI_SIGMA, 20.0 / DIGI_SIGMA]);
+        let mut out = [0u8; 2];
+        for s in &spectra {
+            assert_eq!(levels.quantize(s, &mut out), 0);
+            for ((&x, &o), (scale, offset)) in s
+                .iter()
+                .zip(&out)
+                .zip(levels.scales.iter().zip(&levels.offsets))
+            {
+                if x.is_nan() {
+                    assert_eq!(o, DIGI_MEAN as u8);
+                } else {
+                    assert!((f32::from(o) * scale + offset - x).abs() <= *scale);
+                }
+            }
+        }
+        // A channel that was all fill keeps the levels it had
+        fit.add(&[0.0, f32::NAN]);
+        let before = levels.offsets[1];
+        fit.update(&mut levels);
+        assert_eq!(levels.offsets[1], before);
+        assert_eq!(levels.offsets[0], -DIGI_MEAN);
+    }
+
+    #[test]
+    fn dada_levels_read_back() {
+        let dir = std::env::temp_dir().join(format!("grex_t0-levels-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let start = Epoch::from_gregorian_utc_at_midnight(2024, 1, 1);
+        let stokes: Vec<_> = (0..CHANNELS).map(|c| c as f32).collect();
+        let mut header = HashMap::from([("NCHAN".to_owned(), CHANNELS.to_string())]);
+        let mut requant = Requant8::start(&dir, start, &stokes, &mut header).unwrap();
+        let mut out = vec![0u8; CHANNELS];
+        requant.apply(&stokes, &mut out);
+        let first = requant.levels.clone();
+        requant.next_block();
+        // And the start of a block that never got finished
+        write!(requant.file, "BLOCK 2\nDAT_SCL 1 2").unwrap();
+        requant.file.flush().unwrap();
+        let read = DadaLevels::read(&requant.path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(read.header, header);
+        assert_eq!(read.header["DIGI_SCALE"], first.scales[0].to_string());
+        assert_eq!(read.blocks, [(0, first), (1, requant.levels.clone())]);
+        // Which undo the requantization to within a level
+        let mut undone = vec![0.0; CHANNELS];
+        read.blocks[0].1.unquantize(&out, &mut undone);
+        let scale = read.blocks[0].1.scales[0];
+        for (x, y) in stokes.iter().zip(&undone) {
+            // Clipped at the top and bottom of the range
+            if (1.0..255.0).contains(&((y - read.blocks[0].1.offsets[0]) / scale)) {
+                assert!((x - y).abs() <= scale, "{x} came back as {y}");
+            }
+        }
+    }
+}
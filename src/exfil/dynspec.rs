@@ -0,0 +1,469 @@
+//! The downsampled dynamic spectrum as chunked HDF5 or Zarr, for the ML training pipeline
+//!
+//! Either way a file holds three arrays: `spectra` (time, pol, freq), `time` (MJD UTC of the start
+//! of each spectrum), and `freq` (the centre of each channel in MHz), with the dimension names each
+//! runs along and attributes describing the observation. The spectra are written a chunk of `chunk`
+//! spectra at a time, and the lengths of `spectra` and `time` kept up to date as they go, so a file
+//! can be read while it grows. Zarr is written as a version 2 directory store without compression,
+//! with `_ARRAY_DIMENSIONS` on each array so xarray picks up the coordinates.
+use super::{
+    close_on_skip, create_new, create_new_dir, spectrum_time, split_requested, ExfilMetrics,
+    PAUSED, SPLITS,
+};
+use crate::{
+    catalog,
+    common::{epoch_after, Spectrum, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE},
+    dumps::h5,
+    fluxcal, latency, observation, pointing, weights,
+};
+use clap::ValueEnum;
+use eyre::bail;
+use hdf5::{Dataset, Location};
+use hifitime::prelude::*;
+use ndarray::{s, ArrayView1, ArrayView3};
+use serde_json::{json, Value};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::Ordering,
+    time::Instant,
+};
+use thingbuf::mpsc::blocking::Receiver;
+use thingbuf::mpsc::errors::RecvTimeoutError;
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// File format of the dynamic spectra
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DynspecFormat {
+    /// A single HDF5 file
+    #[default]
+    Hdf5,
+    /// A Zarr (v2) directory store
+    Zarr,
+}
+
+impl DynspecFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            DynspecFormat::Hdf5 => "h5",
+            DynspecFormat::Zarr => "zarr",
+        }
+    }
+}
+
+/// Units of the `time` coordinate, which CF-aware readers turn into datetimes
+const TIME_UNITS: &str = "days since 1858-11-17 00:00:00";
+
+/// Set attribute `name` on `loc` from its JSON equivalent
+fn h5_attribute(loc: &Location, name: &str, value: &Value) -> eyre::Result<()> {
+    match value {
+        Value::String(v) => h5::scalar(loc, name, &h5::text(v)?),
+        Value::Number(v) => match (v.as_u64(), v.as_i64(), v.as_f64()) {
+            (Some(v), _, _) => h5::scalar(loc, name, &v),
+            (_, Some(v), _) => h5::scalar(loc, name, &v),
+            (_, _, Some(v)) => h5::scalar(loc, name, &v),
+            _ => unreachable!(),
+        },
+        other => bail!("No HDF5 equivalent for attribute {name} ({other})"),
+    }
+}
+
+/// Write `value` to `path` as JSON, to the side and moved over so a reader never sees half of it
+fn write_json(path: &Path, value: &Value) -> eyre::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Metadata of a Zarr array of little-endian `dtype` with this `shape`, chunked by `chunks`
+fn zarray(shape: &[usize], chunks: &[usize], dtype: &str) -> Value {
+    json!({
+        "zarr_format": 2,
+        "shape": shape,
+        "chunks": chunks,
+        "dtype": dtype,
+        "compressor": null,
+        "fill_value": 0.0,
+        "order": "C",
+        "filters": null,
+        "dimension_separator": ".",
+    })
+}
+
+/// Where the arrays live
+enum Store {
+    Hdf5 {
+        file: hdf5::File,
+        spectra: Dataset,
+        time: Dataset,
+    },
+    Zarr,
+}
+
+/// The dynamic spectrum file we're writing
+struct DynspecFile {
+    path: PathBuf,
+    store: Store,
+    npol: usize,
+    chunk: usize,
+    tsamp: f64,
+    start: Epoch,
+    /// Spectra written so far
+    written: usize,
+}
+
+impl DynspecFile {
+    /// Start a new file in `dir` with its first spectrum at `start`, with the coordinates and
+    /// attributes but no spectra yet
+    fn create(
+        dir: &Path,
+        format: DynspecFormat,
+        tsamp: f64,
+        npol: usize,
+        chunk: usize,
+        start: Epoch,
+    ) -> eyre::Result<Self> {
+        let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
+        let stem = format!(
+            "grex-{}",
+            Formatter::in_time_scale(start, fmt, TimeScale::UTC)
+        );
+        let path = match format {
+            // HDF5 creates the file itself, so claim the name with an empty one for it to go over
+            DynspecFormat::Hdf5 => create_new(dir, &stem, format.extension())?.0,
+            DynspecFormat::Zarr => create_new_dir(dir, &stem, format.extension())?,
+        };
+        let obs = observation::current();
+        let freqs: Vec<f64> = (0..CHANNELS).map(|chan| obs.freq(chan)).collect();
+        let attributes = Self::attributes(npol, tsamp, start);
+        let store = match format {
+            DynspecFormat::Hdf5 => {
+                let file = hdf5::File::create(&path)?;
+                if let Value::Object(attrs) = &attributes {
+                    for (name, value) in attrs {
+                        h5_attribute(&file, name, value)?;
+                    }
+                }
+                let spectra = file
+                    .new_dataset::<f32>()
+                    .shape((0.., npol, CHANNELS))
+                    .chunk([chunk, npol, CHANNELS])
+                    .create("spectra")?;
+                h5::describe(&spectra, "Stokes Parameters", "time pol freq")?;
+                let time = file
+                    .new_dataset::<f64>()
+                    .shape(0..)
+                    .chunk([chunk])
+                    .create("time")?;
+                h5::describe(&time, "Modified Julian Date (UTC)", "time")?;
+                h5::scalar(&time, "units", &h5::text(TIME_UNITS)?)?;
+                let freq = file
+                    .new_dataset_builder()
+                    .with_data(&freqs)
+                    .create("freq")?;
+                h5::describe(&freq, "Frequency", "freq")?;
+                h5::scalar(&freq, "units", &h5::text("MHz")?)?;
+                file.flush()?;
+                Store::Hdf5 {
+                    file,
+                    spectra,
+                    time,
+                }
+            }
+            DynspecFormat::Zarr => {
+                for array in ["spectra", "time", "freq"] {
+                    std::fs::create_dir(path.join(array))?;
+                }
+                write_json(&path.join(".zgroup"), &json!({ "zarr_format": 2 }))?;
+                write_json(&path.join(".zattrs"), &attributes)?;
+                write_json(
+                    &path.join("freq/.zattrs"),
+                    &json!({
+                        "_ARRAY_DIMENSIONS": ["freq"],
+                        "long_name": "Frequency",
+                        "units": "MHz",
+                    }),
+                )?;
+                write_json(
+                    &path.join("freq/.zarray"),
+                    &zarray(&[CHANNELS], &[CHANNELS], "<f8"),
+                )?;
+                let bytes: Vec<u8> = freqs.iter().flat_map(|f| f.to_le_bytes()).collect();
+                std::fs::write(path.join("freq/0"), bytes)?;
+                write_json(
+                    &path.join("time/.zattrs"),
+                    &json!({
+                        "_ARRAY_DIMENSIONS": ["time"],
+                        "long_name": "Modified Julian Date (UTC)",
+                        "units": TIME_UNITS,
+                    }),
+                )?;
+                write_json(
+                    &path.join("spectra/.zattrs"),
+                    &json!({
+                        "_ARRAY_DIMENSIONS": ["time", "pol", "freq"],
+                        "long_name": "Stokes Parameters",
+                    }),
+                )?;
+                Store::Zarr
+            }
+        };
+        info!("Writing dynamic spectra to {}", path.display());
+        let mut file = Self {
+            path,
+            store,
+            npol,
+            chunk,
+            tsamp,
+            start,
+            written: 0,
+        };
+        file.resize()?;
+        Ok(file)
+    }
+
+    /// Attributes describing the observation, for the root of the file
+    fn attributes(npol: usize, tsamp: f64, start: Epoch) -> Value {
+        let obs = observation::current();
+        let pointing = pointing::current().unwrap_or_default();
+        let mut attrs = json!({
+            "software_version": env!("CARGO_PKG_VERSION"),
+            "telescope": obs.telescope,
+            "start_mjd": start.to_mjd_utc_days(),
+            "tsamp": tsamp,
+            "stokes": if npol == 4 { "IQUV" } else { "I" },
+            "band_mask": weights::band_mask().to_string(),
+            "units": if fluxcal::applied_table().is_some() { "Jy" } else { "Arbitrary" },
+        });
+        if let Some(source) = &pointing.source {
+            attrs["source"] = json!(source);
+        }
+        if let Some(ra) = pointing.ra {
+            attrs["ra"] = json!(ra);
+        }
+        if let Some(dec) = pointing.dec {
+            attrs["dec"] = json!(dec);
+        }
+        attrs
+    }
+
+    /// Time of the next spectrum we'll write
+    fn end(&self) -> Epoch {
+        epoch_after(&self.start, (self.tsamp * self.written as f64).seconds())
+    }
+
+    /// Bring the recorded lengths of `spectra` and `time` up to what we've written
+    fn resize(&mut self) -> eyre::Result<()> {
+        match &self.store {
+            Store::Hdf5 {
+                file,
+                spectra,
+                time,
+            } => {
+                spectra.resize((self.written, self.npol, CHANNELS))?;
+                time.resize(self.written)?;
+                file.flush()?;
+            }
+            Store::Zarr => {
+                write_json(
+                    &self.path.join("spectra/.zarray"),
+                    &zarray(
+                        &[self.written, self.npol, CHANNELS],
+                        &[self.chunk, self.npol, CHANNELS],
+                        "<f4",
+                    ),
+                )?;
+                write_json(
+                    &self.path.join("time/.zarray"),
+                    &zarray(&[self.written], &[self.chunk], "<f8"),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a chunk of spectra, each all the channels of each Stokes parameter in turn, returning
+    /// how many spectra that was. Only the last chunk of a file may be short.
+    fn write_chunk(&mut self, block: &[f32]) -> eyre::Result<usize> {
+        let n = block.len() / (self.npol * CHANNELS);
+        let start = self.end();
+        let times: Vec<f64> = (0..n)
+            .map(|i| epoch_after(&start, (self.tsamp * i as f64).seconds()).to_mjd_utc_days())
+            .collect();
+        let range = self.written..self.written + n;
+        self.written += n;
+        match &self.store {
+            Store::Hdf5 { spectra, time, .. } => {
+                spectra.resize((self.written, self.npol, CHANNELS))?;
+                time.resize(self.written)?;
+                let block = ArrayView3::from_shape((n, self.npol, CHANNELS), block)?;
+                spectra.write_slice(block, s![range.clone(), .., ..])?;
+                time.write_slice(ArrayView1::from(&times), s![range])?;
+            }
+            Store::Zarr => {
+                // Chunks are always whole in Zarr, so a short one is padded out with the fill value
+                let idx = range.start / self.chunk;
+                let mut bytes: Vec<u8> = block.iter().flat_map(|x| x.to_le_bytes()).collect();
+                bytes.resize(self.chunk * self.npol * CHANNELS * 4, 0);
+                std::fs::write(self.path.join(format!("spectra/{idx}.0.0")), bytes)?;
+                let mut bytes: Vec<u8> = times.iter().flat_map(|t| t.to_le_bytes()).collect();
+                bytes.resize(self.chunk * 8, 0);
+                std::fs::write(self.path.join(format!("time/{idx}")), bytes)?;
+            }
+        }
+        self.resize()?;
+        Ok(n)
+    }
+
+    /// Close out the file, recording it in the catalog
+    fn finish(self) {
+        catalog::record(
+            catalog::Product::Dynspec,
+            Some(&self.path),
+            self.start,
+            self.end(),
+            json!({
+                "nchan": CHANNELS,
+                "npol": self.npol,
+                "tsamp": self.tsamp,
+                "chunk": self.chunk,
+                "spectra": self.written,
+                "flux_cal": fluxcal::applied_table(),
+            }),
+        );
+    }
+}
+
+/// Like the filterbank consumer, but writing the dynamic spectrum as chunked HDF5 or Zarr, a chunk
+/// of `chunk` spectra at a time. The file is split (between chunks) whenever
+/// [`super::request_split`] is called.
+pub fn dynspec_consumer<S: Spectrum>(
+    stokes_rcv: Receiver<S>,
+    payload_start: Epoch,
+    downsample_factor: usize,
+    format: DynspecFormat,
+    chunk: usize,
+    path: &Path,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting dynamic spectrum consumer");
+    let tsamp = PACKET_CADENCE * downsample_factor as f64;
+    let chunk_len = chunk * S::NPOL * CHANNELS;
+    let mut block = Vec::with_capacity(chunk_len);
+    let metrics = ExfilMetrics::new("dynspec");
+    let paused = PAUSED.subscribe();
+    // Write a chunk, counting it in the metrics
+    let write = |df: &mut DynspecFile, block: &[f32]| -> eyre::Result<()> {
+        let started = Instant::now();
+        let n = df.write_chunk(block)?;
+        metrics.stalled(started);
+        // Each spectrum has its timestamp alongside
+        metrics.wrote(n, block.len() * 4 + n * 8);
+        metrics.committed();
+        Ok(())
+    };
+    // Index of the incoming spectrum, and if a latency probe is in the chunk we're filling
+    let mut in_idx = 0u64;
+    let mut probe = false;
+    // We will open the file (and capture the timestamp) on the first spectrum
+    let mut current: Option<DynspecFile> = None;
+    // Packets capture had skipped over as of the last spectrum
+    let mut skipped = None;
+    // Anything from before we started doesn't count
+    let mut splits = SPLITS.load(Ordering::Acquire);
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Exfil task stopping");
+            break;
+        }
+        match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(mut stokes) => {
+                if *paused.borrow() {
+                    // Close off the file, starting a new one once we resume
+                    if let Some(mut done) = current.take() {
+                        if !block.is_empty() {
+                            write(&mut done, &block)?;
+                            block.clear();
+                        }
+                        done.finish();
+                    }
+                    probe = false;
+                    latency::discarded(in_idx);
+                    in_idx += 1;
+                    metrics.dropped();
+                    continue;
+                }
+                // Chunks can be short, so what we have goes in the old file
+                close_on_skip(
+                    "dynamic spectrum file",
+                    &mut current,
+                    &mut skipped,
+                    downsample_factor,
+                    in_idx,
+                    &mut probe,
+                    |mut done| {
+                        if !block.is_empty() {
+                            write(&mut done, &block)?;
+                            block.clear();
+                        }
+                        done.finish();
+                        Ok(true)
+                    },
+                )?;
+                let df = match current.as_mut() {
+                    Some(df) => df,
+                    None => current.insert(DynspecFile::create(
+                        path,
+                        format,
+                        tsamp,
+                        S::NPOL,
+                        chunk,
+                        spectrum_time(&payload_start, downsample_factor, in_idx),
+                    )?),
+                };
+                for pol in stokes.chunks_mut(CHANNELS) {
+                    weights::band_mask().apply(pol);
+                    fluxcal::apply(pol);
+                }
+                probe |= latency::arrived(in_idx);
+                in_idx += 1;
+                block.extend_from_slice(&stokes);
+                if block.len() < chunk_len {
+                    continue;
+                }
+                write(df, &block)?;
+                block.clear();
+                if probe {
+                    latency::visible();
+                    probe = false;
+                }
+                if split_requested(&mut splits) {
+                    let next = df.end();
+                    if let Some(done) = current.take() {
+                        done.finish();
+                    }
+                    current = Some(DynspecFile::create(
+                        path,
+                        format,
+                        tsamp,
+                        S::NPOL,
+                        chunk,
+                        next,
+                    )?);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        }
+    }
+    if let Some(mut df) = current {
+        if !block.is_empty() {
+            write(&mut df, &block)?;
+        }
+        df.finish();
+    }
+    Ok(())
+}
@@ -0,0 +1,100 @@
+//! Multicasting the occasional spectrum over UDP, for quick-look displays in the control room
+//!
+//! Every `every`th spectrum goes out as a single datagram to the multicast group, in the same frame
+//! as the stream exfil, so a display can plot the live band by joining the group without going
+//! near PSRDADA or the metrics endpoint. Nothing waits on the displays, and there's no telling if
+//! anyone's listening.
+use super::{spectrum_time, stream::frame, ExfilMetrics, PAUSED};
+use crate::{
+    common::{Spectrum, BLOCK_TIMEOUT},
+    latency,
+};
+use hifitime::prelude::*;
+use socket2::{Domain, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use thingbuf::mpsc::blocking::Receiver;
+use thingbuf::mpsc::errors::RecvTimeoutError;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Where and how often to multicast spectra
+#[derive(Debug, Clone, Copy)]
+pub struct MulticastConfig {
+    pub group: Ipv4Addr,
+    pub port: u16,
+    /// Send one spectrum in this many
+    pub every: u32,
+    /// Hops the datagrams may take (1 keeps them on the local network)
+    pub ttl: u32,
+    /// Address of the interface to send from, the default route's otherwise
+    pub interface: Option<Ipv4Addr>,
+}
+
+fn socket(config: &MulticastConfig) -> eyre::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+    socket.set_multicast_ttl_v4(config.ttl)?;
+    if let Some(interface) = &config.interface {
+        socket.set_multicast_if_v4(interface)?;
+    }
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+    Ok(socket.into())
+}
+
+/// Multicast every `config.every`th spectrum from `stokes_rcv`
+pub fn multicast_consumer<S: Spectrum>(
+    stokes_rcv: Receiver<S>,
+    payload_start: Epoch,
+    downsample_factor: usize,
+    config: MulticastConfig,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting multicast consumer");
+    let sock = socket(&config)?;
+    let dest = SocketAddr::from((config.group, config.port));
+    info!(%dest, every = config.every, "Multicasting spectra");
+    let metrics = ExfilMetrics::new("multicast");
+    let paused = PAUSED.subscribe();
+    let mut idx = 0u64;
+    // Spectra until the next one we send
+    let mut skip = 0;
+    // So a missing route doesn't fill the logs
+    let mut failing = false;
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Exfil task stopping");
+            break;
+        }
+        let stokes = match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(s) => s,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        };
+        let probe = latency::arrived(idx);
+        if *paused.borrow() {
+            metrics.dropped();
+        } else if skip == 0 {
+            skip = config.every;
+            let time = spectrum_time(&payload_start, downsample_factor, idx);
+            let datagram = frame(idx, time, S::NPOL, &stokes);
+            match sock.send_to(&datagram, dest) {
+                Ok(_) => {
+                    metrics.wrote(1, datagram.len());
+                    failing = false;
+                }
+                Err(e) => {
+                    if !failing {
+                        warn!(%dest, "Couldn't multicast a spectrum - {e}");
+                    }
+                    failing = true;
+                }
+            }
+        }
+        if probe {
+            latency::visible();
+        }
+        skip -= 1;
+        idx += 1;
+    }
+    Ok(())
+}
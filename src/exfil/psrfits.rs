@@ -0,0 +1,502 @@
+//! Search-mode PSRFITS, for downstream tools (like PRESTO) that would rather not read filterbanks
+//!
+//! Each file is a primary HDU describing the observation followed by a SUBINT binary table, with a
+//! row for every `nsblk` spectra. Samples are stored as 8 bits, scaled per channel (and Stokes
+//! parameter) in each row by `DAT_SCL` and `DAT_OFFS`, alongside the channel weights at the time
+//! in `DAT_WTS`. The row count is kept up to date as rows are written, so the file can be read
+//! while it grows.
+use super::{
+    close_on_skip, create_new, spectrum_time, split_requested, ExfilMetrics, LevelFit, Levels,
+    PAUSED, SPLITS,
+};
+use crate::{
+    catalog,
+    common::{epoch_after, Spectrum, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE},
+    fluxcal, latency, observation, pointing, weights,
+};
+use hifitime::prelude::*;
+use serde_json::json;
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::atomic::Ordering,
+    time::Instant,
+};
+use thingbuf::mpsc::blocking::Receiver;
+use thingbuf::mpsc::errors::RecvTimeoutError;
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// FITS files are made of blocks of this many bytes
+const BLOCK: usize = 2880;
+/// Length of a header card
+const CARD: usize = 80;
+
+/// A FITS header, built up a card at a time
+#[derive(Default)]
+struct Header(Vec<u8>);
+
+impl Header {
+    /// Add a card with an already formatted `value`
+    fn card(&mut self, key: &str, value: &str, comment: &str) {
+        let mut card = format!("{key:<8}= {value}");
+        if !comment.is_empty() {
+            card.push_str(" / ");
+            card.push_str(comment);
+        }
+        let mut bytes = card.into_bytes();
+        bytes.resize(CARD, b' ');
+        self.0.extend(bytes);
+    }
+
+    fn logical(&mut self, key: &str, value: bool, comment: &str) {
+        self.card(
+            key,
+            &format!("{:>20}", if value { "T" } else { "F" }),
+            comment,
+        );
+    }
+
+    fn int(&mut self, key: &str, value: impl Display, comment: &str) {
+        self.card(key, &format!("{value:>20}"), comment);
+    }
+
+    fn float(&mut self, key: &str, value: f64, comment: &str) {
+        let mut value = value.to_string();
+        if !value.contains('.') {
+            value.push_str(".0");
+        }
+        self.card(key, &format!("{value:>20}"), comment);
+    }
+
+    fn string(&mut self, key: &str, value: &str, comment: &str) {
+        // Headers are printable ASCII, with quotes doubled, and strings padded out to 8 characters
+        let value: String = value
+            .chars()
+            .map(|c| {
+                if c.is_ascii_graphic() || c == ' ' {
+                    c
+                } else {
+                    '?'
+                }
+            })
+            .take(60)
+            .collect();
+        let value = value.replace('\'', "''");
+        self.card(key, &format!("'{value:<8}'"), comment);
+    }
+
+    /// Finish off the header, padded out to a whole block
+    fn end(mut self) -> Vec<u8> {
+        let mut end = b"END".to_vec();
+        end.resize(CARD, b' ');
+        self.0.extend(end);
+        let len = self.0.len().next_multiple_of(BLOCK);
+        self.0.resize(len, b' ');
+        self.0
+    }
+}
+
+/// One column of the SUBINT table, `count` values of FITS type `code`
+struct Column {
+    name: &'static str,
+    count: usize,
+    code: char,
+    unit: &'static str,
+}
+
+impl Column {
+    fn width(&self) -> usize {
+        self.count
+            * match self.code {
+                'D' => 8,
+                'E' => 4,
+                _ => 1,
+            }
+    }
+}
+
+/// Centre frequency (MHz) of every channel, in the order we write them
+fn freqs() -> Vec<f64> {
+    let obs = observation::current();
+    (0..CHANNELS).map(|chan| obs.freq(chan)).collect()
+}
+
+/// Map a row's worth of spectra onto 8 bits with each channel's levels fit over the row, undone
+/// with `x = u8 * DAT_SCL + DAT_OFFS`. NaN fill is left out of the levels, and goes to the mean.
+fn requantize(block: &[f32], width: usize) -> (Vec<u8>, Levels) {
+    let mut fit = LevelFit::new(width);
+    block
+        .chunks_exact(width)
+        .for_each(|spectrum| fit.add(spectrum));
+    let mut levels = Levels::uniform(width, 0.0, 0.0);
+    fit.update(&mut levels);
+    let mut data = vec![0u8; block.len()];
+    for (spectrum, out) in block.chunks_exact(width).zip(data.chunks_exact_mut(width)) {
+        levels.quantize(spectrum, out);
+    }
+    (data, levels)
+}
+
+/// One PSRFITS file as it's being written
+struct PsrfitsFile {
+    path: PathBuf,
+    file: File,
+    npol: usize,
+    nsblk: usize,
+    tbin: f64,
+    /// Time of the first spectrum
+    start: Epoch,
+    /// Number of rows written
+    rows: u64,
+    /// Where the SUBINT table's NAXIS2 (row count) card is
+    naxis2_at: u64,
+    /// End of the last row, before the padding out to a whole block
+    data_end: u64,
+}
+
+impl PsrfitsFile {
+    fn columns(npol: usize, nsblk: usize) -> Vec<Column> {
+        let column = |name, count, code, unit| Column {
+            name,
+            count,
+            code,
+            unit,
+        };
+        vec![
+            column("TSUBINT", 1, 'D', "s"),
+            column("OFFS_SUB", 1, 'D', "s"),
+            column("RA_SUB", 1, 'D', "deg"),
+            column("DEC_SUB", 1, 'D', "deg"),
+            column("TEL_AZ", 1, 'E', "deg"),
+            column("TEL_ZEN", 1, 'E', "deg"),
+            column("DAT_FREQ", CHANNELS, 'D', "MHz"),
+            column("DAT_WTS", CHANNELS, 'E', ""),
+            column("DAT_OFFS", CHANNELS * npol, 'E', ""),
+            column("DAT_SCL", CHANNELS * npol, 'E', ""),
+            column("DATA", nsblk * CHANNELS * npol, 'B', "Jy"),
+        ]
+    }
+
+    /// Start a new file for `sink` in `dir` with its first spectrum at `start`, writing both headers.
+    /// The filename has the sink in it too if it isn't the only PSRFITS sink.
+    fn create(
+        sink: &str,
+        dir: &Path,
+        tbin: f64,
+        npol: usize,
+        nsblk: usize,
+        start: Epoch,
+    ) -> eyre::Result<Self> {
+        let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
+        let mut stem = format!(
+            "grex-{}",
+            Formatter::in_time_scale(start, fmt, TimeScale::UTC)
+        );
+        if sink != "psrfits" {
+            stem.push_str(&format!("_{sink}"));
+        }
+        let iso = Format::from_str("%Y-%m-%dT%H:%M:%S").unwrap();
+        let obs = observation::current();
+        let pointing = pointing::current().unwrap_or_default();
+        // Split the start into the integer MJD and seconds into the day, without losing precision
+        let imjd = start.to_mjd_utc_days().floor();
+        let secs = (start - Epoch::from_mjd_utc(imjd)).to_seconds();
+
+        let mut primary = Header::default();
+        primary.logical("SIMPLE", true, "File conforms to FITS standard");
+        primary.int("BITPIX", 8, "");
+        primary.int("NAXIS", 0, "No data in the primary HDU");
+        primary.logical("EXTEND", true, "Extensions follow");
+        primary.string("HDRVER", "6.1", "Header version");
+        primary.string("FITSTYPE", "PSRFITS", "");
+        if let Ok(now) = Epoch::now() {
+            let now = Formatter::in_time_scale(now, iso, TimeScale::UTC);
+            primary.string("DATE", &now.to_string(), "File creation date (UTC)");
+        }
+        primary.string("TELESCOP", &obs.telescope, "");
+        primary.string("BACKEND", "GReX-T0", "");
+        primary.string(
+            "OBS_MODE",
+            "SEARCH",
+            concat!("grex_t0 ", env!("CARGO_PKG_VERSION")),
+        );
+        primary.string(
+            "DATE-OBS",
+            &Formatter::in_time_scale(start, iso, TimeScale::UTC).to_string(),
+            "Start of the first sample (UTC)",
+        );
+        primary.float("OBSFREQ", obs.center_freq, "Centre frequency (MHz)");
+        primary.float("OBSBW", obs.signed_bandwidth(), "Bandwidth (MHz)");
+        primary.int("OBSNCHAN", CHANNELS, "");
+        primary.string(
+            "SRC_NAME",
+            pointing.source.as_deref().unwrap_or("Unknown"),
+            "",
+        );
+        primary.string("COORD_MD", "J2000", "");
+        primary.float("EQUINOX", 2000.0, "");
+        if let Some(ra) = pointing.psrfits_ra() {
+            primary.string("RA", &ra, "");
+        }
+        if let Some(dec) = pointing.psrfits_dec() {
+            primary.string("DEC", &dec, "");
+        }
+        primary.string(
+            "BANDMASK",
+            &weights::band_mask().to_string(),
+            "Zeroed channels",
+        );
+        primary.int("STT_IMJD", imjd as i64, "Start MJD (UTC days)");
+        primary.int(
+            "STT_SMJD",
+            secs.trunc() as i64,
+            "Start time (s past 00h UTC)",
+        );
+        primary.float("STT_OFFS", secs.fract(), "Start time offset (s)");
+        let primary = primary.end();
+
+        let columns = Self::columns(npol, nsblk);
+        let mut subint = Header::default();
+        subint.string("XTENSION", "BINTABLE", "Binary table extension");
+        subint.int("BITPIX", 8, "");
+        subint.int("NAXIS", 2, "");
+        subint.int(
+            "NAXIS1",
+            columns.iter().map(Column::width).sum::<usize>(),
+            "Bytes per row",
+        );
+        let naxis2_at = (primary.len() + subint.0.len()) as u64;
+        subint.int("NAXIS2", 0, "Number of rows");
+        subint.int("PCOUNT", 0, "");
+        subint.int("GCOUNT", 1, "");
+        subint.int("TFIELDS", columns.len(), "");
+        for (i, c) in columns.iter().enumerate() {
+            let n = i + 1;
+            subint.string(&format!("TTYPE{n}"), c.name, "");
+            subint.string(&format!("TFORM{n}"), &format!("{}{}", c.count, c.code), "");
+            if !c.unit.is_empty() {
+                subint.string(&format!("TUNIT{n}"), c.unit, "");
+            }
+            if c.name == "DATA" {
+                subint.string(
+                    &format!("TDIM{n}"),
+                    &format!("(1,{CHANNELS},{npol},{nsblk})"),
+                    "",
+                );
+            }
+        }
+        subint.string("EXTNAME", "SUBINT", "");
+        subint.string("INT_TYPE", "TIME", "");
+        subint.string("INT_UNIT", "SEC", "");
+        subint.string("SCALE", "FluxDen", "");
+        subint.string("POL_TYPE", if npol == 4 { "IQUV" } else { "AA+BB" }, "");
+        subint.int("NPOL", npol, "");
+        subint.float("TBIN", tbin, "Time per sample (s)");
+        subint.int("NBIN", 1, "");
+        subint.int("NBITS", 8, "");
+        subint.float("ZERO_OFF", 0.0, "");
+        subint.int("SIGNINT", 0, "Samples are unsigned");
+        subint.int("NSUBOFFS", 0, "");
+        subint.int("NCHAN", CHANNELS, "");
+        subint.float("CHAN_BW", obs.foff(), "Channel width (MHz)");
+        subint.int("NCHNOFFS", 0, "");
+        subint.int("NSBLK", nsblk, "Samples per row");
+        let subint = subint.end();
+
+        let (path, mut file) = create_new(dir, &stem, "fits")?;
+        file.write_all(&primary)?;
+        file.write_all(&subint)?;
+        info!("Writing PSRFITS to {}", path.display());
+        Ok(Self {
+            path,
+            file,
+            npol,
+            nsblk,
+            tbin,
+            start,
+            rows: 0,
+            naxis2_at,
+            data_end: (primary.len() + subint.len()) as u64,
+        })
+    }
+
+    /// Time of the next spectrum we'll write
+    fn end(&self) -> Epoch {
+        epoch_after(
+            &self.start,
+            (self.tbin * (self.rows * self.nsblk as u64) as f64).seconds(),
+        )
+    }
+
+    /// Write a row of `nsblk` spectra, each all the channels of each Stokes parameter in turn,
+    /// returning the size of the row in bytes
+    fn write_row(&mut self, block: &[f32]) -> eyre::Result<usize> {
+        let width = self.npol * CHANNELS;
+        let tsubint = self.tbin * self.nsblk as f64;
+        let pointing = pointing::current().unwrap_or_default();
+        let (data, levels) = requantize(block, width);
+        let mut row = vec![];
+        row.extend(tsubint.to_be_bytes());
+        row.extend(((self.rows as f64 + 0.5) * tsubint).to_be_bytes());
+        row.extend(pointing.ra.unwrap_or_default().to_be_bytes());
+        row.extend(pointing.dec.unwrap_or_default().to_be_bytes());
+        row.extend((pointing.az.unwrap_or_default() as f32).to_be_bytes());
+        row.extend((pointing.el.map_or(0.0, |el| 90.0 - el) as f32).to_be_bytes());
+        row.extend(freqs().iter().flat_map(|f| f.to_be_bytes()));
+        row.extend(weights::current().iter().flat_map(|w| w.to_be_bytes()));
+        row.extend(levels.offsets.iter().flat_map(|o| o.to_be_bytes()));
+        row.extend(levels.scales.iter().flat_map(|s| s.to_be_bytes()));
+        row.extend(data);
+
+        // Overwrite the last padding with the row, then pad again so the file is always whole
+        self.file.seek(SeekFrom::Start(self.data_end))?;
+        self.file.write_all(&row)?;
+        self.data_end += row.len() as u64;
+        let padding = (self.data_end as usize).next_multiple_of(BLOCK) - self.data_end as usize;
+        self.file.write_all(&vec![0u8; padding])?;
+        self.rows += 1;
+        let mut naxis2 = Header::default();
+        naxis2.int("NAXIS2", self.rows, "Number of rows");
+        self.file.seek(SeekFrom::Start(self.naxis2_at))?;
+        self.file.write_all(&naxis2.0)?;
+        Ok(row.len())
+    }
+
+    /// Close out the file, recording it in the catalog
+    fn finish(self) {
+        catalog::record(
+            catalog::Product::Psrfits,
+            Some(&self.path),
+            self.start,
+            self.end(),
+            json!({
+                "nchan": CHANNELS,
+                "npol": self.npol,
+                "tbin": self.tbin,
+                "nsblk": self.nsblk,
+                "rows": self.rows,
+                "flux_cal": fluxcal::applied_table(),
+            }),
+        );
+    }
+}
+
+/// Like the filterbank consumer, but writing search-mode PSRFITS in rows of `nsblk` spectra.
+/// Spectra short of a whole row at shutdown are dropped, and the file is split (between rows)
+/// whenever [`super::request_split`] is called.
+pub fn psrfits_consumer<S: Spectrum>(
+    sink: &str,
+    stokes_rcv: Receiver<S>,
+    payload_start: Epoch,
+    downsample_factor: usize,
+    nsblk: usize,
+    path: &Path,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting PSRFITS consumer");
+    let tbin = PACKET_CADENCE * downsample_factor as f64;
+    let row_len = nsblk * S::NPOL * CHANNELS;
+    let mut block = Vec::with_capacity(row_len);
+    let metrics = ExfilMetrics::new("psrfits");
+    let paused = PAUSED.subscribe();
+    // Index of the incoming spectrum, and if a latency probe is in the row we're filling
+    let mut in_idx = 0u64;
+    let mut probe = false;
+    // We will open the file (and capture the timestamp) on the first spectrum
+    let mut current: Option<PsrfitsFile> = None;
+    // Packets capture had skipped over as of the last spectrum
+    let mut skipped = None;
+    // Anything from before we started doesn't count
+    let mut splits = SPLITS.load(Ordering::Acquire);
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Exfil task stopping");
+            break;
+        }
+        match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(mut stokes) => {
+                if *paused.borrow() {
+                    // Close off the file (dropping any part row), starting a new one once we resume
+                    if let Some(done) = current.take() {
+                        done.finish();
+                    }
+                    block.clear();
+                    probe = false;
+                    latency::discarded(in_idx);
+                    in_idx += 1;
+                    metrics.dropped();
+                    continue;
+                }
+                // Any part row is dropped, as rows are all the same length
+                close_on_skip(
+                    "PSRFITS file",
+                    &mut current,
+                    &mut skipped,
+                    downsample_factor,
+                    in_idx,
+                    &mut probe,
+                    |done| {
+                        done.finish();
+                        block.clear();
+                        Ok(false)
+                    },
+                )?;
+                let pf = match current.as_mut() {
+                    Some(pf) => pf,
+                    None => current.insert(PsrfitsFile::create(
+                        sink,
+                        path,
+                        tbin,
+                        S::NPOL,
+                        nsblk,
+                        spectrum_time(&payload_start, downsample_factor, in_idx),
+                    )?),
+                };
+                for pol in stokes.chunks_mut(CHANNELS) {
+                    weights::band_mask().apply(pol);
+                    fluxcal::apply(pol);
+                }
+                probe |= latency::arrived(in_idx);
+                in_idx += 1;
+                block.extend_from_slice(&stokes);
+                if block.len() < row_len {
+                    continue;
+                }
+                let started = Instant::now();
+                let bytes = pf.write_row(&block)?;
+                metrics.stalled(started);
+                metrics.wrote(nsblk, bytes);
+                metrics.committed();
+                block.clear();
+                if probe {
+                    latency::visible();
+                    probe = false;
+                }
+                if split_requested(&mut splits) {
+                    let next = pf.end();
+                    if let Some(done) = current.take() {
+                        done.finish();
+                    }
+                    current = Some(PsrfitsFile::create(sink, path, tbin, S::NPOL, nsblk, next)?);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        }
+    }
+    if !block.is_empty() {
+        info!(
+            spectra = block.len() / (S::NPOL * CHANNELS),
+            "Dropping the spectra short of a whole PSRFITS row"
+        );
+    }
+    if let Some(pf) = current {
+        pf.finish();
+    }
+    Ok(())
+}
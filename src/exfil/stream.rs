@@ -0,0 +1,154 @@
+//! Streaming the downsampled spectra over TCP, for a remote T1 machine or live QuickLook display
+//!
+//! Subscribers connect to the stream port and get every spectrum from then on as a frame of
+//! - `b"GRXS"`
+//! - spectrum count since the start of the run (`u64`)
+//! - MJD (UTC) of the start of the spectrum (`f64`)
+//! - number of channels (`u32`) and of Stokes parameters (`u32`)
+//! - the spectrum itself, all the channels of each Stokes parameter in turn (`f32`s),
+//!
+//! all little-endian. Each subscriber has its own small backlog, so one that falls behind misses
+//! frames rather than holding up the pipeline.
+use super::{spectrum_time, ExfilMetrics, PAUSED};
+use crate::{
+    common::{Spectrum, BLOCK_TIMEOUT, CHANNELS},
+    latency,
+};
+use byte_slice_cast::AsByteSlice;
+use hifitime::prelude::*;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use std::{
+    io::{self, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        Arc,
+    },
+};
+use thingbuf::mpsc::blocking::Receiver;
+use thingbuf::mpsc::errors::RecvTimeoutError;
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+lazy_static! {
+    static ref STREAM_SUBSCRIBERS: IntGauge = register_int_gauge!(
+        "stream_subscribers",
+        "Number of clients subscribed to the spectra stream"
+    )
+    .unwrap();
+    static ref STREAM_DROPPED: IntCounter = register_int_counter!(
+        "stream_frames_dropped",
+        "Number of spectra not sent to a stream subscriber that had fallen behind"
+    )
+    .unwrap();
+}
+
+/// Start of every frame
+const MAGIC: &[u8; 4] = b"GRXS";
+/// Frames each subscriber can fall behind by before it starts missing them
+const BACKLOG: usize = 1024;
+
+/// A spectrum (`npol` Stokes parameters) as a frame, starting at `time` and `idx` since the start
+pub(super) fn frame(idx: u64, time: Epoch, npol: usize, stokes: &[f32]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(28 + stokes.len() * 4);
+    frame.extend_from_slice(MAGIC);
+    frame.extend_from_slice(&idx.to_le_bytes());
+    frame.extend_from_slice(&time.to_mjd_utc_days().to_le_bytes());
+    frame.extend_from_slice(&(CHANNELS as u32).to_le_bytes());
+    frame.extend_from_slice(&(npol as u32).to_le_bytes());
+    if cfg!(target_endian = "little") {
+        frame.extend_from_slice(stokes.as_byte_slice());
+    } else {
+        frame.extend(stokes.iter().flat_map(|x| x.to_le_bytes()));
+    }
+    frame
+}
+
+/// Send frames to `stream` until it goes away
+fn subscriber(mut stream: TcpStream, frames: std::sync::mpsc::Receiver<Arc<Vec<u8>>>) {
+    let peer = stream.peer_addr().ok();
+    for frame in frames {
+        if let Err(e) = stream.write_all(&frame) {
+            info!(?peer, "Stream subscriber left - {e}");
+            return;
+        }
+    }
+}
+
+/// Publish the spectra from `stokes_rcv` to anyone connected to `port`
+pub fn stream_consumer<S: Spectrum>(
+    stokes_rcv: Receiver<S>,
+    payload_start: Epoch,
+    downsample_factor: usize,
+    port: u16,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting stream consumer");
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    listener.set_nonblocking(true)?;
+    info!(port, "Waiting for stream subscribers");
+    let mut subscribers: Vec<SyncSender<Arc<Vec<u8>>>> = vec![];
+    let metrics = ExfilMetrics::new("stream");
+    let paused = PAUSED.subscribe();
+    let mut idx = 0u64;
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Exfil task stopping");
+            break;
+        }
+        // Pick up anyone new
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    info!(?peer, "New stream subscriber");
+                    stream.set_nodelay(true)?;
+                    stream.set_nonblocking(false)?;
+                    let (s, r) = sync_channel(BACKLOG);
+                    std::thread::Builder::new()
+                        .name("stream-sub".to_string())
+                        .spawn(move || subscriber(stream, r))?;
+                    subscribers.push(s);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Couldn't accept a stream subscriber - {e}");
+                    break;
+                }
+            }
+        }
+        STREAM_SUBSCRIBERS.set(subscribers.len() as i64);
+        let stokes = match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(s) => s,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        };
+        let probe = latency::arrived(idx);
+        if *paused.borrow() {
+            metrics.dropped();
+        } else if !subscribers.is_empty() {
+            let time = spectrum_time(&payload_start, downsample_factor, idx);
+            let frame = Arc::new(frame(idx, time, S::NPOL, &stokes));
+            let mut sent = 0;
+            subscribers.retain(|s| match s.try_send(frame.clone()) {
+                Ok(()) => {
+                    sent += 1;
+                    true
+                }
+                Err(TrySendError::Full(_)) => {
+                    STREAM_DROPPED.inc();
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+            metrics.wrote(sent, sent * frame.len());
+        }
+        if probe {
+            latency::visible();
+        }
+        idx += 1;
+    }
+    STREAM_SUBSCRIBERS.set(0);
+    Ok(())
+}
@@ -0,0 +1,286 @@
+//! VDIF baseband from the raw payloads, for VLBI-style or coherent dedispersion processing offline
+//!
+//! Every payload becomes a frame per polarization (thread 0 for pol a, thread 1 for pol b) holding
+//! all the channels of that time sample as complex 8-bit offset binary. The fanout offers us the
+//! payloads without waiting, so a disk that can't keep up (it's about 1 GB/s) loses frames rather
+//! than holding up detection, and frames capture filled in for dropped packets are marked invalid.
+//!
+//! VDIF wants a whole number of frames a second, which the SNAP's 8.192 us spectra aren't, so a
+//! frame's number is its place among the frames starting in that second. The exact start time
+//! and cadence go in a `.hdr` sidecar alongside, for readers that need better than a frame.
+use super::{create_new, heimdall_timestamp, write_sidecar_header, ExfilMetrics, PAUSED};
+use crate::{
+    catalog,
+    common::{epoch_after, Payload, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE},
+    observation, pointing, timing,
+};
+use hifitime::prelude::*;
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+};
+use thingbuf::mpsc::blocking::Receiver;
+use thingbuf::mpsc::errors::RecvTimeoutError;
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// Payloads the writer can fall behind by before the fanout starts dropping them
+pub const VDIF_CHANNEL_SIZE: usize = 16384;
+/// Length of a (non-legacy) VDIF header
+const HEADER_BYTES: usize = 32;
+/// A frame holds one time sample of every channel, as an 8-bit real and imaginary part each
+const FRAME_BYTES: usize = HEADER_BYTES + 2 * CHANNELS;
+/// Write buffer, about a millisecond of frames
+const BUFFER_BYTES: usize = 256 * FRAME_BYTES;
+
+/// Where to write VDIF and how much of it
+#[derive(Debug, Clone)]
+pub struct VdifConfig {
+    /// Directory the files go in
+    pub path: PathBuf,
+    /// Stop after this many seconds (the length of the scan), or carry on until shutdown
+    pub seconds: Option<f64>,
+    /// Two character station code
+    pub station: [u8; 2],
+}
+
+/// The VDIF reference epoch `time` falls in, as its number (half years since 2000) and start
+fn reference_epoch(time: Epoch) -> (u32, Epoch) {
+    let (year, month, ..) = time.to_gregorian_utc();
+    let second_half = month > 6;
+    let start = Epoch::from_gregorian_utc_at_midnight(year, if second_half { 7 } else { 1 }, 1);
+    ((2 * (year - 2000) + i32::from(second_half)) as u32, start)
+}
+
+/// A VDIF frame header for `frame` (of those starting in the `seconds`th second since the start of
+/// reference epoch `epoch`) on `thread`
+fn header(
+    seconds: u32,
+    epoch: u32,
+    frame: u32,
+    thread: u16,
+    station: [u8; 2],
+    invalid: bool,
+) -> [u8; HEADER_BYTES] {
+    let words = [
+        u32::from(invalid) << 31 | (seconds & 0x3fff_ffff),
+        (epoch & 0x3f) << 24 | (frame & 0x00ff_ffff),
+        // Version 0, log2 of the channel count, and the frame length in 8 byte units
+        (CHANNELS.ilog2() << 24) | (FRAME_BYTES / 8) as u32,
+        // Complex, 8 bits per sample
+        1 << 31
+            | (8 - 1) << 26
+            | u32::from(thread & 0x3ff) << 16
+            | u32::from(u16::from_be_bytes(station)),
+        // No extended user data
+        0,
+        0,
+        0,
+        0,
+    ];
+    let mut bytes = [0u8; HEADER_BYTES];
+    for (chunk, word) in bytes.chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    bytes
+}
+
+/// The VDIF file we're writing
+struct VdifFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    station: [u8; 2],
+    /// Time of the first frame, and the packet count it came from
+    start: Epoch,
+    first_count: u64,
+    /// The reference epoch we count seconds from
+    epoch: u32,
+    epoch_start: Epoch,
+    /// Payloads written, and the packet count of the last
+    payloads: u64,
+    last_count: u64,
+    buf: Vec<u8>,
+}
+
+impl VdifFile {
+    /// Start a new file in `dir` with `payload` (timestamped from `packet_start`) as its first frames
+    fn create(
+        dir: &Path,
+        station: [u8; 2],
+        payload: &Payload,
+        packet_start: &Epoch,
+    ) -> eyre::Result<Self> {
+        let start = payload.real_time(packet_start) + timing::correction();
+        let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
+        let stem = format!(
+            "grex-{}",
+            Formatter::in_time_scale(start, fmt, TimeScale::UTC)
+        );
+        // Claim the name first, so the sidecar goes with a file that's ours
+        let (path, file) = create_new(dir, &stem, "vdif")?;
+        let (epoch, epoch_start) = reference_epoch(start);
+        let obs = observation::current();
+        let mut entries = vec![
+            ("FORMAT".to_owned(), "VDIF".to_owned()),
+            ("UTC_START".to_owned(), heimdall_timestamp(&start)),
+            ("MJD_START".to_owned(), start.to_mjd_utc_days().to_string()),
+            ("FIRST_COUNT".to_owned(), payload.count.to_string()),
+            ("TSAMP".to_owned(), (PACKET_CADENCE * 1e6).to_string()),
+            ("FRAME_BYTES".to_owned(), FRAME_BYTES.to_string()),
+            ("NCHAN".to_owned(), CHANNELS.to_string()),
+            ("NBIT".to_owned(), "8".to_owned()),
+            ("THREAD_0".to_owned(), "POL_A".to_owned()),
+            ("THREAD_1".to_owned(), "POL_B".to_owned()),
+            (
+                "STATION".to_owned(),
+                String::from_utf8_lossy(&station).into_owned(),
+            ),
+            ("REF_EPOCH".to_owned(), epoch.to_string()),
+            ("FREQ".to_owned(), obs.center_freq.to_string()),
+            ("BW".to_owned(), obs.signed_bandwidth().to_string()),
+            ("TELESCOPE".to_owned(), obs.telescope.clone()),
+            (
+                "TIME_CORRECTION".to_owned(),
+                timing::correction().to_seconds().to_string(),
+            ),
+        ];
+        if let Some(sync) = timing::time_sync() {
+            entries.extend(sync.header_entries());
+        }
+        if let Some(p) = pointing::current() {
+            entries.extend(p.header_entries());
+        }
+        write_sidecar_header(&path.with_extension("hdr"), &entries)?;
+        info!("Writing VDIF to {}", path.display());
+        Ok(Self {
+            file: BufWriter::with_capacity(BUFFER_BYTES, file),
+            path,
+            station,
+            start,
+            first_count: payload.count,
+            epoch,
+            epoch_start,
+            payloads: 0,
+            last_count: payload.count,
+            buf: Vec::with_capacity(2 * FRAME_BYTES),
+        })
+    }
+
+    /// Seconds of data since the first frame
+    fn elapsed(&self, payload: &Payload) -> f64 {
+        PACKET_CADENCE * payload.count.saturating_sub(self.first_count) as f64
+    }
+
+    /// Write the frames of both polarizations of `payload`, returning how many bytes that was
+    fn write(&mut self, payload: &Payload) -> eyre::Result<usize> {
+        let time = epoch_after(&self.start, self.elapsed(payload).seconds());
+        let since = (time - self.epoch_start).to_seconds();
+        let seconds = since.floor();
+        let frame = ((since - seconds) / PACKET_CADENCE).floor();
+        let (pol_a, pol_b) = payload.packed_pols();
+        self.buf.clear();
+        for (thread, pol) in [pol_a, pol_b].into_iter().enumerate() {
+            self.buf.extend_from_slice(&header(
+                seconds as u32,
+                self.epoch,
+                frame as u32,
+                thread as u16,
+                self.station,
+                payload.synthetic,
+            ));
+            // Two's complement to offset binary
+            self.buf.extend(pol.iter().map(|&x| x as u8 ^ 0x80));
+        }
+        self.file.write_all(&self.buf)?;
+        self.payloads += 1;
+        self.last_count = payload.count;
+        Ok(self.buf.len())
+    }
+
+    /// Close out the file, recording it in the catalog
+    fn finish(mut self) -> eyre::Result<()> {
+        self.file.flush()?;
+        let stop = epoch_after(
+            &self.start,
+            (PACKET_CADENCE * (self.last_count + 1 - self.first_count) as f64).seconds(),
+        );
+        catalog::record(
+            catalog::Product::Vdif,
+            Some(&self.path),
+            self.start,
+            stop,
+            json!({
+                "nchan": CHANNELS,
+                "threads": 2,
+                "frame_bytes": FRAME_BYTES,
+                "frames": 2 * self.payloads,
+                "station": String::from_utf8_lossy(&self.station),
+            }),
+        );
+        Ok(())
+    }
+}
+
+/// Write the raw payloads coming in on `receiver` (timestamped from `packet_start`) as VDIF until
+/// the scan is over or we shut down. Nothing is written while the exfil is paused.
+pub fn vdif_task(
+    config: VdifConfig,
+    packet_start: Epoch,
+    receiver: Receiver<Payload>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting VDIF task");
+    let metrics = ExfilMetrics::new("vdif");
+    let paused = PAUSED.subscribe();
+    let mut current: Option<VdifFile> = None;
+    // Once the scan's over we keep taking payloads (so the fanout doesn't think we died) but drop them
+    let mut done = false;
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("VDIF task stopping");
+            break;
+        }
+        let payload = match receiver.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(p) => p,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        };
+        if done {
+            continue;
+        }
+        if *paused.borrow() {
+            metrics.dropped();
+            continue;
+        }
+        let vf = match current.as_mut() {
+            Some(vf) => vf,
+            None => current.insert(VdifFile::create(
+                &config.path,
+                config.station,
+                &payload,
+                &packet_start,
+            )?),
+        };
+        if config.seconds.is_some_and(|s| vf.elapsed(&payload) >= s) {
+            info!("VDIF scan finished");
+            if let Some(vf) = current.take() {
+                vf.finish()?;
+            }
+            done = true;
+            continue;
+        }
+        let started = Instant::now();
+        let bytes = vf.write(&payload)?;
+        metrics.stalled(started);
+        metrics.wrote(1, bytes);
+    }
+    if let Some(vf) = current {
+        vf.finish()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,271 @@
+//! Flux calibration against a calibrator source or noise diode with scheduled on/off times
+//!
+//! With a calibrator of known flux density S_cal on and off, the system equivalent flux density of
+//! each channel is SEFD = S_cal / (P_on / P_off - 1), and S_cal / (P_on - P_off) converts our power
+//! units to Janskys. Measuring writes a table of both, which can be fed back in to put the exfil
+//! output in Janskys.
+use crate::{
+    catalog,
+    common::{CHANNELS, PACKET_CADENCE},
+    events, observation,
+};
+use hifitime::prelude::*;
+use serde::Deserialize;
+use serde_json::json;
+use std::{
+    fmt::Write as _,
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+use tracing::{info, warn};
+
+fn default_reference_freq() -> f64 {
+    1400.0
+}
+
+fn default_path() -> PathBuf {
+    PathBuf::from(".")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    On,
+    Off,
+}
+
+/// A stretch of time with the calibrator on or off
+#[derive(Debug, Clone, Deserialize)]
+pub struct Window {
+    pub state: State,
+    /// UTC start (e.g. "2024-03-01T04:00:00")
+    pub start: String,
+    /// UTC end
+    pub end: String,
+}
+
+/// The `[fluxcal]` section of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FluxCalConfig {
+    /// Flux density of the calibrator (or noise diode equivalent) at the reference frequency (Jy)
+    pub flux_jy: f64,
+    /// Spectral index of the calibrator (0 for a flat noise diode)
+    #[serde(default)]
+    pub spectral_index: f64,
+    /// Frequency the flux density is quoted at (MHz)
+    #[serde(default = "default_reference_freq")]
+    pub reference_freq: f64,
+    /// Directory to write the calibration table to
+    #[serde(default = "default_path")]
+    pub path: PathBuf,
+    pub windows: Vec<Window>,
+}
+
+fn channel_freq(chan: usize) -> f64 {
+    observation::current().freq(chan)
+}
+
+#[derive(Debug)]
+struct Accumulator {
+    sum: Vec<f64>,
+    n: u64,
+}
+
+impl Accumulator {
+    fn new() -> Self {
+        Self {
+            sum: vec![0.0; CHANNELS],
+            n: 0,
+        }
+    }
+
+    fn add(&mut self, stokes: &[f32]) {
+        for (s, x) in self.sum.iter_mut().zip(stokes) {
+            *s += f64::from(*x);
+        }
+        self.n += 1;
+    }
+
+    fn mean(&self, chan: usize) -> f64 {
+        self.sum[chan] / self.n as f64
+    }
+}
+
+/// Measures a flux calibration over the scheduled windows
+pub struct FluxCal {
+    config: FluxCalConfig,
+    /// The windows as ranges of packet counts
+    windows: Vec<(State, Range<u64>)>,
+    on: Accumulator,
+    off: Accumulator,
+    done: bool,
+}
+
+impl FluxCal {
+    pub fn new(config: FluxCalConfig, packet_start: &Epoch) -> eyre::Result<Self> {
+        let to_count = |s: &str| -> eyre::Result<u64> {
+            let t = Epoch::from_str(s)?;
+            Ok(((t - *packet_start).to_seconds() / PACKET_CADENCE).max(0.0) as u64)
+        };
+        let windows = config
+            .windows
+            .iter()
+            .map(|w| Ok((w.state, to_count(&w.start)?..to_count(&w.end)?)))
+            .collect::<eyre::Result<Vec<_>>>()?;
+        if !windows
+            .iter()
+            .any(|(s, r)| *s == State::On && !r.is_empty())
+            || !windows
+                .iter()
+                .any(|(s, r)| *s == State::Off && !r.is_empty())
+        {
+            eyre::bail!("Flux calibration needs at least one future on and one off window");
+        }
+        info!("Flux calibration scheduled over {} windows", windows.len());
+        Ok(Self {
+            config,
+            windows,
+            on: Accumulator::new(),
+            off: Accumulator::new(),
+            done: false,
+        })
+    }
+
+    /// Fold in the next (downsampled) spectrum, starting at packet `count`
+    pub fn update(&mut self, stokes: &[f32], count: u64) {
+        if self.done {
+            return;
+        }
+        if let Some((state, _)) = self.windows.iter().find(|(_, r)| r.contains(&count)) {
+            match state {
+                State::On => self.on.add(stokes),
+                State::Off => self.off.add(stokes),
+            }
+        } else if self.windows.iter().all(|(_, r)| count >= r.end) {
+            self.done = true;
+            match self.write_table() {
+                Ok(path) => {
+                    info!("Flux calibration written to {}", path.display());
+                    if let Some((start, stop)) = self.span() {
+                        catalog::record(
+                            catalog::Product::Calibration,
+                            Some(&path),
+                            start,
+                            stop,
+                            json!({
+                                "method": "flux",
+                                "flux_jy": self.config.flux_jy,
+                                "spectral_index": self.config.spectral_index,
+                                "reference_freq": self.config.reference_freq,
+                            }),
+                        );
+                    }
+                    events::record(
+                        events::Kind::Calibration,
+                        json!({ "method": "flux", "table": path }),
+                    );
+                }
+                Err(e) => warn!("Failed to write flux calibration - {e}"),
+            }
+        }
+    }
+
+    /// The time covered by the calibration windows
+    fn span(&self) -> Option<(Epoch, Epoch)> {
+        let parse = |s: &String| Epoch::from_str(s).ok();
+        let start = self
+            .config
+            .windows
+            .iter()
+            .filter_map(|w| parse(&w.start))
+            .min()?;
+        let stop = self
+            .config
+            .windows
+            .iter()
+            .filter_map(|w| parse(&w.end))
+            .max()?;
+        Some((start, stop))
+    }
+
+    fn write_table(&self) -> eyre::Result<PathBuf> {
+        if self.on.n == 0 || self.off.n == 0 {
+            eyre::bail!("No data in the on or off windows");
+        }
+        let mut table = String::new();
+        writeln!(
+            table,
+            "# flux_jy={} spectral_index={} reference_freq={} on_spectra={} off_spectra={}",
+            self.config.flux_jy,
+            self.config.spectral_index,
+            self.config.reference_freq,
+            self.on.n,
+            self.off.n
+        )?;
+        writeln!(table, "channel,freq_mhz,on,off,sefd_jy,jy_per_unit")?;
+        for chan in 0..CHANNELS {
+            let freq = channel_freq(chan);
+            let s_cal = self.config.flux_jy
+                * (freq / self.config.reference_freq).powf(self.config.spectral_index);
+            let (on, off) = (self.on.mean(chan), self.off.mean(chan));
+            // Channels where the calibrator didn't show up get flagged with zeros
+            let (sefd, jy_per_unit) = if on > off && off > 0.0 {
+                (s_cal / (on / off - 1.0), s_cal / (on - off))
+            } else {
+                (0.0, 0.0)
+            };
+            writeln!(table, "{chan},{freq},{on},{off},{sefd},{jy_per_unit}")?;
+        }
+        let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
+        let path = self.config.path.join(format!(
+            "grex_fluxcal-{}.csv",
+            Formatter::new(Epoch::now()?, fmt)
+        ));
+        std::fs::write(&path, table)?;
+        Ok(path)
+    }
+}
+
+/// The table being applied to the exfil output, if any
+static APPLIED: OnceLock<(PathBuf, Vec<f32>)> = OnceLock::new();
+
+/// Load a flux calibration table to be applied to the exfil output
+pub fn load_table(path: &Path) -> eyre::Result<()> {
+    let gains = std::fs::read_to_string(path)?
+        .lines()
+        .filter(|l| !l.starts_with('#') && !l.starts_with("channel"))
+        .map(|l| {
+            l.rsplit(',')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .parse::<f32>()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if gains.len() != CHANNELS {
+        eyre::bail!(
+            "Flux calibration has {} channels, expected {CHANNELS}",
+            gains.len()
+        );
+    }
+    info!("Applying flux calibration from {}", path.display());
+    let _ = APPLIED.set((path.to_owned(), gains));
+    Ok(())
+}
+
+/// The flux calibration table we're applying, if any
+pub fn applied_table() -> Option<&'static Path> {
+    APPLIED.get().map(|(p, _)| p.as_path())
+}
+
+/// Convert a spectrum to Janskys, if we're applying a flux calibration
+pub fn apply(stokes: &mut [f32]) {
+    if let Some((_, gains)) = APPLIED.get() {
+        for (x, g) in stokes.iter_mut().zip(gains) {
+            *x *= g;
+        }
+    }
+}
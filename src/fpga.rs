@@ -1,34 +1,515 @@
 //! Control of the SNAP board running the gateware
+use casper_utils::design_sources::{fpg::read_fpg_file, FpgaDesign};
 use casperfpga::transport::{
     tapcp::{Platform, Tapcp},
     Transport,
 };
 use casperfpga_derive::fpga_from_fpg;
+use clap::{Args, ValueEnum};
 use eyre::bail;
 use fixed::{types::extra::U0, FixedU16};
 use hifitime::{prelude::*, UNIX_REF_EPOCH};
 use rsntp::SynchronizationResult;
-use std::net::{Ipv4Addr, SocketAddr};
-use tracing::debug;
+use serde::{Deserialize, Deserializer};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::Path,
+    sync::atomic::{AtomicU8, Ordering},
+    time::Instant,
+};
+use tracing::{debug, info, warn};
 
-use crate::common::PACKET_CADENCE;
+use crate::{args::parse_mac, calibrate, common::PACKET_CADENCE};
 
 fpga_from_fpg!(GrexFpga, "gateware/grex_gateware.fpg");
 
+/// FFT shift schedule we run the PFB with (shift at every stage)
+pub const FFT_SHIFT: u32 = 4095;
+
+/// The registers we use (and their sizes in bytes). The typed register map is built against
+/// `gateware/grex_gateware.fpg`, but as TAPCP addresses registers by name, any revision with these works.
+const REQUIRED_REGISTERS: &[(&str, usize)] = &[
+    ("adc_snap_bram", 0x4000),
+    ("adc_snap_ctrl", 4),
+    ("adc_snap_status", 4),
+    ("adc_snap_trig", 4),
+    ("adc16_controller", 0x100),
+    ("arm", 4),
+    ("dest_ip", 4),
+    ("dest_port", 4),
+    ("fft_overflow_cnt", 4),
+    ("fft_shift", 4),
+    ("gbe1", 0xf000),
+    ("gbe1_linkup", 4),
+    ("master_rst", 4),
+    ("pps_cnt", 4),
+    ("pps_trig", 4),
+    ("requant_gains_a", 0x1000),
+    ("requant_gains_b", 0x1000),
+    ("spec_a_vacc", 0x4000),
+    ("spec_b_vacc", 0x4000),
+    ("spec_vacc_n", 4),
+    ("spec_vacc_trig", 4),
+    ("stokes_vacc", 0x4000),
+    ("stokes_vacc_n", 4),
+    ("stokes_vacc_trig", 4),
+    ("tx_en", 4),
+];
+
+#[derive(thiserror::Error, Debug)]
+/// Errors from reaching the SNAP and checking its gateware against what we expect
+pub enum Error {
+    #[error("Couldn't reach the SNAP at {addr} after {attempts} attempts ({reason}), is it powered on and on the network?")]
+    Unreachable {
+        addr: SocketAddr,
+        attempts: u32,
+        reason: String,
+    },
+    #[error("The SNAP at {0} is up but isn't running any gateware, it needs programming first")]
+    NotProgrammed(SocketAddr),
+    #[error("The gateware has no {0} register")]
+    MissingRegister(String),
+    #[error("The {name} register is {found} bytes in the gateware, but we expect {expected}")]
+    RegisterSize {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("The SNAP isn't running the given gateware (the {0} register differs)")]
+    DesignMismatch(String),
+}
+
+/// The digitizer boards we can run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Board {
+    /// The original SNAP
+    #[default]
+    Snap,
+    /// SNAP2 (same gateware interface, different flash layout)
+    Snap2,
+}
+
+impl Board {
+    fn support(self) -> Box<dyn BoardSupport> {
+        match self {
+            Board::Snap => Box::new(Snap),
+            Board::Snap2 => Box::new(Snap2),
+        }
+    }
+
+    /// The TAPCP platform this board connects as
+    pub fn platform(self) -> Platform {
+        self.support().platform()
+    }
+}
+
+/// What differs between boards, so the control, calibration, and monitoring code can be shared.
+/// A new digitizer (like the planned RFSoC) slots in by implementing this.
+pub trait BoardSupport: Send {
+    /// The TAPCP platform we connect as
+    fn platform(&self) -> Platform;
+
+    /// Get the data link in working order, sending to `port` on the interface with MAC `mac`
+    fn start_networking(
+        &self,
+        fpga: &mut GrexFpga<Tapcp>,
+        mac: &[u8; 6],
+        port: u16,
+        network: &Network,
+    ) -> eyre::Result<()>;
+}
+
+struct Snap;
+
+impl BoardSupport for Snap {
+    fn platform(&self) -> Platform {
+        Platform::SNAP
+    }
+
+    fn start_networking(
+        &self,
+        fpga: &mut GrexFpga<Tapcp>,
+        mac: &[u8; 6],
+        port: u16,
+        network: &Network,
+    ) -> eyre::Result<()> {
+        start_tengbe(fpga, mac, port, network)
+    }
+}
+
+struct Snap2;
+
+impl BoardSupport for Snap2 {
+    fn platform(&self) -> Platform {
+        Platform::SNAP2
+    }
+
+    fn start_networking(
+        &self,
+        fpga: &mut GrexFpga<Tapcp>,
+        mac: &[u8; 6],
+        port: u16,
+        network: &Network,
+    ) -> eyre::Result<()> {
+        start_tengbe(fpga, mac, port, network)
+    }
+}
+
+/// The link's addresses on the usual GReX subnet
+const DEFAULT_DEST_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const DEFAULT_SNAP_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 20);
+const DEFAULT_NETMASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+/// The MAC address built into the gateware
+const DEFAULT_SNAP_MAC: &str = "02:2e:46:e0:64:a1";
+
+/// Addresses for the 10 GbE link from the SNAP to the capture interface
+///
+/// These can be set with flags or a `[network]` table in the `--config` file (which takes
+/// precedence over the flags). The destination port is the capture port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Args, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Network {
+    /// IP address of the interface the SNAP sends to
+    #[arg(long, default_value_t = DEFAULT_DEST_IP)]
+    pub dest_ip: Ipv4Addr,
+    /// IP address the SNAP sends from
+    #[arg(long, default_value_t = DEFAULT_SNAP_IP)]
+    pub snap_ip: Ipv4Addr,
+    /// MAC address the SNAP sends from
+    #[arg(long, value_parser = parse_mac, default_value = DEFAULT_SNAP_MAC)]
+    #[serde(deserialize_with = "deserialize_mac")]
+    pub snap_mac: [u8; 6],
+    /// Netmask of the subnet the SNAP is on
+    #[arg(long, default_value_t = DEFAULT_NETMASK)]
+    pub netmask: Ipv4Addr,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self {
+            dest_ip: DEFAULT_DEST_IP,
+            snap_ip: DEFAULT_SNAP_IP,
+            snap_mac: parse_mac(DEFAULT_SNAP_MAC).unwrap(),
+            netmask: DEFAULT_NETMASK,
+        }
+    }
+}
+
+/// MAC addresses are written the usual way in the config file too
+fn deserialize_mac<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 6], D::Error> {
+    let s = String::deserialize(deserializer)?;
+    parse_mac(&s).map_err(serde::de::Error::custom)
+}
+
+/// Bring up the `gbe1` 10 GbE core of our gateware, sending to `port` on the interface with MAC `mac`
+fn start_tengbe(
+    fpga: &mut GrexFpga<Tapcp>,
+    mac: &[u8; 6],
+    port: u16,
+    network: &Network,
+) -> eyre::Result<()> {
+    info!(
+        dest = %network.dest_ip,
+        port,
+        snap = %network.snap_ip,
+        "Starting 10 GbE"
+    );
+    // Disable
+    fpga.tx_en.write(false)?;
+    fpga.gbe1.set_ip(network.snap_ip)?;
+    fpga.gbe1.set_gateway(network.dest_ip)?;
+    fpga.gbe1.set_netmask(network.netmask)?;
+    fpga.gbe1.set_port(port)?;
+    fpga.gbe1.set_mac(&network.snap_mac)?;
+    fpga.gbe1.set_enable(true)?;
+    fpga.gbe1.toggle_reset()?;
+    // Set destination registers
+    fpga.dest_port.write(port.into())?;
+    fpga.dest_ip.write(u32::from(network.dest_ip).into())?;
+    fpga.gbe1.set_single_arp_entry(network.dest_ip, mac)?;
+    // Turn on the core
+    fpga.tx_en.write(true)?;
+    // Check the link
+    if !fpga.gbe1_linkup.read()? {
+        bail!("10GbE Link Failed to come up");
+    }
+    Ok(())
+}
+
+/// Largest coarse gain of the HMCAD1511 ADCs (dB)
+pub const MAX_ADC_GAIN: u8 = 12;
+/// The register we bit-bang the ADCs' 3-wire interface through
+const ADC_CONTROLLER: &str = "adc16_controller";
+/// HMCAD1511 coarse gains for dual and single channel modes
+const ADC_COARSE_GAIN_REG: u8 = 0x2B;
+/// HMCAD1511 gain configuration (dB vs. "x" coarse gain steps and fine gain enable)
+const ADC_GAIN_CTL_REG: u8 = 0x33;
+/// Placeholder for no pending ADC gain change
+const NO_ADC_GAIN: u8 = u8::MAX;
+/// An ADC gain someone asked for while the monitoring task had the device
+static ADC_GAIN_REQUEST: AtomicU8 = AtomicU8::new(NO_ADC_GAIN);
+
+/// Ask whoever holds the device to set the ADC gain (dB)
+pub fn request_adc_gain(db: u8) {
+    ADC_GAIN_REQUEST.store(db, Ordering::Release);
+}
+
+/// The ADC gain that was requested, if any (clearing the request)
+pub fn take_adc_gain_request() -> Option<u8> {
+    match ADC_GAIN_REQUEST.swap(NO_ADC_GAIN, Ordering::AcqRel) {
+        NO_ADC_GAIN => None,
+        db => Some(db),
+    }
+}
+
+/// Bounds on how long to wait between attempts to reach the SNAP
+const CONNECT_BACKOFF_MIN: std::time::Duration = std::time::Duration::from_secs(1);
+const CONNECT_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How hard to try reaching the SNAP before giving up
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectOptions {
+    /// Most attempts to make
+    pub attempts: u32,
+    /// Give up once this long has passed, however many attempts that was
+    pub timeout: std::time::Duration,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            timeout: std::time::Duration::from_secs(60),
+        }
+    }
+}
+
+/// Get through to the SNAP at `addr`, trying again with backoff while it doesn't answer, returning
+/// the connection and if it's running any gateware
+fn reach(
+    addr: SocketAddr,
+    platform: Platform,
+    connect: ConnectOptions,
+) -> Result<(Tapcp, bool), Error> {
+    let started = Instant::now();
+    let mut backoff = CONNECT_BACKOFF_MIN;
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let running = Tapcp::connect(addr, platform).and_then(|mut transport| {
+            let running = transport.is_running()?;
+            Ok((transport, running))
+        });
+        match running {
+            Ok(reached) => return Ok(reached),
+            Err(e) => {
+                if attempts >= connect.attempts || started.elapsed() + backoff > connect.timeout {
+                    return Err(Error::Unreachable {
+                        addr,
+                        attempts,
+                        reason: e.to_string(),
+                    });
+                }
+                warn!(%addr, attempts, "Couldn't reach the SNAP, trying again in {backoff:?} - {e}");
+                std::thread::sleep(backoff);
+                backoff = (backoff * 2).min(CONNECT_BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+/// Connect to the `board` at `addr` as a bare transport, without setting anything up (or resetting
+/// it once we're done, as a [`Device`] does), for poking at its registers
+pub fn transport(addr: SocketAddr, board: Board, connect: ConnectOptions) -> eyre::Result<Tapcp> {
+    let (transport, running) = reach(addr, board.platform(), connect)?;
+    if !running {
+        return Err(Error::NotProgrammed(addr).into());
+    }
+    Ok(transport)
+}
+
+/// What the SNAP says about the gateware it's running
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewareVersion {
+    /// The `sys_rev` register, the toolflow's revision
+    pub rev: u32,
+    /// The `sys_rev_rcs` register, the design's git revision (and if it had uncommitted changes)
+    pub rcs: u32,
+    /// MD5 of the design in flash, from the board's metadata
+    pub md5: Option<String>,
+}
+
+impl GatewareVersion {
+    /// Read it off the board
+    fn read(transport: &mut Tapcp) -> eyre::Result<Self> {
+        Ok(Self {
+            rev: transport.read::<u32, 4>("sys_rev", 0)?,
+            rcs: transport.read::<u32, 4>("sys_rev_rcs", 0)?,
+            md5: transport
+                .metadata()
+                .ok()
+                .and_then(|meta| meta.get("md5").cloned()),
+        })
+    }
+}
+
+impl std::fmt::Display for GatewareVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "rev {:#x}, rcs {:08x}, md5 {}",
+            self.rev,
+            self.rcs,
+            self.md5.as_deref().unwrap_or("unknown")
+        )
+    }
+}
+
+/// Check every register we use is in a design, given the size of each of its registers by name
+fn check_required(size: impl Fn(&str) -> Option<usize>) -> Result<(), Error> {
+    for (name, expected) in REQUIRED_REGISTERS {
+        let found = size(name).ok_or_else(|| Error::MissingRegister(name.to_string()))?;
+        if found != *expected {
+            return Err(Error::RegisterSize {
+                name: name.to_string(),
+                expected: *expected,
+                found,
+            });
+        }
+    }
+    Ok(())
+}
+
 pub struct Device {
     pub fpga: GrexFpga<Tapcp>,
+    /// The board-specific parts
+    board: Box<dyn BoardSupport>,
+    gateware: GatewareVersion,
+    /// The requant gains we last set for (a, b)
+    requant_gains: (Vec<u16>, Vec<u16>),
+    /// The ADC coarse gain we last set (dB)
+    adc_gain: Option<u8>,
 }
 
 impl Device {
-    pub fn new(addr: SocketAddr) -> Self {
-        let fpga = GrexFpga::new(Tapcp::connect(addr, Platform::SNAP).expect("Connection failed"))
-            .expect("Failed to build FPGA object");
-        assert!(
-            fpga.transport.lock().unwrap().is_running().unwrap(),
-            "SNAP board is not programmed/running"
+    /// Connect to a SNAP
+    pub fn new(addr: SocketAddr) -> eyre::Result<Self> {
+        Self::on_board(addr, Board::Snap, ConnectOptions::default())
+    }
+
+    /// Connect to a `board` running the gateware
+    pub fn on_board(addr: SocketAddr, board: Board, connect: ConnectOptions) -> eyre::Result<Self> {
+        Self::with_support(addr, board.support(), connect)
+    }
+
+    /// Connect to a board we don't know about yet, given what's different about it
+    pub fn with_support(
+        addr: SocketAddr,
+        board: Box<dyn BoardSupport>,
+        connect: ConnectOptions,
+    ) -> eyre::Result<Self> {
+        let (mut transport, running) = reach(addr, board.platform(), connect)?;
+        if !running {
+            return Err(Error::NotProgrammed(addr).into());
+        }
+        let gateware = GatewareVersion::read(&mut transport)?;
+        info!(%gateware, "Connected to the SNAP");
+        let fpga = GrexFpga::new(transport)?;
+        let mut device = Self {
+            fpga,
+            board,
+            gateware,
+            requant_gains: (vec![], vec![]),
+            adc_gain: None,
+        };
+        // Refuse anything we'd be poking the wrong registers of
+        device.check_register_map()?;
+        device.set_fft_shift()?;
+        Ok(device)
+    }
+
+    /// Program the `board` at `addr` with the gateware in the .fpg at `path`, unless it's already
+    /// running it, waiting for it to boot the new design
+    pub fn program(
+        addr: SocketAddr,
+        board: Board,
+        path: &Path,
+        connect: ConnectOptions,
+    ) -> eyre::Result<()> {
+        let fpg = read_fpg_file(path)?;
+        let (mut transport, running) = reach(addr, board.support().platform(), connect)?;
+        // A board that's never been programmed has no metadata to compare against
+        let programmed = transport
+            .metadata()
+            .ok()
+            .and_then(|meta| meta.get("md5").cloned());
+        if running && programmed.as_deref() == Some(fpg.md5_string().as_str()) {
+            info!("The SNAP is already running {}", path.display());
+            return Ok(());
+        }
+        info!("Programming the SNAP with {}", path.display());
+        transport.program(&fpg, true)?;
+        info!("SNAP programmed");
+        Ok(())
+    }
+
+    /// Set the FFT shift schedule we expect
+    pub fn set_fft_shift(&mut self) -> eyre::Result<()> {
+        self.fpga.fft_shift.write(FFT_SHIFT.into())?;
+        Ok(())
+    }
+
+    /// Is the SNAP still running our gateware as we set it up? Registers come back at their defaults
+    /// if the board has rebooted or been reprogrammed, so an FFT shift that isn't ours means a restart.
+    /// Errors if we can't reach the board at all.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn still_running(&self) -> eyre::Result<bool> {
+        if !self.fpga.transport.lock().unwrap().is_running()? {
+            return Ok(false);
+        }
+        Ok(u32::from(self.fpga.fft_shift.read()?) == FFT_SHIFT)
+    }
+
+    /// Check a gateware revision (read from the .fpg at `path`) has every register we use, and that it's
+    /// what the SNAP is running. This lets minor gateware revisions run without rebuilding.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn check_design(&mut self, path: &Path) -> eyre::Result<()> {
+        let fpg = read_fpg_file(path)?;
+        check_required(|name| fpg.registers.get(name).map(|r| r.size as usize))?;
+        let running = self.fpga.transport.lock().unwrap().listdev()?;
+        for (name, reg) in &fpg.registers {
+            match running.get(name) {
+                Some(r) if r.addr == reg.addr as usize && r.length == reg.size as usize => (),
+                _ => return Err(Error::DesignMismatch(name.to_string()).into()),
+            }
+        }
+        // The toolflow stamps the build time on the design's top level
+        let built = fpg
+            .devices
+            .values()
+            .find_map(|d| d.metadata.get("builddate"))
+            .map(|date| date.replace("\\_", " "));
+        info!(
+            registers = fpg.registers.len(),
+            built = built.as_deref().unwrap_or("unknown"),
+            "Gateware {} is compatible",
+            path.display()
         );
-        fpga.fft_shift.write(4095u32.into()).unwrap();
-        Self { fpga }
+        Ok(())
+    }
+
+    /// Check the SNAP is running gateware with every register we use, at the sizes in the register
+    /// map we were built against
+    #[allow(clippy::missing_panics_doc)]
+    pub fn check_register_map(&self) -> eyre::Result<()> {
+        let running = self.fpga.transport.lock().unwrap().listdev()?;
+        check_required(|name| running.get(name).map(|r| r.length))?;
+        Ok(())
+    }
+
+    /// The version of the gateware the SNAP is running, as of when we connected
+    pub fn gateware(&self) -> &GatewareVersion {
+        &self.gateware
     }
 
     /// Resets the state of the SNAP
@@ -38,33 +519,15 @@ impl Device {
         Ok(())
     }
 
-    /// Gets the 10 GbE data connection in working order
-    pub fn start_networking(&mut self, mac: &[u8; 6]) -> eyre::Result<()> {
-        let dest_ip: Ipv4Addr = "192.168.0.1".parse()?;
-        let dest_port = 60000u16;
-        // Disable
-        self.fpga.tx_en.write(false)?;
-        self.fpga.gbe1.set_ip("192.168.0.20".parse()?)?;
-        self.fpga.gbe1.set_gateway(dest_ip)?;
-        self.fpga.gbe1.set_netmask("255.255.255.0".parse()?)?;
-        self.fpga.gbe1.set_port(dest_port)?;
-        // Fixed in gateware
-        self.fpga
-            .gbe1
-            .set_mac(&[0x02, 0x2E, 0x46, 0xE0, 0x64, 0xA1])?;
-        self.fpga.gbe1.set_enable(true)?;
-        self.fpga.gbe1.toggle_reset()?;
-        // Set destination registers
-        self.fpga.dest_port.write(dest_port.into())?;
-        self.fpga.dest_ip.write(u32::from(dest_ip).into())?;
-        self.fpga.gbe1.set_single_arp_entry(dest_ip, mac)?;
-        // Turn on the core
-        self.fpga.tx_en.write(true)?;
-        // Check the link
-        if !self.fpga.gbe1_linkup.read()? {
-            bail!("10GbE Link Failed to come up");
-        }
-        Ok(())
+    /// Gets the 10 GbE data connection in working order, sending to `port` on the interface with MAC `mac`
+    pub fn start_networking(
+        &mut self,
+        mac: &[u8; 6],
+        port: u16,
+        network: &Network,
+    ) -> eyre::Result<()> {
+        self.board
+            .start_networking(&mut self.fpga, mac, port, network)
     }
 
     /// Send a trigger pulse to start the flow of bytes, returning the true time of the start of packets
@@ -102,6 +565,32 @@ impl Device {
         Ok(start_time)
     }
 
+    /// Send a trigger pulse to start the flow of bytes, given the time of the PPS edge that just passed
+    /// (as reported by a GPS receiver). Packets will start on the edge after the one we arm after.
+    pub fn gps_trigger(&mut self, last_edge: Epoch) -> eyre::Result<Epoch> {
+        // Like the other triggers, arm a little after an edge and well before the next. The host
+        // clock tells us how far past the reported edge we are, if it agrees with GPS at all.
+        let now = hifitime::Epoch::now()?;
+        let since = now - last_edge;
+        let mut edge = last_edge;
+        if since < 0.seconds() || since >= 1.seconds() {
+            warn!("Host clock disagrees with GPS by {since}, arming right away");
+        } else {
+            if since > 0.5.seconds() {
+                // Too close to the next edge, so wait for the one after
+                edge += 1.seconds();
+            }
+            let trigger_time = edge + 0.1.seconds();
+            if trigger_time > now {
+                std::thread::sleep((trigger_time - now).into());
+            }
+        }
+        // Send the trigger
+        self.fpga.arm.write(true)?;
+        self.fpga.arm.write(false)?;
+        Ok(edge + 1.seconds())
+    }
+
     /// Force a PPS pulse (timing will be inaccurate)
     #[allow(clippy::missing_panics_doc)]
     pub fn force_pps(&mut self) -> eyre::Result<()> {
@@ -199,8 +688,62 @@ impl Device {
         let b_fixed: Vec<_> = b.iter().map(|x| FixedU16::<U0>::from_num(*x)).collect();
         self.fpga.requant_gains_a.write(&a_fixed)?;
         self.fpga.requant_gains_b.write(&b_fixed)?;
+        self.requant_gains = (a.to_vec(), b.to_vec());
+        calibrate::record_gains(a, b);
+        Ok(())
+    }
+
+    /// The requant gains we last set for (a, b)
+    pub fn requant_gains(&self) -> &(Vec<u16>, Vec<u16>) {
+        &self.requant_gains
+    }
+
+    /// Write a register of every HMCAD1511 ADC over the controller's 3-wire interface
+    #[allow(clippy::missing_panics_doc)]
+    fn write_adc_register(&mut self, addr: u8, value: u16) -> eyre::Result<()> {
+        let mut transport = self.fpga.transport.lock().unwrap();
+        // The controller word has the clock in bit 9, data in bit 8, and the chip selects in the low byte
+        let mut send = |sclk: bool, sdata: bool, cs: u8| {
+            transport.write_bytes(
+                ADC_CONTROLLER,
+                0,
+                &[0, 0, (u8::from(sclk) << 1) | u8::from(sdata), cs],
+            )
+        };
+        // Idle
+        send(true, false, 0)?;
+        // Address then data, MSB first, clocked in on the rising edge
+        let bits = (u32::from(addr) << 16) | u32::from(value);
+        for i in (0..24).rev() {
+            let bit = (bits >> i) & 1 == 1;
+            send(false, bit, 0xFF)?;
+            send(true, bit, 0xFF)?;
+        }
+        send(true, false, 0)?;
         Ok(())
     }
+
+    /// Set the coarse gain of both inputs of every ADC (in dB, up to [`MAX_ADC_GAIN`]).
+    /// The current gateware doesn't expose any attenuators in the analog chain, so this is all the input
+    /// level control we have from here.
+    pub fn set_adc_gain(&mut self, db: u8) -> eyre::Result<()> {
+        if db > MAX_ADC_GAIN {
+            bail!("ADC gain must be at most {MAX_ADC_GAIN} dB, not {db}");
+        }
+        // Coarse gain in dB steps, fine gain disabled
+        self.write_adc_register(ADC_GAIN_CTL_REG, 0b01)?;
+        let nibble = u16::from(db);
+        self.write_adc_register(ADC_COARSE_GAIN_REG, nibble | nibble << 4 | nibble << 8)?;
+        self.adc_gain = Some(db);
+        calibrate::record_adc_gain(db);
+        info!(db, "Set ADC gain");
+        Ok(())
+    }
+
+    /// The ADC coarse gain we last set (dB), if we've set one
+    pub fn adc_gain(&self) -> Option<u8> {
+        self.adc_gain
+    }
 }
 
 impl Drop for Device {
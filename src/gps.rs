@@ -0,0 +1,167 @@
+//! Timing from a local GPS receiver, either through gpsd or straight from the NMEA serial stream
+//!
+//! Receivers report the time of each PPS edge shortly *after* that edge, so the edge we learn about
+//! has always just passed, and the next one is exactly one second later.
+use eyre::{bail, eyre};
+use hifitime::prelude::*;
+use regex::Regex;
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    os::fd::AsRawFd,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+/// How long we'll wait to hear a time report from the receiver
+const GPS_TIMEOUT: Duration = Duration::from_secs(5);
+/// Typical accuracy of a timing receiver's PPS edge (seconds)
+pub const PPS_UNCERTAINTY: f64 = 1e-7;
+/// Default gpsd address
+const GPSD_DEFAULT_ADDR: &str = "localhost:2947";
+
+/// Where we get GPS time from
+#[derive(Debug, Clone)]
+pub enum GpsSource {
+    /// A gpsd instance at `host:port`
+    Gpsd(String),
+    /// A serial device streaming NMEA (already configured for the right baud rate)
+    Serial(PathBuf),
+}
+
+impl Display for GpsSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GpsSource::Gpsd(addr) => write!(f, "gpsd://{addr}"),
+            GpsSource::Serial(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for GpsSource {
+    type Err = String;
+
+    /// `gpsd` or `gpsd://host:port` for gpsd, anything else is treated as a serial device path
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "gpsd" {
+            Ok(Self::Gpsd(GPSD_DEFAULT_ADDR.to_owned()))
+        } else if let Some(addr) = s.strip_prefix("gpsd://") {
+            Ok(Self::Gpsd(addr.to_owned()))
+        } else if s.is_empty() {
+            Err("Empty GPS source".to_owned())
+        } else {
+            Ok(Self::Serial(s.into()))
+        }
+    }
+}
+
+/// Parse the time out of an NMEA RMC sentence (e.g. `$GPRMC,123519.00,A,...,230394,...`)
+fn parse_rmc(sentence: &str) -> Option<Epoch> {
+    let fields: Vec<_> = sentence.trim().split(',').collect();
+    if !fields.first()?.ends_with("RMC") || fields.len() < 10 {
+        return None;
+    }
+    // Skip fixes the receiver itself says are invalid
+    if fields[2] != "A" {
+        return None;
+    }
+    let (time, date) = (fields[1], fields[9]);
+    if time.len() < 6 || date.len() != 6 {
+        return None;
+    }
+    let num = |s: &str| s.parse::<u8>().ok();
+    Some(Epoch::from_gregorian_utc_hms(
+        2000 + i32::from(num(&date[4..6])?),
+        num(&date[2..4])?,
+        num(&date[0..2])?,
+        num(&time[0..2])?,
+        num(&time[2..4])?,
+        num(&time[4..6])?,
+    ))
+}
+
+/// Parse the time out of a gpsd TPV report
+fn parse_tpv(report: &str, re: &Regex) -> Option<Epoch> {
+    if !report.contains("\"class\":\"TPV\"") {
+        return None;
+    }
+    let cap = re.captures(report)?;
+    Epoch::from_gregorian_str(&cap[1]).ok()
+}
+
+/// Read lines from `reader` until `parse` yields a time, skipping the first one we see as it may be stale
+fn next_report<R: BufRead>(
+    reader: R,
+    mut parse: impl FnMut(&str) -> Option<Epoch>,
+) -> eyre::Result<Epoch> {
+    let start = Instant::now();
+    let mut skipped_first = false;
+    for line in reader.lines() {
+        if start.elapsed() > GPS_TIMEOUT {
+            break;
+        }
+        if let Some(t) = parse(&line?) {
+            if skipped_first {
+                return Ok(t);
+            }
+            skipped_first = true;
+        }
+    }
+    bail!("Didn't hear a time report from the GPS receiver")
+}
+
+/// Have reads from the serial port give up after [`GPS_TIMEOUT`] without any data, rather than
+/// blocking forever on a receiver that's gone quiet
+fn set_serial_timeout(file: &File) -> eyre::Result<()> {
+    let fd = file.as_raw_fd();
+    // Safety: termios is plain old data, filled in by tcgetattr
+    let mut tio: libc::termios = unsafe { std::mem::zeroed() };
+    if unsafe { libc::tcgetattr(fd, &mut tio) } != 0 {
+        // Not a terminal (a recorded NMEA file, say), so reads won't hang anyway
+        return Ok(());
+    }
+    // Non-canonical, as the timeout doesn't apply to line-at-a-time reads
+    tio.c_lflag &= !(libc::ICANON | libc::ECHO);
+    tio.c_cc[libc::VMIN] = 0;
+    tio.c_cc[libc::VTIME] = (GPS_TIMEOUT.as_millis() / 100).min(255) as libc::cc_t;
+    if unsafe { libc::tcsetattr(fd, libc::TCSANOW, &tio) } != 0 {
+        bail!(
+            "Couldn't set a read timeout on the GPS serial port - {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Block until the receiver reports a fresh second, returning the time of the PPS edge it labels
+pub fn last_edge(source: &GpsSource) -> eyre::Result<Epoch> {
+    let edge = match source {
+        GpsSource::Gpsd(addr) => {
+            let mut stream = TcpStream::connect(addr)
+                .map_err(|e| eyre!("Couldn't connect to gpsd at {addr} - {e}"))?;
+            stream.set_read_timeout(Some(GPS_TIMEOUT))?;
+            stream.write_all(b"?WATCH={\"enable\":true,\"json\":true}\n")?;
+            let re = Regex::new(r#""time":"([^"]+)""#).unwrap();
+            next_report(BufReader::new(stream), |l| parse_tpv(l, &re))?
+        }
+        GpsSource::Serial(path) => {
+            let file = File::open(path)
+                .map_err(|e| eyre!("Couldn't open GPS serial port {} - {e}", path.display()))?;
+            set_serial_timeout(&file)?;
+            next_report(BufReader::new(file), parse_rmc)?
+        }
+    };
+    // Reports shouldn't lag the edge by more than a second, otherwise we'll be off by one
+    if let Ok(now) = Epoch::now() {
+        let lag = now - edge;
+        debug!("GPS report lags host clock by {lag}");
+        if lag.abs() > 0.9.seconds() {
+            warn!("GPS time and host time disagree by {lag}, check both clocks");
+        }
+    }
+    Ok(edge)
+}
@@ -0,0 +1,165 @@
+//! gRPC control plane, giving the observatory M&C system a typed and versioned interface
+//!
+//! The service is defined in `proto/grex_t0.proto` and served alongside the metrics webserver.
+use crate::{
+    args::{Cli, Exfil},
+    calibrate,
+    capture::{FIRST_PACKET, LAST_PACKET},
+    dumps::Trigger,
+    injection, pointing, t2, timing,
+};
+use hifitime::Epoch;
+use std::{net::SocketAddr, sync::atomic::Ordering};
+use thingbuf::mpsc::blocking::Sender;
+use tokio::sync::broadcast;
+use tonic::{transport::Server, Request, Response, Status};
+use tracing::info;
+
+pub mod proto {
+    tonic::include_proto!("grex_t0.v1");
+}
+
+use proto::{
+    control_server::{Control, ControlServer},
+    CalibrateReply, CalibrateRequest, Configuration, GetConfigRequest, GetStateRequest,
+    InjectionState, SetInjectionRequest, State, TriggerDumpReply, TriggerDumpRequest,
+};
+
+pub struct ControlService {
+    config: Configuration,
+    trigger: Sender<Trigger>,
+}
+
+impl ControlService {
+    pub fn new(config: Configuration, trigger: Sender<Trigger>) -> Self {
+        Self { config, trigger }
+    }
+}
+
+impl Configuration {
+    /// Snapshot how we were configured at startup
+    pub fn from_cli(cli: &Cli) -> eyre::Result<Self> {
+        let exfils = cli.exfils();
+        let exfil = if exfils.is_empty() {
+            "none".to_owned()
+        } else {
+            exfils.iter().map(Exfil::name).collect::<Vec<_>>().join("+")
+        };
+        let config_toml = cli
+            .config
+            .as_deref()
+            .map(std::fs::read_to_string)
+            .transpose()?;
+        Ok(Self {
+            downsample_power: cli.downsample_power,
+            vbuf_power: cli.vbuf_power,
+            cap_port: cli.cap_port[0].into(),
+            trig_port: cli.trig_port.into(),
+            metrics_port: cli.metrics_port.into(),
+            fpga_addr: cli.fpga_addr.map(|a| a.to_string()).unwrap_or_default(),
+            exfil,
+            dump_path: cli.dump_path.display().to_string(),
+            config_toml,
+        })
+    }
+}
+
+fn injection_state() -> InjectionState {
+    InjectionState {
+        enabled: injection::is_enabled(),
+    }
+}
+
+#[tonic::async_trait]
+impl Control for ControlService {
+    async fn get_state(&self, _: Request<GetStateRequest>) -> Result<Response<State>, Status> {
+        let sync = timing::time_sync();
+        Ok(Response::new(State {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            first_packet: FIRST_PACKET.load(Ordering::Acquire),
+            last_packet: LAST_PACKET.load(Ordering::Acquire),
+            trigger_epoch_mjd: sync.map_or(0.0, |s| s.trigger_epoch.to_mjd_utc_days()),
+            time_source: sync.map_or(String::new(), |s| s.source.to_string()),
+            time_correction: timing::correction().to_seconds(),
+            t2_connected: t2::is_connected(),
+            injection: Some(injection_state()),
+            pointing: pointing::current().map(|p| proto::Pointing {
+                source: p.source,
+                ra: p.ra,
+                dec: p.dec,
+                az: p.az,
+                el: p.el,
+                since_mjd: p.since_mjd,
+            }),
+        }))
+    }
+
+    async fn get_config(
+        &self,
+        _: Request<GetConfigRequest>,
+    ) -> Result<Response<Configuration>, Status> {
+        Ok(Response::new(self.config.clone()))
+    }
+
+    async fn trigger_dump(
+        &self,
+        request: Request<TriggerDumpRequest>,
+    ) -> Result<Response<TriggerDumpReply>, Status> {
+        let req = request.into_inner();
+        let source = if req.source.is_empty() {
+            "grpc".to_owned()
+        } else {
+            req.source
+        };
+        let trigger = Trigger {
+            source,
+            time: req.mjd.map(Epoch::from_mjd_utc),
+            dm: req.dm,
+            snr: req.snr,
+            width: req.width,
+            window: req.window,
+        };
+        info!(?trigger, "Dump requested over gRPC");
+        let accepted = self.trigger.try_send(trigger).is_ok();
+        Ok(Response::new(TriggerDumpReply { accepted }))
+    }
+
+    async fn calibrate(
+        &self,
+        _: Request<CalibrateRequest>,
+    ) -> Result<Response<CalibrateReply>, Status> {
+        info!("Recalibration requested over gRPC");
+        calibrate::request_recalibration();
+        Ok(Response::new(CalibrateReply { queued: true }))
+    }
+
+    async fn set_injection(
+        &self,
+        request: Request<SetInjectionRequest>,
+    ) -> Result<Response<InjectionState>, Status> {
+        let req = request.into_inner();
+        if let Some(enabled) = req.enabled {
+            injection::set_enabled(enabled);
+        }
+        if req.inject_now {
+            injection::inject_now();
+        }
+        Ok(Response::new(injection_state()))
+    }
+}
+
+pub async fn serve(
+    service: ControlService,
+    port: u16,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting gRPC control server");
+    Server::builder()
+        .add_service(ControlServer::new(service))
+        .serve_with_shutdown(SocketAddr::from(([0, 0, 0, 0], port)), async move {
+            let _ = shutdown.recv().await;
+            info!("gRPC control server stopping");
+        })
+        .await?;
+    Ok(())
+}
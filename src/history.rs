@@ -0,0 +1,275 @@
+//! A long ring of further-downsampled Stokes, dumped to a filterbank on its own trigger
+//!
+//! The voltage ring only holds a fraction of a second, so this gives minutes of detected-power
+//! context around events, at a few bytes per channel per millisecond.
+use crate::{
+    catalog,
+    common::{epoch_after, Stokes, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE},
+    dumps::Trigger,
+    events,
+    exfil::filterbank_writer,
+    timing, weights,
+};
+use hifitime::prelude::*;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+};
+use thingbuf::mpsc::{
+    blocking::{Receiver, Sender},
+    errors::{RecvTimeoutError, TrySendError},
+};
+use tokio::{net::UdpSocket, sync::broadcast};
+use tracing::{info, warn};
+
+lazy_static! {
+    static ref HISTORY_DROPS: IntCounter = register_int_counter!(
+        "history_dropped_spectra",
+        "Number of spectra that didn't make it into the Stokes history ring"
+    )
+    .unwrap();
+}
+
+/// Averages the downsampled Stokes further and hands them to the history ring
+pub struct Tap {
+    sender: Sender<(u64, Stokes)>,
+    factor: usize,
+    buf: [f32; CHANNELS],
+    n: usize,
+    /// Spectra in the current average that weren't NaN fill
+    valid: usize,
+    /// Packet count at the start of the current average
+    start_count: u64,
+}
+
+impl Tap {
+    pub fn new(sender: Sender<(u64, Stokes)>, factor: usize) -> Self {
+        Self {
+            sender,
+            factor,
+            buf: [0.0; CHANNELS],
+            n: 0,
+            valid: 0,
+            start_count: 0,
+        }
+    }
+
+    /// Fold in the next downsampled spectrum, which started at packet `count`. Spectra of NaN fill
+    /// are left out of the average (which is all NaNs if there's nothing else in it).
+    pub fn push(&mut self, stokes: &[f32], count: u64) {
+        if self.n == 0 {
+            self.start_count = count;
+        }
+        if !stokes.first().is_some_and(|v| v.is_nan()) {
+            self.buf.iter_mut().zip(stokes).for_each(|(x, y)| *x += y);
+            self.valid += 1;
+        }
+        self.n += 1;
+        if self.n == self.factor {
+            let valid = self.valid as f32;
+            self.buf.iter_mut().for_each(|v| *v /= valid);
+            // Never hold up the fast path for the history
+            match self.sender.try_send((self.start_count, self.buf.into())) {
+                Ok(_) | Err(TrySendError::Closed(_)) => (),
+                Err(_) => HISTORY_DROPS.inc(),
+            }
+            self.buf.iter_mut().for_each(|v| *v = 0.0);
+            self.n = 0;
+            self.valid = 0;
+        }
+    }
+}
+
+/// The ring of averaged spectra
+pub struct History {
+    capacity: usize,
+    spectra: Vec<f32>,
+    counts: Vec<u64>,
+    write_index: usize,
+    len: usize,
+    /// Seconds per spectrum
+    tsamp: f64,
+    packets_per_spectrum: u64,
+}
+
+impl History {
+    /// A ring holding `seconds` of spectra, each averaged over `packets_per_spectrum` packets
+    pub fn new(seconds: f64, packets_per_spectrum: usize) -> Self {
+        let tsamp = PACKET_CADENCE * packets_per_spectrum as f64;
+        let capacity = (seconds / tsamp).ceil().max(1.0) as usize;
+        info!(
+            capacity,
+            tsamp,
+            "Keeping {:.0} MB of Stokes history",
+            (capacity * CHANNELS * 4) as f64 / 1e6
+        );
+        Self {
+            capacity,
+            spectra: vec![0.0; capacity * CHANNELS],
+            counts: vec![0; capacity],
+            write_index: 0,
+            len: 0,
+            tsamp,
+            packets_per_spectrum: packets_per_spectrum as u64,
+        }
+    }
+
+    fn push(&mut self, count: u64, stokes: &[f32]) {
+        let idx = self.write_index;
+        self.spectra[idx * CHANNELS..(idx + 1) * CHANNELS].copy_from_slice(stokes);
+        self.counts[idx] = count;
+        self.write_index = (idx + 1) % self.capacity;
+        self.len = (self.len + 1).min(self.capacity);
+    }
+
+    /// Ring indices from oldest to newest
+    fn ordered(&self) -> impl Iterator<Item = usize> + '_ {
+        let oldest = (self.write_index + self.capacity - self.len) % self.capacity;
+        (0..self.len).map(move |i| (oldest + i) % self.capacity)
+    }
+
+    /// Write the ring out as a filterbank, returning its path and how many spectra it holds. Each
+    /// spectrum goes where its packet count puts it, with zeros for any that didn't make it into
+    /// the ring, going back as far as a full ring would reach.
+    fn dump(
+        &self,
+        start_time: &Epoch,
+        path: &Path,
+        trigger: &Trigger,
+    ) -> eyre::Result<(PathBuf, u64)> {
+        let newest = self
+            .ordered()
+            .last()
+            .ok_or_else(|| eyre::eyre!("Stokes history is empty"))?;
+        let earliest = self.counts[newest]
+            .saturating_sub((self.capacity as u64 - 1) * self.packets_per_spectrum);
+        let kept: Vec<_> = self
+            .ordered()
+            .filter(|&i| self.counts[i] >= earliest)
+            .collect();
+        let first = kept[0];
+        let tstart = epoch_after(
+            start_time,
+            (self.counts[first] as f64 * PACKET_CADENCE).seconds(),
+        ) + timing::correction();
+        let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
+        let file_path = path.join(format!(
+            "grex_history-{}.fil",
+            Formatter::new(Epoch::now()?, fmt)
+        ));
+        let mut fb = filterbank_writer(self.tsamp, 1);
+        fb.tstart = Some(tstart.to_mjd_utc_days());
+        let mut file = BufWriter::new(File::create(&file_path)?);
+        file.write_all(&fb.header_bytes())?;
+        let zeros = [0f32; CHANNELS];
+        let mut written = 0u64;
+        for idx in kept {
+            // The nearest whole spectrum after the first, as averages may not line up after a skip
+            let slot = (self.counts[idx] - self.counts[first] + self.packets_per_spectrum / 2)
+                / self.packets_per_spectrum;
+            while written < slot {
+                file.write_all(&fb.pack(&zeros))?;
+                written += 1;
+            }
+            file.write_all(&fb.pack(&self.spectra[idx * CHANNELS..(idx + 1) * CHANNELS]))?;
+            written += 1;
+        }
+        file.flush()?;
+        let weights_path = weights::companion_path(&file_path);
+        weights::write(&weights_path, &weights::current())?;
+        catalog::record(
+            catalog::Product::Filterbank,
+            Some(&file_path),
+            tstart,
+            epoch_after(&tstart, (self.tsamp * written as f64).seconds()),
+            json!({
+                "history": true,
+                "nchans": CHANNELS,
+                "tsamp": self.tsamp,
+                "spectra": written,
+                "weights": weights_path,
+                "trigger": trigger.describe(),
+            }),
+        );
+        Ok((file_path, written))
+    }
+}
+
+pub fn history_task(
+    mut history: History,
+    receiver: Receiver<(u64, Stokes)>,
+    triggers: Receiver<Trigger>,
+    start_time: Epoch,
+    path: PathBuf,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting Stokes history task!");
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("History task stopping");
+            break;
+        }
+        if let Ok(trigger) = triggers.try_recv() {
+            info!("Dumping Stokes history");
+            let started = Instant::now();
+            match history.dump(&start_time, &path, &trigger) {
+                Ok((file_path, spectra)) => events::record(
+                    events::Kind::Dump,
+                    json!({
+                        "filename": file_path,
+                        "history": true,
+                        "duration_seconds": history.tsamp * spectra as f64,
+                        "write_seconds": started.elapsed().as_secs_f64(),
+                        "trigger": trigger.source,
+                    }),
+                ),
+                Err(e) => warn!("Error in dumping Stokes history - {e}"),
+            }
+        }
+        match receiver.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(spectrum) => history.push(spectrum.0, &spectrum.1),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        }
+    }
+    Ok(())
+}
+
+/// Listen for (any) datagram on `port` to dump the Stokes history
+pub async fn trigger_task(
+    sender: Sender<Trigger>,
+    port: Option<u16>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    let port = match port {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    info!("Starting Stokes history trigger task!");
+    let sock = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    let mut buf = [0; 10];
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("Stokes history trigger task stopping");
+                break;
+            }
+            _ = sock.recv_from(&mut buf) => {
+                let trigger = Trigger::from_source("udp");
+                events::record(events::Kind::Trigger, json!({ "history": true, "source": trigger.source }));
+                if sender.try_send(trigger).is_err() {
+                    warn!("Stokes history dump already pending, ignoring trigger");
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -1,11 +1,27 @@
 //! Task for injecting a fake pulse into the timestream to test/validate downstream components
-use crate::common::{Payload, BLOCK_TIMEOUT, CHANNELS};
+//!
+//! Pulses are unpolarized by default. With a [`Polarization`], part of each pulse is instead added
+//! coherently to both polarizations, with the Jones vector for the given linear and circular
+//! fractions and position angle, Faraday rotated across the band. That sets Q, U, and V of the pulse
+//! as they'd come out of the Stokes computation, for validating the polarization products and RM
+//! tools downstream.
+use crate::common::{Channel, Payload, BLOCK_TIMEOUT, CHANNELS};
+use crate::{events, observation};
 use byte_slice_cast::AsSliceOf;
+use clap::Args;
+use eyre::eyre;
+use lazy_static::lazy_static;
 use memmap2::Mmap;
 use ndarray::{s, ArrayView, ArrayView2};
+use num_complex::Complex;
+use prometheus::{
+    register_gauge, register_int_counter, register_int_gauge_vec, Gauge, IntCounter, IntGaugeVec,
+};
+use serde_json::json;
 use std::{
     fs::File,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
     time::{Duration, Instant},
 };
 use thingbuf::mpsc::{
@@ -15,6 +31,147 @@ use thingbuf::mpsc::{
 use tokio::sync::broadcast;
 use tracing::{info, warn};
 
+lazy_static! {
+    static ref PULSES_INJECTED: IntCounter =
+        register_int_counter!("pulses_injected", "Number of fake pulses injected").unwrap();
+    static ref SINCE_INJECTION: Gauge = register_gauge!(
+        "seconds_since_injection",
+        "Time since the start of the last pulse injection"
+    )
+    .unwrap();
+    static ref CURRENT_PULSE: IntGaugeVec = register_int_gauge_vec!(
+        "injection_pulse",
+        "The pulse that will be injected next (or is being injected)",
+        &["pulse"]
+    )
+    .unwrap();
+}
+
+/// Speed of light (m/s), for the wavelengths in the Faraday rotation
+const SPEED_OF_LIGHT: f64 = 299_792_458.0;
+
+/// Number of payloads between updates of the time since the last injection (about a tenth of a second)
+const SINCE_UPDATE_INTERVAL: u64 = 16384;
+
+/// Whether we're injecting at the usual cadence
+static ENABLED: AtomicBool = AtomicBool::new(true);
+/// Set to inject the next pulse right away (regardless of the cadence)
+static INJECT_NOW: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable the regular injections
+pub fn set_enabled(enabled: bool) {
+    info!(enabled, "Setting pulse injection");
+    ENABLED.store(enabled, Ordering::Release);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Inject the next pulse as soon as we're done with the current one
+pub fn inject_now() {
+    INJECT_NOW.store(true, Ordering::Release);
+}
+
+/// Note which pulse file is loaded
+fn set_current_pulse(path: &Path) {
+    CURRENT_PULSE.reset();
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    CURRENT_PULSE.with_label_values(&[&name]).set(1);
+}
+
+/// Polarization of the injected pulses
+#[derive(Debug, Clone, Copy, Default, Args)]
+pub struct Polarization {
+    /// Linearly polarized fraction of the injected pulses
+    #[arg(long = "injection-linear-fraction", default_value_t = 0.0)]
+    pub linear: f64,
+    /// Circularly polarized fraction of the injected pulses (negative for left-handed)
+    #[arg(long = "injection-circular-fraction", default_value_t = 0.0)]
+    pub circular: f64,
+    /// Position angle of the linear polarization, before Faraday rotation (degrees)
+    #[arg(long = "injection-pa", default_value_t = 0.0)]
+    pub position_angle: f64,
+    /// Rotation measure to Faraday rotate the injected pulses by (rad/m^2)
+    #[arg(long = "injection-rm", default_value_t = 0.0)]
+    pub rm: f64,
+}
+
+impl Polarization {
+    /// Total polarized fraction
+    fn fraction(&self) -> f64 {
+        self.linear.hypot(self.circular)
+    }
+
+    pub fn validate(&self) -> eyre::Result<()> {
+        if !(0.0..=1.0).contains(&self.linear) || self.fraction() > 1.0 {
+            return Err(eyre!(
+                "The injection's linear fraction must be positive, and its polarized fraction at most 1"
+            ));
+        }
+        Ok(())
+    }
+
+    /// The (pol_a, pol_b) Jones vector of the polarized part in each channel, normalized so the
+    /// polarized power matches an unpolarized pulse of the same amplitude
+    fn jones(&self) -> Vec<(Complex<f64>, Complex<f64>)> {
+        // Ellipticity angle
+        let chi = self.circular.atan2(self.linear) / 2.0;
+        let scale = std::f64::consts::SQRT_2;
+        let obs = observation::current();
+        (0..CHANNELS)
+            .map(|chan| {
+                let freq = obs.freq(chan) * 1e6;
+                let lambda = SPEED_OF_LIGHT / freq;
+                let psi = self.position_angle.to_radians() + self.rm * lambda * lambda;
+                let a = Complex::new(psi.cos() * chi.cos(), -psi.sin() * chi.sin());
+                let b = Complex::new(psi.sin() * chi.cos(), psi.cos() * chi.sin());
+                (a * scale, b * scale)
+            })
+            .collect()
+    }
+}
+
+/// Everything needed to add a sample of pulse to a payload
+struct Injector {
+    /// Amplitude of the part added to each polarization independently
+    unpolarized: f64,
+    /// Amplitude of the part added to both coherently
+    polarized: f64,
+    jones: Vec<(Complex<f64>, Complex<f64>)>,
+}
+
+impl Injector {
+    fn new(pol: &Polarization) -> Self {
+        Self {
+            unpolarized: (1.0 - pol.fraction()).sqrt(),
+            polarized: pol.fraction().sqrt(),
+            jones: pol.jones(),
+        }
+    }
+
+    /// Add the pulse `sample` (one amplitude per channel) into `payload`
+    fn add(&self, payload: &mut Payload, sample: ArrayView<f64, ndarray::Ix1>) {
+        // The unit phasor of this channel's voltage, so the pulse adds to the noise
+        let phasor =
+            |c: &Channel| Complex::from_polar(1.0, f64::from(c.0.im).atan2(f64::from(c.0.re)));
+        let add = |c: &mut Channel, v: Complex<f64>| {
+            c.0.re += v.re.round() as i8;
+            c.0.im += v.im.round() as i8;
+        };
+        for (chan, pulse_val) in sample.iter().enumerate() {
+            let a_phasor = phasor(&payload.pol_a[chan]);
+            let b_phasor = phasor(&payload.pol_b[chan]);
+            let (ja, jb) = self.jones[chan];
+            // The polarized part shares pol_a's phase, so it's correlated between the two
+            let a = *pulse_val * (self.unpolarized * a_phasor + self.polarized * ja * a_phasor);
+            let b = *pulse_val * (self.unpolarized * b_phasor + self.polarized * jb * a_phasor);
+            add(&mut payload.pol_a[chan], a);
+            add(&mut payload.pol_b[chan], b);
+        }
+    }
+}
+
 fn read_pulse(pulse_mmap: &Mmap) -> eyre::Result<ArrayView2<f64>> {
     let floats = pulse_mmap[..].as_slice_of::<f64>()?;
     let time_samples = floats.len() / CHANNELS;
@@ -27,6 +184,7 @@ pub fn pulse_injection_task(
     output: StaticSender<Payload>,
     cadence: Duration,
     pulse_path: PathBuf,
+    polarization: Polarization,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     // Grab all the .dat files in the given directory
@@ -50,15 +208,18 @@ pub fn pulse_injection_task(
 
         let mut pulse_cycle = pulses.iter().cycle();
 
-        info!("Starting pulse injection!");
+        info!(?polarization, "Starting pulse injection!");
+        let injector = Injector::new(&polarization);
 
         let mut i = 0;
         let mut currently_injecting = false;
         let mut last_injection = Instant::now();
 
         // State for current pulse
-        let mut current_mmap = unsafe { Mmap::map(&File::open(pulse_cycle.next().unwrap())?)? };
+        let mut current_path = pulse_cycle.next().unwrap();
+        let mut current_mmap = unsafe { Mmap::map(&File::open(current_path)?)? };
         let mut current_pulse = read_pulse(&current_mmap)?;
+        set_current_pulse(current_path);
 
         loop {
             if shutdown.try_recv().is_ok() {
@@ -68,36 +229,40 @@ pub fn pulse_injection_task(
             // Grab payload from packet capture
             match input.recv_timeout(BLOCK_TIMEOUT) {
                 Ok(mut payload) => {
-                    if last_injection.elapsed() >= cadence {
+                    if payload.count.is_multiple_of(SINCE_UPDATE_INTERVAL) {
+                        SINCE_INJECTION.set(last_injection.elapsed().as_secs_f64());
+                    }
+                    let due = is_enabled() && last_injection.elapsed() >= cadence;
+                    if !currently_injecting && (due || INJECT_NOW.swap(false, Ordering::AcqRel)) {
                         last_injection = Instant::now();
                         currently_injecting = true;
                         i = 0;
+                        PULSES_INJECTED.inc();
+                        events::record(
+                            events::Kind::Injection,
+                            json!({
+                                "pulse": current_path,
+                                "packet": payload.count,
+                                "linear_fraction": polarization.linear,
+                                "circular_fraction": polarization.circular,
+                                "position_angle": polarization.position_angle,
+                                "rm": polarization.rm,
+                            }),
+                        );
                     }
                     if currently_injecting {
                         // Get the slice of fake pulse data
                         let this_sample = current_pulse.slice(s![.., i]);
                         // Add the current time slice of the fake pulse into the stream of real data
-                        // For both polarizations, add the real and imaginary part by the value of the corresponding channel in the fake pulse data
-                        for (payload_val, pulse_val) in payload.pol_a.iter_mut().zip(this_sample) {
-                            // Compute the phase of this channel's voltage
-                            let phase = (payload_val.0.im as f64).atan2(payload_val.0.re as f64);
-                            // Create the phasor for the pulse and add to the complex components in our data
-                            payload_val.0.re += (pulse_val * phase.cos()).round() as i8;
-                            payload_val.0.im += (pulse_val * phase.sin()).round() as i8;
-                        }
-                        // And again for pol_b
-                        for (payload_val, pulse_val) in payload.pol_b.iter_mut().zip(this_sample) {
-                            let phase = (payload_val.0.im as f64).atan2(payload_val.0.re as f64);
-                            payload_val.0.re += (pulse_val * phase.cos()).round() as i8;
-                            payload_val.0.im += (pulse_val * phase.sin()).round() as i8;
-                        }
+                        injector.add(&mut payload, this_sample);
                         i += 1;
                         // If we've gone through all of it, stop and move to the next pulse
                         if i == current_pulse.shape()[1] {
                             currently_injecting = false;
-                            current_mmap =
-                                unsafe { Mmap::map(&File::open(pulse_cycle.next().unwrap())?)? };
+                            current_path = pulse_cycle.next().unwrap();
+                            current_mmap = unsafe { Mmap::map(&File::open(current_path)?)? };
                             current_pulse = read_pulse(&current_mmap)?;
+                            set_current_pulse(current_path);
                         }
                     }
                     output.send(payload)?;
@@ -0,0 +1,161 @@
+//! Checking (and optionally fixing) where the capture NIC's interrupts land
+//!
+//! Interrupts serviced on the same core as one of our busy threads (or on another NUMA node) are the
+//! most common cause of drops on a new machine, so we want them on a spare core next to capture.
+use std::{
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
+use tracing::{info, warn};
+
+/// The IRQs belonging to a network interface
+pub fn nic_irqs(iface: &str) -> eyre::Result<Vec<u32>> {
+    // MSI(-X) devices list their vectors in sysfs
+    let msi = Path::new("/sys/class/net")
+        .join(iface)
+        .join("device/msi_irqs");
+    if let Ok(entries) = fs::read_dir(&msi) {
+        let mut irqs: Vec<u32> = entries
+            .filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+            .collect();
+        irqs.sort_unstable();
+        return Ok(irqs);
+    }
+    // Otherwise, look for the interface's name in the interrupt table
+    let table = fs::read_to_string("/proc/interrupts")?;
+    Ok(table
+        .lines()
+        .filter(|l| l.contains(iface))
+        .filter_map(|l| l.split(':').next()?.trim().parse().ok())
+        .collect())
+}
+
+/// Parse a kernel CPU list like "0-3,8"
+fn parse_cpu_list(s: &str) -> Vec<usize> {
+    s.trim()
+        .split(',')
+        .filter(|p| !p.is_empty())
+        .flat_map(|part| match part.split_once('-') {
+            Some((a, b)) => match (a.parse(), b.parse()) {
+                (Ok(a), Ok(b)) => (a..=b).collect(),
+                _ => vec![],
+            },
+            None => part.parse().into_iter().collect(),
+        })
+        .collect()
+}
+
+/// Format CPUs as the comma-separated 32 bit hex mask the RPS/XPS files want
+fn cpu_mask(cpus: &[usize]) -> String {
+    let words = cpus.iter().max().map_or(1, |m| m / 32 + 1);
+    let mut mask = vec![0u32; words];
+    for cpu in cpus {
+        mask[cpu / 32] |= 1 << (cpu % 32);
+    }
+    mask.iter()
+        .rev()
+        .map(|w| format!("{w:08x}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn affinity_path(irq: u32) -> PathBuf {
+    PathBuf::from(format!("/proc/irq/{irq}/smp_affinity_list"))
+}
+
+/// The cores the NIC's interrupts should go to: whatever's left over in our range, or else the one just past it
+pub fn target_cores(core_range: &RangeInclusive<usize>, busy: &[usize]) -> Vec<usize> {
+    let spare: Vec<_> = core_range.clone().filter(|c| !busy.contains(c)).collect();
+    if spare.is_empty() {
+        vec![core_range.end() + 1]
+    } else {
+        spare
+    }
+}
+
+/// Warn about any of the NIC's interrupts that are allowed onto one of our busy cores
+pub fn check(iface: &str, busy: &[usize], capture_cpu: usize) -> eyre::Result<()> {
+    let irqs = nic_irqs(iface)?;
+    if irqs.is_empty() {
+        warn!(iface, "Couldn't find any interrupts for the capture NIC");
+        return Ok(());
+    }
+    let mut bad = 0;
+    for irq in &irqs {
+        let cpus = parse_cpu_list(&fs::read_to_string(affinity_path(*irq))?);
+        if cpus.contains(&capture_cpu) {
+            warn!(irq, ?cpus, "NIC interrupt can land on the capture core");
+            bad += 1;
+        } else if cpus.iter().any(|c| busy.contains(c)) {
+            warn!(irq, ?cpus, "NIC interrupt can land on a pipeline core");
+            bad += 1;
+        }
+    }
+    if bad == 0 {
+        info!(
+            iface,
+            irqs = irqs.len(),
+            "NIC interrupts are clear of the pipeline cores"
+        );
+    } else {
+        warn!(
+            iface,
+            bad, "Misplaced NIC interrupts will likely cause drops, consider --pin-irqs"
+        );
+    }
+    Ok(())
+}
+
+/// Pin the NIC's interrupts and its RPS/XPS masks to `cores` (needs root)
+pub fn pin(iface: &str, cores: &[usize]) -> eyre::Result<()> {
+    let list = cores
+        .iter()
+        .map(|c| c.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    for irq in nic_irqs(iface)? {
+        fs::write(affinity_path(irq), &list)?;
+    }
+    // Steer the receive processing (and transmit queues) the same way
+    let mask = cpu_mask(cores);
+    let queues = Path::new("/sys/class/net").join(iface).join("queues");
+    for queue in fs::read_dir(queues)? {
+        let queue = queue?.path();
+        let name = queue.file_name().unwrap_or_default().to_string_lossy();
+        let file = if name.starts_with("rx-") {
+            "rps_cpus"
+        } else if name.starts_with("tx-") {
+            "xps_cpus"
+        } else {
+            continue;
+        };
+        // Not every driver supports XPS
+        if let Err(e) = fs::write(queue.join(file), &mask) {
+            warn!("Couldn't set {} for {name} - {e}", file);
+        }
+    }
+    info!(iface, ?cores, "Pinned NIC interrupts");
+    if Path::new("/run/irqbalance.pid").exists() || Path::new("/var/run/irqbalance.pid").exists() {
+        warn!("irqbalance is running and may undo the interrupt pinning");
+    }
+    Ok(())
+}
+
+/// Optionally pin, then verify, the IRQ placement of the capture NIC
+pub fn setup(
+    iface: &str,
+    core_range: &RangeInclusive<usize>,
+    busy: &[usize],
+    capture_cpu: usize,
+    pin_irqs: bool,
+) {
+    if pin_irqs {
+        if let Err(e) = pin(iface, &target_cores(core_range, busy)) {
+            warn!("Couldn't pin NIC interrupts (are we root?) - {e}");
+        }
+    }
+    if let Err(e) = check(iface, busy, capture_cpu) {
+        warn!("Couldn't check NIC interrupt placement - {e}");
+    }
+}
@@ -0,0 +1,108 @@
+//! End-to-end latency probing, from packet capture to data being visible downstream
+//!
+//! Every so often, capture marks the payload count it's forwarding as the probe. The downsample task notes which
+//! spectrum that payload ended up in, and as the Stokes channel is lossless and in-order,
+//! exfil can recognize that spectrum just by counting.
+use lazy_static::lazy_static;
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter, HistogramVec, IntCounter,
+};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// How often we send a probe through the pipeline, in packets (about a second)
+pub const PROBE_EVERY: u64 = 1 << 17;
+/// How long a probe gets to make it through before we give up on it (if its payload or spectrum
+/// was lost on the way, say) and send another
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Sentinel for "no probe at this stage"
+const NO_PROBE: u64 = u64::MAX;
+
+lazy_static! {
+    static ref REFERENCE: Instant = Instant::now();
+    static ref LATENCY_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "pipeline_latency_seconds",
+        "Time between a packet's capture and its data reaching each pipeline stage",
+        &["stage"],
+        exponential_buckets(1e-4, 2.0, 22).unwrap()
+    )
+    .unwrap();
+    static ref PROBES_EXPIRED: IntCounter = register_int_counter!(
+        "latency_probes_expired",
+        "Latency probes given up on before they made it through the pipeline"
+    )
+    .unwrap();
+}
+
+/// The payload count being probed
+static PROBE_COUNT: AtomicU64 = AtomicU64::new(NO_PROBE);
+/// The index of the downsampled spectrum that contains the probed payload
+static PROBE_SPECTRUM: AtomicU64 = AtomicU64::new(NO_PROBE);
+/// Nanoseconds since `REFERENCE` when the probe was captured
+static PROBE_STAMP: AtomicU64 = AtomicU64::new(0);
+
+fn now_nanos() -> u64 {
+    REFERENCE.elapsed().as_nanos() as u64
+}
+
+fn record(stage: &str) {
+    let elapsed = now_nanos().saturating_sub(PROBE_STAMP.load(Ordering::Acquire));
+    LATENCY_HISTOGRAM
+        .with_label_values(&[stage])
+        .observe(elapsed as f64 / 1e9);
+}
+
+/// Capture side: start a new probe on the payload with this count, which is on its way
+/// downstream, if the last one has made it through (or has timed out)
+pub fn launch(count: u64) {
+    let now = now_nanos();
+    if PROBE_COUNT.load(Ordering::Acquire) != NO_PROBE
+        || PROBE_SPECTRUM.load(Ordering::Acquire) != NO_PROBE
+    {
+        let age = Duration::from_nanos(now.saturating_sub(PROBE_STAMP.load(Ordering::Acquire)));
+        if age < PROBE_TIMEOUT {
+            return;
+        }
+        PROBES_EXPIRED.inc();
+        PROBE_SPECTRUM.store(NO_PROBE, Ordering::Release);
+    }
+    PROBE_STAMP.store(now, Ordering::Release);
+    PROBE_COUNT.store(count, Ordering::Release);
+}
+
+/// Downsample side: is this payload the one being probed?
+pub fn is_probe(count: u64) -> bool {
+    PROBE_COUNT.load(Ordering::Acquire) == count
+}
+
+/// Downsample side: the probed payload was averaged into the spectrum with index `idx`, which was just sent
+pub fn downsampled(idx: u64) {
+    record("downsample");
+    PROBE_SPECTRUM.store(idx, Ordering::Release);
+    PROBE_COUNT.store(NO_PROBE, Ordering::Release);
+}
+
+/// Exfil side: check if the spectrum with index `idx` carries the probe, recording its arrival if so
+pub fn arrived(idx: u64) -> bool {
+    if PROBE_SPECTRUM.load(Ordering::Acquire) == idx {
+        record("exfil");
+        true
+    } else {
+        false
+    }
+}
+
+/// Exfil side: the probed spectrum is now visible to downstream consumers, finishing the probe
+pub fn visible() {
+    record("visible");
+    PROBE_SPECTRUM.store(NO_PROBE, Ordering::Release);
+}
+
+/// Exfil side: the spectrum with index `idx` was thrown away, so give up on the probe if it was in there
+pub fn discarded(idx: u64) {
+    if PROBE_SPECTRUM.load(Ordering::Acquire) == idx {
+        PROBE_SPECTRUM.store(NO_PROBE, Ordering::Release);
+    }
+}
@@ -5,11 +5,41 @@
 
 pub mod args;
 pub mod calibrate;
+pub mod candidates;
 pub mod capture;
+pub mod catalog;
+pub mod cluster;
+pub mod codec;
 pub mod common;
+pub mod config;
+pub mod convert;
+pub mod daemon;
+pub mod discover;
 pub mod dumps;
+pub mod events;
 pub mod exfil;
+pub mod fluxcal;
 pub mod fpga;
+pub mod gps;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod history;
 pub mod injection;
+pub mod irq;
+pub mod latency;
 pub mod monitoring;
+pub mod observation;
+pub mod peek;
+pub mod pipeline;
+pub mod pointing;
 pub mod processing;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod rfi;
+pub mod selftest;
+pub mod servo;
+pub mod simulate;
+pub mod state;
+pub mod t2;
+pub mod timing;
+pub mod weights;
@@ -1,54 +1,95 @@
 pub use clap::Parser;
-use core_affinity::CoreId;
-use eyre::bail;
 use grex_t0::{
-    args,
-    calibrate::calibrate,
-    capture,
-    common::{Payload, CHANNELS},
-    dumps::{self, DumpRing},
-    exfil,
-    fpga::Device,
-    injection, monitoring, processing,
+    args, catalog, cluster,
+    config::Config,
+    convert,
+    daemon::InstanceLock,
+    discover, dumps, events, exfil, observation, peek,
+    pipeline::{spawn_named, Pipeline, PipelineConfig, Sink},
+    selftest, simulate,
 };
-use rsntp::SntpClient;
-use std::time::Duration;
-use thingbuf::mpsc::blocking::{channel, StaticChannel};
 use tokio::{
     signal::unix::{signal, SignalKind},
     sync::broadcast,
-    try_join,
 };
 use tracing::info;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-// Setup the static channels
-const FAST_PATH_CHANNEL_SIZE: usize = 4096;
-static CAPTURE_CHAN: StaticChannel<Payload, FAST_PATH_CHANNEL_SIZE> = StaticChannel::new();
-static INJECT_CHAN: StaticChannel<Payload, FAST_PATH_CHANNEL_SIZE> = StaticChannel::new();
-static DUMP_CHAN: StaticChannel<Payload, FAST_PATH_CHANNEL_SIZE> = StaticChannel::new();
-
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> eyre::Result<()> {
     color_eyre::install()?;
+    // Try to save the voltage ring if anything panics
+    dumps::install_salvage_hook();
     // Get the CLI options
-    let cli = args::Cli::parse();
-    // Get the CPU core range
-    let mut cpus = cli.core_range;
+    let cli = args::Cli::parse_with_profile();
     // Logger init
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
+    // The console layer wants tokio's own trace events, so only filter what we print
+    let registry = tracing_subscriber::registry()
+        .with(fmt::layer().with_filter(EnvFilter::from_default_env()));
+    #[cfg(feature = "console")]
+    let registry = registry.with(console_subscriber::spawn());
+    registry.init();
+    // Structured settings
+    let config = cli
+        .config
+        .as_deref()
+        .map(Config::load)
+        .transpose()?
+        .unwrap_or_default();
+    observation::set(
+        config
+            .observation
+            .clone()
+            .unwrap_or_else(|| cli.observation.clone()),
+    );
+    // Split off the non-pipeline subcommands
+    match &cli.command {
+        Some(args::Command::Query(query)) => return events::query(&cli.event_db, query),
+        Some(args::Command::Simulate(sim)) => return simulate::simulate(sim).map(drop),
+        Some(args::Command::Convert(conv)) => return convert::convert(conv).map(drop),
+        Some(args::Command::Discover(disc)) => return discover::discover(disc),
+        Some(args::Command::Fpga(regs)) => {
+            let addr = match cli.fpga_addr {
+                Some(addr) => addr,
+                None => discover::pick(&cli.discover_subnet, cli.board, false)?,
+            };
+            return peek::run(regs, addr, cli.board);
+        }
+        Some(args::Command::ReceiveDumps(recv)) => return dumps::stream::receive(recv),
+        Some(args::Command::Exfil(_)) | None => (),
+    }
+    let exfils = cli.exfils();
+    if cli.self_test {
+        return selftest::run(cli.cap_port[0], cli.downsample_power);
+    }
+    events::open(&cli.event_db)?;
+    catalog::open(&cli.catalog)?;
+    // Make sure we're the only one using the capture port and DADA buffer
+    let mut locks = cli
+        .cap_port
+        .iter()
+        .map(|port| InstanceLock::named(&cli.lock_dir, &format!("port-{port}")))
+        .collect::<Result<Vec<_>, _>>()?;
+    for exfil in &exfils {
+        if let args::Exfil::Psrdada { key, .. } = exfil {
+            locks.push(InstanceLock::named(
+                &cli.lock_dir,
+                &format!("dada-{key:x}"),
+            )?);
+        }
+    }
+    if let Some(pidfile) = &cli.pidfile {
+        locks.push(InstanceLock::at(pidfile)?);
+    }
+    if let Some(cluster_config) = config.cluster {
+        cluster::init(cluster_config)?;
+    }
+    let pipeline_config =
+        PipelineConfig::from_cli(&cli, config.fluxcal, config.network.unwrap_or(cli.network))?;
     // Setup the exit handler
-    let (sd_s, sd_cap_r) = broadcast::channel(1);
-    let sd_mon_r = sd_s.subscribe();
-    let sd_inject_r = sd_s.subscribe();
-    let sd_downsamp_r = sd_s.subscribe();
-    let sd_dump_r = sd_s.subscribe();
-    let sd_exfil_r = sd_s.subscribe();
-    let sd_trig_r = sd_s.subscribe();
-    tokio::spawn(async move {
+    let (sd_s, _) = broadcast::channel(1);
+    let sd_signal_s = sd_s.clone();
+    spawn_named("signals", async move {
         let mut term = signal(SignalKind::terminate()).unwrap();
         let mut quit = signal(SignalKind::quit()).unwrap();
         let mut int = signal(SignalKind::interrupt()).unwrap();
@@ -58,141 +99,78 @@ async fn main() -> eyre::Result<()> {
             _ = int.recv() => (),
         }
         info!("Shutting down!");
-        sd_s.send(()).unwrap()
+        sd_signal_s.send(()).unwrap()
     });
-    // Setup NTP
-    let time_sync = if !cli.skip_ntp {
-        info!("Synchronizing time with NTP");
-        let client = SntpClient::new();
-        Some(client.synchronize(cli.ntp_addr).unwrap())
-    } else {
-        info!("Skipping NTP time sync");
-        None
-    };
-    // Setup the FPGA
-    info!("Setting up SNAP");
-    let mut device = Device::new(cli.fpga_addr);
-    device.reset()?;
-    device.start_networking(&cli.mac)?;
-    let packet_start = if !cli.skip_ntp {
-        info!("Triggering the flow of packets via PPS");
-        device.trigger(&time_sync.unwrap())?
-    } else {
-        info!("Blindly triggering (no GPS), timing will be off");
-        device.blind_trigger()?
-    };
-    // Create a clone of the packet start time to hand off to the other thread
-    let psc = packet_start;
-    if cli.trig {
-        device.force_pps()?;
-    }
-    // Perform the bandpass calibration routine (if needed)
-    if let Some(requant_gain) = cli.requant_gain {
-        info!("Setting requant gains directly without bandpass calibration");
-        let gain = [requant_gain; CHANNELS];
-        device.set_requant_gains(&gain, &gain)?;
-    } else {
-        info!("Calibrating bandpass");
-        calibrate(&mut device)?;
-    }
-    // Create the dump ring
-    let ring = DumpRing::new(cli.vbuf_power);
-    // These may not need to be static
-    let (cap_s, cap_r) = CAPTURE_CHAN.split();
-    let (dump_s, dump_r) = DUMP_CHAN.split();
-    let (inject_s, inject_r) = INJECT_CHAN.split();
-    // Fast path channels
-    let (ex_s, ex_r) = channel(FAST_PATH_CHANNEL_SIZE);
-
-    // Less important channels, these don't have to be static
-    let (trig_s, trig_r) = channel(5);
-    let (stat_s, stat_r) = channel(100);
-
-    // Start the threads
-    macro_rules! thread_spawn {
-            ($(($thread_name:literal, $fcall:expr)), +) => {
-                  vec![$({let cpu = cpus.next().unwrap();
-                    std::thread::Builder::new()
-                        .name($thread_name.to_string())
-                        .spawn( move || {
-                            if !core_affinity::set_for_current(CoreId { id: cpu}) {
-                                bail!("Couldn't set core affinity on thread {}", $thread_name);
-                            }
-                            $fcall
-                        })
-                        .unwrap()}),+]
-            };
+    // SIGUSR1 starts a new output file, so schedulers can split runs by scan
+    spawn_named("split", async move {
+        let mut usr1 = signal(SignalKind::user_defined1()).unwrap();
+        while usr1.recv().await.is_some() {
+            info!("Splitting the output file");
+            exfil::request_split();
         }
-    // Spawn all the threads
-    let handles = thread_spawn!(
-        (
-            "collect",
-            monitoring::monitor_task(device, stat_r, sd_mon_r)
-        ),
-        (
-            "injection",
-            injection::pulse_injection_task(
-                cap_r,
-                inject_s,
-                Duration::from_secs(cli.injection_cadence),
-                cli.pulse_path,
-                sd_inject_r
-            )
-        ),
-        (
-            "downsample",
-            processing::downsample_task(
-                inject_r,
-                ex_s,
-                dump_s,
-                cli.downsample_power,
-                sd_downsamp_r
-            )
-        ),
-        (
-            "dump",
-            dumps::dump_task(ring, dump_r, trig_r, packet_start, cli.dump_path, sd_dump_r)
-        ),
-        (
-            "exfil",
-            match cli.exfil {
-                Some(e) => match e {
-                    args::Exfil::Psrdada { key, samples } => exfil::dada_consumer(
-                        key,
-                        ex_r,
-                        psc,
-                        2usize.pow(cli.downsample_power),
-                        samples,
-                        sd_exfil_r
-                    ),
-                    args::Exfil::Filterbank => exfil::filterbank_consumer(
-                        ex_r,
-                        psc,
-                        2usize.pow(cli.downsample_power),
-                        &cli.filterbank_path,
-                        sd_exfil_r
-                    ),
+    });
+    let sinks = exfils.into_iter().map(|e| {
+        let sink = match e {
+            args::Exfil::Psrdada {
+                key, samples, nbit, ..
+            } => Sink::Psrdada {
+                key,
+                samples,
+                nbit,
+                levels: (nbit == 8).then(|| cli.dada_levels_path.clone()),
+            },
+            args::Exfil::Filterbank {
+                rotate_secs,
+                rotate_gb,
+                dated_dirs,
+                ..
+            } => Sink::Filterbank {
+                path: cli.filterbank_path.clone(),
+                rotation: exfil::Rotation {
+                    secs: rotate_secs,
+                    bytes: rotate_gb.map(|gb| (gb * 1e9) as u64),
+                    dated_dirs,
                 },
-                None => exfil::dummy_consumer(ex_r, sd_exfil_r),
-            }
-        ),
-        (
-            "capture",
-            capture::cap_task(cli.cap_port, cap_s, stat_s, sd_cap_r)
-        )
-    );
-
-    let _ = try_join!(
-        // Start the webserver
-        tokio::spawn(monitoring::start_web_server(cli.metrics_port)?),
-        // Start the trigger watch
-        tokio::spawn(dumps::trigger_task(trig_s, cli.trig_port, sd_trig_r))
-    )?;
-
-    // Join them all when we kill the task
-    for handle in handles {
-        handle.join().unwrap()?;
-    }
+            },
+            args::Exfil::Psrfits { nsblk, .. } => Sink::Psrfits {
+                path: cli.filterbank_path.clone(),
+                nsblk,
+            },
+            args::Exfil::Dynspec { format, chunk, .. } => Sink::Dynspec {
+                path: cli.filterbank_path.clone(),
+                format,
+                chunk,
+            },
+            args::Exfil::Stream { port, .. } => Sink::Stream { port },
+            args::Exfil::Multicast {
+                group,
+                port,
+                every,
+                ttl,
+                interface,
+                ..
+            } => Sink::Multicast(exfil::MulticastConfig {
+                group,
+                port,
+                every,
+                ttl,
+                interface,
+            }),
+        };
+        match e.rebin_power() {
+            0 => sink,
+            power => Sink::Rebinned {
+                power,
+                sink: Box::new(sink),
+            },
+        }
+    });
+    sinks
+        .fold(Pipeline::new(pipeline_config), Pipeline::sink)
+        .shutdown(sd_s)
+        .run()
+        .await?;
 
+    drop(locks);
     Ok(())
 }
@@ -1,17 +1,82 @@
-use crate::fpga::Device;
-use crate::{capture::Stats, common::BLOCK_TIMEOUT};
-use actix_web::{dev::Server, get, App, HttpResponse, HttpServer, Responder};
+use crate::calibrate::{self, calibrate, request_recalibration, take_recalibration_request};
+use crate::fpga::{self, Device, GatewareVersion};
+use crate::servo::GainServo;
+use crate::{
+    capture::{Stats, PRIMARY_BOARD},
+    common::BLOCK_TIMEOUT,
+    dumps::{self, Trigger, TriggerMessage},
+};
+use crate::{
+    catalog, cluster, events, exfil, observation,
+    pipeline::{bandpass_detail, SnapRestart},
+    state, timing, weights,
+};
+use actix_web::{dev::Server, get, post, web, App, HttpResponse, HttpServer, Responder};
+use hifitime::Epoch;
 use lazy_static::lazy_static;
 use prometheus::{
-    register_gauge, register_gauge_vec, register_int_gauge, register_int_gauge_vec, Gauge,
-    GaugeVec, IntGauge, IntGaugeVec, TextEncoder,
+    register_gauge, register_gauge_vec, register_int_counter, register_int_gauge,
+    register_int_gauge_vec, Gauge, GaugeVec, IntCounter, IntGauge, IntGaugeVec, TextEncoder,
 };
-use thingbuf::mpsc::blocking::Receiver;
-use thingbuf::mpsc::errors::RecvTimeoutError;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use thingbuf::mpsc::blocking::{Receiver, Sender};
+use thingbuf::mpsc::errors::{RecvTimeoutError, TrySendError};
 use tokio::sync::broadcast;
 use tracing::{info, warn};
 
 const MONITOR_ACCUMULATIONS: u32 = 1048576; // Around 8 second at 8.192us
+/// How long without capture stats (i.e. packets) before we go check on the SNAP anyway
+const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+/// How many times in a row we can fail to reach the SNAP before trying to restart it
+const RESTART_AFTER_FAILURES: usize = 3;
+/// Least time between looks at the PPS counter for it not moving to mean the PPS is gone
+const PPS_GRACE: Duration = Duration::from_secs(2);
+
+/// A monitoring spectrum, kept for `/spectrum/history`
+#[derive(Debug, Clone, Serialize)]
+struct Spectrum {
+    /// When the accumulation finished (ISO 8601 UTC)
+    utc: String,
+    mjd_utc: f64,
+    a: Vec<f64>,
+    b: Vec<f64>,
+    stokes: Vec<f64>,
+}
+
+/// The last few monitoring spectra, oldest first
+struct SpectrumHistory {
+    len: usize,
+    spectra: VecDeque<Spectrum>,
+}
+
+static HISTORY: Mutex<SpectrumHistory> = Mutex::new(SpectrumHistory {
+    len: 0,
+    spectra: VecDeque::new(),
+});
+
+/// Keep the last `len` monitoring spectra for `/spectrum/history`
+#[allow(clippy::missing_panics_doc)]
+pub fn keep_spectra(len: usize) {
+    let mut history = HISTORY.lock().unwrap();
+    history.len = len;
+    history.spectra = VecDeque::with_capacity(len);
+}
+
+fn record_spectrum(spectrum: Spectrum) {
+    let mut history = HISTORY.lock().unwrap();
+    if history.len == 0 {
+        return;
+    }
+    if history.spectra.len() == history.len {
+        history.spectra.pop_front();
+    }
+    history.spectra.push_back(spectrum);
+}
 
 lazy_static! {
     static ref CHANNEL_GAUGE: IntGaugeVec = register_int_gauge_vec!(
@@ -30,9 +95,61 @@ lazy_static! {
         register_int_gauge!("processed_packets", "Number of packets we've processed").unwrap();
     static ref DROP_GAUGE: IntGauge =
         register_int_gauge!("dropped_packets", "Number of packets we've dropped").unwrap();
+    static ref KERNEL_DROP_GAUGE: IntGauge = register_int_gauge!(
+        "kernel_dropped_packets",
+        "Number of packets the kernel dropped because the capture socket's receive queue was full"
+    )
+    .unwrap();
     static ref SHUFFLED_GAUGE: IntGauge = register_int_gauge!(
         "shuffled_packets",
-        "Number of packets that were out of order"
+        "Number of packets that were too far out of order to put back in place"
+    )
+    .unwrap();
+    static ref REORDERED_GAUGE: IntGauge = register_int_gauge!(
+        "reordered_packets",
+        "Number of packets that were out of order but put back in place"
+    )
+    .unwrap();
+    static ref BOARD_PACKET_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "board_processed_packets",
+        "Number of packets we've processed from each board",
+        &["board"]
+    )
+    .unwrap();
+    static ref BOARD_DROP_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "board_dropped_packets",
+        "Number of packets we've dropped from each board",
+        &["board"]
+    )
+    .unwrap();
+    static ref BOARD_PACKET_RATE_GAUGE: GaugeVec = register_gauge_vec!(
+        "board_packets_per_second",
+        "Packets received per second from each board, over the last stats interval",
+        &["board"]
+    )
+    .unwrap();
+    static ref BOARD_BYTE_RATE_GAUGE: GaugeVec = register_gauge_vec!(
+        "board_bytes_per_second",
+        "Payload bytes received per second from each board, over the last stats interval",
+        &["board"]
+    )
+    .unwrap();
+    static ref BOARD_JITTER_GAUGE: GaugeVec = register_gauge_vec!(
+        "board_packet_jitter_seconds",
+        "Quantiles of how far packet arrivals strayed from the packet cadence, over the last stats interval",
+        &["board", "quantile"]
+    )
+    .unwrap();
+    static ref BOARD_MALFORMED_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "board_malformed_packets",
+        "Number of packets from each board thrown away as corrupt",
+        &["board"]
+    )
+    .unwrap();
+    static ref BOARD_RESTART_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "board_capture_restarts",
+        "Number of times each board's capture socket failed and was bound again",
+        &["board"]
     )
     .unwrap();
     static ref FFT_OVFL_GAUGE: IntGauge =
@@ -47,6 +164,42 @@ lazy_static! {
         register_gauge!("fpga_temp", "Internal FPGA temperature").unwrap();
     static ref ADC_RMS_GAUGE: GaugeVec =
         register_gauge_vec!("adc_rms", "RMS value of raw adc values", &["channel"]).unwrap();
+    static ref SNAP_RESTARTS: IntCounter = register_int_counter!(
+        "snap_restarts",
+        "Number of times we've set the SNAP back up after it rebooted or went away"
+    )
+    .unwrap();
+    static ref PPS_COUNT: IntGauge =
+        register_int_gauge!("pps_count", "PPS edges the SNAP has counted").unwrap();
+    static ref PPS_PRESENT: IntGauge = register_int_gauge!(
+        "pps_present",
+        "Whether the SNAP's PPS counter is still advancing"
+    )
+    .unwrap();
+    static ref PPS_LOSSES: IntCounter = register_int_counter!(
+        "pps_losses",
+        "Number of times the SNAP's PPS counter stopped advancing"
+    )
+    .unwrap();
+    static ref GATEWARE_INFO: IntGaugeVec = register_int_gauge_vec!(
+        "gateware_info",
+        "Always 1, labelled with the version of the gateware the SNAP is running",
+        &["version"]
+    )
+    .unwrap();
+    static ref SNAP_HEALTHY: IntGauge = register_int_gauge!(
+        "snap_healthy",
+        "Whether the SNAP is reachable and running the gateware as we set it up"
+    )
+    .unwrap();
+}
+
+/// Report the gateware version on the metrics endpoint
+pub fn record_gateware(version: &GatewareVersion) {
+    GATEWARE_INFO.reset();
+    GATEWARE_INFO
+        .with_label_values(&[&version.to_string()])
+        .set(1);
 }
 
 #[get("/metrics")]
@@ -57,6 +210,111 @@ async fn metrics() -> impl Responder {
     HttpResponse::Ok().body(body_str)
 }
 
+#[get("/cluster")]
+async fn cluster_status() -> impl Responder {
+    match cluster::combined_status() {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => HttpResponse::NotFound().body("Not running as part of a cluster"),
+    }
+}
+
+/// When the packets started, with the conversions every consumer needs done once, here
+#[get("/start_time")]
+async fn start_time() -> impl Responder {
+    match timing::time_sync() {
+        Some(sync) => HttpResponse::Ok().json(sync.start_time()),
+        None => HttpResponse::ServiceUnavailable().body("Packets haven't started yet"),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct HistoryQuery {
+    /// Only the most recent this many
+    last: Option<usize>,
+}
+
+/// The recent monitoring spectra (a, b, and stokes, normalized as in the `spectrum` metric), oldest first
+#[get("/spectrum/history")]
+async fn spectrum_history(query: web::Query<HistoryQuery>) -> impl Responder {
+    let history = HISTORY.lock().unwrap();
+    let skip = query
+        .last
+        .map_or(0, |n| history.spectra.len().saturating_sub(n));
+    let spectra: Vec<_> = history.spectra.iter().skip(skip).collect();
+    HttpResponse::Ok().json(spectra)
+}
+
+/// The band and telescope we're observing with
+#[get("/observation")]
+async fn observation_settings() -> impl Responder {
+    HttpResponse::Ok().json(observation::current())
+}
+
+/// Close the current filterbank and start a new one
+#[post("/split")]
+async fn split() -> impl Responder {
+    exfil::request_split();
+    HttpResponse::Accepted().finish()
+}
+
+/// Stop writing out data, leaving the rest of the pipeline (and the SNAP) running
+#[post("/exfil/pause")]
+async fn exfil_pause() -> impl Responder {
+    exfil::set_paused(true);
+    HttpResponse::Accepted().finish()
+}
+
+#[post("/exfil/resume")]
+async fn exfil_resume() -> impl Responder {
+    exfil::set_paused(false);
+    HttpResponse::Accepted().finish()
+}
+
+/// Dump the voltage ring, with the candidate behind it if the body is a JSON trigger message
+#[post("/trigger")]
+async fn trigger(body: web::Bytes, sender: web::Data<Sender<Trigger>>) -> impl Responder {
+    let trigger = if body.iter().all(u8::is_ascii_whitespace) {
+        Trigger::from_source("http")
+    } else {
+        match serde_json::from_slice::<TriggerMessage>(&body) {
+            Ok(msg) => Trigger::from_message(msg, "http"),
+            Err(e) => return HttpResponse::BadRequest().body(format!("Bad trigger message - {e}")),
+        }
+    };
+    info!(?trigger, "Dump triggered over HTTP");
+    cluster::fan_out(&trigger);
+    match sender.try_send(trigger) {
+        Ok(()) => HttpResponse::Accepted().finish(),
+        Err(TrySendError::Full(_)) => {
+            dumps::count_skipped_trigger("queue_full");
+            HttpResponse::ServiceUnavailable().body("Too many triggers waiting on dumps already")
+        }
+        Err(_) => HttpResponse::ServiceUnavailable().body("Not dumping anymore"),
+    }
+}
+
+/// The channels detection should zap, as a Heimdall kill file
+#[get("/kill_file")]
+async fn kill_file() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/plain")
+        .body(weights::kill_file())
+}
+
+/// Set the ADC gain (dB), which also triggers a recalibration
+#[post("/adc_gain/{db}")]
+async fn adc_gain(db: web::Path<u8>) -> impl Responder {
+    let db = db.into_inner();
+    if db > fpga::MAX_ADC_GAIN {
+        return HttpResponse::BadRequest().body(format!(
+            "ADC gain must be at most {} dB",
+            fpga::MAX_ADC_GAIN
+        ));
+    }
+    fpga::request_adc_gain(db);
+    HttpResponse::Accepted().finish()
+}
+
 fn update_spec(device: &mut Device) -> eyre::Result<()> {
     // Capture the spectrum
     let (a, b, stokes) = device.perform_both_vacc(MONITOR_ACCUMULATIONS)?;
@@ -89,15 +347,84 @@ fn update_spec(device: &mut Device) -> eyre::Result<()> {
             .with_label_values(&[&i.to_string(), "stokes"])
             .set(*v);
     }
+    let now = Epoch::now()?;
+    record_spectrum(Spectrum {
+        utc: timing::iso8601(now),
+        mjd_utc: now.to_mjd_utc_days(),
+        a: a_norm,
+        b: b_norm,
+        stokes: stokes_norm,
+    });
     Ok(())
 }
 
+/// Watches the SNAP's PPS counter, which stops if the GPS's PPS goes away. Without it, every
+/// timestamp after the next trigger (or restart) is wrong, and nothing else would tell us.
+#[derive(Debug, Default)]
+struct PpsWatch {
+    /// The count at the last look, and when that was
+    last: Option<(u32, Instant)>,
+    lost: bool,
+}
+
+impl PpsWatch {
+    fn update(&mut self, count: u32) {
+        PPS_COUNT.set(count.into());
+        let now = Instant::now();
+        let Some((last, at)) = self.last else {
+            self.last = Some((count, now));
+            return;
+        };
+        let elapsed = now - at;
+        if count < last {
+            // The SNAP was reset, so start over
+            self.last = Some((count, now));
+            return;
+        }
+        if count == last && elapsed < PPS_GRACE {
+            // Too soon to tell
+            return;
+        }
+        self.last = Some((count, now));
+        let present = count > last;
+        PPS_PRESENT.set(present.into());
+        // Only say something when it changes
+        if self.lost != present {
+            return;
+        }
+        self.lost = !present;
+        if self.lost {
+            PPS_LOSSES.inc();
+            warn!(
+                count,
+                "The SNAP's PPS counter hasn't moved in {elapsed:?}, the PPS is gone and timestamps can't be trusted"
+            );
+        } else {
+            info!(count, "The SNAP's PPS counter is moving again");
+        }
+        events::record(
+            events::Kind::Pps,
+            serde_json::json!({ "present": present, "count": count }),
+        );
+    }
+
+    /// Forget what we saw, e.g. when the SNAP's been set up again
+    fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
 pub fn monitor_task(
-    mut device: Device,
+    mut device: Option<Device>,
+    restart: Option<SnapRestart>,
     stats: Receiver<Stats>,
+    mut servo: GainServo,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     info!("Starting monitoring task!");
+    let mut last_check = Instant::now();
+    let mut failures = 0;
+    let mut pps = PpsWatch::default();
     loop {
         // Look for shutdown signal
         if shutdown.try_recv().is_ok() {
@@ -107,17 +434,121 @@ pub fn monitor_task(
         // Blocking here is ok, these are infrequent events
         match stats.recv_ref_timeout(BLOCK_TIMEOUT) {
             Ok(stat) => {
-                PACKET_GAUGE.set(stat.processed.try_into().unwrap());
-                DROP_GAUGE.set(stat.drops.try_into().unwrap());
-                SHUFFLED_GAUGE.set(stat.shuffled.try_into().unwrap());
+                let board = stat.board.to_string();
+                BOARD_PACKET_GAUGE
+                    .with_label_values(&[&board])
+                    .set(stat.processed.try_into().unwrap());
+                BOARD_DROP_GAUGE
+                    .with_label_values(&[&board])
+                    .set(stat.drops.try_into().unwrap());
+                BOARD_RESTART_GAUGE
+                    .with_label_values(&[&board])
+                    .set(stat.restarts.try_into().unwrap());
+                BOARD_MALFORMED_GAUGE
+                    .with_label_values(&[&board])
+                    .set(stat.malformed.try_into().unwrap());
+                BOARD_PACKET_RATE_GAUGE
+                    .with_label_values(&[&board])
+                    .set(stat.throughput.packets_per_second);
+                BOARD_BYTE_RATE_GAUGE
+                    .with_label_values(&[&board])
+                    .set(stat.throughput.bytes_per_second);
+                if let Some(jitter) = stat.throughput.jitter {
+                    for (quantile, j) in ["0.5", "0.99", "0.999"].iter().zip(jitter) {
+                        BOARD_JITTER_GAUGE
+                            .with_label_values(&[&board, quantile])
+                            .set(j);
+                    }
+                }
+                if stat.board == PRIMARY_BOARD {
+                    PACKET_GAUGE.set(stat.processed.try_into().unwrap());
+                    DROP_GAUGE.set(stat.drops.try_into().unwrap());
+                    KERNEL_DROP_GAUGE.set(stat.kernel_drops.into());
+                    SHUFFLED_GAUGE.set(stat.shuffled.try_into().unwrap());
+                    REORDERED_GAUGE.set(stat.reordered.try_into().unwrap());
+                    state::record_stats(&stat);
+                } else if last_check.elapsed() < STALL_TIMEOUT {
+                    // The other boards' stats don't tell us the SNAP we control is still going
+                    continue;
+                }
             }
-            Err(RecvTimeoutError::Timeout) => continue,
+            // No packets is one way to notice the SNAP went away, so check on it every so often
+            Err(RecvTimeoutError::Timeout) if last_check.elapsed() < STALL_TIMEOUT => continue,
+            Err(RecvTimeoutError::Timeout) => warn!("No packets for a while, checking on the SNAP"),
             Err(RecvTimeoutError::Closed) => break,
             Err(_) => unreachable!(),
         }
 
+        // Everything else comes from the SNAP
+        let Some(device) = device.as_mut() else {
+            continue;
+        };
+        last_check = Instant::now();
+
+        // Make sure the SNAP is still there and running as we left it
+        match device.still_running() {
+            Ok(true) => failures = 0,
+            Ok(false) => {
+                warn!("SNAP gateware restarted");
+                failures = RESTART_AFTER_FAILURES;
+            }
+            Err(e) => {
+                warn!("Couldn't reach the SNAP - {e}");
+                failures += 1;
+            }
+        }
+        SNAP_HEALTHY.set((failures == 0).into());
+        if failures > 0 {
+            if failures >= RESTART_AFTER_FAILURES {
+                if let Some(restart) = &restart {
+                    match restart.restart(device) {
+                        Ok(()) => {
+                            SNAP_RESTARTS.inc();
+                            pps.reset();
+                            failures = 0;
+                        }
+                        Err(e) => warn!("Failed to restart the SNAP - {e}"),
+                    }
+                }
+            }
+            // Nothing else is going to work until it's back
+            continue;
+        }
+
+        // Changing the input level changes the bandpass, so recalibrate after
+        if let Some(db) = fpga::take_adc_gain_request() {
+            match device.set_adc_gain(db) {
+                Ok(_) => request_recalibration(),
+                Err(e) => warn!("Failed to set ADC gain - {e}"),
+            }
+        }
+
+        // Recalibrate if we've been asked to
+        if take_recalibration_request() {
+            info!("Recalibrating bandpass");
+            match calibrate(device) {
+                Ok(_) => {
+                    servo.reset();
+                    if let Ok(now) = hifitime::Epoch::now() {
+                        catalog::record(
+                            catalog::Product::Calibration,
+                            None,
+                            now,
+                            now,
+                            bandpass_detail(device),
+                        );
+                    }
+                    events::record(
+                        events::Kind::Calibration,
+                        serde_json::json!({ "method": "bandpass", "requested": true }),
+                    );
+                }
+                Err(e) => warn!("Recalibration failed - {e}"),
+            }
+        }
+
         // Update channel data from FPGA
-        match update_spec(&mut device) {
+        match update_spec(device) {
             Ok(_) => (),
             Err(e) => warn!("SNAP Error - {e}"),
         }
@@ -128,6 +559,11 @@ pub fn monitor_task(
             Err(e) => warn!("SNAP Error - {e}, {:?}", e),
         }
 
+        match device.fpga.pps_cnt.read() {
+            Ok(v) => pps.update(u32::from(v)),
+            Err(e) => warn!("SNAP Error - {e}, {:?}", e),
+        }
+
         // match device.fpga.requant_a_overflow.read() {
         //     Ok(v) => REQUANT_OVFL_GAUGE
         //         .with_label_values(&["a"])
@@ -165,6 +601,10 @@ pub fn monitor_task(
                     rms_b = ((1.0 / (n as f64)) * rms_b).sqrt();
                     ADC_RMS_GAUGE.with_label_values(&["a"]).set(rms_a);
                     ADC_RMS_GAUGE.with_label_values(&["b"]).set(rms_b);
+                    calibrate::record_adc_rms(rms_a, rms_b);
+                    if let Err(e) = servo.update(device, rms_a, rms_b) {
+                        warn!("Gain servo failed to set gains - {e}");
+                    }
                 }
                 Err(e) => warn!("SNAP Error - {e}, {:?}", e),
             }
@@ -173,11 +613,29 @@ pub fn monitor_task(
     Ok(())
 }
 
-pub fn start_web_server(metrics_port: u16) -> eyre::Result<Server> {
+pub fn start_web_server(
+    metrics_port: u16,
+    trigger_sender: Sender<Trigger>,
+) -> eyre::Result<Server> {
     info!("Starting metrics webserver");
-    let server = HttpServer::new(|| App::new().service(metrics))
-        .bind(("0.0.0.0", metrics_port))?
-        .workers(1)
-        .run();
+    let trigger_sender = web::Data::new(trigger_sender);
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(trigger_sender.clone())
+            .service(metrics)
+            .service(cluster_status)
+            .service(adc_gain)
+            .service(kill_file)
+            .service(start_time)
+            .service(observation_settings)
+            .service(exfil_pause)
+            .service(exfil_resume)
+            .service(split)
+            .service(spectrum_history)
+            .service(trigger)
+    })
+    .bind(("0.0.0.0", metrics_port))?
+    .workers(1)
+    .run();
     Ok(server)
 }
@@ -0,0 +1,127 @@
+//! What we're observing with: the band the channels cover, and the telescope
+//!
+//! This defaults to GReX's own band, and can be set with flags or an `[observation]` table in the
+//! `--config` file (which takes precedence over the flags). A source and position given here stand
+//! in as the pointing until the telescope control system reports one.
+use crate::{common::CHANNELS, pointing::Pointing};
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use tracing::warn;
+
+/// Centre of the GReX band (MHz)
+const DEFAULT_CENTER_FREQ: f64 = 1405.0;
+/// Width of the GReX band (MHz)
+const DEFAULT_BANDWIDTH: f64 = 250.0;
+const DEFAULT_TELESCOPE: &str = "GReX";
+
+/// Which way the channels run in frequency
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Sideband {
+    /// Channel 0 is the bottom of the band
+    Upper,
+    /// Channel 0 is the top of the band (as the SNAP gives us)
+    Lower,
+}
+
+#[derive(Debug, Clone, PartialEq, Args, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Observation {
+    /// Centre frequency of the band (MHz)
+    #[arg(long, default_value_t = DEFAULT_CENTER_FREQ)]
+    pub center_freq: f64,
+    /// Width of the band (MHz)
+    #[arg(long, default_value_t = DEFAULT_BANDWIDTH)]
+    pub bandwidth: f64,
+    /// Which way the channels run in frequency
+    #[arg(long, value_enum, default_value_t = Sideband::Lower)]
+    pub sideband: Sideband,
+    /// Telescope name for the data product headers
+    #[arg(long, default_value = DEFAULT_TELESCOPE)]
+    pub telescope: String,
+    /// SIGPROC telescope ID for the filterbank headers
+    #[arg(long)]
+    pub telescope_id: Option<u32>,
+    /// Source we're pointed at, until the telescope control system says otherwise
+    #[arg(long)]
+    pub source_name: Option<String>,
+    /// J2000 right ascension of the source (degrees)
+    #[arg(long)]
+    pub source_ra: Option<f64>,
+    /// J2000 declination of the source (degrees)
+    #[arg(long)]
+    pub source_dec: Option<f64>,
+}
+
+impl Default for Observation {
+    fn default() -> Self {
+        Self {
+            center_freq: DEFAULT_CENTER_FREQ,
+            bandwidth: DEFAULT_BANDWIDTH,
+            sideband: Sideband::Lower,
+            telescope: DEFAULT_TELESCOPE.to_owned(),
+            telescope_id: None,
+            source_name: None,
+            source_ra: None,
+            source_dec: None,
+        }
+    }
+}
+
+impl Observation {
+    /// Step in frequency from one channel to the next (MHz), negative when they run downwards
+    pub fn foff(&self) -> f64 {
+        let sign = match self.sideband {
+            Sideband::Upper => 1.0,
+            Sideband::Lower => -1.0,
+        };
+        sign * self.bandwidth / CHANNELS as f64
+    }
+
+    /// Centre frequency of the first channel (MHz)
+    pub fn fch1(&self) -> f64 {
+        self.center_freq - self.foff() * (CHANNELS - 1) as f64 / 2.0
+    }
+
+    /// Centre frequency of channel `chan` (MHz)
+    pub fn freq(&self, chan: usize) -> f64 {
+        self.fch1() + chan as f64 * self.foff()
+    }
+
+    /// Centre frequency of the highest channel (MHz)
+    pub fn top_freq(&self) -> f64 {
+        self.fch1().max(self.freq(CHANNELS - 1))
+    }
+
+    /// The bandwidth, signed the same way as `foff` (as DADA and PSRFITS like it)
+    pub fn signed_bandwidth(&self) -> f64 {
+        self.foff() * CHANNELS as f64
+    }
+
+    /// The source given here as a pointing, if there was one
+    pub fn pointing(&self) -> Option<Pointing> {
+        (self.source_name.is_some() || self.source_ra.is_some() || self.source_dec.is_some()).then(
+            || Pointing {
+                source: self.source_name.clone(),
+                ra: self.source_ra,
+                dec: self.source_dec,
+                ..Default::default()
+            },
+        )
+    }
+}
+
+static OBSERVATION: OnceLock<Observation> = OnceLock::new();
+
+/// Set what we're observing with, before anything that needs to know starts
+pub fn set(observation: Observation) {
+    if OBSERVATION.set(observation).is_err() {
+        warn!("The observation was already set, keeping the first one");
+    }
+}
+
+/// What we're observing with
+pub fn current() -> &'static Observation {
+    OBSERVATION.get_or_init(Observation::default)
+}
@@ -0,0 +1,119 @@
+//! Reading and writing the SNAP's registers by name, for commissioning and debugging without a
+//! casperfpga session
+//!
+//! This talks to the board directly, so it works alongside a running pipeline, but anything written
+//! to a register the pipeline set up stays written until it next sets it.
+use crate::fpga::{self, Board, ConnectOptions};
+use casperfpga::transport::{tapcp::Tapcp, Transport};
+use clap::{Args, Subcommand};
+use eyre::{bail, eyre};
+use std::net::SocketAddr;
+use tracing::warn;
+
+/// Registers are read and written a (big-endian) 32-bit word at a time
+const WORD: usize = 4;
+
+#[derive(Debug, Args)]
+pub struct Fpga {
+    #[command(subcommand)]
+    pub command: RegisterCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum RegisterCommand {
+    /// List every register in the running design, with its address and size
+    List,
+    /// Print words of a register
+    Read {
+        /// Name of the register
+        register: String,
+        /// Byte offset into the register to start from
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+        /// Number of words to read
+        #[arg(long, default_value_t = 1)]
+        count: usize,
+    },
+    /// Write a word to a register
+    Write {
+        /// Name of the register
+        register: String,
+        /// Value to write, in decimal or hex (with a leading 0x)
+        #[arg(value_parser = parse_word)]
+        value: u32,
+        /// Byte offset into the register to write at
+        #[arg(long, default_value_t = 0)]
+        offset: usize,
+    },
+}
+
+fn parse_word(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|e| e.to_string())
+}
+
+/// Make sure `count` words at `offset` fit in `register`
+fn check_span(
+    transport: &mut Tapcp,
+    register: &str,
+    offset: usize,
+    count: usize,
+) -> eyre::Result<()> {
+    let registers = transport.listdev()?;
+    let length = registers
+        .get(register)
+        .ok_or_else(|| eyre!("The running design has no {register} register"))?
+        .length;
+    if !offset.is_multiple_of(WORD) {
+        bail!("Offsets have to be a whole number of {WORD} byte words");
+    }
+    if offset + count * WORD > length {
+        bail!("{register} is only {length} bytes long");
+    }
+    Ok(())
+}
+
+/// Run a register command against the `board` at `addr`
+pub fn run(args: &Fpga, addr: SocketAddr, board: Board) -> eyre::Result<()> {
+    let mut transport = fpga::transport(addr, board, ConnectOptions::default())?;
+    match &args.command {
+        RegisterCommand::List => {
+            let mut registers: Vec<_> = transport.listdev()?.into_iter().collect();
+            registers.sort_by_key(|(_, r)| r.addr);
+            for (name, r) in registers {
+                println!("{name:<24} {:#010x} {:>8} bytes", r.addr, r.length);
+            }
+        }
+        RegisterCommand::Read {
+            register,
+            offset,
+            count,
+        } => {
+            check_span(&mut transport, register, *offset, *count)?;
+            for i in 0..*count {
+                let at = offset + i * WORD;
+                let word: u32 = transport.read::<u32, WORD>(register, at)?;
+                println!("{register}[{at:#x}] = {word:#010x} ({word})");
+            }
+        }
+        RegisterCommand::Write {
+            register,
+            value,
+            offset,
+        } => {
+            check_span(&mut transport, register, *offset, 1)?;
+            let before: u32 = transport.read::<u32, WORD>(register, *offset)?;
+            warn!(
+                register,
+                offset, before, value, "Writing to a SNAP register"
+            );
+            transport.write::<u32, WORD>(register, *offset, value)?;
+            let after: u32 = transport.read::<u32, WORD>(register, *offset)?;
+            println!("{register}[{offset:#x}] = {after:#010x} ({after}), was {before:#010x}");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,1358 @@
+//! Building and running the capture/processing/exfil graph
+//!
+//! This is everything `main` does once the CLI has been dealt with, so other binaries and
+//! integration tests can embed T0 (and swap in their own sinks) with
+//!
+//! ```ignore
+//! Pipeline::new(config).sink(Sink::Custom(Box::new(my_sink))).run().await?;
+//! ```
+//!
+//! The fast path channels are statics, so a pipeline can only be run once per process.
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::{
+    args::{parse_mac, Cli},
+    calibrate::{self, calibrate},
+    candidates::{self, CandidateFilter},
+    capture, catalog, cluster,
+    common::{Payload, Spectrum, Stokes, StokesFull, CHANNELS, PACKET_CADENCE},
+    discover,
+    dumps::{self, stream::DumpTarget, DumpFormat, DumpLimits, DumpRing},
+    events, exfil,
+    fluxcal::{self, FluxCal, FluxCalConfig},
+    fpga::{self, Board, Device},
+    gps::{self, GpsSource},
+    history::{self, History},
+    injection, irq, monitoring, pointing, processing,
+    rfi::Occupancy,
+    servo::{GainServo, ServoMode},
+    simulate::Simulate,
+    state::{self, State},
+    t2,
+    timing::{self, TimeSource, TimeSync},
+    weights::{self, BandMask},
+};
+use core_affinity::CoreId;
+use eyre::{bail, eyre};
+use hifitime::Epoch;
+use rsntp::SntpClient;
+use serde_json::json;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::Duration,
+};
+use thingbuf::mpsc::blocking::{channel, Receiver, StaticChannel};
+use tokio::{sync::broadcast, task::JoinHandle, try_join};
+use tracing::{error, info, info_span, warn, Instrument};
+
+// Setup the static channels
+const FAST_PATH_CHANNEL_SIZE: usize = 4096;
+/// How often to save the runtime state for `--resume`
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+/// How long to let the pipeline drain after a replay runs out, before shutting it down
+const REPLAY_DRAIN: Duration = Duration::from_secs(2);
+static CAPTURE_CHAN: StaticChannel<Payload, FAST_PATH_CHANNEL_SIZE> = StaticChannel::new();
+static INJECT_CHAN: StaticChannel<Payload, FAST_PATH_CHANNEL_SIZE> = StaticChannel::new();
+static DOWNSAMP_CHAN: StaticChannel<Payload, FAST_PATH_CHANNEL_SIZE> = StaticChannel::new();
+static DUMP_CHAN: StaticChannel<Payload, FAST_PATH_CHANNEL_SIZE> = StaticChannel::new();
+/// Most boards we can capture at once
+const MAX_BOARDS: usize = 4;
+/// Where the boards past the first send their payloads, each to a voltage ring of its own
+static BOARD_CHANS: [StaticChannel<Payload, FAST_PATH_CHANNEL_SIZE>; MAX_BOARDS - 1] =
+    [const { StaticChannel::new() }; MAX_BOARDS - 1];
+
+/// Spawn a thread running `task`, pinned to `cpu`, logging if it fails
+fn spawn_pinned<F>(
+    name: String,
+    cpu: usize,
+    task: F,
+) -> std::io::Result<std::thread::JoinHandle<eyre::Result<()>>>
+where
+    F: FnOnce() -> eyre::Result<()> + Send + 'static,
+{
+    std::thread::Builder::new()
+        .name(name.clone())
+        .spawn(move || {
+            if !core_affinity::set_for_current(CoreId { id: cpu }) {
+                bail!("Couldn't set core affinity on thread {name}");
+            }
+            let res = task();
+            if let Err(e) = &res {
+                error!("{name} task failed - {e}");
+            }
+            res
+        })
+}
+
+/// Spawn an async task with a name, which shows up in the logs and in tokio-console
+pub fn spawn_named<F>(name: &'static str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    let future = future.instrument(info_span!("task", name));
+    #[cfg(tokio_unstable)]
+    return tokio::task::Builder::new()
+        .name(name)
+        .spawn(future)
+        .expect("Failed to spawn task");
+    #[cfg(not(tokio_unstable))]
+    tokio::spawn(future)
+}
+
+/// A user-provided consumer of the downsampled Stokes, given the time of the first packet
+pub type CustomSink =
+    Box<dyn FnOnce(Receiver<Stokes>, Epoch, broadcast::Receiver<()>) -> eyre::Result<()> + Send>;
+
+/// Where the downsampled Stokes go
+pub enum Sink {
+    /// Stream to a PSRDADA buffer with the given key, in windows of `samples` of `nbit` (8 or 32) bits,
+    /// recording the levels of 8-bit blocks in a file per stream in the directory `levels`
+    Psrdada {
+        key: i32,
+        samples: usize,
+        nbit: u32,
+        levels: Option<PathBuf>,
+    },
+    /// Write a filterbank in the given directory, split into new files as `rotation` says
+    Filterbank {
+        path: PathBuf,
+        rotation: exfil::Rotation,
+    },
+    /// Write search-mode PSRFITS in the given directory, with `nsblk` spectra per row
+    Psrfits {
+        path: PathBuf,
+        nsblk: usize,
+    },
+    /// Write the dynamic spectrum in the given directory, in chunks of `chunk` spectra
+    Dynspec {
+        path: PathBuf,
+        format: exfil::DynspecFormat,
+        chunk: usize,
+    },
+    /// Publish them over TCP to subscribers on the given port
+    Stream {
+        port: u16,
+    },
+    /// Multicast the occasional one over UDP
+    Multicast(exfil::MulticastConfig),
+    /// Drop them on the floor
+    Discard,
+    Custom(CustomSink),
+    /// Average another 2^`power` spectra together before handing them to `sink`, so it can be
+    /// coarser than the detection path
+    Rebinned {
+        power: u32,
+        sink: Box<Sink>,
+    },
+}
+
+impl Sink {
+    /// What to call the sink's thread
+    fn name(&self) -> &'static str {
+        match self {
+            Sink::Psrdada { .. } => "psrdada",
+            Sink::Filterbank { .. } => "filterbank",
+            Sink::Psrfits { .. } => "psrfits",
+            Sink::Dynspec { .. } => "dynspec",
+            Sink::Stream { .. } => "stream",
+            Sink::Multicast(_) => "multicast",
+            Sink::Discard => "discard",
+            Sink::Custom(_) => "custom",
+            Sink::Rebinned { sink, .. } => sink.name(),
+        }
+    }
+
+    /// The sink under any rebinning, and how many spectra it averages together
+    fn unwrap_rebin(self) -> (Sink, usize) {
+        match self {
+            Sink::Rebinned { power, sink } => {
+                let (sink, factor) = sink.unwrap_rebin();
+                (sink, factor * 2usize.pow(power))
+            }
+            sink => (sink, 1),
+        }
+    }
+
+    fn is_custom(&self) -> bool {
+        match self {
+            Sink::Custom(_) => true,
+            Sink::Rebinned { sink, .. } => sink.is_custom(),
+            _ => false,
+        }
+    }
+}
+
+/// Spectra the sinks can take: any for our own, but only Stokes I for custom ones
+trait SinkSpectrum: Spectrum {
+    fn custom(
+        consumer: CustomSink,
+        receiver: Receiver<Self>,
+        packet_start: Epoch,
+        shutdown: broadcast::Receiver<()>,
+    ) -> eyre::Result<()>;
+}
+
+impl SinkSpectrum for Stokes {
+    fn custom(
+        consumer: CustomSink,
+        receiver: Receiver<Self>,
+        packet_start: Epoch,
+        shutdown: broadcast::Receiver<()>,
+    ) -> eyre::Result<()> {
+        consumer(receiver, packet_start, shutdown)
+    }
+}
+
+impl SinkSpectrum for StokesFull {
+    fn custom(
+        _: CustomSink,
+        _: Receiver<Self>,
+        _: Epoch,
+        _: broadcast::Receiver<()>,
+    ) -> eyre::Result<()> {
+        bail!("Custom sinks only take Stokes I")
+    }
+}
+
+/// Work for a thread of its own
+type Task = Box<dyn FnOnce() -> eyre::Result<()> + Send>;
+
+/// What the exfil thread does with the spectra from `receiver`: run the sink itself if there's only
+/// one at full resolution, or otherwise tee them out (averaged as each sink wants) to the sinks,
+/// which are returned to run on their own threads
+fn exfil_tasks<S: SinkSpectrum>(
+    sinks: Vec<Sink>,
+    receiver: Receiver<S>,
+    packet_start: Epoch,
+    downsample_factor: usize,
+    shutdown: &broadcast::Sender<()>,
+) -> (Task, Vec<(&'static str, Task)>) {
+    let mut sinks: Vec<_> = sinks.into_iter().map(Sink::unwrap_rebin).collect();
+    let mut labels = sink_labels(&sinks);
+    if let [(_, 1)] = sinks[..] {
+        let (sink, _) = sinks.pop().unwrap();
+        let label = labels.pop().unwrap();
+        let sd = shutdown.subscribe();
+        return (
+            Box::new(move || consume(sink, label, receiver, packet_start, downsample_factor, sd)),
+            vec![],
+        );
+    }
+    let (senders, tasks) = sinks
+        .into_iter()
+        .zip(labels)
+        .map(|((sink, factor), label)| {
+            let (s, r) = channel(FAST_PATH_CHANNEL_SIZE);
+            let sd = shutdown.subscribe();
+            let name = sink.name();
+            let task: Task = Box::new(move || {
+                consume(sink, label, r, packet_start, downsample_factor * factor, sd)
+            });
+            ((s, factor), (name, task))
+        })
+        .unzip();
+    let sd = shutdown.subscribe();
+    (
+        Box::new(move || exfil::tee_task(receiver, senders, sd)),
+        tasks,
+    )
+}
+
+/// A name for each sink, which is its kind unless there's more than one of that kind, in which case
+/// it's numbered (e.g. `filterbank0` and `filterbank1`)
+fn sink_labels(sinks: &[(Sink, usize)]) -> Vec<String> {
+    let kinds: Vec<_> = sinks.iter().map(|(sink, _)| sink.name()).collect();
+    kinds
+        .iter()
+        .enumerate()
+        .map(|(i, kind)| {
+            if kinds.iter().filter(|k| *k == kind).count() == 1 {
+                kind.to_string()
+            } else {
+                let n = kinds[..i].iter().filter(|k| *k == kind).count();
+                format!("{kind}{n}")
+            }
+        })
+        .collect()
+}
+
+/// Run a sink, known as `label`, on the downsampled spectra coming in on `receiver`
+fn consume<S: SinkSpectrum>(
+    sink: Sink,
+    label: String,
+    receiver: Receiver<S>,
+    packet_start: Epoch,
+    downsample_factor: usize,
+    shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    match sink {
+        Sink::Psrdada {
+            key,
+            samples,
+            nbit,
+            levels,
+        } => exfil::dada_consumer(
+            key,
+            receiver,
+            packet_start,
+            downsample_factor,
+            samples,
+            nbit,
+            levels,
+            shutdown,
+        ),
+        Sink::Filterbank { path, rotation } => exfil::filterbank_consumer(
+            &label,
+            receiver,
+            packet_start,
+            downsample_factor,
+            &path,
+            rotation,
+            shutdown,
+        ),
+        Sink::Psrfits { path, nsblk } => exfil::psrfits_consumer(
+            &label,
+            receiver,
+            packet_start,
+            downsample_factor,
+            nsblk,
+            &path,
+            shutdown,
+        ),
+        Sink::Dynspec {
+            path,
+            format,
+            chunk,
+        } => exfil::dynspec_consumer(
+            receiver,
+            packet_start,
+            downsample_factor,
+            format,
+            chunk,
+            &path,
+            shutdown,
+        ),
+        Sink::Stream { port } => {
+            exfil::stream_consumer(receiver, packet_start, downsample_factor, port, shutdown)
+        }
+        Sink::Multicast(config) => {
+            exfil::multicast_consumer(receiver, packet_start, downsample_factor, config, shutdown)
+        }
+        Sink::Discard => exfil::dummy_consumer(receiver, downsample_factor, shutdown),
+        Sink::Custom(consumer) => S::custom(consumer, receiver, packet_start, shutdown),
+        Sink::Rebinned { .. } => unreachable!("Rebinning is done by the tee"),
+    }
+}
+
+/// The SNAP board and how to set it up
+#[derive(Debug, Clone)]
+pub struct SnapConfig {
+    /// Socket address of the SNAP Board
+    pub addr: SocketAddr,
+    /// What kind of board it is
+    pub board: Board,
+    /// How hard to try reaching it
+    pub connect: fpga::ConnectOptions,
+    /// MAC address of the interface which data comes in on (used in ARP)
+    pub mac: [u8; 6],
+    /// Addresses of the SNAP's end of the link
+    pub network: fpga::Network,
+    /// Force a pps trigger
+    pub force_pps: bool,
+    /// Manual requantization gain (disables bandpass flattening)
+    pub requant_gain: Option<u16>,
+    /// Gateware revision the SNAP is running, checked against the registers we use
+    pub fpg_path: Option<PathBuf>,
+    /// Gateware to program the SNAP with first
+    pub program: Option<PathBuf>,
+    /// ADC coarse gain (dB), left alone if unset
+    pub adc_gain: Option<u8>,
+    pub gain_servo: ServoMode,
+    pub gain_servo_window: f64,
+    pub gain_servo_step: f64,
+}
+
+/// The MAC address of the capture interface, for the SNAP's ARP entry: `mac` if given, or otherwise
+/// read from the interface `nic`
+fn capture_mac(mac: Option<[u8; 6]>, nic: Option<&str>) -> eyre::Result<[u8; 6]> {
+    let from_nic = nic
+        .map(|nic| {
+            let path = Path::new("/sys/class/net").join(nic).join("address");
+            let address = std::fs::read_to_string(&path)
+                .map_err(|e| eyre!("Couldn't read the MAC address of {nic} - {e}"))?;
+            parse_mac(address.trim()).map_err(|e| eyre!("{nic} has a bad MAC address - {e}"))
+        })
+        .transpose()?;
+    let mac = match (mac, from_nic) {
+        (Some(mac), Some(found)) if mac != found => {
+            warn!(
+                nic = nic.unwrap_or_default(),
+                "--mac doesn't match the interface's own MAC address, using --mac"
+            );
+            mac
+        }
+        (Some(mac), _) | (None, Some(mac)) => mac,
+        (None, None) => bail!("--mac (or --nic to read it from) is required to run the pipeline"),
+    };
+    // Some switches drop frames sent to the null address rather than flooding them
+    if mac == [0; 6] {
+        bail!("The capture interface's MAC address can't be 00:00:00:00:00:00");
+    }
+    Ok(mac)
+}
+
+/// Everything needed to build the pipeline
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    /// CPU cores to which we'll pin the threads
+    pub core_range: RangeInclusive<usize>,
+    /// Interface the data comes in on (to check its IRQ placement) and if we should pin its IRQs
+    pub nic: Option<String>,
+    pub pin_irqs: bool,
+    /// The SNAP we're getting data from. Without one, we just listen on the capture port
+    /// (e.g. for simulated packets) and take the first packet to have started now.
+    pub snap: Option<SnapConfig>,
+    /// Capture port of each board, the first is the SNAP we control
+    pub cap_ports: Vec<u16>,
+    /// Most packets to receive per syscall
+    pub capture_batch: usize,
+    /// Packets past a gap to wait for it to fill
+    pub reorder_window: u64,
+    /// What to fill in dropped packets with
+    pub drop_fill: capture::FillPolicy,
+    /// Furthest ahead of the expected count to believe a packet, if we're checking
+    pub max_count_jump: Option<u64>,
+    /// How to set up the capture sockets
+    pub socket: capture::SocketOptions,
+    /// Where capture gets its packets
+    pub input: capture::Input,
+    /// Where to record the raw packets, if anywhere
+    pub record_raw: Option<capture::RecordConfig>,
+    /// Where to write the raw voltages as VDIF, if anywhere
+    pub vdif: Option<exfil::VdifConfig>,
+    pub trig_port: u16,
+    pub cand_port: Option<u16>,
+    pub cand_filter: CandidateFilter,
+    pub t2_addr: Option<SocketAddr>,
+    pub t2_port: u16,
+    pub pointing_port: Option<u16>,
+    pub metrics_port: u16,
+    /// Number of monitoring spectra to keep
+    pub spectrum_history: usize,
+    /// Control plane port and the configuration it reports
+    #[cfg(feature = "grpc")]
+    pub control: Option<(u16, grpc::proto::Configuration)>,
+    pub downsample_power: u32,
+    /// Compute and exfil full Stokes (IQUV) rather than just Stokes I
+    pub full_stokes: bool,
+    pub vbuf_power: u32,
+    pub dump_path: PathBuf,
+    /// Hold triggers until the voltage ring has wrapped once
+    pub defer_early_dumps: bool,
+    /// Seconds either side of a candidate to dump, unless the trigger says otherwise
+    pub dump_window: Option<f64>,
+    /// Deflate level of the voltage dumps (0 for none)
+    pub dump_compression: u32,
+    pub dump_format: DumpFormat,
+    /// How often triggers may turn into dumps
+    pub dump_limits: DumpLimits,
+    /// Storage node to send dumps on to
+    pub dump_target: Option<DumpTarget>,
+    /// Seconds of Stokes history to keep (none if unset), downsampled by another 2^power
+    pub history_seconds: Option<f64>,
+    pub history_downsample_power: u32,
+    pub history_trig_port: u16,
+    pub ntp_addr: String,
+    pub ntp_interval: Duration,
+    /// How often to check the packet counts against the host clock, and how far off to warn at
+    pub drift_interval: Duration,
+    pub drift_threshold: Duration,
+    pub skip_ntp: bool,
+    pub gps: Option<GpsSource>,
+    pub injection_cadence: Duration,
+    /// Whether to start with the regular injections on
+    pub inject: bool,
+    pub pulse_path: PathBuf,
+    pub injection_polarization: injection::Polarization,
+    pub rfi_path: Option<PathBuf>,
+    pub rfi_threshold: f32,
+    /// Channels zeroed at the edges of the band
+    pub band_mask: BandMask,
+    /// Where to keep the Heimdall kill file
+    pub kill_file: Option<PathBuf>,
+    /// Where to save the runtime state (not saved if unset)
+    pub state_file: Option<PathBuf>,
+    /// Pick up from the saved state rather than setting the SNAP up from scratch
+    pub resume: bool,
+    pub fluxcal: Option<FluxCalConfig>,
+    pub fluxcal_table: Option<PathBuf>,
+}
+
+impl PipelineConfig {
+    /// The pipeline as configured on the command line (and the flux calibration from the config file)
+    pub fn from_cli(
+        cli: &Cli,
+        fluxcal: Option<FluxCalConfig>,
+        network: fpga::Network,
+    ) -> eyre::Result<Self> {
+        cli.injection_polarization.validate()?;
+        let input = match (&cli.replay, cli.capture_backend) {
+            _ if cli.simulate => capture::Input::Simulate(Simulate {
+                pulse_dm: cli.simulate_pulse_dm,
+                ..Default::default()
+            }),
+            (Some(path), _) => capture::Input::Replay(capture::ReplayConfig {
+                path: path.clone(),
+                realtime: !cli.replay_fast,
+            }),
+            (None, capture::CaptureBackend::Socket) => capture::Input::Socket,
+            (None, capture::CaptureBackend::Uring) => capture::Input::Uring,
+            (None, capture::CaptureBackend::Xdp) => capture::Input::Xdp {
+                interface: cli.nic.clone().ok_or_else(|| {
+                    eyre!("--capture-backend xdp needs the interface to capture on from --nic")
+                })?,
+                config: cli.xdp.clone(),
+            },
+        };
+        if cli.cap_port.len() > 1
+            && !matches!(input, capture::Input::Socket | capture::Input::Uring)
+        {
+            bail!("Capturing more than one board needs the socket or io_uring capture backend");
+        }
+        if cli.cap_port.len() > MAX_BOARDS {
+            bail!("Can't capture more than {MAX_BOARDS} boards");
+        }
+        // Replays and simulations don't need (or touch) a SNAP
+        let snap = if cli.replay.is_some() || cli.simulate {
+            None
+        } else {
+            Some(SnapConfig {
+                addr: match cli.fpga_addr {
+                    Some(addr) => addr,
+                    None => discover::pick(&cli.discover_subnet, cli.board, cli.program.is_some())?,
+                },
+                board: cli.board,
+                connect: fpga::ConnectOptions {
+                    attempts: cli.fpga_connect_attempts,
+                    timeout: Duration::try_from_secs_f64(cli.fpga_connect_timeout).map_err(
+                        |_| eyre!("--fpga-connect-timeout must be a positive number of seconds"),
+                    )?,
+                },
+                mac: capture_mac(cli.mac, cli.nic.as_deref())?,
+                network,
+                force_pps: cli.trig,
+                requant_gain: cli.requant_gain,
+                adc_gain: cli.adc_gain,
+                // Whatever we program is what it'll be running
+                fpg_path: cli.fpg_path.clone().or_else(|| cli.program.clone()),
+                program: cli.program.clone(),
+                gain_servo: cli.gain_servo,
+                gain_servo_window: cli.gain_servo_window,
+                gain_servo_step: cli.gain_servo_step,
+            })
+        };
+        // Report the SNAP we actually found
+        #[cfg(feature = "grpc")]
+        let fpga_addr = snap
+            .as_ref()
+            .map(|s| s.addr.to_string())
+            .unwrap_or_default();
+        Ok(Self {
+            core_range: cli.core_range.clone(),
+            nic: cli.nic.clone(),
+            pin_irqs: cli.pin_irqs,
+            snap,
+            cap_ports: cli.cap_port.clone(),
+            capture_batch: cli.capture_batch,
+            reorder_window: cli.reorder_window,
+            drop_fill: cli.drop_fill,
+            max_count_jump: cli.max_count_jump,
+            socket: cli.socket.clone(),
+            input,
+            record_raw: cli.record_raw.clone().map(|path| capture::RecordConfig {
+                path,
+                file_size: cli.record_raw_file_size * 1024 * 1024,
+                keep: cli.record_raw_keep,
+            }),
+            vdif: cli.vdif_path.clone().map(|path| exfil::VdifConfig {
+                path,
+                seconds: cli.vdif_seconds,
+                station: cli.vdif_station,
+            }),
+            trig_port: cli.trig_port,
+            cand_port: cli.cand_port,
+            cand_filter: CandidateFilter {
+                min_snr: cli.cand_min_snr,
+                min_dm: cli.cand_min_dm,
+                max_filter: cli.cand_max_filter,
+            },
+            t2_addr: cli.t2_addr,
+            t2_port: cli.t2_port,
+            pointing_port: cli.pointing_port,
+            metrics_port: cli.metrics_port,
+            spectrum_history: cli.spectrum_history,
+            #[cfg(feature = "grpc")]
+            control: Some((
+                cli.grpc_port,
+                grpc::proto::Configuration {
+                    fpga_addr,
+                    ..grpc::proto::Configuration::from_cli(cli)?
+                },
+            )),
+            downsample_power: cli.downsample_power,
+            full_stokes: cli.full_stokes,
+            vbuf_power: cli.vbuf_power,
+            dump_path: cli.dump_path.clone(),
+            defer_early_dumps: cli.defer_early_dumps,
+            dump_window: cli.dump_window,
+            dump_compression: cli.dump_compression,
+            dump_format: cli.dump_format,
+            dump_target: cli.dump_target.clone(),
+            dump_limits: DumpLimits {
+                coalesce: cli.coalesce_triggers,
+                per_hour: cli.max_dumps_per_hour,
+            },
+            history_seconds: cli.history_seconds,
+            history_downsample_power: cli.history_downsample_power,
+            history_trig_port: cli.history_trig_port,
+            ntp_addr: cli.ntp_addr.clone(),
+            ntp_interval: Duration::from_secs(cli.ntp_interval),
+            drift_interval: Duration::from_secs(cli.drift_interval),
+            drift_threshold: Duration::try_from_secs_f64(cli.drift_threshold_ms / 1e3)
+                .map_err(|_| eyre!("--drift-threshold-ms must be a positive number"))?,
+            skip_ntp: cli.skip_ntp,
+            gps: cli.gps.clone(),
+            injection_cadence: Duration::from_secs(cli.injection_cadence),
+            inject: !cli.no_injection,
+            pulse_path: cli.pulse_path.clone(),
+            injection_polarization: cli.injection_polarization,
+            rfi_path: cli.rfi_path.clone(),
+            rfi_threshold: cli.rfi_threshold,
+            band_mask: cli.band_mask.clone(),
+            kill_file: cli.kill_file.clone(),
+            state_file: Some(cli.state_file.clone()),
+            resume: cli.resume,
+            fluxcal,
+            fluxcal_table: cli.fluxcal_table.clone(),
+        })
+    }
+}
+
+pub struct Pipeline {
+    config: PipelineConfig,
+    sinks: Vec<Sink>,
+    shutdown: Option<broadcast::Sender<()>>,
+}
+
+impl Pipeline {
+    pub fn new(config: PipelineConfig) -> Self {
+        Self {
+            config,
+            sinks: vec![],
+            shutdown: None,
+        }
+    }
+
+    /// Where to send the downsampled Stokes (discarded by default). Give more than one to send
+    /// each a copy, with every sink on a thread (and core) of its own.
+    pub fn sink(mut self, sink: Sink) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    /// Stop the pipeline when something is sent on this channel (otherwise it runs forever)
+    pub fn shutdown(mut self, shutdown: broadcast::Sender<()>) -> Self {
+        self.shutdown = Some(shutdown);
+        self
+    }
+
+    /// Set up the SNAP (if we have one), start every task, and wait for them to finish
+    pub async fn run(self) -> eyre::Result<()> {
+        let Pipeline {
+            config: c,
+            mut sinks,
+            shutdown,
+        } = self;
+        if sinks.is_empty() {
+            sinks.push(Sink::Discard);
+        }
+        weights::set_band_mask(c.band_mask.clone());
+        if c.full_stokes && sinks.iter().any(Sink::is_custom) {
+            bail!("Custom sinks only take Stokes I, so can't be used with full Stokes");
+        }
+        let mut cpus = c.core_range.clone();
+        // Setup the exit handler
+        let sd_s = shutdown.unwrap_or_else(|| broadcast::channel(1).0);
+        let sd_cap_r = sd_s.subscribe();
+        let sd_mon_r = sd_s.subscribe();
+        let sd_inject_r = sd_s.subscribe();
+        let sd_fanout_r = sd_s.subscribe();
+        let sd_downsamp_r = sd_s.subscribe();
+        let sd_dump_r = sd_s.subscribe();
+        let sd_trig_r = sd_s.subscribe();
+        let sd_t2_r = sd_s.subscribe();
+        let sd_cand_r = sd_s.subscribe();
+        let sd_ntp_r = sd_s.subscribe();
+        let sd_drift_r = sd_s.subscribe();
+        let sd_cluster_r = sd_s.subscribe();
+        let sd_pointing_r = sd_s.subscribe();
+        let sd_history_r = sd_s.subscribe();
+        let sd_hist_trig_r = sd_s.subscribe();
+        let sd_state_r = sd_s.subscribe();
+        #[cfg(feature = "grpc")]
+        let sd_grpc_r = sd_s.subscribe();
+
+        let resumed = if c.resume {
+            let path = c
+                .state_file
+                .as_deref()
+                .ok_or_else(|| eyre!("Can't resume without a state file"))?;
+            Some(state::resume(path)?)
+        } else {
+            None
+        };
+        let mut restart = None;
+        let (device, packet_start) = match &c.snap {
+            Some(snap) => {
+                let (device, packet_start) = match resumed {
+                    Some(saved) => resume_snap(snap, saved)?,
+                    None => setup_snap(&c, snap)?,
+                };
+                monitoring::record_gateware(device.gateware());
+                restart = Some(SnapRestart {
+                    config: c.clone(),
+                    snap: snap.clone(),
+                    packet_start,
+                });
+                (Some(device), packet_start)
+            }
+            None => {
+                info!("Running without a SNAP, timing will be off");
+                let packet_start = Epoch::now()?;
+                timing::set_time_sync(TimeSync {
+                    source: TimeSource::Blind,
+                    uncertainty: None,
+                    trigger_epoch: packet_start,
+                });
+                (None, packet_start)
+            }
+        };
+        // Create a clone of the packet start time to hand off to the other thread
+        let psc = packet_start;
+        // Start tracking RFI (if we're keeping records)
+        let rfi = c
+            .rfi_path
+            .as_deref()
+            .map(|p| Occupancy::new(p, c.rfi_threshold, 2usize.pow(c.downsample_power)))
+            .transpose()?;
+        if let Some(path) = &c.kill_file {
+            weights::keep_kill_file(path)?;
+        }
+        monitoring::keep_spectra(c.spectrum_history);
+        if !c.inject {
+            injection::set_enabled(false);
+        }
+        // Measure and/or apply a flux calibration
+        let fluxcal = c
+            .fluxcal
+            .clone()
+            .map(|fc| FluxCal::new(fc, &packet_start))
+            .transpose()?;
+        if let Some(table) = &c.fluxcal_table {
+            fluxcal::load_table(table)?;
+        }
+        // Create the dump ring
+        dumps::set_run_info(dumps::RunInfo {
+            gateware: c.snap.as_ref().map(|snap| {
+                snap.fpg_path.as_ref().map_or_else(
+                    || "grex_gateware.fpg (built in)".to_string(),
+                    |p| p.display().to_string(),
+                )
+            }),
+            downsample_power: c.downsample_power,
+        });
+        let ring = DumpRing::new(c.vbuf_power)
+            .with_compression(c.dump_compression)
+            .with_format(c.dump_format);
+        // These may not need to be static
+        let (cap_s, cap_r) = CAPTURE_CHAN.split();
+        let (dump_s, dump_r) = DUMP_CHAN.split();
+        let (inject_s, inject_r) = INJECT_CHAN.split();
+        let (downsamp_s, downsamp_r) = DOWNSAMP_CHAN.split();
+        // Fast path channels, with room only in the one for the Stokes we're computing
+        let (ex_size, ex_full_size) = if c.full_stokes {
+            (1, FAST_PATH_CHANNEL_SIZE)
+        } else {
+            (FAST_PATH_CHANNEL_SIZE, 1)
+        };
+        let (ex_s, ex_r) = channel::<Stokes>(ex_size);
+        let (ex_full_s, ex_full_r) = channel::<StokesFull>(ex_full_size);
+
+        // Less important channels, these don't have to be static
+        let (trig_s, trig_r) = channel(5);
+        let (stat_s, stat_r) = channel(100);
+        let (hist_s, hist_r) = channel(1024);
+        let (hist_trig_s, hist_trig_r) = channel(1);
+        let (record_s, record_r) = channel(capture::RECORD_CHANNEL_SIZE);
+        let recorder = c.record_raw.is_some().then_some(record_s);
+        let (vdif_s, vdif_r) = channel(exfil::VDIF_CHANNEL_SIZE);
+        let mut taps: Vec<(&'static str, Box<dyn processing::Tap>)> =
+            vec![("dumps", Box::new(dump_s))];
+        if c.vdif.is_some() {
+            taps.push(("vdif", Box::new(vdif_s)));
+        }
+
+        // Keep a longer, coarser record of the Stokes (if asked to)
+        let history_factor = 2usize.pow(c.history_downsample_power);
+        let history = c
+            .history_seconds
+            .map(|s| History::new(s, 2usize.pow(c.downsample_power) * history_factor));
+        let history_tap = history
+            .is_some()
+            .then(|| history::Tap::new(hist_s, history_factor));
+
+        let servo = match &c.snap {
+            Some(snap) => GainServo::new(
+                snap.gain_servo,
+                snap.gain_servo_window,
+                snap.gain_servo_step,
+            ),
+            None => GainServo::new(ServoMode::Off, 0.0, 0.0),
+        };
+        let downsample_factor = 2usize.pow(c.downsample_power);
+        let (exfil_task, tee_tasks) = if c.full_stokes {
+            exfil_tasks(sinks, ex_full_r, psc, downsample_factor, &sd_s)
+        } else {
+            exfil_tasks(sinks, ex_r, psc, downsample_factor, &sd_s)
+        };
+        let dump_path = c.dump_path.clone();
+        let history_path = c.dump_path.clone();
+        let pulse_path = c.pulse_path.clone();
+        let capture_config = |board: usize, port: u16, input| capture::CaptureConfig {
+            board: board as u8,
+            port,
+            batch: c.capture_batch,
+            reorder_window: c.reorder_window,
+            fill: c.drop_fill,
+            max_count_jump: c.max_count_jump,
+            socket: c.socket.clone(),
+            input,
+        };
+        let primary_capture = capture_config(0, c.cap_ports[0], c.input.clone());
+        // Every other board fills a voltage ring of its own, dumped on the same triggers as ours
+        let mut followers = vec![];
+        let mut board_dumps = vec![];
+        for board in 1..c.cap_ports.len() {
+            let (board_trig_s, board_trig_r) = channel(5);
+            followers.push(board_trig_s);
+            let (board_s, board_r) = BOARD_CHANS[board - 1].split();
+            let ring = DumpRing::new(c.vbuf_power)
+                .with_compression(c.dump_compression)
+                .with_format(c.dump_format)
+                .with_board(board as u8);
+            let (path, target, sd_r) =
+                (c.dump_path.clone(), c.dump_target.clone(), sd_s.subscribe());
+            let (defer, window, limits) = (c.defer_early_dumps, c.dump_window, c.dump_limits);
+            let dump = move || {
+                dumps::dump_task(
+                    ring,
+                    board_r,
+                    board_trig_r,
+                    vec![],
+                    packet_start,
+                    path,
+                    defer,
+                    window,
+                    limits,
+                    target,
+                    sd_r,
+                )
+            };
+            board_dumps.push((board, board_s, dump));
+        }
+        let extra_stats = stat_s.clone();
+        let extra_recorder = recorder.clone();
+        // The other boards come in the same way as the primary
+        let extra_input = c.input.clone();
+        let replaying = matches!(c.input, capture::Input::Replay(_));
+        let sd_replay_s = sd_s.clone();
+
+        // Start the threads, keeping track of where they went
+        let mut placement = vec![];
+        macro_rules! thread_spawn {
+            ($(($thread_name:literal, $fcall:expr)), +) => {
+                  vec![$({let cpu = cpus.next().ok_or_else(|| {
+                        eyre!("Not enough cores in the core range for the {} thread", $thread_name)
+                    })?;
+                    placement.push(($thread_name, cpu));
+                    std::thread::Builder::new()
+                        .name($thread_name.to_string())
+                        .spawn( move || {
+                            if !core_affinity::set_for_current(CoreId { id: cpu}) {
+                                bail!("Couldn't set core affinity on thread {}", $thread_name);
+                            }
+                            let res = $fcall;
+                            if let Err(e) = &res {
+                                error!("{} task failed - {}", $thread_name, e);
+                                dumps::request_salvage();
+                            }
+                            res
+                        })?}),+]
+            };
+        }
+        // Spawn all the threads
+        let mut handles = thread_spawn!(
+            (
+                "collect",
+                monitoring::monitor_task(device, restart, stat_r, servo, sd_mon_r)
+            ),
+            (
+                "injection",
+                injection::pulse_injection_task(
+                    cap_r,
+                    inject_s,
+                    c.injection_cadence,
+                    pulse_path,
+                    c.injection_polarization,
+                    sd_inject_r
+                )
+            ),
+            (
+                "fanout",
+                processing::fanout_task(inject_r, downsamp_s, taps, sd_fanout_r)
+            ),
+            (
+                "downsample",
+                if c.full_stokes {
+                    processing::downsample_task(
+                        downsamp_r,
+                        ex_full_s,
+                        c.downsample_power,
+                        c.drop_fill,
+                        rfi,
+                        fluxcal,
+                        history_tap,
+                        sd_downsamp_r,
+                    )
+                } else {
+                    processing::downsample_task(
+                        downsamp_r,
+                        ex_s,
+                        c.downsample_power,
+                        c.drop_fill,
+                        rfi,
+                        fluxcal,
+                        history_tap,
+                        sd_downsamp_r,
+                    )
+                }
+            ),
+            (
+                "dump",
+                dumps::dump_task(
+                    ring,
+                    dump_r,
+                    trig_r,
+                    followers,
+                    packet_start,
+                    dump_path,
+                    c.defer_early_dumps,
+                    c.dump_window,
+                    c.dump_limits,
+                    c.dump_target.clone(),
+                    sd_dump_r
+                )
+            ),
+            ("exfil", exfil_task()),
+            ("capture", {
+                let res = capture::cap_task(primary_capture, recorder, cap_s, stat_s, sd_cap_r);
+                // Once a replay runs out, give the rest of the pipeline a moment to finish off
+                // what we sent, then stop
+                if replaying && res.is_ok() {
+                    std::thread::sleep(REPLAY_DRAIN);
+                    let _ = sd_replay_s.send(());
+                }
+                res
+            })
+        );
+
+        // The Stokes history only gets a core if we're keeping one
+        if let Some(history) = history {
+            let cpu = cpus.next().ok_or_else(|| {
+                eyre!("Not enough cores in the core range for the Stokes history")
+            })?;
+            placement.push(("history", cpu));
+            handles.push(spawn_pinned("history".to_string(), cpu, move || {
+                history::history_task(
+                    history,
+                    hist_r,
+                    hist_trig_r,
+                    packet_start,
+                    history_path,
+                    sd_history_r,
+                )
+            })?);
+        }
+
+        // Each tee'd sink gets a core of its own too
+        for (name, task) in tee_tasks {
+            let cpu = cpus
+                .next()
+                .ok_or_else(|| eyre!("Not enough cores in the core range for the {name} sink"))?;
+            placement.push((name, cpu));
+            handles.push(spawn_pinned(name.to_string(), cpu, task)?);
+        }
+
+        // Capture any other boards, and fill their rings, each on its own core too
+        for (board, board_s, dump) in board_dumps {
+            let config = capture_config(board, c.cap_ports[board], extra_input.clone());
+            let (stat_s, sd_r) = (extra_stats.clone(), sd_s.subscribe());
+            let recorder = extra_recorder.clone();
+            let mut next_cpu = || {
+                cpus.next().ok_or_else(|| {
+                    eyre!("Not enough cores in the core range to capture board {board}")
+                })
+            };
+            let (capture_cpu, dump_cpu) = (next_cpu()?, next_cpu()?);
+            placement.push(("capture", capture_cpu));
+            placement.push(("dump", dump_cpu));
+            handles.push(spawn_pinned(
+                format!("capture{board}"),
+                capture_cpu,
+                move || capture::cap_task(config, recorder, board_s, stat_s, sd_r),
+            )?);
+            handles.push(spawn_pinned(format!("dump{board}"), dump_cpu, dump)?);
+        }
+
+        // Write out the raw packets off to the side, on a core of its own
+        if let Some(config) = c.record_raw.clone() {
+            let (ports, sd_r) = (c.cap_ports.clone(), sd_s.subscribe());
+            handles.extend(thread_spawn!((
+                "record",
+                capture::record_task(config, ports, record_r, sd_r)
+            )));
+        }
+
+        // Write out the raw voltages as VDIF, also off to the side
+        if let Some(config) = c.vdif.clone() {
+            let sd_r = sd_s.subscribe();
+            handles.extend(thread_spawn!((
+                "vdif",
+                exfil::vdif_task(config, psc, vdif_r, sd_r)
+            )));
+        }
+
+        // Make sure the NIC isn't interrupting our busy threads
+        if let Some(nic) = &c.nic {
+            let busy: Vec<_> = placement.iter().map(|(_, cpu)| *cpu).collect();
+            let capture_cpu = placement
+                .iter()
+                .find(|(name, _)| *name == "capture")
+                .map(|(_, cpu)| *cpu)
+                .unwrap();
+            irq::setup(nic, &c.core_range, &busy, capture_cpu, c.pin_irqs);
+        }
+
+        // Serve the control plane
+        #[cfg(feature = "grpc")]
+        let control = spawn_named("grpc", {
+            let trig_s = trig_s.clone();
+            let control = c.control.clone();
+            async move {
+                match control {
+                    Some((port, config)) => {
+                        grpc::serve(grpc::ControlService::new(config, trig_s), port, sd_grpc_r)
+                            .await
+                    }
+                    None => Ok(()),
+                }
+            }
+        });
+        #[cfg(not(feature = "grpc"))]
+        let control = spawn_named("grpc", async { eyre::Ok(()) });
+
+        let _ = try_join!(
+            control,
+            // Start the webserver
+            spawn_named(
+                "web",
+                monitoring::start_web_server(c.metrics_port, trig_s.clone())?
+            ),
+            // Start the trigger watch
+            spawn_named(
+                "trigger",
+                dumps::trigger_task(trig_s.clone(), c.trig_port, sd_trig_r)
+            ),
+            // Talk to the other T0s
+            spawn_named(
+                "cluster",
+                cluster::cluster_task(trig_s.clone(), sd_cluster_r)
+            ),
+            // Start listening for candidates
+            spawn_named(
+                "candidates",
+                candidates::candidate_task(
+                    trig_s,
+                    c.cand_port,
+                    c.cand_filter,
+                    psc,
+                    PACKET_CADENCE * downsample_factor as f64,
+                    sd_cand_r
+                )
+            ),
+            // Keep an eye on NTP
+            spawn_named(
+                "ntp",
+                timing::ntp_monitor_task(
+                    c.ntp_addr.clone(),
+                    packet_start,
+                    if c.skip_ntp || c.gps.is_some() || c.snap.is_none() {
+                        Duration::ZERO
+                    } else {
+                        c.ntp_interval
+                    },
+                    sd_ntp_r
+                )
+            ),
+            // And on the packet counts
+            spawn_named(
+                "drift",
+                timing::drift_monitor_task(
+                    packet_start,
+                    if c.snap.is_none() {
+                        Duration::ZERO
+                    } else {
+                        c.drift_interval
+                    },
+                    c.drift_threshold,
+                    sd_drift_r
+                )
+            ),
+            // Start the T2 heartbeats
+            spawn_named("t2", t2::heartbeat_task(c.t2_addr, c.t2_port, sd_t2_r)),
+            // Start the Stokes history trigger watch
+            spawn_named(
+                "history_trigger",
+                history::trigger_task(
+                    hist_trig_s,
+                    c.history_seconds.map(|_| c.history_trig_port),
+                    sd_hist_trig_r
+                )
+            ),
+            spawn_named(
+                "pointing",
+                pointing::pointing_task(c.pointing_port, sd_pointing_r)
+            ),
+            spawn_named(
+                "state",
+                state::save_task(c.state_file.clone(), STATE_SAVE_INTERVAL, sd_state_r)
+            )
+        )?;
+
+        // Join them all when we kill the task
+        for handle in handles {
+            handle.join().unwrap()?;
+        }
+        // One last save, with everything wrapped up
+        if let Some(path) = &c.state_file {
+            if let Err(e) = state::save(path) {
+                warn!("Failed to save pipeline state - {e}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Catalog detail for a bandpass calibration, summarizing the gains it set
+pub fn bandpass_detail(device: &Device) -> serde_json::Value {
+    let (a, b) = device.requant_gains();
+    let mean = |g: &[u16]| g.iter().map(|&x| f64::from(x)).sum::<f64>() / g.len().max(1) as f64;
+    let verification = calibrate::state().verification;
+    json!({
+        "method": "bandpass",
+        "mean_gain_a": mean(a),
+        "mean_gain_b": mean(b),
+        "adc_gain_db": device.adc_gain(),
+        "verified_level": verification.as_ref().map(|v| v.level),
+        "failed_channels": verification.map(|v| v.failed),
+    })
+}
+
+/// Sync time and trigger the SNAP on the next PPS edge, returning the time packet count 0 went out
+/// and how we know that
+fn trigger_snap(
+    c: &PipelineConfig,
+    device: &mut Device,
+) -> eyre::Result<(Epoch, TimeSource, Option<f64>)> {
+    Ok(if let Some(gps) = &c.gps {
+        info!("Triggering the flow of packets via PPS, timed by GPS");
+        let edge = gps::last_edge(gps)?;
+        (
+            device.gps_trigger(edge)?,
+            TimeSource::Gps(gps.to_string()),
+            Some(gps::PPS_UNCERTAINTY),
+        )
+    } else if !c.skip_ntp {
+        info!("Synchronizing time with NTP");
+        let client = SntpClient::new();
+        let sync = client.synchronize(c.ntp_addr.as_str())?;
+        info!("Triggering the flow of packets via PPS");
+        (
+            device.trigger(&sync)?,
+            TimeSource::Ntp {
+                server: c.ntp_addr.clone(),
+                offset: sync.clock_offset().as_secs_f64(),
+            },
+            // Half the round trip is as well as SNTP can do
+            Some(sync.round_trip_delay().as_secs_f64() / 2.0),
+        )
+    } else {
+        info!("Blindly triggering (no GPS or NTP), timing will be off");
+        (device.blind_trigger()?, TimeSource::Blind, None)
+    })
+}
+
+/// Everything needed to bring the SNAP back if it reboots partway through a run
+#[derive(Debug, Clone)]
+pub struct SnapRestart {
+    config: PipelineConfig,
+    snap: SnapConfig,
+    /// When packet count 0 was, originally
+    packet_start: Epoch,
+}
+
+impl SnapRestart {
+    /// Set the SNAP back up as it was (networking, ADC and requant gains), and trigger it again.
+    /// The new packet counts are offset to pick up where they would have been, so the timestamps
+    /// downstream stay right and the gap is filled in like any other dropped packets.
+    pub fn restart(&self, device: &mut Device) -> eyre::Result<()> {
+        info!("Restarting the SNAP");
+        let (gain_a, gain_b) = device.requant_gains().clone();
+        device.reset()?;
+        device.set_fft_shift()?;
+        if let Some(db) = device.adc_gain().or(self.snap.adc_gain) {
+            device.set_adc_gain(db)?;
+        }
+        device.start_networking(&self.snap.mac, self.config.cap_ports[0], &self.snap.network)?;
+        let (new_start, source, _) = trigger_snap(&self.config, device)?;
+        if self.snap.force_pps {
+            device.force_pps()?;
+        }
+        device.set_requant_gains(&gain_a, &gain_b)?;
+        let offset = ((new_start - self.packet_start).to_seconds() / PACKET_CADENCE).round() as u64;
+        capture::COUNT_OFFSET.store(offset, Ordering::Release);
+        info!(offset, %new_start, "SNAP restarted, offsetting the packet counts to match");
+        events::record(
+            events::Kind::Restart,
+            json!({ "packet_start_mjd": new_start.to_mjd_utc_days(), "count_offset": offset, "time_source": source.to_string() }),
+        );
+        Ok(())
+    }
+}
+
+/// Pick up a SNAP that kept running while we were down, restoring the timing and gains we'd set up,
+/// returning the original time of the first packet
+fn resume_snap(snap: &SnapConfig, saved: &State) -> eyre::Result<(Device, Epoch)> {
+    info!("Resuming with the SNAP as we left it");
+    let mut device = Device::on_board(snap.addr, snap.board, snap.connect)?;
+    if let Some(fpg) = &snap.fpg_path {
+        device.check_design(fpg)?;
+    }
+    if !device.still_running()? {
+        bail!("The SNAP isn't running as we left it, start without --resume to set it up again");
+    }
+    timing::set_time_sync(TimeSync {
+        source: saved.time_source.clone(),
+        uncertainty: saved.time_uncertainty,
+        trigger_epoch: saved.packet_start,
+    });
+    capture::FIRST_PACKET.store(saved.first_packet, Ordering::Release);
+    capture::COUNT_OFFSET.store(saved.count_offset, Ordering::Release);
+    capture::LAST_PACKET.store(saved.last_packet, Ordering::Release);
+    if let Some(db) = saved.adc_gain {
+        device.set_adc_gain(db)?;
+    }
+    let (a, b) = &saved.requant_gains;
+    device.set_requant_gains(a, b)?;
+    weights::set_calibration_dead(a, b);
+    if let Some(calibrated) = saved.calibrated {
+        calibrate::record_calibration(calibrated);
+    }
+    events::record(
+        events::Kind::Resume,
+        json!({
+            "saved_mjd": saved.saved.to_mjd_utc_days(),
+            "next_count": saved.next_count(),
+            "filterbanks": saved.filterbanks.values().map(|fb| &fb.path).collect::<Vec<_>>(),
+        }),
+    );
+    Ok((device, saved.packet_start))
+}
+
+/// Sync time, set up and trigger the SNAP, and calibrate, returning the time of the first packet
+fn setup_snap(c: &PipelineConfig, snap: &SnapConfig) -> eyre::Result<(Device, Epoch)> {
+    if c.gps.is_some() {
+        info!("Using GPS for timing, skipping NTP time sync");
+    } else if c.skip_ntp {
+        info!("Skipping NTP time sync");
+    }
+    // Setup the FPGA
+    info!("Setting up SNAP");
+    if let Some(fpg) = &snap.program {
+        Device::program(snap.addr, snap.board, fpg, snap.connect)?;
+    }
+    let mut device = Device::on_board(snap.addr, snap.board, snap.connect)?;
+    if let Some(fpg) = &snap.fpg_path {
+        device.check_design(fpg)?;
+    }
+    device.reset()?;
+    if let Some(db) = snap.adc_gain {
+        device.set_adc_gain(db)?;
+    }
+    device.start_networking(&snap.mac, c.cap_ports[0], &snap.network)?;
+    let (packet_start, source, uncertainty) = trigger_snap(c, &mut device)?;
+    timing::set_time_sync(TimeSync {
+        source,
+        uncertainty,
+        trigger_epoch: packet_start,
+    });
+    if snap.force_pps {
+        device.force_pps()?;
+    }
+    // Perform the bandpass calibration routine (if needed)
+    if let Some(requant_gain) = snap.requant_gain {
+        info!("Setting requant gains directly without bandpass calibration");
+        let gain = [requant_gain; CHANNELS];
+        device.set_requant_gains(&gain, &gain)?;
+        calibrate::record_calibration(Epoch::now()?);
+        events::record(
+            events::Kind::Calibration,
+            json!({ "method": "manual", "requant_gain": requant_gain }),
+        );
+        catalog::record(
+            catalog::Product::Calibration,
+            None,
+            packet_start,
+            packet_start,
+            json!({ "method": "manual", "requant_gain": requant_gain }),
+        );
+    } else {
+        cluster::wait_for_calibration()?;
+        info!("Calibrating bandpass");
+        calibrate(&mut device)?;
+        events::record(events::Kind::Calibration, json!({ "method": "bandpass" }));
+        catalog::record(
+            catalog::Product::Calibration,
+            None,
+            packet_start,
+            packet_start,
+            bandpass_detail(&device),
+        );
+    }
+    Ok((device, packet_start))
+}
@@ -0,0 +1,204 @@
+//! Pointing and source information from the telescope control system
+//!
+//! The control system sends a JSON datagram to our pointing port whenever the pointing changes
+//! (and may repeat it as often as it likes), e.g.
+//! `{"source": "B0329+54", "ra": 53.247, "dec": 54.579, "az": 0.0, "el": 82.6}`.
+//! Every field is optional, angles are in degrees. The latest pointing is stamped into the data
+//! products as they're written.
+use crate::observation;
+use hifitime::prelude::*;
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, register_int_counter, GaugeVec, IntCounter};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+use tokio::{net::UdpSocket, sync::broadcast};
+use tracing::{info, warn};
+
+lazy_static! {
+    static ref POINTING_GAUGE: GaugeVec = register_gauge_vec!(
+        "pointing_degrees",
+        "Current pointing reported by the telescope control system",
+        &["coordinate"]
+    )
+    .unwrap();
+    static ref POINTING_UPDATES: IntCounter = register_int_counter!(
+        "pointing_updates",
+        "Number of pointing changes reported by the telescope control system"
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Pointing {
+    /// Name of the source we're pointed at
+    pub source: Option<String>,
+    /// J2000 right ascension (degrees)
+    pub ra: Option<f64>,
+    /// J2000 declination (degrees)
+    pub dec: Option<f64>,
+    /// Azimuth (degrees)
+    pub az: Option<f64>,
+    /// Elevation (degrees)
+    pub el: Option<f64>,
+    /// When we started pointing here (MJD UTC), set on receipt
+    #[serde(default)]
+    pub since_mjd: f64,
+}
+
+impl Pointing {
+    /// Key-value pairs describing the pointing, in the style of a DADA header
+    pub fn header_entries(&self) -> Vec<(String, String)> {
+        let mut entries = vec![];
+        if let Some(source) = &self.source {
+            entries.push(("SOURCE".to_owned(), source.clone()));
+        }
+        if let Some(ra) = self.ra {
+            entries.push(("RA".to_owned(), ra.to_string()));
+        }
+        if let Some(dec) = self.dec {
+            entries.push(("DEC".to_owned(), dec.to_string()));
+        }
+        if let Some(az) = self.az {
+            entries.push(("AZ".to_owned(), az.to_string()));
+        }
+        if let Some(el) = self.el {
+            entries.push(("EL".to_owned(), el.to_string()));
+        }
+        entries.push(("POINTING_MJD".to_owned(), self.since_mjd.to_string()));
+        entries
+    }
+
+    /// Right ascension in SIGPROC's `hhmmss.s` convention
+    pub fn sigproc_raj(&self) -> Option<f64> {
+        self.ra.map(|ra| sexagesimal(ra.rem_euclid(360.0) / 15.0))
+    }
+
+    /// Declination in SIGPROC's `ddmmss.s` convention
+    pub fn sigproc_dej(&self) -> Option<f64> {
+        self.dec.map(sexagesimal)
+    }
+
+    /// Right ascension in PSRFITS' `hh:mm:ss.ssss` convention
+    pub fn psrfits_ra(&self) -> Option<String> {
+        self.ra
+            .map(|ra| colon_sexagesimal(ra.rem_euclid(360.0) / 15.0, false))
+    }
+
+    /// Declination in PSRFITS' `+dd:mm:ss.ssss` convention
+    pub fn psrfits_dec(&self) -> Option<String> {
+        self.dec.map(|dec| colon_sexagesimal(dec, true))
+    }
+}
+
+/// Pack a value into the `xxmmss.s` form SIGPROC uses for angles
+fn sexagesimal(value: f64) -> f64 {
+    let sign = value.signum();
+    let value = value.abs();
+    let whole = value.trunc();
+    let minutes = ((value - whole) * 60.0).trunc();
+    let seconds = ((value - whole) * 60.0 - minutes) * 60.0;
+    sign * (whole * 10000.0 + minutes * 100.0 + seconds)
+}
+
+/// Write a value out as `xx:mm:ss.ssss`, with a leading sign if `signed`
+fn colon_sexagesimal(value: f64, signed: bool) -> String {
+    let sign = match (signed, value < 0.0) {
+        (_, true) => "-",
+        (true, false) => "+",
+        (false, false) => "",
+    };
+    // Count in ten-thousandths of a second so rounding carries properly
+    let total = (value.abs() * 3600.0 * 1e4).round() as u64;
+    let whole = total / 36_000_000;
+    let minutes = total / 600_000 % 60;
+    let seconds = (total % 600_000) as f64 / 1e4;
+    format!("{sign}{whole:02}:{minutes:02}:{seconds:07.4}")
+}
+
+static POINTING: RwLock<Option<Pointing>> = RwLock::new(None);
+/// Bumped every time the pointing changes, so consumers can cheaply check for updates
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The latest pointing, if we've heard from the control system, or otherwise the source we were
+/// configured with (if any)
+#[allow(clippy::missing_panics_doc)]
+pub fn current() -> Option<Pointing> {
+    POINTING
+        .read()
+        .unwrap()
+        .clone()
+        .or_else(|| observation::current().pointing())
+}
+
+/// A number that changes whenever the pointing does
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+fn update(mut pointing: Pointing) {
+    let mut current = POINTING.write().unwrap();
+    let same = current.as_ref().is_some_and(|c| {
+        c.source == pointing.source
+            && c.ra == pointing.ra
+            && c.dec == pointing.dec
+            && c.az == pointing.az
+            && c.el == pointing.el
+    });
+    if same {
+        return;
+    }
+    pointing.since_mjd = Epoch::now().map_or(0.0, |e| e.to_mjd_utc_days());
+    info!(
+        source = pointing.source,
+        ra = pointing.ra,
+        dec = pointing.dec,
+        "New pointing"
+    );
+    for (name, value) in [
+        ("ra", pointing.ra),
+        ("dec", pointing.dec),
+        ("az", pointing.az),
+        ("el", pointing.el),
+    ] {
+        if let Some(v) = value {
+            POINTING_GAUGE.with_label_values(&[name]).set(v);
+        }
+    }
+    POINTING_UPDATES.inc();
+    *current = Some(pointing);
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+pub async fn pointing_task(
+    port: Option<u16>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    let port = match port {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    info!("Starting pointing listener!");
+    let sock = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    let mut buf = [0u8; 1024];
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("Pointing task stopping");
+                break;
+            }
+            Ok((n, addr)) = sock.recv_from(&mut buf) => {
+                match serde_json::from_slice::<Pointing>(&buf[..n]) {
+                    Ok(p) => update(p),
+                    Err(e) => warn!("Malformed pointing from {addr} - {e}"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
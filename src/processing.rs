@@ -1,25 +1,118 @@
-//! Inter-thread processing (downsampling, etc)
-use crate::common::{Payload, Stokes, BLOCK_TIMEOUT, CHANNELS};
+//! Inter-thread processing (fanout, downsampling, etc)
+use crate::{
+    capture::FillPolicy,
+    common::{Payload, Spectrum, BLOCK_TIMEOUT, CHANNELS},
+    fluxcal::FluxCal,
+    history, latency,
+    rfi::Occupancy,
+};
 use eyre::bail;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
 use thingbuf::mpsc::{
     blocking::{Sender, StaticReceiver, StaticSender},
-    errors::RecvTimeoutError,
+    errors::{RecvTimeoutError, TrySendError},
 };
 use tokio::sync::broadcast;
 use tracing::info;
 
-#[allow(clippy::missing_panics_doc)]
-pub fn downsample_task(
+lazy_static! {
+    static ref TAP_DROPPED: IntCounterVec = register_int_counter_vec!(
+        "fanout_dropped_payloads",
+        "Number of payloads a fanout tap was too busy to take",
+        &["tap"]
+    )
+    .unwrap();
+}
+
+/// Somewhere the fanout hands copies of the payloads to, without ever waiting on it
+pub trait Tap: Send {
+    /// Offer a copy of the payload, which fails if the tap is full or has gone away
+    fn offer(&self, payload: &Payload) -> Result<(), TrySendError>;
+}
+
+/// Drop the payload from a failed send, we only care why
+fn reason<T>(e: TrySendError<T>) -> TrySendError {
+    if e.is_full() {
+        TrySendError::Full(())
+    } else {
+        TrySendError::Closed(())
+    }
+}
+
+impl Tap for StaticSender<Payload> {
+    fn offer(&self, payload: &Payload) -> Result<(), TrySendError> {
+        self.try_send(*payload).map_err(reason)
+    }
+}
+
+impl Tap for Sender<Payload> {
+    fn offer(&self, payload: &Payload) -> Result<(), TrySendError> {
+        self.try_send(*payload).map_err(reason)
+    }
+}
+
+/// Pass every payload on to the downsampler, offering copies to each of the `taps` along the way.
+/// The taps are lossy, so a slow consumer (like the voltage dumps) never holds up detection.
+pub fn fanout_task(
+    receiver: StaticReceiver<Payload>,
+    to_downsample: StaticSender<Payload>,
+    taps: Vec<(&'static str, Box<dyn Tap>)>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting fanout task");
+    let dropped: Vec<_> = taps
+        .iter()
+        .map(|(name, _)| TAP_DROPPED.with_label_values(&[name]))
+        .collect();
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Fanout task stopping");
+            break;
+        }
+        let payload = match receiver.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(p) => p,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        };
+        for ((name, tap), dropped) in taps.iter().zip(&dropped) {
+            match tap.offer(&payload) {
+                Ok(()) => (),
+                Err(TrySendError::Full(_)) => dropped.inc(),
+                Err(_) => bail!("Channel to the {name} tap closed"),
+            }
+        }
+        to_downsample.send(*payload)?;
+    }
+    Ok(())
+}
+
+/// Average the Stokes parameters of 2^`downsample_power` payloads at a time, as Stokes I alone or
+/// full Stokes depending on the spectra `sender` takes. Only Stokes I goes to the RFI, flux
+/// calibration, and history taps.
+#[allow(clippy::missing_panics_doc, clippy::too_many_arguments)]
+pub fn downsample_task<S: Spectrum>(
     receiver: StaticReceiver<Payload>,
-    sender: Sender<Stokes>,
-    to_dumps: StaticSender<Payload>,
+    sender: Sender<S>,
     downsample_power: u32,
+    fill: FillPolicy,
+    mut rfi: Option<Occupancy>,
+    mut fluxcal: Option<FluxCal>,
+    mut history: Option<history::Tap>,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     info!("Starting downsample task");
     let downsamp_iters = 2usize.pow(downsample_power);
-    let mut downsamp_buf = [0f32; CHANNELS];
+    let mut downsamp_buf = vec![0f32; S::NPOL * CHANNELS];
     let mut local_downsamp_iters = 0;
+    // Packet count at the start of the current window
+    let mut window_start = 0u64;
+    // Index of the next spectrum we'll send and if it contains the latency probe
+    let mut spectrum_idx = 0u64;
+    let mut probe_in_window = false;
+    // If the current window has fill in it that we're to mark with NaNs
+    let mut nan_in_window = false;
 
     loop {
         if shutdown.try_recv().is_ok() {
@@ -32,29 +125,54 @@ pub fn downsample_task(
             Err(RecvTimeoutError::Closed) => break,
             Err(_) => unreachable!(),
         };
-        // Compute Stokes I
-        let stokes = payload.stokes_i();
-        // Send payload to dump (non-blocking)
-        if let Err(thingbuf::mpsc::errors::TrySendError::Closed(_)) = to_dumps.try_send(*payload) {
-            bail!("Channel closed")
+        if latency::is_probe(payload.count) {
+            probe_in_window = true;
+        }
+        if local_downsamp_iters == 0 {
+            window_start = payload.count;
+        }
+        if payload.synthetic && fill == FillPolicy::Nan {
+            nan_in_window = true;
+        } else {
+            // Compute the Stokes parameters into the averaging buf
+            S::accumulate(&payload, &mut downsamp_buf);
         }
-        debug_assert_eq!(stokes.len(), CHANNELS);
-        // Add to averaging bufs
-        downsamp_buf
-            .iter_mut()
-            .zip(&stokes)
-            .for_each(|(x, y)| *x += y);
 
         // Increment the count
         local_downsamp_iters += 1;
 
         // Check for downsample exit condition
         if local_downsamp_iters == downsamp_iters {
-            // Write averages directly into it
-            downsamp_buf
-                .iter_mut()
-                .for_each(|v| *v /= local_downsamp_iters as f32);
-            sender.send(downsamp_buf.into())?;
+            if nan_in_window {
+                // Mark the whole spectrum as fill, and keep it out of the monitoring
+                downsamp_buf.iter_mut().for_each(|v| *v = f32::NAN);
+                nan_in_window = false;
+            } else {
+                // Write averages directly into it
+                downsamp_buf
+                    .iter_mut()
+                    .for_each(|v| *v /= local_downsamp_iters as f32);
+                let stokes_i = &downsamp_buf[..CHANNELS];
+                if let Some(rfi) = rfi.as_mut() {
+                    rfi.update(stokes_i);
+                }
+                if let Some(fluxcal) = fluxcal.as_mut() {
+                    fluxcal.update(stokes_i, window_start);
+                }
+            }
+            // The history keeps its windows in step by counting the NaNs too
+            if let Some(history) = history.as_mut() {
+                history.push(&downsamp_buf[..CHANNELS], window_start);
+            }
+            let Ok(spectrum) = S::try_from(&downsamp_buf) else {
+                unreachable!("The averaging buffer is exactly one spectrum long")
+            };
+            sender.send(spectrum)?;
+            if probe_in_window {
+                latency::downsampled(spectrum_idx);
+                probe_in_window = false;
+            }
+            spectrum_idx += 1;
 
             // And reset averaging
             downsamp_buf.iter_mut().for_each(|v| *v = 0.0);
@@ -0,0 +1,206 @@
+//! Python bindings for device control, calibration, and reading dumps and 8-bit DADA levels
+//!
+//! Built with `maturin develop` (see `pyproject.toml`), so commissioning notebooks can drive the SNAP
+//! with exactly the register and gain logic the pipeline uses.
+use crate::{
+    calibrate::{self, REQUANT_SCALE, SMOOTH_LAMBDA, SMOOTH_ORDER},
+    dumps,
+    exfil::{DadaLevels, Levels},
+    fpga::{Board, ConnectOptions, Device, Network},
+};
+use clap::ValueEnum;
+use ndarray::Array2;
+use netcdf::AttributeValue;
+use numpy::{IntoPyArray, PyArray1, PyArray2, PyArray4};
+use pyo3::{exceptions::PyRuntimeError, prelude::*, types::PyDict};
+use std::{net::SocketAddr, path::PathBuf};
+
+fn to_py(e: impl std::fmt::Display) -> PyErr {
+    PyRuntimeError::new_err(e.to_string())
+}
+
+/// Connection to a SNAP board running the GReX gateware.
+/// The SNAP is reset when this object is dropped, just like in the pipeline.
+#[pyclass(name = "Device", unsendable)]
+pub struct PyDevice(Device);
+
+#[pymethods]
+impl PyDevice {
+    /// Connect to the board at `addr` (e.g. "192.168.0.3:69"), which is a "snap" or "snap2"
+    #[new]
+    #[pyo3(signature = (addr, board = "snap"))]
+    fn new(addr: &str, board: &str) -> PyResult<Self> {
+        let addr: SocketAddr = addr.parse().map_err(to_py)?;
+        let board = Board::from_str(board, true).map_err(to_py)?;
+        let device = Device::on_board(addr, board, ConnectOptions::default()).map_err(to_py)?;
+        Ok(Self(device))
+    }
+
+    /// Reset the state of the SNAP
+    fn reset(&mut self) -> PyResult<()> {
+        self.0.reset().map_err(to_py)
+    }
+
+    /// Bring up the 10 GbE link, sending to `port` on the interface with the given MAC (e.g.
+    /// "aa:bb:cc:dd:ee:ff") at `dest_ip`, from the SNAP's default addresses
+    #[pyo3(signature = (mac, port = 60000, dest_ip = None))]
+    fn start_networking(&mut self, mac: &str, port: u16, dest_ip: Option<&str>) -> PyResult<()> {
+        let mac = crate::args::parse_mac(mac).map_err(to_py)?;
+        let mut network = Network::default();
+        if let Some(ip) = dest_ip {
+            network.dest_ip = ip.parse().map_err(to_py)?;
+        }
+        self.0.start_networking(&mac, port, &network).map_err(to_py)
+    }
+
+    /// Force a PPS pulse (timing will be inaccurate)
+    fn force_pps(&mut self) -> PyResult<()> {
+        self.0.force_pps().map_err(to_py)
+    }
+
+    /// Set the requant gains for both polarizations
+    fn set_requant_gains(&mut self, a: Vec<u16>, b: Vec<u16>) -> PyResult<()> {
+        self.0.set_requant_gains(&a, &b).map_err(to_py)
+    }
+
+    /// The requant gains last set through this object, as (a, b)
+    #[getter]
+    fn requant_gains(&self) -> (Vec<u16>, Vec<u16>) {
+        self.0.requant_gains().clone()
+    }
+
+    /// Set the coarse gain of every ADC input (dB)
+    fn set_adc_gain(&mut self, db: u8) -> PyResult<()> {
+        self.0.set_adc_gain(db).map_err(to_py)
+    }
+
+    /// The ADC coarse gain last set through this object (dB)
+    #[getter]
+    fn adc_gain(&self) -> Option<u8> {
+        self.0.adc_gain()
+    }
+
+    /// Accumulate `n` pre-requant spectra, returning the raw (a, b) powers
+    #[allow(clippy::type_complexity)]
+    fn spec_vacc<'py>(
+        &mut self,
+        py: Python<'py>,
+        n: u32,
+    ) -> PyResult<(Bound<'py, PyArray1<u64>>, Bound<'py, PyArray1<u64>>)> {
+        let (a, b) = self.0.perform_spec_vacc(n).map_err(to_py)?;
+        Ok((a.into_pyarray(py), b.into_pyarray(py)))
+    }
+
+    /// Accumulate `n` post-requant Stokes I spectra
+    fn stokes_vacc<'py>(&mut self, py: Python<'py>, n: u32) -> PyResult<Bound<'py, PyArray1<u64>>> {
+        let stokes = self.0.perform_stokes_vacc(n).map_err(to_py)?;
+        Ok(stokes.into_pyarray(py))
+    }
+
+    /// Internal FPGA temperature (C)
+    fn temperature(&self) -> PyResult<f32> {
+        self.0
+            .fpga
+            .transport
+            .lock()
+            .unwrap()
+            .temperature()
+            .map_err(to_py)
+    }
+}
+
+/// Run the bandpass calibration the pipeline does at startup, setting the requant gains
+#[pyfunction(name = "calibrate")]
+fn calibrate_bandpass(device: &mut PyDevice) -> PyResult<()> {
+    calibrate::calibrate(&mut device.0).map_err(to_py)
+}
+
+/// Compute requant gains from `n` accumulated spectra, as the bandpass calibration does
+#[pyfunction]
+#[pyo3(signature = (powers, n, scale = REQUANT_SCALE, lambda = SMOOTH_LAMBDA, order = SMOOTH_ORDER))]
+fn compute_gains(
+    powers: Vec<u64>,
+    n: u32,
+    scale: f64,
+    lambda: f64,
+    order: usize,
+) -> PyResult<Vec<u16>> {
+    calibrate::compute_gains(scale, n, &powers, lambda, order).map_err(to_py)
+}
+
+fn set_attribute(dict: &Bound<'_, PyDict>, name: &str, value: AttributeValue) -> PyResult<()> {
+    match value {
+        AttributeValue::Str(v) => dict.set_item(name, v),
+        AttributeValue::Double(v) => dict.set_item(name, v),
+        AttributeValue::Float(v) => dict.set_item(name, v),
+        AttributeValue::Int(v) => dict.set_item(name, v),
+        AttributeValue::Uint(v) => dict.set_item(name, v),
+        AttributeValue::Longlong(v) => dict.set_item(name, v),
+        AttributeValue::Ulonglong(v) => dict.set_item(name, v),
+        AttributeValue::Short(v) => dict.set_item(name, v),
+        AttributeValue::Ushort(v) => dict.set_item(name, v),
+        AttributeValue::Schar(v) => dict.set_item(name, v),
+        AttributeValue::Uchar(v) => dict.set_item(name, v),
+        v => dict.set_item(name, format!("{v:?}")),
+    }
+}
+
+/// Read a voltage dump, returning a dict of `time` (TDB days since J2000), `freq` (MHz),
+/// `voltages` (time, pol, freq, reim), `requant_gains` (pol, freq) if recorded, and the file's
+/// `attributes`
+#[pyfunction]
+fn read_dump<'py>(py: Python<'py>, path: PathBuf) -> PyResult<Bound<'py, PyDict>> {
+    let dump = dumps::read::open(&path).map_err(to_py)?;
+    let time: Vec<_> = dump
+        .times
+        .iter()
+        .map(|t| t.to_tdb_days_since_j2000())
+        .collect();
+    let attributes = PyDict::new(py);
+    for (name, value) in dump.attributes {
+        set_attribute(&attributes, &name, value)?;
+    }
+    let dict = PyDict::new(py);
+    dict.set_item("time", time.into_pyarray(py))?;
+    dict.set_item("freq", dump.freqs.into_pyarray(py))?;
+    let voltages: Bound<'py, PyArray4<i8>> = dump.voltages.into_pyarray(py);
+    dict.set_item("voltages", voltages)?;
+    if let Some(gains) = dump.requant_gains {
+        let gains: Bound<'py, PyArray2<u16>> = gains.into_pyarray(py);
+        dict.set_item("requant_gains", gains)?;
+    }
+    dict.set_item("attributes", attributes)?;
+    Ok(dict)
+}
+
+/// Read the levels an 8-bit DADA stream was requantized with, returning a dict of the stream's
+/// `header`, the `block` numbers, and the `scales` and `offsets` (block, channel) that undo the
+/// requantization with `x = u8 * scale + offset`
+#[pyfunction]
+fn read_dada_levels<'py>(py: Python<'py>, path: PathBuf) -> PyResult<Bound<'py, PyDict>> {
+    let levels = DadaLevels::read(&path).map_err(to_py)?;
+    let blocks: Vec<_> = levels.blocks.iter().map(|(b, _)| *b).collect();
+    let width = levels.blocks.first().map_or(0, |(_, l)| l.scales.len());
+    let table = |values: fn(&Levels) -> &[f32]| {
+        let flat = levels.blocks.iter().flat_map(|(_, l)| values(l)).copied();
+        Array2::from_shape_vec((blocks.len(), width), flat.collect()).map_err(to_py)
+    };
+    let scales: Bound<'py, PyArray2<f32>> = table(|l| &l.scales)?.into_pyarray(py);
+    let offsets: Bound<'py, PyArray2<f32>> = table(|l| &l.offsets)?.into_pyarray(py);
+    let dict = PyDict::new(py);
+    dict.set_item("header", &levels.header)?;
+    dict.set_item("block", blocks.into_pyarray(py))?;
+    dict.set_item("scales", scales)?;
+    dict.set_item("offsets", offsets)?;
+    Ok(dict)
+}
+
+#[pymodule]
+fn grex_t0(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyDevice>()?;
+    m.add_function(wrap_pyfunction!(calibrate_bandpass, m)?)?;
+    m.add_function(wrap_pyfunction!(compute_gains, m)?)?;
+    m.add_function(wrap_pyfunction!(read_dump, m)?)?;
+    m.add_function(wrap_pyfunction!(read_dada_levels, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,349 @@
+//! Long-run RFI occupancy statistics, to inform band planning and flagging
+//!
+//! Each downsampled spectrum is compared channel-by-channel to a slowly adapting baseline, and a
+//! channel is "occupied" whenever it sits more than the threshold above it. We keep hourly and
+//! all-time counts, appending a summary line for every hour and persisting the all-time totals so
+//! they survive restarts.
+//!
+//! The same statistics drive the live channel mask. Every metrics interval, each channel's
+//! occupancy and variance over the interval are folded into slow running averages, and channels
+//! that are occupied too often or much noisier than the rest of the band get masked (zero weighted).
+//! A masked channel only comes back once it's been well under both thresholds for a while, so
+//! channels near the edge don't flap.
+use crate::{common::CHANNELS, weights};
+use hifitime::prelude::*;
+use lazy_static::lazy_static;
+use prometheus::{
+    register_gauge, register_gauge_vec, register_int_gauge, Gauge, GaugeVec, IntGauge,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// How often to write an occupancy summary and fold it into the all-time totals
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(3600);
+/// How often to update the metrics
+const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+/// How many spectra between checks of the timers
+const TIMER_CHECK_SPECTRA: u64 = 1024;
+/// Number of spectra the baseline averages over
+const BASELINE_SPECTRA: f32 = 4096.0;
+/// File holding the all-time totals
+const TOTALS_FILE: &str = "rfi_occupancy.json";
+/// File the hourly summaries get appended to
+const SUMMARY_FILE: &str = "rfi_hourly.jsonl";
+/// Number of metrics intervals the mask's running averages are over
+const MASK_AVERAGING: f64 = 10.0;
+/// Averaged occupancy below which a masked channel counts as clean again
+const UNMASK_OCCUPANCY: f64 = 0.2;
+/// Averaged variance (relative to the band median) above which a channel is masked
+const MASK_VARIANCE: f64 = 4.0;
+/// Averaged variance (relative to the band median) below which a masked channel counts as clean again
+const UNMASK_VARIANCE: f64 = 2.0;
+/// Consecutive clean intervals before a masked channel is unmasked
+const UNMASK_AFTER: u32 = 10;
+
+lazy_static! {
+    static ref OCCUPANCY_GAUGE: GaugeVec = register_gauge_vec!(
+        "rfi_occupancy",
+        "All-time fraction of time each channel has been above the RFI threshold",
+        &["channel"]
+    )
+    .unwrap();
+    static ref BAND_OCCUPANCY_GAUGE: Gauge = register_gauge!(
+        "rfi_band_occupancy",
+        "Fraction of the band above the RFI threshold over the current summary period"
+    )
+    .unwrap();
+    static ref MASKED_GAUGE: IntGauge = register_int_gauge!(
+        "rfi_masked_channels",
+        "Number of channels currently masked for anomalous occupancy or variance"
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Counts {
+    spectra: u64,
+    flagged: Vec<u64>,
+}
+
+impl Default for Counts {
+    fn default() -> Self {
+        Self {
+            spectra: 0,
+            flagged: vec![0; CHANNELS],
+        }
+    }
+}
+
+impl Counts {
+    fn occupancy(&self, chan: usize) -> f64 {
+        if self.spectra == 0 {
+            0.0
+        } else {
+            self.flagged[chan] as f64 / self.spectra as f64
+        }
+    }
+
+    fn band_occupancy(&self) -> f64 {
+        (0..CHANNELS).map(|c| self.occupancy(c)).sum::<f64>() / CHANNELS as f64
+    }
+}
+
+/// Per-channel statistics over the current metrics interval
+#[derive(Debug, Clone)]
+struct Window {
+    counts: Counts,
+    sum: Vec<f64>,
+    sum_sq: Vec<f64>,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Self {
+            counts: Counts::default(),
+            sum: vec![0.0; CHANNELS],
+            sum_sq: vec![0.0; CHANNELS],
+        }
+    }
+}
+
+impl Window {
+    /// Variance of each channel relative to its mean squared (so independent of the bandpass)
+    fn relative_variance(&self) -> Vec<f64> {
+        let n = self.counts.spectra as f64;
+        self.sum
+            .iter()
+            .zip(&self.sum_sq)
+            .map(|(s, sq)| {
+                let mean = s / n;
+                if mean > 0.0 {
+                    (sq / n - mean * mean).max(0.0) / (mean * mean)
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+}
+
+/// Which channels are masked, from their long-term behaviour
+#[derive(Debug, Clone)]
+struct Mask {
+    /// Running average of each channel's occupancy
+    occupancy: Vec<f64>,
+    /// Running average of each channel's relative variance, over the band median
+    variance: Vec<f64>,
+    masked: Vec<bool>,
+    /// Consecutive intervals each masked channel has been clean
+    clean: Vec<u32>,
+    /// If we've seen a window yet (to start the averages from)
+    primed: bool,
+}
+
+impl Default for Mask {
+    fn default() -> Self {
+        Self {
+            occupancy: vec![0.0; CHANNELS],
+            variance: vec![1.0; CHANNELS],
+            masked: vec![false; CHANNELS],
+            clean: vec![0; CHANNELS],
+            primed: false,
+        }
+    }
+}
+
+impl Mask {
+    /// Fold in the last interval's statistics, returning if the mask changed
+    fn update(&mut self, window: &Window) -> bool {
+        if window.counts.spectra == 0 {
+            return false;
+        }
+        let variance = window.relative_variance();
+        let mut sorted = variance.clone();
+        sorted.sort_by(f64::total_cmp);
+        let median = sorted[CHANNELS / 2];
+        let alpha = if self.primed {
+            1.0 / MASK_AVERAGING
+        } else {
+            1.0
+        };
+        self.primed = true;
+        let mut changed = false;
+        for (chan, rel_var) in variance.iter().enumerate() {
+            let v = if median > 0.0 { rel_var / median } else { 1.0 };
+            self.occupancy[chan] += alpha * (window.counts.occupancy(chan) - self.occupancy[chan]);
+            self.variance[chan] += alpha * (v - self.variance[chan]);
+            let (occ, var) = (self.occupancy[chan], self.variance[chan]);
+            if self.masked[chan] {
+                if occ < UNMASK_OCCUPANCY && var < UNMASK_VARIANCE {
+                    self.clean[chan] += 1;
+                    if self.clean[chan] >= UNMASK_AFTER {
+                        self.masked[chan] = false;
+                        changed = true;
+                    }
+                } else {
+                    self.clean[chan] = 0;
+                }
+            } else if occ > weights::RFI_ZAP_OCCUPANCY || var > MASK_VARIANCE {
+                self.masked[chan] = true;
+                self.clean[chan] = 0;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn count(&self) -> usize {
+        self.masked.iter().filter(|m| **m).count()
+    }
+}
+
+pub struct Occupancy {
+    path: PathBuf,
+    /// Multiple of the baseline above which a channel is flagged
+    threshold: f32,
+    baseline: Vec<f32>,
+    /// Spectra that have gone into the baseline before we start flagging
+    warmup: u64,
+    all_time: Counts,
+    period: Counts,
+    period_start: Epoch,
+    window: Window,
+    mask: Mask,
+    last_summary: Instant,
+    last_metrics: Instant,
+}
+
+impl Occupancy {
+    /// Track occupancy of spectra that are averages of `downsample_factor` packets, flagging at
+    /// `sigma` standard deviations above the baseline, and keeping our records in `path`
+    pub fn new(path: &Path, sigma: f32, downsample_factor: usize) -> eyre::Result<Self> {
+        std::fs::create_dir_all(path)?;
+        let all_time = match std::fs::read_to_string(path.join(TOTALS_FILE)) {
+            Ok(s) => serde_json::from_str(&s)?,
+            Err(_) => Counts::default(),
+        };
+        info!(
+            "Tracking RFI occupancy, {} spectra so far",
+            all_time.spectra
+        );
+        // Stokes I of one packet is a sum of four squared gaussians (two pols, re and im), so it
+        // has a relative standard deviation of 1/sqrt(2), and averaging brings that down further
+        let rel_std = 1.0 / (2.0 * downsample_factor as f32).sqrt();
+        Ok(Self {
+            path: path.to_owned(),
+            threshold: 1.0 + sigma * rel_std,
+            baseline: vec![0.0; CHANNELS],
+            warmup: 0,
+            all_time,
+            period: Counts::default(),
+            period_start: Epoch::now()?,
+            window: Window::default(),
+            mask: Mask::default(),
+            last_summary: Instant::now(),
+            last_metrics: Instant::now(),
+        })
+    }
+
+    /// Fold in the next (downsampled) spectrum
+    pub fn update(&mut self, stokes: &[f32]) {
+        if (self.warmup as f32) < BASELINE_SPECTRA {
+            // Still warming up, so just average to get a starting point
+            self.warmup += 1;
+            for (b, x) in self.baseline.iter_mut().zip(stokes) {
+                *b += (x - *b) / self.warmup as f32;
+            }
+            return;
+        }
+        self.period.spectra += 1;
+        self.window.counts.spectra += 1;
+        for (chan, (b, x)) in self.baseline.iter_mut().zip(stokes).enumerate() {
+            self.window.sum[chan] += f64::from(*x);
+            self.window.sum_sq[chan] += f64::from(*x) * f64::from(*x);
+            if *x > *b * self.threshold {
+                self.period.flagged[chan] += 1;
+                self.window.counts.flagged[chan] += 1;
+            } else {
+                // Only track the clean data, so persistent RFI doesn't drag the baseline up
+                *b += (x - *b) / BASELINE_SPECTRA;
+            }
+        }
+        if self.period.spectra.is_multiple_of(TIMER_CHECK_SPECTRA) {
+            if self.last_metrics.elapsed() >= METRICS_INTERVAL {
+                self.update_metrics();
+                self.last_metrics = Instant::now();
+            }
+            if self.last_summary.elapsed() >= SUMMARY_INTERVAL {
+                if let Err(e) = self.summarize() {
+                    warn!("Failed to write RFI occupancy summary - {e}");
+                }
+                self.last_summary = Instant::now();
+            }
+        }
+    }
+
+    fn update_metrics(&mut self) {
+        let total_spectra = self.all_time.spectra + self.period.spectra;
+        for chan in 0..CHANNELS {
+            let flagged = self.all_time.flagged[chan] + self.period.flagged[chan];
+            OCCUPANCY_GAUGE
+                .with_label_values(&[&chan.to_string()])
+                .set(flagged as f64 / total_spectra as f64);
+        }
+        BAND_OCCUPANCY_GAUGE.set(self.period.band_occupancy());
+        let window = std::mem::take(&mut self.window);
+        if self.mask.update(&window) {
+            info!(masked = self.mask.count(), "RFI mask changed");
+            weights::set_rfi_zapped(self.mask.masked.clone());
+        }
+        MASKED_GAUGE.set(self.mask.count() as i64);
+    }
+
+    /// Write out the summary of the current period and start a new one
+    fn summarize(&mut self) -> eyre::Result<()> {
+        let now = Epoch::now()?;
+        let summary = json!({
+            "start": self.period_start.to_string(),
+            "end": now.to_string(),
+            "spectra": self.period.spectra,
+            "band_occupancy": self.period.band_occupancy(),
+            "occupancy": (0..CHANNELS).map(|c| self.period.occupancy(c)).collect::<Vec<_>>(),
+        });
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.join(SUMMARY_FILE))?;
+        writeln!(file, "{summary}")?;
+        // Fold into the totals
+        self.all_time.spectra += self.period.spectra;
+        for (t, p) in self.all_time.flagged.iter_mut().zip(&self.period.flagged) {
+            *t += p;
+        }
+        self.period = Counts::default();
+        self.period_start = now;
+        // Write to the side and move over, so we never leave a half-written file
+        let tmp = self.path.join(format!("{TOTALS_FILE}.tmp"));
+        std::fs::write(&tmp, serde_json::to_string(&self.all_time)?)?;
+        std::fs::rename(tmp, self.path.join(TOTALS_FILE))?;
+        Ok(())
+    }
+}
+
+impl Drop for Occupancy {
+    fn drop(&mut self) {
+        // Don't lose the partial period on shutdown
+        if self.period.spectra > 0 {
+            if let Err(e) = self.summarize() {
+                warn!("Failed to write RFI occupancy summary - {e}");
+            }
+        }
+    }
+}
@@ -0,0 +1,239 @@
+//! Loopback self test, running the simulator against the capture and processing stages on localhost
+//!
+//! This exercises everything but the SNAP itself: the socket setup, packet parsing, ordering,
+//! downsampling, and the science data path (by finding a simulated pulse in the output).
+use crate::{
+    capture::{Capture, FillPolicy, SocketOptions, DEFAULT_BATCH},
+    common::{epoch_after, Payload, Stokes, CHANNELS, PACKET_CADENCE},
+    simulate::{dispersion_delays, simulate, Simulate},
+};
+use hifitime::prelude::*;
+use std::{
+    net::SocketAddr,
+    thread,
+    time::{Duration, Instant},
+};
+use thingbuf::mpsc::{
+    blocking::{channel, StaticChannel},
+    errors::RecvTimeoutError,
+};
+use tokio::sync::broadcast;
+use tracing::{error, info};
+
+/// How long to run the simulator for
+const SELF_TEST_SECONDS: f64 = 3.0;
+/// DM of the simulated pulses
+const SELF_TEST_DM: f64 = 100.0;
+/// Seconds between simulated pulses, the one we look for is the second
+const SELF_TEST_PULSE_PERIOD: f64 = 1.0;
+/// Minimum S/N of the recovered pulse
+const SELF_TEST_MIN_SNR: f64 = 10.0;
+/// How long to wait for the pipeline to drain after the simulator stops
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+const SELF_TEST_CHANNEL_SIZE: usize = 4096;
+static SELF_TEST_CAP: StaticChannel<Payload, SELF_TEST_CHANNEL_SIZE> = StaticChannel::new();
+static SELF_TEST_DUMP: StaticChannel<Payload, SELF_TEST_CHANNEL_SIZE> = StaticChannel::new();
+static SELF_TEST_DOWNSAMP: StaticChannel<Payload, SELF_TEST_CHANNEL_SIZE> = StaticChannel::new();
+
+fn check(name: &str, passed: bool, detail: String) -> bool {
+    if passed {
+        info!("PASS {name}: {detail}");
+    } else {
+        error!("FAIL {name}: {detail}");
+    }
+    passed
+}
+
+/// S/N of the dedispersed time series at `idx`, against a robust estimate of the noise
+fn snr_at(series: &[f64], idx: usize, width: usize) -> f64 {
+    let mut sorted = series.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    let mut deviations: Vec<_> = series.iter().map(|x| (x - median).abs()).collect();
+    deviations.sort_by(f64::total_cmp);
+    let sigma = 1.4826 * deviations[deviations.len() / 2];
+    let lo = idx.saturating_sub(width);
+    let hi = (idx + width).min(series.len() - 1);
+    let peak = series[lo..=hi].iter().copied().fold(f64::MIN, f64::max);
+    (peak - median) / sigma
+}
+
+/// Run the self test, returning an error if anything failed
+pub fn run(port: u16, downsample_power: u32) -> eyre::Result<()> {
+    info!("Starting loopback self test");
+    let downsample_factor = 2usize.pow(downsample_power);
+    let tsamp = PACKET_CADENCE * downsample_factor as f64;
+
+    let (sd_s, sd_cap_r) = broadcast::channel(1);
+    let sd_fanout_r = sd_s.subscribe();
+    let sd_downsamp_r = sd_s.subscribe();
+    let (cap_s, cap_r) = SELF_TEST_CAP.split();
+    let (dump_s, dump_r) = SELF_TEST_DUMP.split();
+    let (downsamp_s, downsamp_r) = SELF_TEST_DOWNSAMP.split();
+    let (ex_s, ex_r) = channel(SELF_TEST_CHANNEL_SIZE);
+    let (stat_s, stat_r) = channel(100);
+
+    // Bind before the simulator starts so we don't miss anything
+    let mut cap = Capture::new(port, &SocketOptions::default())?;
+    // Stats after every batch, so the drop count is as fresh as it can be.
+    // Capture blocks in recv, so it will never see the shutdown once the packets stop.
+    // We leave it be, as we're about to exit anyway.
+    thread::Builder::new()
+        .name("capture".to_string())
+        .spawn(move || cap.start(DEFAULT_BATCH, cap_s, stat_s, Duration::ZERO, sd_cap_r))?;
+    let fanout = thread::Builder::new()
+        .name("fanout".to_string())
+        .spawn(move || {
+            crate::processing::fanout_task(
+                cap_r,
+                downsamp_s,
+                vec![("dumps", Box::new(dump_s))],
+                sd_fanout_r,
+            )
+        })?;
+    let downsample = thread::Builder::new()
+        .name("downsample".to_string())
+        .spawn(move || {
+            crate::processing::downsample_task(
+                downsamp_r,
+                ex_s,
+                downsample_power,
+                FillPolicy::Zeros,
+                None,
+                None,
+                None,
+                sd_downsamp_r,
+            )
+        })?;
+    // The packet counts are our timestamps, so make sure they come out the other end in order.
+    // The dump tap is lossy by design, so only going backwards counts against us.
+    let timestamps = thread::Builder::new()
+        .name("dump".to_string())
+        .spawn(move || {
+            let mut out_of_order = 0usize;
+            let mut first: Option<u64> = None;
+            let mut last: Option<u64> = None;
+            loop {
+                match dump_r.recv_ref_timeout(DRAIN_TIMEOUT) {
+                    Ok(pl) => {
+                        if last.is_some_and(|l| pl.count <= l) {
+                            out_of_order += 1;
+                        }
+                        first.get_or_insert(pl.count);
+                        last = Some(pl.count);
+                    }
+                    Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Closed) => break,
+                    Err(_) => unreachable!(),
+                }
+            }
+            (first.zip(last), out_of_order)
+        })?;
+    let sim = thread::Builder::new()
+        .name("simulate".to_string())
+        .spawn(move || {
+            simulate(&Simulate {
+                target: SocketAddr::from(([127, 0, 0, 1], port)),
+                noise_std: 8.0,
+                bandpass: None,
+                pulse_dm: Some(SELF_TEST_DM),
+                pulse_period: SELF_TEST_PULSE_PERIOD,
+                pulse_width: 1e-3,
+                pulse_gain: 2.0,
+                drop_rate: 0.0,
+                reorder_rate: 0.0,
+                duration: Some(SELF_TEST_SECONDS),
+            })
+        })?;
+
+    // Dedisperse on the fly as the spectra come in
+    let shifts: Vec<_> = dispersion_delays(SELF_TEST_DM)
+        .into_iter()
+        .map(|d| (d / tsamp).round() as usize)
+        .collect();
+    let expected_spectra = (SELF_TEST_SECONDS / tsamp) as usize;
+    let mut series = vec![0f64; expected_spectra];
+    let mut spectra = 0usize;
+    let mut stats = None;
+    let mut last_spectrum = Instant::now();
+    while last_spectrum.elapsed() < DRAIN_TIMEOUT {
+        while let Ok(s) = stat_r.try_recv() {
+            stats = Some(s);
+        }
+        match ex_r.recv_ref_timeout(Duration::from_millis(100)) {
+            Ok(stokes) => {
+                let stokes: &Stokes = &stokes;
+                for (i, (power, shift)) in stokes.iter().zip(&shifts).enumerate() {
+                    // Leave the band edges out, there's nothing there
+                    if !(CHANNELS / 8..CHANNELS * 7 / 8).contains(&i) {
+                        continue;
+                    }
+                    if let Some(t) = spectra.checked_sub(*shift) {
+                        if let Some(v) = series.get_mut(t) {
+                            *v += f64::from(*power);
+                        }
+                    }
+                }
+                spectra += 1;
+                last_spectrum = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        }
+    }
+    let sent = sim.join().expect("Simulator panicked")?;
+    sd_s.send(())?;
+    fanout.join().expect("Fanout panicked")?;
+    downsample.join().expect("Downsample panicked")?;
+    let (counts, out_of_order) = timestamps.join().expect("Timestamp check panicked");
+    while let Ok(s) = stat_r.try_recv() {
+        stats = Some(s);
+    }
+
+    // And see how we did
+    let mut passed = true;
+    // Dropped packets are filled in with zeros, so everything from the first to the last count
+    // flowed through the pipeline
+    let delivered = counts.map_or(0, |(first, last)| last - first + 1);
+    let drops = stats.as_ref().map_or(0, |s| s.drops);
+    passed &= check(
+        "capture",
+        counts.is_some_and(|(first, _)| first == 0) && delivered == sent && drops == 0,
+        format!("sent {sent} packets, delivered {delivered} ({stats:?})"),
+    );
+    passed &= check(
+        "downsample",
+        spectra as u64 == delivered / downsample_factor as u64,
+        format!("{spectra} spectra from {delivered} packets at {downsample_factor}x"),
+    );
+    let span = counts.map(|(_, c)| {
+        let start = Epoch::from_gregorian_utc_at_midnight(2000, 1, 1);
+        epoch_after(&start, (c as f64 * PACKET_CADENCE).seconds()) - start
+    });
+    passed &= check(
+        "timestamps",
+        out_of_order == 0,
+        format!("{out_of_order} out of order, packets {counts:?} spanning {span:?}"),
+    );
+    let pulse_idx = (SELF_TEST_PULSE_PERIOD / tsamp).round() as usize;
+    let max_shift = shifts.iter().copied().max().unwrap_or(0);
+    let valid = spectra.saturating_sub(max_shift).min(series.len());
+    let snr = if pulse_idx < valid {
+        snr_at(&series[..valid], pulse_idx, (1e-3 / tsamp).ceil() as usize)
+    } else {
+        0.0
+    };
+    passed &= check(
+        "pulse",
+        snr >= SELF_TEST_MIN_SNR,
+        format!("recovered DM {SELF_TEST_DM} pulse at S/N {snr:.1}"),
+    );
+
+    if passed {
+        info!("Self test passed");
+        Ok(())
+    } else {
+        eyre::bail!("Self test failed")
+    }
+}
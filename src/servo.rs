@@ -0,0 +1,148 @@
+//! Slow feedback on the requant gains to ride out drifts in the input power
+//!
+//! The ADC RMS right after calibration is taken as the reference. If it later drifts by more than
+//! the window (as it does with the day/night temperature swing), we either warn the operator or
+//! scale the requant gains towards the inverse of the drift, a bounded step at a time.
+use crate::{events, fpga::Device};
+use clap::ValueEnum;
+use lazy_static::lazy_static;
+use prometheus::{register_gauge_vec, register_int_gauge_vec, GaugeVec, IntGaugeVec};
+use serde_json::json;
+use tracing::{info, warn};
+
+lazy_static! {
+    static ref OUT_OF_WINDOW_GAUGE: IntGaugeVec = register_int_gauge_vec!(
+        "adc_rms_out_of_window",
+        "Whether the ADC RMS has drifted outside the gain servo window since calibration",
+        &["channel"]
+    )
+    .unwrap();
+    static ref SERVO_FACTOR_GAUGE: GaugeVec = register_gauge_vec!(
+        "gain_servo_factor",
+        "Factor the gain servo has applied to the calibrated requant gains",
+        &["channel"]
+    )
+    .unwrap();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ServoMode {
+    /// Don't watch the input power
+    Off,
+    /// Warn when the input power drifts out of the window
+    Flag,
+    /// Adjust the requant gains to compensate for drifts
+    Adjust,
+}
+
+pub struct GainServo {
+    mode: ServoMode,
+    /// Allowed fractional deviation before we act
+    window: f64,
+    /// Largest fractional change to the gains per update
+    max_step: f64,
+    /// ADC RMS of (a, b) right after calibration
+    reference: Option<(f64, f64)>,
+    /// Requant gains right after calibration
+    base_gains: (Vec<u16>, Vec<u16>),
+    /// Factor we've scaled the base gains by
+    applied: (f64, f64),
+}
+
+impl GainServo {
+    pub fn new(mode: ServoMode, window: f64, max_step: f64) -> Self {
+        Self {
+            mode,
+            window,
+            max_step,
+            reference: None,
+            base_gains: (vec![], vec![]),
+            applied: (1.0, 1.0),
+        }
+    }
+
+    /// Forget the reference, taking the next measurement (after a recalibration) as the new one
+    pub fn reset(&mut self) {
+        self.reference = None;
+        self.applied = (1.0, 1.0);
+        SERVO_FACTOR_GAUGE.with_label_values(&["a"]).set(1.0);
+        SERVO_FACTOR_GAUGE.with_label_values(&["b"]).set(1.0);
+    }
+
+    /// Step the servo with the latest ADC RMS measurements
+    pub fn update(&mut self, device: &mut Device, rms_a: f64, rms_b: f64) -> eyre::Result<()> {
+        if self.mode == ServoMode::Off || rms_a <= 0.0 || rms_b <= 0.0 {
+            return Ok(());
+        }
+        let (ref_a, ref_b) = match self.reference {
+            Some(r) => r,
+            None => {
+                info!(rms_a, rms_b, "Gain servo reference ADC RMS");
+                self.reference = Some((rms_a, rms_b));
+                self.base_gains = device.requant_gains().clone();
+                if self.mode == ServoMode::Adjust && self.base_gains.0.is_empty() {
+                    warn!("No requant gains have been set, the gain servo will only flag drifts");
+                    self.mode = ServoMode::Flag;
+                }
+                return Ok(());
+            }
+        };
+        // How far off is the requant output, given what we've already done about it
+        let err_a = ref_a / rms_a / self.applied.0;
+        let err_b = ref_b / rms_b / self.applied.1;
+        let out_a = (err_a - 1.0).abs() > self.window;
+        let out_b = (err_b - 1.0).abs() > self.window;
+        OUT_OF_WINDOW_GAUGE
+            .with_label_values(&["a"])
+            .set(out_a.into());
+        OUT_OF_WINDOW_GAUGE
+            .with_label_values(&["b"])
+            .set(out_b.into());
+        if !(out_a || out_b) {
+            return Ok(());
+        }
+        if self.mode == ServoMode::Flag {
+            warn!(
+                rms_a,
+                rms_b, ref_a, ref_b, "ADC input power has drifted since calibration"
+            );
+            return Ok(());
+        }
+        let clamp = |err: f64| err.clamp(1.0 - self.max_step, 1.0 + self.max_step);
+        if out_a {
+            self.applied.0 *= clamp(err_a);
+        }
+        if out_b {
+            self.applied.1 *= clamp(err_b);
+        }
+        let scale = |gains: &[u16], factor: f64| -> Vec<u16> {
+            gains
+                .iter()
+                .map(|g| {
+                    (f64::from(*g) * factor)
+                        .round()
+                        .clamp(0.0, f64::from(u16::MAX)) as u16
+                })
+                .collect()
+        };
+        let a = scale(&self.base_gains.0, self.applied.0);
+        let b = scale(&self.base_gains.1, self.applied.1);
+        device.set_requant_gains(&a, &b)?;
+        SERVO_FACTOR_GAUGE
+            .with_label_values(&["a"])
+            .set(self.applied.0);
+        SERVO_FACTOR_GAUGE
+            .with_label_values(&["b"])
+            .set(self.applied.1);
+        info!(
+            factor_a = self.applied.0,
+            factor_b = self.applied.1,
+            "Gain servo adjusted requant gains"
+        );
+        events::record(
+            events::Kind::Calibration,
+            json!({ "method": "servo", "factor_a": self.applied.0, "factor_b": self.applied.1 }),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+//! A stand-in for the SNAP, sending realistic packets so the pipeline can run without hardware
+//!
+//! Generating ~122k packets of gaussian noise a second is more than a laptop can keep up with, so
+//! we draw a pool of noise spectra up front and cycle through it from random offsets. Pulses,
+//! drops, and reordering are applied on the fly.
+use crate::{
+    common::{Channel, Payload, CHANNELS, PACKET_CADENCE},
+    observation,
+};
+use clap::Args;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use std::{
+    net::{SocketAddr, UdpSocket},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// Number of distinct noise spectra we cycle through
+const NOISE_POOL_SIZE: usize = 1024;
+/// How often to report progress
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// Dispersion constant (MHz^2 s / (pc cm^-3))
+const DISPERSION_CONSTANT: f64 = 4.148808e3;
+
+#[derive(Debug, Clone, Args)]
+pub struct Simulate {
+    /// Where to send the packets (the capture port of a running T0)
+    #[arg(long, default_value = "127.0.0.1:60000")]
+    pub target: SocketAddr,
+    /// Voltage standard deviation (ADC counts) at the peak of the bandpass
+    #[arg(long, default_value_t = 8.0)]
+    pub noise_std: f64,
+    /// Text file of per-channel relative gains (one per line), replacing the default bandpass
+    #[arg(long)]
+    pub bandpass: Option<PathBuf>,
+    /// DM (pc cm^-3) of simulated pulses (no pulses if unset)
+    #[arg(long)]
+    pub pulse_dm: Option<f64>,
+    /// Seconds between simulated pulses
+    #[arg(long, default_value_t = 10.0)]
+    pub pulse_period: f64,
+    /// Width of the simulated pulses (seconds)
+    #[arg(long, default_value_t = 1e-3)]
+    pub pulse_width: f64,
+    /// Voltage gain applied to the noise during a pulse
+    #[arg(long, default_value_t = 2.0)]
+    pub pulse_gain: f64,
+    /// Fraction of packets to drop
+    #[arg(long, default_value_t = 0.0)]
+    pub drop_rate: f64,
+    /// Fraction of packets to swap with the packet after them
+    #[arg(long, default_value_t = 0.0)]
+    pub reorder_rate: f64,
+    /// Stop after this many seconds (runs forever if unset)
+    #[arg(long)]
+    pub duration: Option<f64>,
+}
+
+impl Default for Simulate {
+    fn default() -> Self {
+        Self {
+            target: SocketAddr::from(([127, 0, 0, 1], 60000)),
+            noise_std: 8.0,
+            bandpass: None,
+            pulse_dm: None,
+            pulse_period: 10.0,
+            pulse_width: 1e-3,
+            pulse_gain: 2.0,
+            drop_rate: 0.0,
+            reorder_rate: 0.0,
+            duration: None,
+        }
+    }
+}
+
+/// Something like the real bandpass: flat-ish with a rolloff into the band edges
+fn default_bandpass() -> Vec<f64> {
+    (0..CHANNELS)
+        .map(|i| {
+            let x = (i as f64 + 0.5) / CHANNELS as f64;
+            let edge = |d: f64| 1.0 / (1.0 + (-(d - 0.08) / 0.02).exp());
+            (0.8 + 0.2 * (std::f64::consts::PI * x).sin()) * edge(x) * edge(1.0 - x)
+        })
+        .collect()
+}
+
+fn read_bandpass(path: &PathBuf) -> eyre::Result<Vec<f64>> {
+    let gains = std::fs::read_to_string(path)?
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| l.trim().parse::<f64>())
+        .collect::<Result<Vec<_>, _>>()?;
+    if gains.len() != CHANNELS {
+        eyre::bail!("Bandpass has {} channels, expected {CHANNELS}", gains.len());
+    }
+    Ok(gains)
+}
+
+fn noise_pool(bandpass: &[f64], std: f64) -> eyre::Result<Vec<[[Channel; CHANNELS]; 2]>> {
+    let mut rng = rand::thread_rng();
+    let unit = Normal::new(0.0, 1.0)?;
+    let mut draw = |gain: f64| {
+        (unit.sample(&mut rng) * std * gain)
+            .round()
+            .clamp(-127.0, 127.0) as i8
+    };
+    Ok((0..NOISE_POOL_SIZE)
+        .map(|_| {
+            let mut pols = [[Channel::new(0, 0); CHANNELS]; 2];
+            for pol in pols.iter_mut() {
+                for (chan, gain) in pol.iter_mut().zip(bandpass) {
+                    *chan = Channel::new(draw(*gain), draw(*gain));
+                }
+            }
+            pols
+        })
+        .collect())
+}
+
+/// Per-channel arrival delay (seconds) of a pulse with this DM, relative to the top of the band
+pub fn dispersion_delays(dm: f64) -> Vec<f64> {
+    let obs = observation::current();
+    let top = obs.top_freq();
+    (0..CHANNELS)
+        .map(|i| {
+            let f = obs.freq(i);
+            DISPERSION_CONSTANT * dm * (f.powi(-2) - top.powi(-2))
+        })
+        .collect()
+}
+
+fn scale(chan: &mut Channel, gain: f64) {
+    chan.0.re = (chan.0.re as f64 * gain).round().clamp(-127.0, 127.0) as i8;
+    chan.0.im = (chan.0.im as f64 * gain).round().clamp(-127.0, 127.0) as i8;
+}
+
+/// Makes simulated payloads, paced to the real packet cadence
+pub struct Generator {
+    pool: Vec<[[Channel; CHANNELS]; 2]>,
+    pool_idx: usize,
+    delays: Option<Vec<f64>>,
+    pulse_packets: u64,
+    pulse_width: f64,
+    pulse_gain: f64,
+    drop_rate: f64,
+    reorder_rate: f64,
+    /// Packets to make in total, if we stop at all
+    total: Option<u64>,
+    rng: StdRng,
+    /// A payload we're holding back to send after the next
+    held: Option<Payload>,
+    /// A held payload whose turn has come
+    ready: Option<Payload>,
+    /// Count of the next payload
+    count: u64,
+    start: Instant,
+    behind: bool,
+}
+
+impl Generator {
+    pub fn new(args: &Simulate) -> eyre::Result<Self> {
+        let bandpass = match &args.bandpass {
+            Some(p) => read_bandpass(p)?,
+            None => default_bandpass(),
+        };
+        info!("Generating noise");
+        Ok(Self {
+            pool: noise_pool(&bandpass, args.noise_std)?,
+            pool_idx: 0,
+            delays: args.pulse_dm.map(dispersion_delays),
+            pulse_packets: (args.pulse_period / PACKET_CADENCE).round().max(1.0) as u64,
+            pulse_width: args.pulse_width,
+            pulse_gain: args.pulse_gain,
+            drop_rate: args.drop_rate,
+            reorder_rate: args.reorder_rate,
+            total: args.duration.map(|d| (d / PACKET_CADENCE) as u64),
+            rng: StdRng::from_entropy(),
+            held: None,
+            ready: None,
+            count: 0,
+            start: Instant::now(),
+            behind: false,
+        })
+    }
+
+    /// If we've made every packet we were going to
+    pub fn finished(&self) -> bool {
+        self.held.is_none() && self.ready.is_none() && self.total.is_some_and(|t| self.count >= t)
+    }
+
+    /// Make the next payload, when it's due
+    fn make(&mut self) -> Payload {
+        // Keep to the real cadence
+        let due = self.start + Duration::from_secs_f64(self.count as f64 * PACKET_CADENCE);
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        } else if !self.behind && now - due > Duration::from_millis(100) {
+            warn!("Simulator can't keep up with the real packet cadence");
+            self.behind = true;
+        }
+
+        // Start with a chunk of noise, jumping around the pool every so often so it doesn't repeat
+        if self.pool_idx.is_multiple_of(NOISE_POOL_SIZE) {
+            self.pool_idx = self.rng.gen_range(0..NOISE_POOL_SIZE);
+        }
+        let [pol_a, pol_b] = self.pool[self.pool_idx % NOISE_POOL_SIZE];
+        self.pool_idx += 1;
+        let mut payload = Payload {
+            count: self.count,
+            pol_a,
+            pol_b,
+            ..Default::default()
+        };
+
+        // Brighten the channels that the dispersed pulse is passing through
+        if let Some(delays) = &self.delays {
+            let t = (self.count % self.pulse_packets) as f64 * PACKET_CADENCE;
+            for (i, delay) in delays.iter().enumerate() {
+                if (t - delay).abs() < self.pulse_width / 2.0 {
+                    scale(&mut payload.pol_a[i], self.pulse_gain);
+                    scale(&mut payload.pol_b[i], self.pulse_gain);
+                }
+            }
+        }
+        self.count += 1;
+        payload
+    }
+
+    /// Fill up to `payloads.len()` payloads as they come due (dropping and reordering as asked),
+    /// returning how many we made (fewer once we're finished)
+    pub fn fill(&mut self, payloads: &mut [Payload]) -> usize {
+        let mut got = 0;
+        while got < payloads.len() {
+            if let Some(pl) = self.ready.take() {
+                payloads[got] = pl;
+                got += 1;
+                continue;
+            }
+            if self.total.is_some_and(|t| self.count >= t) {
+                // Nothing left to go out after the held one
+                if let Some(pl) = self.held.take() {
+                    payloads[got] = pl;
+                    got += 1;
+                }
+                break;
+            }
+            let payload = self.make();
+            if self.rng.gen_bool(self.drop_rate) {
+                continue;
+            }
+            // Hold on to this one so it goes out after the next
+            if self.held.is_none() && self.rng.gen_bool(self.reorder_rate) {
+                self.held = Some(payload);
+                continue;
+            }
+            payloads[got] = payload;
+            got += 1;
+            self.ready = self.held.take();
+        }
+        got
+    }
+}
+
+/// Run the simulator until `duration` elapses (or forever), returning how many packets we sent
+pub fn simulate(args: &Simulate) -> eyre::Result<u64> {
+    let mut generator = Generator::new(args)?;
+    let sock = UdpSocket::bind("0.0.0.0:0")?;
+    sock.connect(args.target)?;
+    info!("Sending simulated packets to {}", args.target);
+
+    let mut payloads = [Payload::default()];
+    let mut sent = 0u64;
+    let start = Instant::now();
+    let mut last_report = start;
+    while !generator.finished() {
+        if generator.fill(&mut payloads) == 0 {
+            continue;
+        }
+        sock.send(payloads[0].as_packet())?;
+        sent += 1;
+        if last_report.elapsed() >= REPORT_INTERVAL {
+            info!(
+                "Sent {sent} packets ({:.0} per second)",
+                sent as f64 / start.elapsed().as_secs_f64()
+            );
+            last_report = Instant::now();
+        }
+    }
+    info!("Simulation finished after {sent} packets");
+    Ok(sent)
+}
@@ -0,0 +1,211 @@
+//! Runtime state saved as we go, so `--resume` can pick a crashed run back up
+//!
+//! The SNAP keeps sending packets when we go down, so as long as it's still running the design as
+//! we set it up, there's no need to reset and re-trigger it (and lose the original timeline). We
+//! periodically save the time sync, packet count bookkeeping, cumulative capture counters, gains,
+//! and the filterbank each sink is writing. Resuming restores all of that, and capture carries on from the
+//! next spectrum of those filterbanks, so the outage is filled in like any other dropped packets and
+//! the file picks up where it left off. A clean shutdown resets the SNAP, so there's nothing to
+//! resume after one.
+use crate::{
+    calibrate,
+    capture::{Stats, COUNT_OFFSET, FIRST_PACKET, LAST_PACKET},
+    common::CHANNELS,
+    timing::{self, TimeSource},
+};
+use hifitime::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    sync::{atomic::Ordering, Mutex, OnceLock},
+    time::Duration,
+};
+use tokio::{sync::broadcast, time::interval};
+use tracing::{info, warn};
+
+/// Everything we need to rejoin the packet stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct State {
+    /// When packet count 0 went out
+    pub packet_start: Epoch,
+    pub time_source: TimeSource,
+    pub time_uncertainty: Option<f64>,
+    pub first_packet: u64,
+    pub count_offset: u64,
+    /// The most recent packet we'd captured
+    pub last_packet: u64,
+    /// Cumulative capture counters
+    pub stats: Stats,
+    pub requant_gains: (Vec<u16>, Vec<u16>),
+    pub adc_gain: Option<u8>,
+    pub calibrated: Option<Epoch>,
+    /// The filterbanks we were in the middle of, by the name of the sink writing each
+    #[serde(default)]
+    pub filterbanks: BTreeMap<String, OpenFilterbank>,
+    /// When this was saved
+    pub saved: Epoch,
+}
+
+/// A filterbank that's being written, and enough about it to carry on appending
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OpenFilterbank {
+    pub path: PathBuf,
+    /// Time of the first spectrum
+    pub start: Epoch,
+    /// Size of the header (bytes)
+    pub header_len: u64,
+    /// Number of packets that go into each spectrum
+    pub packets_per_spectrum: u64,
+    /// Stokes parameters in each spectrum
+    #[serde(default = "stokes_i_only")]
+    pub npol: usize,
+    /// Packet count of the first spectrum (the run's first packet if we didn't save it)
+    #[serde(default)]
+    pub first_count: Option<u64>,
+}
+
+fn stokes_i_only() -> usize {
+    1
+}
+
+impl OpenFilterbank {
+    /// Number of whole spectra in the file (we write 32-bit samples)
+    pub fn spectra(&self) -> eyre::Result<u64> {
+        let len = std::fs::metadata(&self.path)?.len();
+        Ok(len.saturating_sub(self.header_len) / (self.npol * CHANNELS * 4) as u64)
+    }
+}
+
+impl State {
+    /// The packet count to carry on from: the next spectrum that all the open filterbanks have on
+    /// disk (on a spectrum boundary of the coarsest, so they all carry on in step), or just after
+    /// the last packet we saw
+    pub fn next_count(&self) -> u64 {
+        let written = || {
+            let coarsest = self
+                .filterbanks
+                .values()
+                .max_by_key(|fb| fb.packets_per_spectrum)?;
+            let mut end = u64::MAX;
+            for fb in self.filterbanks.values() {
+                end = end.min(self.first_count(fb) + fb.spectra().ok()? * fb.packets_per_spectrum);
+            }
+            let start = self.first_count(coarsest);
+            let packets = end.saturating_sub(start);
+            Some(start + packets / coarsest.packets_per_spectrum * coarsest.packets_per_spectrum)
+        };
+        written().unwrap_or(self.last_packet + 1)
+    }
+
+    /// Packet count of the first spectrum in `fb`
+    pub fn first_count(&self, fb: &OpenFilterbank) -> u64 {
+        fb.first_count.unwrap_or(self.first_packet)
+    }
+
+    /// How many of the spectra in `fb` to keep, so it carries on from [`Self::next_count`]
+    pub fn spectra_to_keep(&self, fb: &OpenFilterbank) -> u64 {
+        self.next_count().saturating_sub(self.first_count(fb)) / fb.packets_per_spectrum
+    }
+}
+
+/// The latest capture statistics
+static STATS: Mutex<Option<Stats>> = Mutex::new(None);
+/// The filterbanks being written right now, by sink
+static FILTERBANKS: Mutex<BTreeMap<String, OpenFilterbank>> = Mutex::new(BTreeMap::new());
+/// What we resumed from, if we did
+static RESUMED: OnceLock<State> = OnceLock::new();
+
+/// Note the latest capture statistics
+#[allow(clippy::missing_panics_doc)]
+pub fn record_stats(stats: &Stats) {
+    *STATS.lock().unwrap() = Some(stats.clone());
+}
+
+/// Note the filterbank the `sink` is writing (or that it's finished with it)
+#[allow(clippy::missing_panics_doc)]
+pub fn record_filterbank(sink: &str, fb: Option<OpenFilterbank>) {
+    let mut filterbanks = FILTERBANKS.lock().unwrap();
+    match fb {
+        Some(fb) => filterbanks.insert(sink.to_owned(), fb),
+        None => filterbanks.remove(sink),
+    };
+}
+
+/// The state we resumed from, if this run is picking up from a previous one
+pub fn resumed() -> Option<&'static State> {
+    RESUMED.get()
+}
+
+/// Load the state at `path` to resume from
+pub fn resume(path: &Path) -> eyre::Result<&'static State> {
+    let state: State = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    info!(saved = %state.saved, "Resuming from {}", path.display());
+    Ok(RESUMED.get_or_init(|| state))
+}
+
+/// The state as it stands, if we've gotten far enough to have any
+#[allow(clippy::missing_panics_doc)]
+pub fn snapshot() -> eyre::Result<Option<State>> {
+    let Some(sync) = timing::time_sync() else {
+        return Ok(None);
+    };
+    let cal = calibrate::state();
+    // If capture hasn't reported yet this run, we still have what we resumed from
+    let stats = STATS
+        .lock()
+        .unwrap()
+        .clone()
+        .or_else(|| resumed().map(|s| s.stats.clone()))
+        .unwrap_or_default();
+    Ok(Some(State {
+        packet_start: sync.trigger_epoch,
+        time_source: sync.source.clone(),
+        time_uncertainty: sync.uncertainty,
+        first_packet: FIRST_PACKET.load(Ordering::Acquire),
+        count_offset: COUNT_OFFSET.load(Ordering::Acquire),
+        last_packet: LAST_PACKET.load(Ordering::Acquire),
+        stats,
+        requant_gains: cal.gains,
+        adc_gain: cal.adc_gain,
+        calibrated: cal.calibrated,
+        filterbanks: FILTERBANKS.lock().unwrap().clone(),
+        saved: Epoch::now()?,
+    }))
+}
+
+/// Save the current state to `path`
+pub fn save(path: &Path) -> eyre::Result<()> {
+    let Some(state) = snapshot()? else {
+        return Ok(());
+    };
+    // Write to the side and move over, so a crash mid-save doesn't lose the last good one
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, serde_json::to_string_pretty(&state)?)?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Save the state to `path` every `period` until shutdown
+pub async fn save_task(
+    path: Option<PathBuf>,
+    period: Duration,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    info!("Saving pipeline state to {}", path.display());
+    let mut ticker = interval(period);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                if let Err(e) = save(&path) {
+                    warn!("Failed to save pipeline state - {e}");
+                }
+            }
+            _ = shutdown.recv() => break,
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,114 @@
+//! Heartbeats between T0 and the downstream T2 detection machine
+//!
+//! The protocol is intentionally tiny: every `HEARTBEAT_INTERVAL`, T0 sends the ASCII datagram
+//! `GREX_T0 <seq>` to T2, and T2 is expected to send `GREX_T2 <seq> <consuming>` back to our
+//! heartbeat port, where `<consuming>` is `1` if it is actively reading the DADA stream.
+//! We consider T2 connected if we've heard a consuming heartbeat within `HEARTBEAT_TIMEOUT`.
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use std::{
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::{
+    net::UdpSocket,
+    sync::broadcast,
+    time::{interval, Instant},
+};
+use tracing::{info, warn};
+
+/// How often we send heartbeats
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long we'll go without hearing from T2 before considering it gone
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether T2 is currently consuming our data. If we're not talking to T2 at all, assume it is.
+static T2_CONNECTED: AtomicBool = AtomicBool::new(true);
+
+lazy_static! {
+    static ref T2_CONNECTED_GAUGE: IntGauge = register_int_gauge!(
+        "t2_connected",
+        "Whether the T2 detection machine is alive and consuming the DADA stream"
+    )
+    .unwrap();
+    pub static ref T2_PAUSED_SPECTRA: IntCounter = register_int_counter!(
+        "t2_paused_spectra",
+        "Number of spectra discarded while T2 wasn't consuming the DADA stream"
+    )
+    .unwrap();
+}
+
+/// Is T2 (or whoever is on the other end of DADA) consuming data?
+pub fn is_connected() -> bool {
+    T2_CONNECTED.load(Ordering::Acquire)
+}
+
+fn set_connected(connected: bool) {
+    if T2_CONNECTED.swap(connected, Ordering::AcqRel) != connected {
+        if connected {
+            info!("T2 is consuming");
+        } else {
+            warn!("Lost contact with T2");
+        }
+    }
+    T2_CONNECTED_GAUGE.set(connected.into());
+}
+
+/// Parse a T2 heartbeat, returning whether it says it's consuming
+fn parse_heartbeat(msg: &[u8]) -> Option<bool> {
+    let msg = std::str::from_utf8(msg).ok()?;
+    let mut fields = msg.split_whitespace();
+    if fields.next()? != "GREX_T2" {
+        return None;
+    }
+    let _seq: u64 = fields.next()?.parse().ok()?;
+    Some(fields.next()? == "1")
+}
+
+pub async fn heartbeat_task(
+    t2_addr: Option<SocketAddr>,
+    port: u16,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    let t2_addr = match t2_addr {
+        Some(a) => a,
+        None => return Ok(()),
+    };
+    info!("Starting T2 heartbeat task!");
+    // Until we hear otherwise, T2 isn't there
+    set_connected(false);
+    let sock = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    let mut ticker = interval(HEARTBEAT_INTERVAL);
+    let mut seq = 0u64;
+    let mut last_heard: Option<Instant> = None;
+    let mut buf = [0u8; 64];
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("T2 heartbeat task stopping");
+                break;
+            }
+            _ = ticker.tick() => {
+                if let Err(e) = sock.send_to(format!("GREX_T0 {seq}").as_bytes(), t2_addr).await {
+                    warn!("Failed to send heartbeat to T2 - {e}");
+                }
+                seq += 1;
+                let alive = last_heard.is_some_and(|t| t.elapsed() < HEARTBEAT_TIMEOUT);
+                if !alive {
+                    set_connected(false);
+                }
+            }
+            Ok((n, _)) = sock.recv_from(&mut buf) => {
+                match parse_heartbeat(&buf[..n]) {
+                    Some(consuming) => {
+                        last_heard = Some(Instant::now());
+                        set_connected(consuming);
+                    }
+                    None => warn!("Malformed heartbeat from T2"),
+                }
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,265 @@
+//! Keeping track of how well our timestamps line up with real time over long observations
+//!
+//! At startup we map packet counts to UTC via a single time sync. Over days, the FPGA's sample clock
+//! and that initial sync can wander, so we periodically compare the count of the packet we're
+//! receiving right now against NTP-corrected host time, and apply the difference to the timestamps
+//! we write into data products.
+use crate::{
+    capture::{FIRST_PACKET, LAST_PACKET},
+    common::{epoch_after, PACKET_CADENCE},
+};
+use hifitime::prelude::*;
+use lazy_static::lazy_static;
+use prometheus::{register_gauge, Gauge};
+use rsntp::SntpClient;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+};
+use tokio::{sync::broadcast, time::interval};
+use tracing::{debug, info, warn};
+
+lazy_static! {
+    static ref NTP_OFFSET_GAUGE: Gauge = register_gauge!(
+        "ntp_offset_seconds",
+        "Offset of the host clock from NTP (true time = host time + offset)"
+    )
+    .unwrap();
+    static ref NTP_DELAY_GAUGE: Gauge =
+        register_gauge!("ntp_delay_seconds", "Round trip delay of the last NTP sync").unwrap();
+    static ref DRIFT_GAUGE: Gauge = register_gauge!(
+        "timestamp_drift_ms",
+        "Host time less the uncorrected timestamp of the packet arriving now, in milliseconds"
+    )
+    .unwrap();
+    static ref CORRECTION_GAUGE: Gauge = register_gauge!(
+        "timestamp_correction_seconds",
+        "Correction applied to packet-count-derived timestamps to match NTP-corrected time"
+    )
+    .unwrap();
+    static ref CORRECTION_STEP_GAUGE: Gauge = register_gauge!(
+        "timestamp_correction_step_seconds",
+        "How much the timestamp correction moved at the last NTP sync"
+    )
+    .unwrap();
+}
+
+/// Where we got the time that `packet_start` is based on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TimeSource {
+    /// Synchronized against an NTP server, with the measured host clock offset (seconds)
+    Ntp { server: String, offset: f64 },
+    /// Timed by the PPS edge reported by a GPS receiver
+    Gps(String),
+    /// Triggered against the unsynchronized host clock
+    Blind,
+}
+
+impl Display for TimeSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TimeSource::Ntp { server, .. } => write!(f, "ntp:{server}"),
+            TimeSource::Gps(src) => write!(f, "gps:{src}"),
+            TimeSource::Blind => write!(f, "blind"),
+        }
+    }
+}
+
+/// Provenance of the time sync behind all our timestamps
+#[derive(Debug, Clone)]
+pub struct TimeSync {
+    pub source: TimeSource,
+    /// Estimated uncertainty of the trigger epoch (seconds), if we have any idea
+    pub uncertainty: Option<f64>,
+    /// The time of the PPS edge packets started on
+    pub trigger_epoch: Epoch,
+}
+
+/// `t` as an ISO 8601 UTC timestamp
+#[allow(clippy::missing_panics_doc)]
+pub fn iso8601(t: Epoch) -> String {
+    let fmt = Format::from_str("%Y-%m-%dT%H:%M:%S.%f").unwrap();
+    format!("{}Z", Formatter::in_time_scale(t, fmt, TimeScale::UTC))
+}
+
+impl TimeSync {
+    /// Key-value pairs describing the time sync, in the style of a DADA header
+    pub fn header_entries(&self) -> Vec<(String, String)> {
+        let mut entries = vec![
+            ("TIME_SOURCE".to_owned(), self.source.to_string()),
+            (
+                "TIME_UNCERTAINTY".to_owned(),
+                self.uncertainty
+                    .map_or("unknown".to_owned(), |u| u.to_string()),
+            ),
+            (
+                "TRIGGER_MJD".to_owned(),
+                self.trigger_epoch.to_mjd_utc_days().to_string(),
+            ),
+        ];
+        if let TimeSource::Ntp { offset, .. } = self.source {
+            entries.push(("NTP_OFFSET".to_owned(), offset.to_string()));
+        }
+        entries
+    }
+
+    /// The packet start epoch (packet count 0) in the forms our consumers use, along with the count
+    /// of the first packet we actually got and where the time came from
+    #[allow(clippy::missing_panics_doc)]
+    pub fn start_time(&self) -> serde_json::Value {
+        let start = self.trigger_epoch;
+        let first_packet = FIRST_PACKET.load(Ordering::Acquire);
+        let first_packet_time =
+            epoch_after(&start, (first_packet as f64 * PACKET_CADENCE).seconds()) + correction();
+        let ntp_offset = match self.source {
+            TimeSource::Ntp { offset, .. } => Some(offset),
+            _ => None,
+        };
+        json!({
+            "utc": iso8601(start),
+            "mjd_utc": start.to_mjd_utc_days(),
+            "mjd_tai": start.to_mjd_tai_days(),
+            "unix_seconds": start.to_unix_seconds(),
+            "first_packet": first_packet,
+            "first_packet_utc": iso8601(first_packet_time),
+            "packet_cadence": PACKET_CADENCE,
+            "correction_seconds": correction().to_seconds(),
+            "time_source": self.source.to_string(),
+            "time_uncertainty": self.uncertainty,
+            "ntp_offset": ntp_offset,
+        })
+    }
+}
+
+static TIME_SYNC: OnceLock<TimeSync> = OnceLock::new();
+
+/// Record the provenance of our time sync, once we've triggered
+pub fn set_time_sync(sync: TimeSync) {
+    if TIME_SYNC.set(sync).is_err() {
+        warn!("Time sync provenance was already set");
+    }
+}
+
+/// The provenance of our time sync, if we've triggered
+pub fn time_sync() -> Option<&'static TimeSync> {
+    TIME_SYNC.get()
+}
+
+/// The current timestamp correction in seconds, stored as `f64` bits
+static CORRECTION: AtomicU64 = AtomicU64::new(0);
+
+/// The correction to add to timestamps derived from `packet_start` and payload counts
+pub fn correction() -> Duration {
+    f64::from_bits(CORRECTION.load(Ordering::Acquire)).seconds()
+}
+
+/// Compare the count of the packet that's arriving right now with NTP-corrected host time
+fn measure_correction(packet_start: Epoch, ntp_offset: f64) -> eyre::Result<f64> {
+    let count = LAST_PACKET.load(Ordering::Acquire);
+    let now = Epoch::now()? + ntp_offset.seconds();
+    let expected = epoch_after(&packet_start, (count as f64 * PACKET_CADENCE).seconds());
+    Ok((now - expected).to_seconds())
+}
+
+/// Periodically resynchronize with NTP and update the timestamp correction
+pub async fn ntp_monitor_task(
+    ntp_addr: String,
+    packet_start: Epoch,
+    period: std::time::Duration,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    if period.is_zero() {
+        return Ok(());
+    }
+    info!("Starting NTP monitoring task!");
+    let mut ticker = interval(period);
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("NTP monitoring task stopping");
+                break;
+            }
+            _ = ticker.tick() => {
+                let addr = ntp_addr.clone();
+                let sync = tokio::task::spawn_blocking(move || SntpClient::new().synchronize(addr)).await?;
+                let result = match sync {
+                    Ok(r) => r,
+                    Err(e) => {
+                        warn!("NTP sync failed - {e}");
+                        continue;
+                    }
+                };
+                let offset = result.clock_offset().as_secs_f64();
+                NTP_OFFSET_GAUGE.set(offset);
+                NTP_DELAY_GAUGE.set(result.round_trip_delay().as_secs_f64());
+                // No packets yet, nothing to compare against
+                if LAST_PACKET.load(Ordering::Acquire) == 0 {
+                    continue;
+                }
+                let corr = measure_correction(packet_start, offset)?;
+                let step = corr - f64::from_bits(CORRECTION.swap(corr.to_bits(), Ordering::AcqRel));
+                debug!("NTP offset {offset} s, timestamp correction {corr} s (moved {step} s)");
+                CORRECTION_GAUGE.set(corr);
+                CORRECTION_STEP_GAUGE.set(step);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Every `period`, compare the count of the packet arriving now with the one we'd expect from the
+/// host clock and `packet_start`, warning when they're more than `threshold` apart. This leaves
+/// out the correction, which follows the drift at every NTP sync and would otherwise hide it. It
+/// doesn't change the timestamps, it just catches a bad PPS or clock early.
+pub async fn drift_monitor_task(
+    packet_start: Epoch,
+    period: std::time::Duration,
+    threshold: std::time::Duration,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    if period.is_zero() {
+        return Ok(());
+    }
+    info!("Starting timing drift monitoring task!");
+    let threshold_ms = threshold.as_secs_f64() * 1e3;
+    let mut ticker = interval(period);
+    let mut last_count = 0;
+    let mut drifting = false;
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("Timing drift monitoring task stopping");
+                break;
+            }
+            _ = ticker.tick() => {
+                let count = LAST_PACKET.load(Ordering::Acquire);
+                // Without new packets, the last one's count says nothing about now
+                if count == last_count {
+                    continue;
+                }
+                last_count = count;
+                let expected =
+                    epoch_after(&packet_start, (count as f64 * PACKET_CADENCE).seconds());
+                let drift_ms = (Epoch::now()? - expected).to_seconds() * 1e3;
+                DRIFT_GAUGE.set(drift_ms);
+                let over = drift_ms.abs() > threshold_ms;
+                if over && !drifting {
+                    warn!(
+                        count,
+                        "Packet timestamps are {drift_ms:.3} ms off the host clock, check the PPS and NTP"
+                    );
+                } else if !over && drifting {
+                    info!(count, "Packet timestamps are back within {threshold_ms} ms of the host clock");
+                }
+                drifting = over;
+            }
+        }
+    }
+    Ok(())
+}
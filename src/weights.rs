@@ -0,0 +1,256 @@
+//! Per-channel weights for the Stokes data products
+//!
+//! A channel gets a weight of zero if it's in the band-edge mask (`--band-mask`), if the bandpass
+//! calibration left it dead (a requant gain of zero or saturated in either polarization), or if the
+//! RFI flagger has masked it for being occupied too often or too noisy. Each filterbank gets a companion
+//! `.weights` file listing these, so downstream dedispersion can de-weight channels without
+//! re-deriving the mask. The same weights are kept up to date as a Heimdall kill file (one 0 or 1
+//! per line, 0 to zap) and served from `/kill_file`, so detection zaps what we're seeing as bad.
+use crate::{common::CHANNELS, observation};
+use std::{
+    fmt::Display,
+    io::Write,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock, RwLock,
+    },
+};
+use tracing::{info, warn};
+
+/// Channels full of aliasing artifacts at the edges of the band, with our original analog setup
+pub const DEFAULT_BAND_MASK: &str = "0-250,1797-2047";
+/// (Averaged) fraction of the time a channel has to be occupied before the RFI flagger zaps it
+pub const RFI_ZAP_OCCUPANCY: f64 = 0.5;
+
+struct Flags {
+    cal_dead: Vec<bool>,
+    rfi: Vec<bool>,
+}
+
+/// Ranges of channels (inclusive) that are zeroed in the Stokes data products, written as
+/// comma-separated `start-end` pairs (or single channels), or `none`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BandMask(pub Vec<RangeInclusive<usize>>);
+
+impl Default for BandMask {
+    fn default() -> Self {
+        DEFAULT_BAND_MASK.parse().unwrap()
+    }
+}
+
+impl FromStr for BandMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() || s.trim() == "none" {
+            return Ok(Self(vec![]));
+        }
+        s.split(',')
+            .map(|range| {
+                let range = range.trim();
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                let parse = |c: &str| {
+                    c.trim()
+                        .parse::<usize>()
+                        .map_err(|_| format!("Bad channel {c:?} in the band mask"))
+                };
+                let (start, end) = (parse(start)?, parse(end)?);
+                if start > end || end >= CHANNELS {
+                    return Err(format!(
+                        "Bad channel range {range} in the band mask (channels go 0-{})",
+                        CHANNELS - 1
+                    ));
+                }
+                Ok(start..=end)
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl Display for BandMask {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        let ranges: Vec<_> = self
+            .0
+            .iter()
+            .map(|r| format!("{}-{}", r.start(), r.end()))
+            .collect();
+        write!(f, "{}", ranges.join(","))
+    }
+}
+
+impl BandMask {
+    pub fn contains(&self, chan: usize) -> bool {
+        self.0.iter().any(|r| r.contains(&chan))
+    }
+
+    /// Zero the masked channels of a spectrum
+    pub fn apply(&self, spectrum: &mut [f32]) {
+        for range in &self.0 {
+            spectrum[range.clone()].fill(0.0);
+        }
+    }
+
+    /// The channels that aren't masked
+    pub fn unmasked(&self) -> Vec<usize> {
+        (0..CHANNELS).filter(|&c| !self.contains(c)).collect()
+    }
+}
+
+static BAND_MASK: OnceLock<BandMask> = OnceLock::new();
+
+/// Set which channels are masked, before anything that uses the mask starts
+pub fn set_band_mask(mask: BandMask) {
+    info!(%mask, "Masking band edges");
+    if BAND_MASK.set(mask).is_err() {
+        warn!("The band mask was already set, keeping the first one");
+    }
+}
+
+/// The channels masked at the edges of the band
+pub fn band_mask() -> &'static BandMask {
+    BAND_MASK.get_or_init(BandMask::default)
+}
+
+static FLAGS: RwLock<Option<Flags>> = RwLock::new(None);
+/// Bumped every time the flags change, so writers can cheaply check for updates
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+/// Where we keep the Heimdall kill file, if anywhere
+static KILL_FILE: OnceLock<PathBuf> = OnceLock::new();
+
+fn modify(f: impl FnOnce(&mut Flags) -> bool) {
+    let changed = f(FLAGS.write().unwrap().get_or_insert_with(|| Flags {
+        cal_dead: vec![false; CHANNELS],
+        rfi: vec![false; CHANNELS],
+    }));
+    if !changed {
+        return;
+    }
+    GENERATION.fetch_add(1, Ordering::AcqRel);
+    if let Some(path) = KILL_FILE.get() {
+        if let Err(e) = write_kill_file(path) {
+            warn!("Failed to update the kill file - {e}");
+        }
+    }
+}
+
+/// Mark the channels the bandpass calibration couldn't make sense of, from the requant gains
+pub fn set_calibration_dead(a_gain: &[u16], b_gain: &[u16]) {
+    let dead: Vec<_> = a_gain
+        .iter()
+        .zip(b_gain)
+        .map(|(a, b)| [a, b].iter().any(|g| **g == 0 || **g == u16::MAX))
+        .collect();
+    let n = dead.iter().filter(|d| **d).count();
+    if n > 0 {
+        info!(n, "Calibration left channels dead, zero weighting them");
+    }
+    modify(|flags| {
+        let changed = flags.cal_dead != dead;
+        flags.cal_dead = dead;
+        changed
+    });
+}
+
+/// Update the channels the RFI flagger considers unusable
+pub fn set_rfi_zapped(zapped: Vec<bool>) {
+    debug_assert_eq!(zapped.len(), CHANNELS);
+    modify(|flags| {
+        let changed = flags.rfi != zapped;
+        flags.rfi = zapped;
+        changed
+    });
+}
+
+/// A number that changes whenever the weights do
+pub fn generation() -> u64 {
+    GENERATION.load(Ordering::Acquire)
+}
+
+/// The current weight (0 or 1) of every channel, in filterbank order
+#[allow(clippy::missing_panics_doc)]
+pub fn current() -> Vec<f32> {
+    let flags = FLAGS.read().unwrap();
+    let mask = band_mask();
+    (0..CHANNELS)
+        .map(|chan| {
+            let masked = mask.contains(chan)
+                || flags
+                    .as_ref()
+                    .is_some_and(|f| f.cal_dead[chan] || f.rfi[chan]);
+            if masked {
+                0.0
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+/// The current weights as a Heimdall kill file, one line per channel with 0 to zap it
+pub fn kill_file() -> String {
+    current()
+        .into_iter()
+        .map(|w| if w > 0.0 { "1\n" } else { "0\n" })
+        .collect()
+}
+
+fn write_kill_file(path: &Path) -> eyre::Result<()> {
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, kill_file())?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Write the kill file to `path` now, and again whenever the weights change
+pub fn keep_kill_file(path: &Path) -> eyre::Result<()> {
+    write_kill_file(path)?;
+    KILL_FILE
+        .set(path.to_owned())
+        .map_err(|_| eyre::eyre!("Already keeping a kill file"))?;
+    info!("Keeping the Heimdall kill file at {}", path.display());
+    Ok(())
+}
+
+/// Where the weights for a given filterbank go
+pub fn companion_path(fil: &Path) -> PathBuf {
+    fil.with_extension("weights")
+}
+
+/// Read back weights written by [`write`]
+pub fn read(path: &Path) -> eyre::Result<Vec<f32>> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| {
+            l.split_whitespace()
+                .nth(2)
+                .ok_or_else(|| eyre::eyre!("Malformed weights line {l:?}"))?
+                .parse()
+                .map_err(Into::into)
+        })
+        .collect()
+}
+
+/// Write out the weights as lines of `channel frequency(MHz) weight`
+///
+/// Written to the side and moved over, so a reader never sees a half-written table.
+pub fn write(path: &Path, weights: &[f32]) -> eyre::Result<()> {
+    let tmp = path.with_extension("weights.tmp");
+    let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp)?);
+    writeln!(file, "# channel freq_mhz weight")?;
+    let obs = observation::current();
+    for (chan, w) in weights.iter().enumerate() {
+        let freq = obs.freq(chan);
+        writeln!(file, "{chan} {freq:.6} {w}")?;
+    }
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(tmp, path)?;
+    Ok(())
+}